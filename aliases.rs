@@ -0,0 +1,62 @@
+//! Friendly names for GPUs, by uuid or PCI address, exported as the `alias` label
+
+use prometheus::proto::{LabelPair, MetricFamily};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::{Result, GPU_LABELS};
+
+#[derive(Clone)]
+pub struct Aliases(HashMap<String, String>);
+
+impl Aliases {
+    /// A YAML mapping of uuids or PCI addresses to aliases, e.g. `GPU-…: render-left`
+    pub fn load(path: &str) -> Result<Aliases> {
+        let path = Path::new(path);
+        let aliases: HashMap<String, String> =
+            serde_yaml::from_str(&std::fs::read_to_string(path)?)
+                .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+        let aliases = aliases.into_iter().map(|(gpu, alias)| (key(&gpu), alias));
+        Ok(Aliases(aliases.collect()))
+    }
+
+    /// Label the series of GPUs with an alias with it
+    pub fn label(&self, families: &mut [MetricFamily]) {
+        for metric in families
+            .iter_mut()
+            .flat_map(|mf| mf.mut_metric().iter_mut())
+        {
+            let alias = metric
+                .get_label()
+                .iter()
+                .filter(|l| [GPU_LABELS[0], GPU_LABELS[2]].contains(&l.get_name()))
+                .find_map(|l| self.0.get(&key(l.get_value())));
+            if let Some(alias) = alias {
+                let mut label = LabelPair::default();
+                label.set_name("alias".into());
+                label.set_value(alias.clone());
+                metric.mut_label().push(label);
+            }
+        }
+    }
+}
+
+/// PCI addresses as NVML has them, domain padded to 8 digits and in lower case, so that
+/// `01:00.0` and `0000:01:00.0` find `00000000:01:00.0`
+pub fn key(gpu: &str) -> String {
+    if gpu.starts_with("GPU-") || gpu.starts_with("MIG-") {
+        return gpu.to_owned();
+    }
+    let gpu = gpu.to_lowercase();
+    match gpu.matches(':').count() {
+        1 => format!("00000000:{gpu}"),
+        2 => {
+            let (domain, rest) = gpu.split_once(':').unwrap_or_default();
+            match u32::from_str_radix(domain, 16) {
+                Ok(domain) => format!("{domain:08x}:{rest}"),
+                Err(_) => gpu,
+            }
+        }
+        _ => gpu,
+    }
+}
@@ -0,0 +1,221 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Request, Response};
+use tracing::warn;
+
+use crate::Result;
+
+/// Client addresses remembered before forgetting those that haven't failed in a minute
+const MAX_CLIENTS: usize = 1024;
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// HTTP basic authentication against bcrypt-hashed passwords
+pub struct BasicAuth {
+    users: HashMap<String, String>,
+    /// Checked for unknown users, not to give away which ones exist by answering quicker
+    dummy: String,
+    /// Those allowed to use the admin API
+    admins: HashSet<String>,
+    // bcrypt is deliberately slow, so remember credentials that already passed, and whose they
+    // are
+    verified: Mutex<HashMap<String, String>>,
+    /// Failed attempts per minute and client address, after which its credentials aren't
+    /// checked at all, as every check keeps the serving thread busy
+    max_failures: Option<usize>,
+    /// When each client's attempts in the last minute failed
+    failures: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+}
+
+impl BasicAuth {
+    /// Takes a map of user names to bcrypt hashes
+    pub fn new(users: HashMap<String, String>) -> BasicAuth {
+        // As slow as the slowest of the real ones
+        let cost = users
+            .values()
+            .filter_map(|hash| hash.parse::<bcrypt::HashParts>().ok())
+            .map(|parts| parts.get_cost())
+            .max()
+            .unwrap_or(bcrypt::DEFAULT_COST);
+        let dummy = match users.is_empty() {
+            true => String::new(),
+            false => bcrypt::hash("", cost).unwrap_or_default(),
+        };
+        BasicAuth {
+            users,
+            dummy,
+            admins: HashSet::new(),
+            verified: Mutex::new(HashMap::new()),
+            max_failures: None,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Stop checking the credentials of clients that failed `per_minute` times in the last minute
+    pub fn with_failure_limit(mut self, per_minute: usize) -> BasicAuth {
+        self.max_failures = Some(per_minute);
+        self
+    }
+
+    /// Let these users, and only them, use the admin API
+    pub fn with_admins(mut self, admins: &[String]) -> Result<BasicAuth> {
+        for admin in admins {
+            if !self.users.contains_key(admin) {
+                return Err(format!("Admin user {admin} has no basic auth password").into());
+            }
+        }
+        self.admins = admins.iter().cloned().collect();
+        Ok(self)
+    }
+
+    /// Whether any users may use the admin API
+    pub fn admins(&self) -> bool {
+        !self.admins.is_empty()
+    }
+
+    /// Parse `user:bcrypt-hash` entries
+    pub fn parse_users(entries: &[String]) -> Result<HashMap<String, String>> {
+        Ok(entries
+            .iter()
+            .map(|entry| match entry.split_once(':') {
+                Some((user, hash)) => Ok((user.to_owned(), hash.to_owned())),
+                None => Err(format!(
+                    "Basic auth entry {:?} is not of the form user:hash",
+                    entry
+                )),
+            })
+            .collect::<std::result::Result<_, _>>()?)
+    }
+
+    /// Whether the request may proceed. Always true if no users are configured.
+    pub fn check(&self, request: &Request) -> bool {
+        self.users.is_empty() || self.user(request).is_some()
+    }
+
+    /// Whether the request is from an admin user
+    pub fn admin(&self, request: &Request) -> bool {
+        self.user(request)
+            .is_some_and(|user| self.admins.contains(&user))
+    }
+
+    /// Who sent the request, if their password is right
+    fn user(&self, request: &Request) -> Option<String> {
+        let credentials = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .and_then(|h| h.value.as_str().strip_prefix("Basic "))?;
+        let mut verified = self.verified.lock().unwrap();
+        if let Some(user) = verified.get(credentials) {
+            return Some(user.clone());
+        }
+        let remote = request.remote_addr().map(|addr| addr.ip());
+        if remote.is_some_and(|remote| !self.may_try(remote)) {
+            warn!(remote = ?request.remote_addr(), "Not checking credentials, too many failed");
+            return None;
+        }
+        let (user, password) = BASE64
+            .decode(credentials)
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                let (user, password) = decoded.split_once(':')?;
+                Some((user.to_owned(), password.to_owned()))
+            })?;
+        if !self.verify(&user, &password) {
+            if let Some(remote) = remote {
+                self.failed(remote);
+            }
+            return None;
+        }
+        verified.insert(credentials.to_owned(), user.clone());
+        Some(user)
+    }
+
+    /// Whether the client hasn't failed too often lately
+    fn may_try(&self, remote: IpAddr) -> bool {
+        let Some(max) = self.max_failures else {
+            return true;
+        };
+        let mut failures = self.failures.lock().unwrap();
+        let Some(times) = failures.get_mut(&remote) else {
+            return true;
+        };
+        while times.front().is_some_and(|at| at.elapsed() >= MINUTE) {
+            times.pop_front();
+        }
+        times.len() < max
+    }
+
+    fn failed(&self, remote: IpAddr) {
+        if self.max_failures.is_none() {
+            return;
+        }
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_CLIENTS {
+            failures.retain(|_, times| times.back().is_some_and(|at| at.elapsed() < MINUTE));
+        }
+        failures
+            .entry(remote)
+            .or_default()
+            .push_back(Instant::now());
+    }
+
+    fn verify(&self, user: &str, password: &str) -> bool {
+        match self.users.get(user) {
+            Some(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            None => {
+                bcrypt::verify(password, &self.dummy).ok();
+                false
+            }
+        }
+    }
+}
+
+pub fn unauthorized(request: Request) -> Result<()> {
+    let response = Response::from_string("Unauthorized\n")
+        .with_status_code(401)
+        .with_header(Header::from_bytes("WWW-Authenticate", "Basic realm=\"nvml\"").unwrap());
+    crate::http::respond(request, response)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let auth = BasicAuth::new(HashMap::from([("alice".to_owned(), hash)]));
+        assert!(auth.dummy.starts_with("$2b$04$"));
+        assert!(auth.verify("alice", "secret"));
+        assert!(!auth.verify("alice", "wrong"));
+        assert!(!auth.verify("bob", "secret"));
+        assert!(!auth.verify("bob", ""));
+        assert!(!auth.admins());
+        let auth = auth.with_admins(&["alice".to_owned()]).unwrap();
+        assert!(auth.admins());
+        assert!(auth.with_admins(&["bob".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn failure_limit() {
+        let auth = BasicAuth::new(HashMap::new()).with_failure_limit(2);
+        let (client, other) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        auth.failed(client);
+        assert!(auth.may_try(client));
+        auth.failed(client);
+        assert!(!auth.may_try(client));
+        assert!(auth.may_try(other));
+        // A minute later
+        for at in auth.failures.lock().unwrap().get_mut(&client).unwrap() {
+            *at -= MINUTE;
+        }
+        assert!(auth.may_try(client));
+    }
+}
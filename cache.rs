@@ -0,0 +1,81 @@
+//! Serving scrapes from the last collection for a while, for nodes with many GPUs scraped by
+//! several Prometheus replicas, with `?fresh=1` collecting anew regardless
+
+use prometheus::proto::MetricFamily;
+use std::time::{Duration, Instant, SystemTime};
+
+pub struct Cache {
+    ttl: Duration,
+    /// When it was collected, for the metric timestamps, and when that was on the monotonic
+    /// clock, for its age
+    last: Option<(SystemTime, Instant, Vec<MetricFamily>)>,
+}
+
+impl Cache {
+    pub fn new(ttl: Duration) -> Cache {
+        Cache { ttl, last: None }
+    }
+
+    /// The last collection and when it was made, unless it's older than the TTL, or a fresh one
+    /// was asked for
+    pub fn get(&self, fresh: bool, now: Instant) -> Option<(SystemTime, Vec<MetricFamily>)> {
+        let (collected, at, families) = self.last.as_ref().filter(|_| !fresh)?;
+        (now.saturating_duration_since(*at) < self.ttl).then(|| (*collected, families.clone()))
+    }
+
+    pub fn store(&mut self, collected: SystemTime, at: Instant, families: &[MetricFamily]) {
+        self.last = Some((collected, at, families.to_vec()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn family(name: &str) -> Vec<MetricFamily> {
+        let mut family = MetricFamily::default();
+        family.set_name(name.into());
+        vec![family]
+    }
+
+    fn name(cached: Option<(SystemTime, Vec<MetricFamily>)>) -> Option<String> {
+        cached.map(|(_, families)| families[0].get_name().to_owned())
+    }
+
+    #[test]
+    fn empty() {
+        let cache = Cache::new(Duration::from_secs(10));
+        assert!(cache.get(false, Instant::now()).is_none());
+    }
+
+    #[test]
+    fn within_ttl() {
+        let mut cache = Cache::new(Duration::from_secs(10));
+        let at = Instant::now();
+        cache.store(SystemTime::UNIX_EPOCH, at, &family("a"));
+        let cached = cache.get(false, at + Duration::from_secs(9));
+        assert_eq!(
+            cached.as_ref().map(|(collected, _)| *collected),
+            Some(SystemTime::UNIX_EPOCH)
+        );
+        assert_eq!(name(cached).as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn expired() {
+        let mut cache = Cache::new(Duration::from_secs(10));
+        let at = Instant::now();
+        cache.store(SystemTime::now(), at, &family("a"));
+        assert!(cache.get(false, at + Duration::from_secs(10)).is_none());
+    }
+
+    #[test]
+    fn fresh() {
+        let mut cache = Cache::new(Duration::from_secs(10));
+        let at = Instant::now();
+        cache.store(SystemTime::now(), at, &family("a"));
+        assert!(cache.get(true, at).is_none());
+        cache.store(SystemTime::now(), at, &family("b"));
+        assert_eq!(name(cache.get(false, at)).as_deref(), Some("b"));
+    }
+}
@@ -0,0 +1,119 @@
+//! What a device's driver can be asked, by its version, so that queries newer drivers added are
+//! skipped on older ones, in favor of their fallbacks, rather than failing on every collection.
+//! Mixed fleets run the same exporter against whatever driver each host has.
+
+use crate::gpu::Gpu;
+
+/// Major and minor number of a driver version, e.g. 535.104 of `535.104.05`
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct DriverVersion(pub u32, pub u32);
+
+impl DriverVersion {
+    pub fn parse(version: &str) -> Option<DriverVersion> {
+        let mut numbers = version.trim().split('.');
+        let major = numbers.next()?.parse().ok()?;
+        let minor = numbers.next().map_or(Some(0), |minor| minor.parse().ok())?;
+        Some(DriverVersion(major, minor))
+    }
+}
+
+/// Queries not every driver has
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Feature {
+    /// `nvmlDeviceGetRemappedRows`, which supersedes retired pages from Ampere on
+    RemappedRows,
+    /// GPU performance monitoring (`nvmlGpmMetricsGet`), of Hopper and later
+    Gpm,
+    /// `nvmlDeviceGetGpuFabricInfo`, how far the GPU got joining an NVLink fabric of NVSwitches
+    FabricInfo,
+    /// `nvmlDeviceGetC2cModeInfoV`, whether the chip-to-chip link to a Grace CPU is on
+    C2cMode,
+}
+
+impl Feature {
+    /// The first driver that has it
+    pub fn since(self) -> DriverVersion {
+        match self {
+            Feature::RemappedRows => DriverVersion(450, 0),
+            Feature::Gpm => DriverVersion(520, 0),
+            Feature::FabricInfo => DriverVersion(525, 0),
+            Feature::C2cMode => DriverVersion(550, 0),
+        }
+    }
+}
+
+/// What a device's driver has
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Capabilities {
+    driver: Option<DriverVersion>,
+}
+
+impl Capabilities {
+    pub fn probe(gpu: &dyn Gpu) -> Capabilities {
+        let driver = gpu.driver_version().ok();
+        Capabilities {
+            driver: driver.as_deref().and_then(DriverVersion::parse),
+        }
+    }
+
+    pub fn driver(&self) -> Option<DriverVersion> {
+        self.driver
+    }
+
+    /// Whether the driver is new enough for the feature. The GPU may still not support it. If
+    /// the driver's version is unknown, the feature is worth a try.
+    pub fn has(&self, feature: Feature) -> bool {
+        self.driver.is_none_or(|driver| driver >= feature.since())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let versions = [
+            ("535.104.05", Some(DriverVersion(535, 104))),
+            ("550.54.15\n", Some(DriverVersion(550, 54))),
+            ("450", Some(DriverVersion(450, 0))),
+            ("r450", None),
+            ("", None),
+        ];
+        for (version, parsed) in versions {
+            assert_eq!(DriverVersion::parse(version), parsed, "{version:?}");
+        }
+    }
+
+    #[test]
+    fn cutoffs() {
+        let features = [
+            Feature::RemappedRows,
+            Feature::Gpm,
+            Feature::FabricInfo,
+            Feature::C2cMode,
+        ];
+        for feature in features {
+            let DriverVersion(major, _) = feature.since();
+            let driver = |major, minor| Capabilities {
+                driver: Some(DriverVersion(major, minor)),
+            };
+            assert!(!driver(major - 1, 999).has(feature), "{feature:?}");
+            assert!(driver(major, 0).has(feature), "{feature:?}");
+            assert!(driver(major + 1, 0).has(feature), "{feature:?}");
+            assert!(Capabilities::default().has(feature), "{feature:?}");
+        }
+        assert!(!driver_of("440.118.02").has(Feature::RemappedRows));
+        assert!(driver_of("450.51.05").has(Feature::RemappedRows));
+        assert!(!driver_of("515.65.01").has(Feature::Gpm));
+        assert!(driver_of("520.61.05").has(Feature::Gpm));
+        assert!(!driver_of("545.23.08").has(Feature::C2cMode));
+        assert!(driver_of("550.54.15").has(Feature::C2cMode));
+    }
+
+    fn driver_of(version: &str) -> Capabilities {
+        Capabilities {
+            driver: DriverVersion::parse(version),
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! Who a process belongs to, from its cgroup: the container, the Kubernetes pod running it, or
+//! the Slurm job
+//!
+//! PIDs are as NVML reports them, in the host's PID namespace, so in a container this needs the
+//! host's /proc (hostPID).
+
+use std::path::Path;
+
+/// Where the kubelet keeps pod logs, in directories named after the pods
+const POD_LOG_DIR: &str = "/var/log/pods";
+
+/// Empty where unknown, so the labels are left out
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Attribution {
+    pub container: String,
+    pub pod: String,
+    pub namespace: String,
+    pub slurm_job_id: String,
+}
+
+impl Attribution {
+    pub fn of(pid: u32) -> Attribution {
+        match std::fs::read_to_string(format!("/proc/{pid}/cgroup")) {
+            Ok(cgroups) => Attribution::from_cgroups(&cgroups),
+            Err(_) => Attribution::default(),
+        }
+    }
+
+    /// From the contents of /proc/<pid>/cgroup
+    fn from_cgroups(cgroups: &str) -> Attribution {
+        let paths = cgroups
+            .lines()
+            .filter_map(|line| line.splitn(3, ':').nth(2))
+            .collect::<Vec<_>>();
+        let slurm_job_id = paths.iter().find_map(|path| slurm_job_id(path));
+        let slurm_job_id = slurm_job_id.unwrap_or_default();
+        // The unified hierarchy if there is one, otherwise any that names a container
+        let Some(path) = paths.iter().find(|path| container_id(path).is_some()) else {
+            return Attribution {
+                slurm_job_id,
+                ..Default::default()
+            };
+        };
+        let container = container_id(path).unwrap_or_default();
+        let (pod, namespace) = match pod_uid(path) {
+            Some(uid) => match pod_name(&uid) {
+                Some((namespace, pod)) => (pod, namespace),
+                None => (uid, String::new()),
+            },
+            None => Default::default(),
+        };
+        Attribution {
+            // The short form docker and crictl show
+            container: container.chars().take(12).collect(),
+            pod,
+            namespace,
+            slurm_job_id,
+        }
+    }
+}
+
+/// From slurm/uid_<uid>/job_<id>/… (cgroup v1) or slurmstepd.scope/job_<id>/… (v2)
+fn slurm_job_id(path: &str) -> Option<String> {
+    if !path.contains("slurm") {
+        return None;
+    }
+    path.split('/').find_map(|component| {
+        let id = component.strip_prefix("job_")?;
+        (!id.is_empty() && id.bytes().all(|b| b.is_ascii_digit())).then(|| id.to_owned())
+    })
+}
+
+/// The last 64 hex digit component, minus runtime prefixes and the systemd .scope suffix, as in
+/// docker-<id>.scope, cri-containerd-<id>.scope, crio-<id>.scope, or /docker/<id>
+fn container_id(path: &str) -> Option<String> {
+    path.rsplit('/').find_map(|component| {
+        let id = component.strip_suffix(".scope").unwrap_or(component);
+        let id = id.rsplit('-').next()?;
+        (id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit())).then(|| id.to_owned())
+    })
+}
+
+/// From kubepods-burstable-pod<uid>.slice (systemd driver, with _ for -) or pod<uid> (cgroupfs)
+fn pod_uid(path: &str) -> Option<String> {
+    path.split('/').find_map(|component| {
+        let pod = component.strip_suffix(".slice").unwrap_or(component);
+        let uid = &pod[pod.rfind("pod")? + 3..];
+        (uid.len() == 36).then(|| uid.replace('_', "-"))
+    })
+}
+
+/// Namespace and name of a pod, from the kubelet's log directory <namespace>_<name>_<uid>
+fn pod_name(uid: &str) -> Option<(String, String)> {
+    std::fs::read_dir(Path::new(POD_LOG_DIR))
+        .ok()?
+        .flatten()
+        .find_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let mut parts = name.splitn(3, '_');
+            let (namespace, pod) = (parts.next()?, parts.next()?);
+            (parts.next()? == uid).then(|| (namespace.to_owned(), pod.to_owned()))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "3f2b1c9d8e7a6b5c4d3e2f1a0b9c8d7e6f5a4b3c2d1e0f9a8b7c6d5e4f3a2b1c";
+    const UID: &str = "6b0e54a3-7c1d-4f2e-9a8b-1c2d3e4f5a6b";
+
+    /// A /proc/<pid>/cgroup, and the container, pod, and job it's attributed to
+    fn cases() -> Vec<(String, &'static str, &'static str, &'static str)> {
+        let uid_ = UID.replace('-', "_");
+        vec![
+            // Processes on the host
+            (
+                "0::/user.slice/user-1000.slice/session-3.scope\n".into(),
+                "",
+                "",
+                "",
+            ),
+            (
+                "12:memory:/user.slice\n11:devices:/user.slice\n0::/user.slice\n".into(),
+                "",
+                "",
+                "",
+            ),
+            // Docker, cgroupfs driver, v1
+            (
+                format!("12:memory:/docker/{ID}\n11:devices:/docker/{ID}\n1:name=systemd:/docker/{ID}\n"),
+                ID,
+                "",
+                "",
+            ),
+            // Docker, systemd driver, v2
+            (format!("0::/system.slice/docker-{ID}.scope\n"), ID, "", ""),
+            // Podman, rootless
+            (
+                format!("0::/user.slice/user-1000.slice/user@1000.service/user.slice/libpod-{ID}.scope\n"),
+                ID,
+                "",
+                "",
+            ),
+            // Kubernetes, cgroupfs driver, v1
+            (
+                format!("11:devices:/kubepods/burstable/pod{UID}/{ID}\n4:memory:/kubepods/burstable/pod{UID}/{ID}\n"),
+                ID,
+                UID,
+                "",
+            ),
+            // Kubernetes, containerd with the systemd driver, v2
+            (
+                format!("0::/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod{uid_}.slice/cri-containerd-{ID}.scope\n"),
+                ID,
+                UID,
+                "",
+            ),
+            // Kubernetes, cri-o with the systemd driver, v1 and v2
+            (
+                format!("9:devices:/kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod{uid_}.slice/crio-{ID}.scope\n"),
+                ID,
+                UID,
+                "",
+            ),
+            (
+                format!("0::/kubepods.slice/kubepods-pod{uid_}.slice/crio-conmon-{ID}.scope\n"),
+                ID,
+                UID,
+                "",
+            ),
+            // Slurm, v1 and v2
+            (
+                "7:devices:/slurm/uid_1000/job_4242/step_0/task_0\n4:memory:/slurm/uid_1000/job_4242/step_0/task_0\n".into(),
+                "",
+                "",
+                "4242",
+            ),
+            (
+                "0::/system.slice/slurmstepd.scope/job_4242/step_batch/user/task_0\n".into(),
+                "",
+                "",
+                "4242",
+            ),
+            // Only in Slurm's hierarchy
+            (
+                "0::/system.slice/job_4242.scope\n".into(),
+                "",
+                "",
+                "",
+            ),
+        ]
+    }
+
+    #[test]
+    fn from_cgroups() {
+        for (cgroups, container, pod, slurm_job_id) in cases() {
+            assert_eq!(
+                Attribution::from_cgroups(&cgroups),
+                Attribution {
+                    container: container.chars().take(12).collect(),
+                    pod: pod.into(),
+                    namespace: String::new(),
+                    slurm_job_id: slurm_job_id.into(),
+                },
+                "{cgroups}"
+            );
+        }
+    }
+
+    #[test]
+    fn components() {
+        let uid_ = UID.replace('-', "_");
+        let paths = [
+            (format!("/docker/{ID}"), Some(ID), None),
+            (format!("/system.slice/docker-{ID}.scope"), Some(ID), None),
+            (
+                format!("/kubepods/besteffort/pod{UID}/{ID}"),
+                Some(ID),
+                Some(UID),
+            ),
+            (
+                format!("/kubepods.slice/kubepods-pod{uid_}.slice"),
+                None,
+                Some(UID),
+            ),
+            // Too short for an ID
+            ("/system.slice/docker-3f2b1c9d8e7a.scope".into(), None, None),
+            ("/system.slice/podman.service".into(), None, None),
+        ];
+        for (path, container, pod) in paths {
+            assert_eq!(container_id(&path).as_deref(), container, "{path}");
+            assert_eq!(pod_uid(&path).as_deref(), pod, "{path}");
+        }
+        assert_eq!(
+            slurm_job_id("/slurm/uid_0/job_17/step_0").as_deref(),
+            Some("17")
+        );
+        assert_eq!(slurm_job_id("/slurm/uid_0/job_/step_0"), None);
+        assert_eq!(slurm_job_id("/slurm/uid_0/job_17x"), None);
+        assert_eq!(slurm_job_id("/user.slice/job_17"), None);
+    }
+}
@@ -0,0 +1,137 @@
+//! Identifying cloud instances from their providers' metadata services
+
+use prometheus::{IntGauge, Opts};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::Result;
+
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Provider {
+    /// Try each provider in turn
+    Auto,
+    Aws,
+    Gcp,
+    Azure,
+}
+
+struct Instance {
+    provider: &'static str,
+    id: String,
+    kind: String,
+    zone: String,
+}
+
+type Query = fn(&ureq::Agent) -> Result<Instance>;
+
+fn agent() -> ureq::Agent {
+    // Link local, answers quickly if at all
+    ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(1))
+        .build()
+}
+
+fn aws(agent: &ureq::Agent) -> Result<Instance> {
+    const BASE: &str = "http://169.254.169.254/latest";
+    // IMDSv2
+    let token = agent
+        .put(&format!("{BASE}/api/token"))
+        .set("X-aws-ec2-metadata-token-ttl-seconds", "60")
+        .call()?
+        .into_string()?;
+    let get = |path: &str| -> Result<String> {
+        Ok(agent
+            .get(&format!("{BASE}/meta-data/{path}"))
+            .set("X-aws-ec2-metadata-token", &token)
+            .call()?
+            .into_string()?)
+    };
+    Ok(Instance {
+        provider: "aws",
+        id: get("instance-id")?,
+        kind: get("instance-type")?,
+        zone: get("placement/availability-zone")?,
+    })
+}
+
+fn gcp(agent: &ureq::Agent) -> Result<Instance> {
+    let get = |path: &str| -> Result<String> {
+        let value = agent
+            .get(&format!(
+                "http://metadata.google.internal/computeMetadata/v1/instance/{path}"
+            ))
+            .set("Metadata-Flavor", "Google")
+            .call()?
+            .into_string()?;
+        // machine-type and zone are projects/<number>/<kind>/<name>
+        Ok(value.rsplit('/').next().unwrap_or_default().to_owned())
+    };
+    Ok(Instance {
+        provider: "gcp",
+        id: get("id")?,
+        kind: get("machine-type")?,
+        zone: get("zone")?,
+    })
+}
+
+fn azure(agent: &ureq::Agent) -> Result<Instance> {
+    let compute: serde_json::Value = serde_json::from_str(
+        &agent
+            .get("http://169.254.169.254/metadata/instance/compute?api-version=2021-02-01")
+            .set("Metadata", "true")
+            .call()?
+            .into_string()?,
+    )?;
+    let get = |key: &str| compute[key].as_str().unwrap_or_default().to_owned();
+    let zone = match get("zone").as_str() {
+        "" => get("location"),
+        zone => format!("{}-{}", get("location"), zone),
+    };
+    Ok(Instance {
+        provider: "azure",
+        id: get("vmId"),
+        kind: get("vmSize"),
+        zone,
+    })
+}
+
+/// Query the metadata service and register `nvml_exporter_cloud_info` with the default registry.
+/// Failures are logged, as the exporter works just as well without.
+pub fn register(provider: Provider) {
+    let agent = agent();
+    let providers: &[(Provider, Query)] = &[
+        (Provider::Aws, aws),
+        (Provider::Gcp, gcp),
+        (Provider::Azure, azure),
+    ];
+    let instance = providers
+        .iter()
+        .filter(|(p, _)| provider == Provider::Auto || provider == *p)
+        .find_map(|(_, query)| {
+            query(&agent)
+                .inspect_err(|e| debug!("No instance metadata: {}", e))
+                .ok()
+        });
+    let Some(instance) = instance else {
+        warn!("Failed to get cloud instance metadata");
+        return;
+    };
+    info!(
+        provider = instance.provider,
+        id = instance.id,
+        kind = instance.kind,
+        zone = instance.zone,
+        "Found cloud instance"
+    );
+    let opts = Opts::new(
+        "nvml_exporter_cloud_info",
+        "Cloud instance the exporter runs on",
+    )
+    .const_label("provider", instance.provider)
+    .const_label("instance_id", instance.id)
+    .const_label("instance_type", instance.kind)
+    .const_label("zone", instance.zone);
+    let info = IntGauge::with_opts(opts).unwrap();
+    info.set(1);
+    prometheus::register(Box::new(info)).ok();
+}
@@ -0,0 +1,172 @@
+use nvml_wrapper::enum_wrappers::device::TemperatureSensor;
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, IntCounterVec};
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::sync::Mutex;
+
+use super::DeviceCollector;
+use crate::{gauge_vec, MetricDevice, Result, GPU_LABELS};
+
+/// Bursty signals, by metric name prefix, and how to read them
+const SIGNALS: [Signal; 3] = [
+    Signal {
+        name: "nvml_power_usage_mw",
+        help: "Power usage (mW)",
+        read: |dev| Some(dev.gpu().power_usage().ok()? as f64),
+        // Not checked by the power collector either
+        valid: 0. ..=f64::INFINITY,
+        unit: 1.,
+    },
+    Signal {
+        name: "nvml_temp",
+        help: "Temperature degC",
+        read: |dev| Some(dev.gpu().temperature(TemperatureSensor::Gpu).ok()? as f64),
+        valid: 1. ..=150.,
+        unit: 1.,
+    },
+    Signal {
+        name: "nvml_utilization_gpu",
+        help: "GPU utilization (0-1)",
+        read: |dev| Some(dev.gpu().utilization_rates().ok()?.gpu as f64),
+        valid: 0. ..=100.,
+        unit: 100.,
+    },
+];
+
+struct Signal {
+    name: &'static str,
+    help: &'static str,
+    /// The raw reading, as the main collectors get it
+    read: fn(&MetricDevice) -> Option<f64>,
+    /// Of the raw reading, as the main collectors check it, see [`MetricDevice::gauge`]
+    valid: RangeInclusive<f64>,
+    /// Raw readings per exported unit
+    unit: f64,
+}
+
+impl Signal {
+    /// The reading, if it is plausible
+    fn sample(&self, dev: &MetricDevice) -> Option<f64> {
+        let value = dev.gauge(self.name, (self.read)(dev)?, self.valid.clone())?;
+        Some(value / self.unit)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Window {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u32,
+}
+
+impl Window {
+    fn add(window: &mut Option<Window>, value: f64) {
+        let window = window.get_or_insert(Window {
+            min: value,
+            max: value,
+            sum: 0.,
+            count: 0,
+        });
+        window.min = window.min.min(value);
+        window.max = window.max.max(value);
+        window.sum += value;
+        window.count += 1;
+    }
+}
+
+struct Gauges {
+    min: GaugeVec,
+    max: GaugeVec,
+    avg: GaugeVec,
+}
+
+/// Minimum, maximum, and average of the readings taken since the previous collection, so spikes
+/// between scrapes aren't lost
+pub struct Aggregates {
+    gauges: Vec<Gauges>,
+    /// By device uuid, one per signal
+    windows: Mutex<HashMap<String, [Option<Window>; SIGNALS.len()]>>,
+}
+
+impl Default for Aggregates {
+    fn default() -> Self {
+        let gauges = SIGNALS
+            .iter()
+            .map(|Signal { name, help, .. }| {
+                let gauge = |kind: &str| {
+                    gauge_vec(
+                        &format!("{name}_{kind}"),
+                        &format!("{help}, {kind} since the previous scrape"),
+                        &GPU_LABELS,
+                    )
+                };
+                Gauges {
+                    min: gauge("min"),
+                    max: gauge("max"),
+                    avg: gauge("avg"),
+                }
+            })
+            .collect();
+        Aggregates {
+            gauges,
+            windows: Default::default(),
+        }
+    }
+}
+
+impl DeviceCollector for Aggregates {
+    fn name(&self) -> &'static str {
+        "aggregates"
+    }
+    fn metrics(&self) -> Vec<(&'static str, &dyn Collector)> {
+        self.gauges
+            .iter()
+            .flat_map(|g| [&g.min, &g.max, &g.avg])
+            .map(|g| ("gauge", g as &dyn Collector))
+            .collect()
+    }
+    fn supported(&self, dev: &MetricDevice) -> bool {
+        SIGNALS.iter().any(|signal| (signal.read)(dev).is_some())
+    }
+    fn reset(&self) {
+        for g in &self.gauges {
+            g.min.reset();
+            g.max.reset();
+            g.avg.reset();
+        }
+    }
+    fn forget(&self, uuid: &str) {
+        self.windows.lock().unwrap().remove(uuid);
+    }
+    fn sample(&self, dev: &MetricDevice) {
+        let mut windows = self.windows.lock().unwrap();
+        let windows = windows.entry(dev.uuid().to_owned()).or_default();
+        for (signal, window) in SIGNALS.iter().zip(windows) {
+            if let Some(value) = signal.sample(dev) {
+                Window::add(window, value);
+            }
+        }
+    }
+    fn update(&self, dev: &MetricDevice, _: &IntCounterVec) -> Result<()> {
+        // The current reading counts too, and is all there is without sampling
+        self.sample(dev);
+        let windows = self.windows.lock().unwrap().remove(dev.uuid());
+        for (g, window) in self.gauges.iter().zip(windows.unwrap_or_default()) {
+            let Some(window) = window else {
+                continue;
+            };
+            g.min
+                .get_metric_with_label_values(&dev.labels())?
+                .set(window.min);
+            g.max
+                .get_metric_with_label_values(&dev.labels())?
+                .set(window.max);
+            g.avg
+                .get_metric_with_label_values(&dev.labels())?
+                .set(window.sum / window.count as f64);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+use prometheus::core::Collector;
+use prometheus::{IntCounterVec, IntGaugeVec};
+
+use super::{available, gated, DeviceCollector, PerDevice};
+use crate::capabilities::Feature;
+use crate::{int_gauge_vec, MetricDevice, Result, GPU_LABELS};
+
+/// The chip-to-chip link between the GPU and CPU of Grace Hopper
+pub struct C2c {
+    pub enabled: PerDevice<IntGaugeVec>,
+}
+
+impl Default for C2c {
+    fn default() -> Self {
+        C2c {
+            enabled: PerDevice::new(int_gauge_vec(
+                "nvml_c2c_enabled",
+                "Whether the NVLink-C2C link to the CPU is enabled (0/1)",
+                &GPU_LABELS,
+            )),
+        }
+    }
+}
+
+fn c2c_mode(dev: &MetricDevice) -> std::result::Result<bool, nvml_wrapper::error::NvmlError> {
+    gated(dev, Feature::C2cMode, || dev.gpu().c2c_mode())
+}
+
+impl DeviceCollector for C2c {
+    fn name(&self) -> &'static str {
+        "c2c"
+    }
+    fn metrics(&self) -> Vec<(&'static str, &dyn Collector)> {
+        vec![("gauge", &self.enabled)]
+    }
+    fn supported(&self, dev: &MetricDevice) -> bool {
+        available(&c2c_mode(dev))
+    }
+    fn forget(&self, uuid: &str) {
+        self.enabled.forget(uuid);
+    }
+    fn update(&self, dev: &MetricDevice, errors: &IntCounterVec) -> Result<()> {
+        let enabled = dev.query(errors, "c2c_mode", c2c_mode(dev))?;
+        self.enabled.get(dev)?.set(enabled.into());
+        Ok(())
+    }
+}
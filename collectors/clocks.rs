@@ -0,0 +1,61 @@
+use nvml_wrapper::enum_wrappers::device::Clock;
+use nvml_wrapper::error::NvmlError;
+use prometheus::core::Collector;
+use prometheus::{IntCounterVec, IntGaugeVec};
+
+use super::{supported, DeviceCollector, PerDevice};
+use crate::gpu::CLOCKS;
+use crate::{int_gauge_vec, MetricDevice, Result, GPU_LABELS};
+
+/// Current clock frequencies, by domain
+pub struct Clocks {
+    pub clock: PerDevice<IntGaugeVec>,
+}
+
+impl Default for Clocks {
+    fn default() -> Self {
+        Clocks {
+            clock: PerDevice::new(int_gauge_vec(
+                "nvml_clock_mhz",
+                "Current clock frequency (MHz)",
+                &[&GPU_LABELS[..], &["clock"][..]].concat(),
+            )),
+        }
+    }
+}
+
+fn label(clock: &Clock) -> &'static str {
+    match clock {
+        Clock::Graphics => "graphics",
+        Clock::SM => "sm",
+        Clock::Memory => "memory",
+        Clock::Video => "video",
+    }
+}
+
+impl DeviceCollector for Clocks {
+    fn name(&self) -> &'static str {
+        "clocks"
+    }
+    fn metrics(&self) -> Vec<(&'static str, &dyn Collector)> {
+        vec![("gauge", &self.clock)]
+    }
+    fn supported(&self, dev: &MetricDevice) -> bool {
+        supported(dev.gpu().clock_info(Clock::Graphics))
+    }
+    fn forget(&self, uuid: &str) {
+        self.clock.forget(uuid);
+    }
+    fn update(&self, dev: &MetricDevice, errors: &IntCounterVec) -> Result<()> {
+        for (i, clock) in CLOCKS.iter().enumerate() {
+            // Not every device has every domain
+            let mhz = dev.gpu().clock_info(clock.clone());
+            if !matches!(mhz, Err(NvmlError::NotSupported)) {
+                self.clock
+                    .get_with(dev, i, label(clock))?
+                    .set(dev.query(errors, "clock_info", mhz)?.into());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use nvml_wrapper::error::NvmlError;
+use prometheus::core::Collector;
+use prometheus::{IntCounterVec, IntGaugeVec};
+
+use super::{available, gated, DeviceCollector, Info, PerDevice};
+use crate::capabilities::Feature;
+use crate::gpu::FabricInfo;
+use crate::{int_gauge_vec, MetricDevice, Result, GPU_LABELS};
+
+/// How far GPUs on NVSwitches got joining the NVLink fabric, which they need for NVLink to
+/// their peers
+pub struct Fabric {
+    pub info: Info,
+    pub failed: PerDevice<IntGaugeVec>,
+}
+
+impl Default for Fabric {
+    fn default() -> Self {
+        Fabric {
+            info: Info::new(
+                "nvml_fabric_info",
+                "The NVLink fabric the GPU joins, and how far it got",
+                &["cluster_uuid", "clique_id", "state"],
+            ),
+            failed: PerDevice::new(int_gauge_vec(
+                "nvml_fabric_failed",
+                "Whether joining the NVLink fabric failed (0/1)",
+                &GPU_LABELS,
+            )),
+        }
+    }
+}
+
+fn fabric_info(dev: &MetricDevice) -> std::result::Result<FabricInfo, NvmlError> {
+    gated(dev, Feature::FabricInfo, || dev.gpu().fabric_info())
+}
+
+impl DeviceCollector for Fabric {
+    fn name(&self) -> &'static str {
+        "fabric"
+    }
+    fn metrics(&self) -> Vec<(&'static str, &dyn Collector)> {
+        vec![("gauge", &self.info), ("gauge", &self.failed)]
+    }
+    fn supported(&self, dev: &MetricDevice) -> bool {
+        available(&fabric_info(dev))
+    }
+    fn forget(&self, uuid: &str) {
+        self.info.forget(uuid);
+        self.failed.forget(uuid);
+    }
+    fn update(&self, dev: &MetricDevice, errors: &IntCounterVec) -> Result<()> {
+        let fabric = dev.query(errors, "fabric_info", fabric_info(dev))?;
+        let labels = vec![
+            fabric.cluster_uuid,
+            fabric.clique_id.to_string(),
+            fabric.state.into(),
+        ];
+        self.info.set(dev, vec![labels])?;
+        self.failed.get(dev)?.set(fabric.failed.into());
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+use nvml_wrapper::error::NvmlError;
+use nvml_wrapper_sys::bindings::{
+    nvmlGpmMetricId_t_NVML_GPM_METRIC_ANY_TENSOR_UTIL as ANY_TENSOR_UTIL,
+    nvmlGpmMetricId_t_NVML_GPM_METRIC_DRAM_BW_UTIL as DRAM_BW_UTIL,
+    nvmlGpmMetricId_t_NVML_GPM_METRIC_GRAPHICS_UTIL as GRAPHICS_UTIL,
+    nvmlGpmMetricId_t_NVML_GPM_METRIC_SM_OCCUPANCY as SM_OCCUPANCY,
+    nvmlGpmMetricId_t_NVML_GPM_METRIC_SM_UTIL as SM_UTIL,
+};
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, IntCounterVec};
+
+use super::{available, gated, DeviceCollector, PerDevice};
+use crate::capabilities::Feature;
+use crate::{gauge_vec, MetricDevice, Result, GPU_LABELS};
+
+/// The GPM metrics collected, by id, in this order
+const METRICS: [(u32, &str, &str); 5] = [
+    (
+        GRAPHICS_UTIL,
+        "nvml_gpm_graphics_activity",
+        "Fraction of time the graphics engine was busy (0-1)",
+    ),
+    (
+        SM_UTIL,
+        "nvml_gpm_sm_activity",
+        "Fraction of time SMs had a warp assigned, averaged over the SMs (0-1)",
+    ),
+    (
+        SM_OCCUPANCY,
+        "nvml_gpm_sm_occupancy",
+        "Fraction of the warps SMs can hold that were resident, averaged over the SMs (0-1)",
+    ),
+    (
+        ANY_TENSOR_UTIL,
+        "nvml_gpm_tensor_activity",
+        "Fraction of time tensor cores were busy (0-1)",
+    ),
+    (
+        DRAM_BW_UTIL,
+        "nvml_gpm_dram_bandwidth",
+        "Fraction of peak memory bandwidth used (0-1)",
+    ),
+];
+
+/// GPU performance monitoring of Hopper and later: what the SMs, tensor cores, and memory were
+/// doing between collections, finer than [`super::Utilization`]
+pub struct Gpm {
+    pub metrics: Vec<PerDevice<GaugeVec>>,
+}
+
+impl Default for Gpm {
+    fn default() -> Self {
+        Gpm {
+            metrics: METRICS
+                .iter()
+                .map(|&(_, name, help)| PerDevice::new(gauge_vec(name, help, &GPU_LABELS)))
+                .collect(),
+        }
+    }
+}
+
+/// Of the metrics in [`METRICS`], each of which may have failed on its own
+type Values = Vec<std::result::Result<f64, NvmlError>>;
+
+fn gpm_metrics(dev: &MetricDevice) -> std::result::Result<Values, NvmlError> {
+    let ids = METRICS.map(|(id, _, _)| id);
+    gated(dev, Feature::Gpm, || dev.gpu().gpm_metrics(&ids))
+}
+
+impl DeviceCollector for Gpm {
+    fn name(&self) -> &'static str {
+        "gpm"
+    }
+    fn metrics(&self) -> Vec<(&'static str, &dyn Collector)> {
+        self.metrics
+            .iter()
+            .map(|metric| ("gauge", metric as &dyn Collector))
+            .collect()
+    }
+    fn supported(&self, dev: &MetricDevice) -> bool {
+        // Also takes the first sample, for the first collection to have metrics
+        available(&gpm_metrics(dev))
+    }
+    fn forget(&self, uuid: &str) {
+        for metric in &self.metrics {
+            metric.forget(uuid);
+        }
+    }
+    fn update(&self, dev: &MetricDevice, errors: &IntCounterVec) -> Result<()> {
+        let values = match gpm_metrics(dev) {
+            // Right after the device showed up again
+            Err(NvmlError::NoData) => return Ok(()),
+            values => dev.query(errors, "gpm_metrics", values)?,
+        };
+        for ((metric, (_, name, _)), value) in self.metrics.iter().zip(METRICS).zip(values) {
+            let Ok(value) = value else { continue };
+            if let Some(value) = dev.gauge(name, value, 0. ..=100.) {
+                metric.get(dev)?.set(value / 100.);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+use prometheus::core::Collector;
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec};
+
+use super::DeviceCollector;
+use crate::{MetricDevice, Result, GPU_LABELS};
+
+/// Signals whose distribution over the readings taken between collections can be exported
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Signal {
+    Power,
+    SmUtilization,
+}
+
+impl Signal {
+    fn histogram(self) -> HistogramVec {
+        let (name, help, buckets) = match self {
+            Signal::Power => (
+                "nvml_sampled_power_usage_mw",
+                "Power usage (mW) of each reading",
+                [
+                    25, 50, 75, 100, 150, 200, 250, 300, 350, 400, 500, 600, 700, 800, 1000,
+                ]
+                .map(|watts| watts as f64 * 1000.)
+                .to_vec(),
+            ),
+            Signal::SmUtilization => (
+                "nvml_sampled_utilization_gpu",
+                "Share of time kernels ran on the GPU (0-1) of each reading",
+                (0..=10).map(|tenths| tenths as f64 / 10.).collect(),
+            ),
+        };
+        let opts = HistogramOpts::new(name, help).buckets(buckets);
+        HistogramVec::new(opts, &GPU_LABELS).unwrap()
+    }
+
+    /// The raw reading, as the main collectors get it
+    fn read(self, dev: &MetricDevice) -> Option<f64> {
+        Some(match self {
+            Signal::Power => dev.gpu().power_usage().ok()? as f64,
+            Signal::SmUtilization => dev.gpu().utilization_rates().ok()?.gpu as f64,
+        })
+    }
+
+    /// The reading, if it is plausible as the main collectors check it, see
+    /// [`MetricDevice::gauge`]
+    fn sample(self, dev: &MetricDevice) -> Option<f64> {
+        let value = self.read(dev)?;
+        Some(match self {
+            // Not checked by the power collector either
+            Signal::Power => value,
+            Signal::SmUtilization => dev.gauge("nvml_utilization_gpu", value, 0. ..=100.)? / 100.,
+        })
+    }
+}
+
+/// Histograms of every reading taken, for the distribution, e.g. for power capping
+pub struct Histograms {
+    histograms: Vec<(Signal, HistogramVec)>,
+}
+
+impl Histograms {
+    pub fn new(signals: &[Signal]) -> Histograms {
+        Histograms {
+            histograms: signals.iter().map(|&s| (s, s.histogram())).collect(),
+        }
+    }
+}
+
+impl DeviceCollector for Histograms {
+    fn name(&self) -> &'static str {
+        "histograms"
+    }
+    fn metrics(&self) -> Vec<(&'static str, &dyn Collector)> {
+        self.histograms
+            .iter()
+            .map(|(_, h)| ("histogram", h as &dyn Collector))
+            .collect()
+    }
+    fn supported(&self, dev: &MetricDevice) -> bool {
+        self.histograms.iter().any(|(s, _)| s.read(dev).is_some())
+    }
+    fn forget(&self, _: &str) {
+        // Cumulative, like counters, and the readings were taken
+    }
+    fn sample(&self, dev: &MetricDevice) {
+        for (signal, histogram) in &self.histograms {
+            if let Some(value) = signal.sample(dev) {
+                if let Ok(histogram) = histogram.get_metric_with_label_values(&dev.labels()) {
+                    histogram.observe(value);
+                }
+            }
+        }
+    }
+    fn update(&self, _: &MetricDevice, _: &IntCounterVec) -> Result<()> {
+        // Only fed by sampling, so the scrape interval doesn't skew the distribution
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+use nvml_wrapper::error::NvmlError;
+use prometheus::core::Collector;
+use prometheus::IntCounterVec;
+
+use super::{DeviceCollector, Info};
+use crate::{MetricDevice, Result};
+
+/// What the device is, and what it runs, as info metrics
+pub struct DeviceInfo {
+    pub gpu: Info,
+    pub driver: Info,
+    pub vbios: Info,
+    pub mig_profile: Info,
+}
+
+impl Default for DeviceInfo {
+    fn default() -> Self {
+        DeviceInfo {
+            gpu: Info::new("nvml_gpu_info", "The GPU", &["index", "mig_mode"]),
+            driver: Info::new("nvml_driver_info", "The driver", &["version"]),
+            vbios: Info::new("nvml_vbios_info", "The video BIOS", &["version"]),
+            mig_profile: Info::new(
+                "nvml_mig_profile_info",
+                "The MIG devices the GPU is partitioned into",
+                &["mig_uuid", "profile"],
+            ),
+        }
+    }
+}
+
+/// The label values, none if the device or driver doesn't have them
+fn values<T>(
+    dev: &MetricDevice,
+    errors: &IntCounterVec,
+    function: &'static str,
+    result: std::result::Result<T, NvmlError>,
+) -> Result<Option<T>> {
+    match result {
+        Err(NvmlError::NotSupported | NvmlError::FunctionNotFound) => Ok(None),
+        result => Ok(Some(dev.query(errors, function, result)?)),
+    }
+}
+
+impl DeviceCollector for DeviceInfo {
+    fn name(&self) -> &'static str {
+        "info"
+    }
+    fn metrics(&self) -> Vec<(&'static str, &dyn Collector)> {
+        vec![
+            ("gauge", &self.gpu),
+            ("gauge", &self.driver),
+            ("gauge", &self.vbios),
+            ("gauge", &self.mig_profile),
+        ]
+    }
+    fn supported(&self, _: &MetricDevice) -> bool {
+        true
+    }
+    fn forget(&self, uuid: &str) {
+        self.gpu.forget(uuid);
+        self.driver.forget(uuid);
+        self.vbios.forget(uuid);
+        self.mig_profile.forget(uuid);
+    }
+    fn update(&self, dev: &MetricDevice, errors: &IntCounterVec) -> Result<()> {
+        let gpu = dev.gpu();
+        let index = values(dev, errors, "index", gpu.index())?;
+        let mig = values(dev, errors, "mig_mode", gpu.mig_mode())?;
+        let mig_mode = match mig {
+            Some(true) => "enabled",
+            Some(false) => "disabled",
+            None => "",
+        };
+        let index = index.map_or_else(String::new, |index| index.to_string());
+        self.gpu.set(dev, vec![vec![index, mig_mode.into()]])?;
+        let driver = values(dev, errors, "driver_version", gpu.driver_version())?;
+        self.driver
+            .set(dev, driver.into_iter().map(|v| vec![v]).collect())?;
+        let vbios = values(dev, errors, "vbios_version", gpu.vbios_version())?;
+        self.vbios
+            .set(dev, vbios.into_iter().map(|v| vec![v]).collect())?;
+        let profiles = match mig {
+            Some(true) => values(dev, errors, "mig_profiles", gpu.mig_profiles())?,
+            _ => None,
+        };
+        let profiles = profiles.into_iter().flatten();
+        self.mig_profile.set(
+            dev,
+            profiles
+                .map(|(uuid, profile)| vec![uuid, profile])
+                .collect(),
+        )?;
+        Ok(())
+    }
+}
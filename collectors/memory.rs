@@ -0,0 +1,75 @@
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, IntCounterVec, IntGaugeVec};
+
+use super::{supported, DeviceCollector, PerDevice};
+use crate::{gauge_vec, int_gauge_vec, MetricDevice, Result, GPU_LABELS};
+
+/// Frame buffer memory
+pub struct Memory {
+    pub free: PerDevice<IntGaugeVec>,
+    pub used: PerDevice<IntGaugeVec>,
+    pub total: PerDevice<IntGaugeVec>,
+    pub used_ratio: PerDevice<GaugeVec>,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory {
+            free: PerDevice::new(int_gauge_vec(
+                "nvml_memory_free_bytes",
+                "Free Memory",
+                &GPU_LABELS,
+            )),
+            used: PerDevice::new(int_gauge_vec(
+                "nvml_memory_used_bytes",
+                "Used Memory",
+                &GPU_LABELS,
+            )),
+            total: PerDevice::new(int_gauge_vec(
+                "nvml_memory_total_bytes",
+                "Total Memory",
+                &GPU_LABELS,
+            )),
+            used_ratio: PerDevice::new(gauge_vec(
+                "nvml_memory_used_ratio",
+                "Used memory of the total (0-1)",
+                &GPU_LABELS,
+            )),
+        }
+    }
+}
+
+impl DeviceCollector for Memory {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+    fn metrics(&self) -> Vec<(&'static str, &dyn Collector)> {
+        vec![
+            ("gauge", &self.free),
+            ("gauge", &self.used),
+            ("gauge", &self.total),
+            ("gauge", &self.used_ratio),
+        ]
+    }
+    fn supported(&self, dev: &MetricDevice) -> bool {
+        supported(dev.gpu().memory_info())
+    }
+    fn forget(&self, uuid: &str) {
+        self.free.forget(uuid);
+        self.used.forget(uuid);
+        self.total.forget(uuid);
+        self.used_ratio.forget(uuid);
+    }
+    fn update(&self, dev: &MetricDevice, errors: &IntCounterVec) -> Result<()> {
+        let meminfo = dev.query(errors, "memory_info", dev.gpu().memory_info())?;
+        self.free.get(dev)?.set(meminfo.free.try_into()?);
+        self.used.get(dev)?.set(meminfo.used.try_into()?);
+        self.total.get(dev)?.set(meminfo.total.try_into()?);
+        if meminfo.total > 0 {
+            self.used_ratio
+                .get(dev)?
+                .set(meminfo.used as f64 / meminfo.total as f64);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+//! The groups of metrics collected from each device
+
+use prometheus::core::{Collector, Desc, MetricVec, MetricVecBuilder};
+use prometheus::proto::MetricFamily;
+use prometheus::{IntCounterVec, IntGaugeVec};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::capabilities::Feature;
+use crate::{int_gauge_vec, MetricDevice, Result, GPU_LABELS};
+
+mod aggregates;
+mod c2c;
+mod clocks;
+mod fabric;
+mod gpm;
+pub mod histograms;
+mod info;
+mod memory;
+mod pcie;
+mod performance;
+mod power;
+mod processes;
+mod retirement;
+mod thermal;
+mod utilization;
+
+pub use aggregates::Aggregates;
+pub use c2c::C2c;
+pub use clocks::Clocks;
+pub use fabric::Fabric;
+pub use gpm::Gpm;
+pub use histograms::Histograms;
+pub use info::DeviceInfo;
+pub use memory::Memory;
+pub use pcie::Pcie;
+pub use performance::Performance;
+pub use power::Power;
+pub use processes::{Processes, PROCESS_LABELS};
+pub use retirement::Retirement;
+pub use thermal::Thermal;
+pub use utilization::Utilization;
+
+/// A group of metrics, updated from each device in turn
+pub trait DeviceCollector: Send + Sync {
+    /// For selecting and reporting on the collector
+    fn name(&self) -> &'static str;
+    /// Its metrics, with their types
+    fn metrics(&self) -> Vec<(&'static str, &dyn Collector)>;
+    /// Whether the device has what this collector collects
+    fn supported(&self, dev: &MetricDevice) -> bool;
+    /// Forget the values of the previous collection that may not be collected again, e.g. of
+    /// processes that exited. Per device values are kept, see [`PerDevice`].
+    fn reset(&self) {}
+    /// Forget the values of a device that is gone, or couldn't be collected from
+    fn forget(&self, uuid: &str);
+    /// Take a reading between collections, for those that aggregate them
+    fn sample(&self, _dev: &MetricDevice) {}
+    /// Query the device and record its values, counting failed queries in `errors`
+    fn update(&self, dev: &MetricDevice, errors: &IntCounterVec) -> Result<()>;
+}
+
+/// What the collectors can be configured with
+#[derive(Clone)]
+pub struct Options {
+    /// Processes exported per GPU, the others are summed up, `None` for all
+    pub process_limit: Option<usize>,
+    /// Whether to export the min, max, and average of readings between collections
+    pub aggregates: bool,
+    /// Signals to export histograms of the readings of
+    pub histograms: Vec<histograms::Signal>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            process_limit: Some(processes::DEFAULT_LIMIT),
+            aggregates: false,
+            histograms: vec![],
+        }
+    }
+}
+
+/// All collectors, in the order they run
+pub fn all() -> Vec<Box<dyn DeviceCollector>> {
+    with_options(&Options::default())
+}
+
+/// All collectors, configured with `options`
+pub fn with_options(options: &Options) -> Vec<Box<dyn DeviceCollector>> {
+    let mut collectors: Vec<Box<dyn DeviceCollector>> = vec![
+        Box::new(Memory::default()),
+        Box::new(Thermal::default()),
+        Box::new(Performance::default()),
+        Box::new(Power::default()),
+        Box::new(Pcie::default()),
+        Box::new(Retirement::default()),
+        Box::new(Utilization::default()),
+        Box::new(Clocks::default()),
+        Box::new(Processes::new(options.process_limit)),
+        Box::new(DeviceInfo::default()),
+        Box::new(Gpm::default()),
+        Box::new(Fabric::default()),
+        Box::new(C2c::default()),
+    ];
+    if options.aggregates {
+        collectors.push(Box::new(Aggregates::default()));
+    }
+    if !options.histograms.is_empty() {
+        collectors.push(Box::new(Histograms::new(&options.histograms)));
+    }
+    collectors
+}
+
+/// A metric vec with the children for each device resolved once, instead of looked up by their
+/// label values on every collection
+pub struct PerDevice<V: ChildVec> {
+    vec: V,
+    /// By uuid
+    children: Mutex<HashMap<String, Children<V::Child>>>,
+}
+
+/// Of a device, by the index of the extra label value, e.g. of the fan, with their label values
+type Children<C> = Vec<Option<(Vec<String>, C)>>;
+
+/// Any of prometheus' metric vecs, for [`PerDevice`]
+pub trait ChildVec: Collector {
+    type Child: Clone + Send;
+    fn child(&self, labels: &[&str]) -> prometheus::Result<Self::Child>;
+    fn remove(&self, labels: &[&str]);
+}
+
+impl<B: MetricVecBuilder> ChildVec for MetricVec<B> {
+    type Child = B::M;
+    fn child(&self, labels: &[&str]) -> prometheus::Result<B::M> {
+        self.get_metric_with_label_values(labels)
+    }
+    fn remove(&self, labels: &[&str]) {
+        self.remove_label_values(labels).ok();
+    }
+}
+
+impl<V: ChildVec> PerDevice<V> {
+    pub fn new(vec: V) -> PerDevice<V> {
+        PerDevice {
+            vec,
+            children: Default::default(),
+        }
+    }
+
+    /// The device's child
+    pub fn get(&self, dev: &MetricDevice) -> Result<V::Child> {
+        self.child(dev, 0, None)
+    }
+
+    /// The device's child with the `extra` label value, which `index` stands for
+    pub fn get_with(&self, dev: &MetricDevice, index: usize, extra: &str) -> Result<V::Child> {
+        self.child(dev, index, Some(extra))
+    }
+
+    fn child(&self, dev: &MetricDevice, index: usize, extra: Option<&str>) -> Result<V::Child> {
+        let mut children = self.children.lock().unwrap();
+        let children = match children.get_mut(dev.uuid()) {
+            Some(children) => children,
+            None => children.entry(dev.uuid().to_owned()).or_default(),
+        };
+        if let Some(Some((_, child))) = children.get(index) {
+            return Ok(child.clone());
+        }
+        let labels = dev.labels().into_iter().chain(extra).collect::<Vec<_>>();
+        let child = self.vec.child(&labels)?;
+        if children.len() <= index {
+            children.resize(index + 1, None);
+        }
+        let labels = labels.into_iter().map(str::to_owned).collect();
+        children[index] = Some((labels, child.clone()));
+        Ok(child)
+    }
+
+    pub fn forget(&self, uuid: &str) {
+        let children = self.children.lock().unwrap().remove(uuid);
+        for (labels, _) in children.into_iter().flatten().flatten() {
+            let labels = labels.iter().map(String::as_str).collect::<Vec<_>>();
+            self.vec.remove(&labels);
+        }
+    }
+}
+
+impl<V: ChildVec> Collector for PerDevice<V> {
+    fn desc(&self) -> Vec<&Desc> {
+        self.vec.desc()
+    }
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.vec.collect()
+    }
+}
+
+/// An info metric: always 1, with what it describes in its labels, after [`GPU_LABELS`]. When
+/// that changes, e.g. with a driver update, the series with the old label values goes away.
+pub struct Info {
+    vec: IntGaugeVec,
+    /// By uuid, the label values of the device's series
+    series: Mutex<HashMap<String, Vec<Vec<String>>>>,
+}
+
+impl Info {
+    pub fn new(name: &str, help: &str, labels: &[&str]) -> Info {
+        Info {
+            vec: int_gauge_vec(name, help, &[&GPU_LABELS[..], labels].concat()),
+            series: Default::default(),
+        }
+    }
+
+    /// Replace the device's series with one for each set of label values, none if there are
+    /// none
+    pub fn set(&self, dev: &MetricDevice, values: Vec<Vec<String>>) -> Result<()> {
+        let series = values
+            .into_iter()
+            .map(|values| [&dev.labels().map(str::to_owned)[..], &values].concat())
+            .collect::<Vec<_>>();
+        let mut current = self.series.lock().unwrap();
+        let previous = current.insert(dev.uuid().to_owned(), series.clone());
+        for labels in previous.into_iter().flatten() {
+            if !series.contains(&labels) {
+                let labels = labels.iter().map(String::as_str).collect::<Vec<_>>();
+                self.vec.remove_label_values(&labels).ok();
+            }
+        }
+        for labels in &series {
+            let labels = labels.iter().map(String::as_str).collect::<Vec<_>>();
+            self.vec.get_metric_with_label_values(&labels)?.set(1);
+        }
+        Ok(())
+    }
+
+    pub fn forget(&self, uuid: &str) {
+        let series = self.series.lock().unwrap().remove(uuid);
+        for labels in series.into_iter().flatten() {
+            let labels = labels.iter().map(String::as_str).collect::<Vec<_>>();
+            self.vec.remove_label_values(&labels).ok();
+        }
+    }
+}
+
+impl Collector for Info {
+    fn desc(&self) -> Vec<&Desc> {
+        self.vec.desc()
+    }
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.vec.collect()
+    }
+}
+
+/// Whether the query didn't fail with NotSupported
+fn supported<T>(result: std::result::Result<T, nvml_wrapper::error::NvmlError>) -> bool {
+    !matches!(result, Err(nvml_wrapper::error::NvmlError::NotSupported))
+}
+
+/// Whether the driver and device have it, the library may not even have the function
+fn available<T>(result: &std::result::Result<T, nvml_wrapper::error::NvmlError>) -> bool {
+    use nvml_wrapper::error::NvmlError;
+    !matches!(
+        result,
+        Err(NvmlError::NotSupported | NvmlError::FunctionNotFound)
+    )
+}
+
+/// The query's result, or NotSupported without asking if the driver is too old for `feature`
+fn gated<T>(
+    dev: &MetricDevice,
+    feature: Feature,
+    query: impl FnOnce() -> std::result::Result<T, nvml_wrapper::error::NvmlError>,
+) -> std::result::Result<T, nvml_wrapper::error::NvmlError> {
+    match dev.capabilities().has(feature) {
+        true => query(),
+        false => Err(nvml_wrapper::error::NvmlError::NotSupported),
+    }
+}
@@ -0,0 +1,55 @@
+use nvml_wrapper_sys::bindings::field_id::NVML_FI_DEV_PCIE_REPLAY_COUNTER;
+use prometheus::core::Collector;
+use prometheus::IntCounterVec;
+
+use super::{supported, DeviceCollector, PerDevice};
+use crate::{int_counter_vec, MetricDevice, Result, GPU_LABELS};
+
+/// PCIe link health
+pub struct Pcie {
+    pub replay: PerDevice<IntCounterVec>,
+}
+
+impl Default for Pcie {
+    fn default() -> Self {
+        Pcie {
+            replay: PerDevice::new(int_counter_vec(
+                "nvml_pci_replay_total",
+                "PCIe replay counter",
+                &GPU_LABELS,
+            )),
+        }
+    }
+}
+
+impl DeviceCollector for Pcie {
+    fn name(&self) -> &'static str {
+        "pcie"
+    }
+    fn metrics(&self) -> Vec<(&'static str, &dyn Collector)> {
+        vec![("counter", &self.replay)]
+    }
+    fn supported(&self, dev: &MetricDevice) -> bool {
+        supported(dev.gpu().pcie_replay_counter())
+    }
+    fn forget(&self, uuid: &str) {
+        self.replay.forget(uuid);
+    }
+    fn update(&self, dev: &MetricDevice, errors: &IntCounterVec) -> Result<()> {
+        let replays = dev.query(
+            errors,
+            "pcie_replay_counter",
+            dev.field(NVML_FI_DEV_PCIE_REPLAY_COUNTER, || {
+                dev.gpu().pcie_replay_counter()
+            }),
+        )?;
+        let Some(replays) = dev.counter("nvml_pci_replay_total", replays.into()) else {
+            return Ok(());
+        };
+        // NVML counts, this only passes it on
+        let replay = self.replay.get(dev)?;
+        replay.reset();
+        replay.inc_by(replays);
+        Ok(())
+    }
+}
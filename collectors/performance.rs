@@ -0,0 +1,66 @@
+use nvml_wrapper::enum_wrappers::device::PerformanceState;
+use prometheus::core::Collector;
+use prometheus::{IntCounterVec, IntGaugeVec};
+
+use super::{supported, DeviceCollector, PerDevice};
+use crate::{int_gauge_vec, MetricDevice, Result, GPU_LABELS};
+
+/// The performance state (P-state)
+pub struct Performance {
+    pub state: PerDevice<IntGaugeVec>,
+}
+
+impl Default for Performance {
+    fn default() -> Self {
+        Performance {
+            state: PerDevice::new(int_gauge_vec(
+                "nvml_performance_state",
+                "Performance State (between 15 (low) and 0 (high))",
+                &GPU_LABELS,
+            )),
+        }
+    }
+}
+
+fn number(state: PerformanceState) -> i64 {
+    use PerformanceState::*;
+    match state {
+        Zero => 0,
+        One => 1,
+        Two => 2,
+        Three => 3,
+        Four => 4,
+        Five => 5,
+        Six => 6,
+        Seven => 7,
+        Eight => 8,
+        Nine => 9,
+        Ten => 10,
+        Eleven => 11,
+        Twelve => 12,
+        Thirteen => 13,
+        Fourteen => 14,
+        Fifteen => 15,
+        Unknown => -1,
+    }
+}
+
+impl DeviceCollector for Performance {
+    fn name(&self) -> &'static str {
+        "performance"
+    }
+    fn metrics(&self) -> Vec<(&'static str, &dyn Collector)> {
+        vec![("gauge", &self.state)]
+    }
+    fn supported(&self, dev: &MetricDevice) -> bool {
+        supported(dev.gpu().performance_state())
+    }
+    fn forget(&self, uuid: &str) {
+        self.state.forget(uuid);
+    }
+    fn update(&self, dev: &MetricDevice, errors: &IntCounterVec) -> Result<()> {
+        let state = dev.query(errors, "performance_state", dev.gpu().performance_state())?;
+        self.state.get(dev)?.set(number(state));
+        Ok(())
+    }
+}
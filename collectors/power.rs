@@ -0,0 +1,93 @@
+use nvml_wrapper::error::NvmlError;
+use nvml_wrapper_sys::bindings::field_id::NVML_FI_DEV_TOTAL_ENERGY_CONSUMPTION;
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, IntCounterVec, IntGaugeVec};
+
+use super::{supported, DeviceCollector, PerDevice};
+use crate::{gauge_vec, int_counter_vec, int_gauge_vec, MetricDevice, Result, GPU_LABELS};
+
+/// Power draw, limit, and energy consumed
+pub struct Power {
+    pub usage: PerDevice<IntGaugeVec>,
+    pub max: PerDevice<IntGaugeVec>,
+    pub energy_used: PerDevice<IntCounterVec>,
+    pub usage_ratio: PerDevice<GaugeVec>,
+}
+
+impl Default for Power {
+    fn default() -> Self {
+        Power {
+            usage: PerDevice::new(int_gauge_vec(
+                "nvml_power_usage_current_mw",
+                "Current power usage (mW)",
+                &GPU_LABELS,
+            )),
+            max: PerDevice::new(int_gauge_vec(
+                "nvml_power_usage_max_mw",
+                "Enforced power limit (mW)",
+                &GPU_LABELS,
+            )),
+            energy_used: PerDevice::new(int_counter_vec(
+                "nvml_power_used_mj_total",
+                "Energy used in total",
+                &GPU_LABELS,
+            )),
+            usage_ratio: PerDevice::new(gauge_vec(
+                "nvml_power_usage_ratio",
+                "Current power usage of the enforced limit (0-1)",
+                &GPU_LABELS,
+            )),
+        }
+    }
+}
+
+impl DeviceCollector for Power {
+    fn name(&self) -> &'static str {
+        "power"
+    }
+    fn metrics(&self) -> Vec<(&'static str, &dyn Collector)> {
+        vec![
+            ("gauge", &self.usage),
+            ("gauge", &self.max),
+            ("counter", &self.energy_used),
+            ("gauge", &self.usage_ratio),
+        ]
+    }
+    fn supported(&self, dev: &MetricDevice) -> bool {
+        supported(dev.gpu().power_usage())
+    }
+    fn forget(&self, uuid: &str) {
+        self.usage.forget(uuid);
+        self.max.forget(uuid);
+        self.energy_used.forget(uuid);
+        self.usage_ratio.forget(uuid);
+    }
+    fn update(&self, dev: &MetricDevice, errors: &IntCounterVec) -> Result<()> {
+        let gpu = dev.gpu();
+        let usage = dev.query(errors, "power_usage", gpu.power_usage())?;
+        self.usage.get(dev)?.set(usage as i64);
+        // Not on every board
+        let limit = gpu.enforced_power_limit();
+        if !matches!(limit, Err(NvmlError::NotSupported)) {
+            let limit = dev.query(errors, "enforced_power_limit", limit)?;
+            self.max.get(dev)?.set(limit as i64);
+            if limit > 0 {
+                self.usage_ratio.get(dev)?.set(usage as f64 / limit as f64);
+            }
+        }
+        // Only available since Volta
+        let energy = dev.field(NVML_FI_DEV_TOTAL_ENERGY_CONSUMPTION, || {
+            gpu.total_energy_consumption()
+        });
+        if !matches!(energy, Err(NvmlError::NotSupported)) {
+            let energy = dev.query(errors, "total_energy_consumption", energy)?;
+            if let Some(energy) = dev.counter("nvml_power_used_mj_total", energy) {
+                // NVML counts, this only passes it on
+                let energy_used = self.energy_used.get(dev)?;
+                energy_used.reset();
+                energy_used.inc_by(energy);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+use nvml_wrapper::enums::device::UsedGpuMemory;
+use nvml_wrapper::error::NvmlError;
+use nvml_wrapper::struct_wrappers::device::{ProcessInfo, ProcessUtilizationSample};
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, IntCounterVec, IntGaugeVec};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::{supported, DeviceCollector};
+use crate::cgroup::Attribution;
+use crate::procfs::Metadata;
+use crate::{gauge_vec, int_counter_vec, int_gauge_vec, MetricDevice, Result, GPU_LABELS};
+
+/// Enough for any training node, while a busy inference node can't flood the TSDB
+pub const DEFAULT_LIMIT: usize = 64;
+
+/// Labels beyond [`GPU_LABELS`]
+pub static PROCESS_LABELS: [&str; 8] = [
+    "pid",
+    "type",
+    "container",
+    "pod",
+    "namespace",
+    "slurm_job_id",
+    "user",
+    "command",
+];
+
+/// Of [`Processes::utilization`], in the order of their samples' fields
+const ENGINES: [&str; 4] = ["sm", "memory", "encoder", "decoder"];
+
+/// Processes using the GPU, with the container and pod, or Slurm job, they run in, and the user
+/// and command line running them
+///
+/// Only the processes using the most memory are exported, up to the limit. The rest are summed
+/// up in a series with pid="other".
+pub struct Processes {
+    pub memory: IntGaugeVec,
+    pub start_time: GaugeVec,
+    /// Since the exporter first saw the process on the GPU
+    pub residency: GaugeVec,
+    /// By engine, as the driver sampled it
+    pub utilization: GaugeVec,
+    /// Unlike the memory, this keeps counting across collections
+    pub dropped: IntCounterVec,
+    /// Processes that showed up on the GPU since the previous collection, and that went away
+    pub started: IntCounterVec,
+    pub exited: IntCounterVec,
+    limit: Option<usize>,
+    /// By uuid, the processes on the device in the last collection, by pid, with when they were
+    /// first seen on it
+    seen: Mutex<HashMap<String, HashMap<u32, Instant>>>,
+    /// By uuid, the label values of the processes exported in the last collection, to remove
+    /// the series of those that exited
+    exported: Mutex<HashMap<String, Vec<Vec<String>>>>,
+}
+
+impl Default for Processes {
+    fn default() -> Self {
+        Processes::new(Some(DEFAULT_LIMIT))
+    }
+}
+
+impl Processes {
+    pub fn new(limit: Option<usize>) -> Processes {
+        Processes {
+            memory: int_gauge_vec(
+                "nvml_process_memory_used_bytes",
+                "GPU memory used by a process",
+                &[&GPU_LABELS[..], &PROCESS_LABELS[..]].concat(),
+            ),
+            start_time: gauge_vec(
+                "nvml_process_start_time_seconds",
+                "When a process started, in seconds since the epoch",
+                &[&GPU_LABELS[..], &PROCESS_LABELS[..]].concat(),
+            ),
+            residency: gauge_vec(
+                "nvml_process_residency_seconds",
+                "How long a process has been using the GPU, since the exporter first saw it",
+                &[&GPU_LABELS[..], &PROCESS_LABELS[..]].concat(),
+            ),
+            utilization: gauge_vec(
+                "nvml_process_utilization",
+                "Fraction of time a process used the engine (sm, memory, encoder, decoder) (0-1)",
+                &[&GPU_LABELS[..], &PROCESS_LABELS[..], &["engine"][..]].concat(),
+            ),
+            dropped: int_counter_vec(
+                "nvml_process_series_dropped_total",
+                "Processes summed up as pid=\"other\" for being over the per GPU limit",
+                &GPU_LABELS,
+            ),
+            started: int_counter_vec(
+                "nvml_process_started_total",
+                "Processes that started using the GPU, between collections",
+                &GPU_LABELS,
+            ),
+            exited: int_counter_vec(
+                "nvml_process_exited_total",
+                "Processes that stopped using the GPU, between collections",
+                &GPU_LABELS,
+            ),
+            limit,
+            seen: Default::default(),
+            exported: Default::default(),
+        }
+    }
+
+    /// Remove the series of a process
+    fn remove(&self, labels: &[String]) {
+        let labels = labels.iter().map(String::as_str).collect::<Vec<_>>();
+        self.memory.remove_label_values(&labels).ok();
+        self.start_time.remove_label_values(&labels).ok();
+        self.residency.remove_label_values(&labels).ok();
+        for engine in ENGINES {
+            let labels = [&labels[..], &[engine][..]].concat();
+            self.utilization.remove_label_values(&labels).ok();
+        }
+    }
+
+    /// Count the processes that started and exited since the previous collection, from the
+    /// pids on the device then and now, and return when each was first seen. Those found in the
+    /// first collection don't count as started.
+    fn track(
+        &self,
+        dev: &MetricDevice,
+        pids: impl Iterator<Item = u32>,
+    ) -> Result<HashMap<u32, Instant>> {
+        let started = self.started.get_metric_with_label_values(&dev.labels())?;
+        let exited = self.exited.get_metric_with_label_values(&dev.labels())?;
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        let previous = seen.remove(dev.uuid());
+        let known = previous.clone().unwrap_or_default();
+        let current = pids
+            .map(|pid| (pid, known.get(&pid).copied().unwrap_or(now)))
+            .collect::<HashMap<_, _>>();
+        if let Some(previous) = previous {
+            let new = current.keys().filter(|pid| !previous.contains_key(pid));
+            started.inc_by(new.count() as u64);
+            let gone = previous.keys().filter(|pid| !current.contains_key(pid));
+            exited.inc_by(gone.count() as u64);
+        }
+        seen.insert(dev.uuid().to_owned(), current.clone());
+        Ok(current)
+    }
+
+    fn record(
+        &self,
+        dev: &MetricDevice,
+        processes: Vec<(&str, ProcessInfo)>,
+        samples: Vec<ProcessUtilizationSample>,
+    ) -> Result<()> {
+        let first_seen = self.track(dev, processes.iter().map(|(_, process)| process.pid))?;
+        let now = Instant::now();
+        // The latest sample of each process
+        let mut utilization = HashMap::<u32, ProcessUtilizationSample>::new();
+        for sample in samples {
+            match utilization.get(&sample.pid) {
+                Some(latest) if latest.timestamp >= sample.timestamp => (),
+                _ => drop(utilization.insert(sample.pid, sample)),
+            }
+        }
+        let mut processes = processes
+            .into_iter()
+            .filter_map(|(kind, process)| match process.used_gpu_memory {
+                UsedGpuMemory::Used(used) => Some((kind, process.pid, used)),
+                // Not available under WDDM
+                UsedGpuMemory::Unavailable => None,
+            })
+            .collect::<Vec<_>>();
+        let limit = self.limit.unwrap_or(usize::MAX);
+        let mut exported = vec![];
+        // Exported from the start, so there is a zero to count up from
+        let dropped = self.dropped.get_metric_with_label_values(&dev.labels())?;
+        if processes.len() > limit {
+            processes.sort_by_key(|&(_, _, used)| std::cmp::Reverse(used));
+            let other = processes.split_off(limit);
+            let other_used: u64 = other.iter().map(|&(_, _, used)| used).sum();
+            let labels = [
+                &dev.labels()[..],
+                &["other", "", "", "", "", "", "", ""][..],
+            ]
+            .concat();
+            self.memory
+                .get_metric_with_label_values(&labels)?
+                .set(other_used.try_into()?);
+            exported.push(labels.iter().map(|&l| l.to_owned()).collect());
+            dropped.inc_by(other.len() as u64);
+        }
+        for (kind, pid, used) in processes {
+            let (who, what) = (Attribution::of(pid), Metadata::of(pid));
+            let sample = utilization.get(&pid);
+            let first_seen = first_seen.get(&pid).copied().unwrap_or(now);
+            let pid = pid.to_string();
+            let labels = [
+                &pid,
+                kind,
+                &who.container,
+                &who.pod,
+                &who.namespace,
+                &who.slurm_job_id,
+                &what.user,
+                &what.command,
+            ];
+            let labels = [&dev.labels()[..], &labels[..]].concat();
+            exported.push(labels.iter().map(|&l| l.to_owned()).collect());
+            self.memory
+                .get_metric_with_label_values(&labels)?
+                .set(used.try_into()?);
+            // Not to keep a value from a previous collection either
+            match what.start_time {
+                Some(start_time) => self
+                    .start_time
+                    .get_metric_with_label_values(&labels)?
+                    .set(start_time),
+                None => drop(self.start_time.remove_label_values(&labels)),
+            }
+            self.residency
+                .get_metric_with_label_values(&labels)?
+                .set(now.duration_since(first_seen).as_secs_f64());
+            let percents = sample.map(|sample| {
+                [
+                    sample.sm_util,
+                    sample.mem_util,
+                    sample.enc_util,
+                    sample.dec_util,
+                ]
+            });
+            for (i, engine) in ENGINES.into_iter().enumerate() {
+                let labels = [&labels[..], &[engine][..]].concat();
+                match percents {
+                    Some(percents) => self
+                        .utilization
+                        .get_metric_with_label_values(&labels)?
+                        .set(percents[i] as f64 / 100.),
+                    None => drop(self.utilization.remove_label_values(&labels)),
+                }
+            }
+        }
+        let previous = self
+            .exported
+            .lock()
+            .unwrap()
+            .insert(dev.uuid().to_owned(), exported.clone());
+        for labels in previous.into_iter().flatten() {
+            if !exported.contains(&labels) {
+                self.remove(&labels);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DeviceCollector for Processes {
+    fn name(&self) -> &'static str {
+        "processes"
+    }
+    fn metrics(&self) -> Vec<(&'static str, &dyn Collector)> {
+        vec![
+            ("gauge", &self.memory),
+            ("gauge", &self.start_time),
+            ("gauge", &self.residency),
+            ("gauge", &self.utilization),
+            ("counter", &self.dropped),
+            ("counter", &self.started),
+            ("counter", &self.exited),
+        ]
+    }
+    fn supported(&self, dev: &MetricDevice) -> bool {
+        supported(dev.gpu().running_compute_processes())
+    }
+    fn forget(&self, uuid: &str) {
+        let exported = self.exported.lock().unwrap().remove(uuid);
+        for labels in exported.into_iter().flatten() {
+            self.remove(&labels);
+        }
+        self.seen.lock().unwrap().remove(uuid);
+    }
+    fn update(&self, dev: &MetricDevice, errors: &IntCounterVec) -> Result<()> {
+        let gpu = dev.gpu();
+        let compute = dev.query(
+            errors,
+            "running_compute_processes",
+            gpu.running_compute_processes(),
+        )?;
+        let graphics = dev.query(
+            errors,
+            "running_graphics_processes",
+            gpu.running_graphics_processes(),
+        )?;
+        // Only since Maxwell
+        let samples = gpu.process_utilization_stats();
+        let samples = match samples {
+            // None sampled recently
+            Err(NvmlError::NotSupported | NvmlError::NotFound) => vec![],
+            samples => dev.query(errors, "process_utilization_stats", samples)?,
+        };
+        let compute = compute.into_iter().map(|process| ("compute", process));
+        let graphics = graphics.into_iter().map(|process| ("graphics", process));
+        self.record(dev, compute.chain(graphics).collect(), samples)
+    }
+}
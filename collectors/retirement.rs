@@ -0,0 +1,223 @@
+use nvml_wrapper::error::NvmlError;
+use prometheus::core::Collector;
+use prometheus::{IntCounterVec, IntGaugeVec};
+
+use super::{available, gated, DeviceCollector, PerDevice};
+use crate::capabilities::Feature;
+use crate::{int_gauge_vec, MetricDevice, Result, GPU_LABELS};
+
+/// Memory taken out of use after errors: remapped rows, or, with GPUs before Ampere and older
+/// drivers, retired pages
+pub struct Retirement {
+    pub remapped_rows: PerDevice<IntGaugeVec>,
+    pub remapping_pending: PerDevice<IntGaugeVec>,
+    pub remapping_failed: PerDevice<IntGaugeVec>,
+    pub retired_pages: PerDevice<IntGaugeVec>,
+    pub retirement_pending: PerDevice<IntGaugeVec>,
+}
+
+impl Default for Retirement {
+    fn default() -> Self {
+        Retirement {
+            remapped_rows: PerDevice::new(int_gauge_vec(
+                "nvml_remapped_rows",
+                "Rows of memory remapped to spares, by the kind of error",
+                &[&GPU_LABELS[..], &["error"][..]].concat(),
+            )),
+            remapping_pending: PerDevice::new(int_gauge_vec(
+                "nvml_remapped_rows_pending",
+                "Whether rows are waiting for a GPU reset to be remapped (0/1)",
+                &GPU_LABELS,
+            )),
+            remapping_failed: PerDevice::new(int_gauge_vec(
+                "nvml_remapped_rows_failed",
+                "Whether remapping a row failed, for lack of spares (0/1)",
+                &GPU_LABELS,
+            )),
+            retired_pages: PerDevice::new(int_gauge_vec(
+                "nvml_retired_pages",
+                "Pages of memory retired, by the kind of ECC error",
+                &[&GPU_LABELS[..], &["error"][..]].concat(),
+            )),
+            retirement_pending: PerDevice::new(int_gauge_vec(
+                "nvml_retired_pages_pending",
+                "Whether pages are waiting for a reboot to be retired (0/1)",
+                &GPU_LABELS,
+            )),
+        }
+    }
+}
+
+/// Remapped rows, if the driver is new enough to ask for them
+fn remapped_rows(dev: &MetricDevice) -> std::result::Result<crate::gpu::RemappedRows, NvmlError> {
+    gated(dev, Feature::RemappedRows, || dev.gpu().remapped_rows())
+}
+
+impl DeviceCollector for Retirement {
+    fn name(&self) -> &'static str {
+        "retirement"
+    }
+    fn metrics(&self) -> Vec<(&'static str, &dyn Collector)> {
+        vec![
+            ("gauge", &self.remapped_rows),
+            ("gauge", &self.remapping_pending),
+            ("gauge", &self.remapping_failed),
+            ("gauge", &self.retired_pages),
+            ("gauge", &self.retirement_pending),
+        ]
+    }
+    fn supported(&self, dev: &MetricDevice) -> bool {
+        available(&remapped_rows(dev)) || available(&dev.gpu().retired_pages())
+    }
+    fn forget(&self, uuid: &str) {
+        self.remapped_rows.forget(uuid);
+        self.remapping_pending.forget(uuid);
+        self.remapping_failed.forget(uuid);
+        self.retired_pages.forget(uuid);
+        self.retirement_pending.forget(uuid);
+    }
+    fn update(&self, dev: &MetricDevice, errors: &IntCounterVec) -> Result<()> {
+        let rows = remapped_rows(dev);
+        if available(&rows) {
+            let rows = dev.query(errors, "remapped_rows", rows)?;
+            let remapped = [
+                ("correctable", rows.correctable),
+                ("uncorrectable", rows.uncorrectable),
+            ];
+            for (i, (error, count)) in remapped.into_iter().enumerate() {
+                self.remapped_rows
+                    .get_with(dev, i, error)?
+                    .set(count.into());
+            }
+            self.remapping_pending.get(dev)?.set(rows.pending.into());
+            self.remapping_failed.get(dev)?.set(rows.failed.into());
+            return Ok(());
+        }
+        let pages = dev.query(errors, "retired_pages", dev.gpu().retired_pages())?;
+        let retired = [
+            ("single_bit", pages.single_bit),
+            ("double_bit", pages.double_bit),
+        ];
+        for (i, (error, count)) in retired.into_iter().enumerate() {
+            self.retired_pages
+                .get_with(dev, i, error)?
+                .set(count.into());
+        }
+        self.retirement_pending.get(dev)?.set(pages.pending.into());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu::{Gpu, RemappedRows, RetiredPages};
+    use nvml_wrapper::enum_wrappers::device::{Clock, PerformanceState, TemperatureSensor};
+    use nvml_wrapper::struct_wrappers::device::{MemoryInfo, ProcessInfo, Utilization};
+
+    /// A GPU that has both remapped rows and retired pages, with the given driver
+    struct Stub(&'static str);
+
+    impl Gpu for Stub {
+        fn uuid(&self) -> std::result::Result<String, NvmlError> {
+            Ok("GPU-0".into())
+        }
+        fn name(&self) -> std::result::Result<String, NvmlError> {
+            Ok("Stub".into())
+        }
+        fn pci_bus_id(&self) -> std::result::Result<String, NvmlError> {
+            Ok("00000000:01:00.0".into())
+        }
+        fn driver_version(&self) -> std::result::Result<String, NvmlError> {
+            Ok(self.0.into())
+        }
+        fn remapped_rows(&self) -> std::result::Result<RemappedRows, NvmlError> {
+            Ok(RemappedRows {
+                correctable: 3,
+                ..Default::default()
+            })
+        }
+        fn retired_pages(&self) -> std::result::Result<RetiredPages, NvmlError> {
+            Ok(RetiredPages {
+                single_bit: 5,
+                ..Default::default()
+            })
+        }
+        fn index(&self) -> std::result::Result<u32, NvmlError> {
+            Err(NvmlError::NotSupported)
+        }
+        fn mig_mode(&self) -> std::result::Result<bool, NvmlError> {
+            Err(NvmlError::NotSupported)
+        }
+        fn memory_info(&self) -> std::result::Result<MemoryInfo, NvmlError> {
+            Err(NvmlError::NotSupported)
+        }
+        fn fan_speed(&self, _: u32) -> std::result::Result<u32, NvmlError> {
+            Err(NvmlError::NotSupported)
+        }
+        fn temperature(&self, _: TemperatureSensor) -> std::result::Result<u32, NvmlError> {
+            Err(NvmlError::NotSupported)
+        }
+        fn performance_state(&self) -> std::result::Result<PerformanceState, NvmlError> {
+            Err(NvmlError::NotSupported)
+        }
+        fn power_usage(&self) -> std::result::Result<u32, NvmlError> {
+            Err(NvmlError::NotSupported)
+        }
+        fn enforced_power_limit(&self) -> std::result::Result<u32, NvmlError> {
+            Err(NvmlError::NotSupported)
+        }
+        fn total_energy_consumption(&self) -> std::result::Result<u64, NvmlError> {
+            Err(NvmlError::NotSupported)
+        }
+        fn pcie_replay_counter(&self) -> std::result::Result<u32, NvmlError> {
+            Err(NvmlError::NotSupported)
+        }
+        fn utilization_rates(&self) -> std::result::Result<Utilization, NvmlError> {
+            Err(NvmlError::NotSupported)
+        }
+        fn clock_info(&self, _: Clock) -> std::result::Result<u32, NvmlError> {
+            Err(NvmlError::NotSupported)
+        }
+        fn running_compute_processes(&self) -> std::result::Result<Vec<ProcessInfo>, NvmlError> {
+            Err(NvmlError::NotSupported)
+        }
+        fn running_graphics_processes(&self) -> std::result::Result<Vec<ProcessInfo>, NvmlError> {
+            Err(NvmlError::NotSupported)
+        }
+    }
+
+    /// The metrics collected with the driver, by name
+    fn collect(driver: &'static str) -> Vec<String> {
+        let dev = MetricDevice::new(Box::new(Stub(driver))).unwrap();
+        let retirement = Retirement::default();
+        let errors = crate::int_counter_vec("errors", "Errors", &["uuid", "function", "error"]);
+        assert!(retirement.supported(&dev));
+        retirement.update(&dev, &errors).unwrap();
+        let mut names = retirement
+            .metrics()
+            .into_iter()
+            .flat_map(|(_, metric)| metric.collect())
+            .filter(|family| !family.get_metric().is_empty())
+            .map(|family| family.get_name().to_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn remapped_rows_since_r450() {
+        let retired = ["nvml_retired_pages", "nvml_retired_pages_pending"];
+        let remapped = [
+            "nvml_remapped_rows",
+            "nvml_remapped_rows_failed",
+            "nvml_remapped_rows_pending",
+        ];
+        assert_eq!(collect("418.87.01"), retired);
+        assert_eq!(collect("440.118.02"), retired);
+        assert_eq!(collect("450.51.05"), remapped);
+        assert_eq!(collect("535.104.05"), remapped);
+        // Worth a try
+        assert_eq!(collect("unknown"), remapped);
+    }
+}
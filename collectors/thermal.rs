@@ -0,0 +1,76 @@
+use nvml_wrapper::enum_wrappers::device::TemperatureSensor;
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, IntCounterVec};
+
+use super::{supported, DeviceCollector, PerDevice};
+use crate::{gauge_vec, MetricDevice, Result, GPU_LABELS};
+
+/// Temperature and fans
+pub struct Thermal {
+    pub temperature: PerDevice<GaugeVec>,
+    pub fan_speed: PerDevice<GaugeVec>,
+    pub fan_speed_ratio: PerDevice<GaugeVec>,
+}
+
+impl Default for Thermal {
+    fn default() -> Self {
+        Thermal {
+            temperature: PerDevice::new(gauge_vec("nvml_temp", "Temperature degC", &GPU_LABELS)),
+            fan_speed: PerDevice::new(gauge_vec(
+                "nvml_fan_speed",
+                "Fan speed (0-1)",
+                &[&GPU_LABELS[..], &["fan"][..]].concat(),
+            )),
+            // The same, named like the other ratios
+            fan_speed_ratio: PerDevice::new(gauge_vec(
+                "nvml_fan_speed_ratio",
+                "Fan speed of the maximum (0-1)",
+                &[&GPU_LABELS[..], &["fan"][..]].concat(),
+            )),
+        }
+    }
+}
+
+impl DeviceCollector for Thermal {
+    fn name(&self) -> &'static str {
+        "thermal"
+    }
+    fn metrics(&self) -> Vec<(&'static str, &dyn Collector)> {
+        vec![
+            ("gauge", &self.temperature),
+            ("gauge", &self.fan_speed),
+            ("gauge", &self.fan_speed_ratio),
+        ]
+    }
+    fn supported(&self, dev: &MetricDevice) -> bool {
+        supported(dev.gpu().temperature(TemperatureSensor::Gpu))
+    }
+    fn forget(&self, uuid: &str) {
+        self.temperature.forget(uuid);
+        self.fan_speed.forget(uuid);
+        self.fan_speed_ratio.forget(uuid);
+    }
+    fn update(&self, dev: &MetricDevice, errors: &IntCounterVec) -> Result<()> {
+        let temperature = dev.query(
+            errors,
+            "temperature",
+            dev.gpu().temperature(TemperatureSensor::Gpu),
+        )?;
+        if let Some(temperature) = dev.gauge("nvml_temp", temperature as f64, 1. ..=150.) {
+            self.temperature.get(dev)?.set(temperature);
+        }
+        for i in 0..dev.fan_count() {
+            let fan = i.to_string();
+            let speed = dev.query(errors, "fan_speed", dev.gpu().fan_speed(i))?;
+            let Some(speed) = dev.gauge("nvml_fan_speed", speed as f64, 0. ..=100.) else {
+                continue;
+            };
+            let speed = speed / 100.;
+            self.fan_speed.get_with(dev, i as usize, &fan)?.set(speed);
+            self.fan_speed_ratio
+                .get_with(dev, i as usize, &fan)?
+                .set(speed);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, IntCounterVec};
+
+use super::{supported, DeviceCollector, PerDevice};
+use crate::{gauge_vec, MetricDevice, Result, GPU_LABELS};
+
+/// How busy the GPU and its memory are
+pub struct Utilization {
+    pub gpu: PerDevice<GaugeVec>,
+    pub memory: PerDevice<GaugeVec>,
+}
+
+impl Default for Utilization {
+    fn default() -> Self {
+        Utilization {
+            gpu: PerDevice::new(gauge_vec(
+                "nvml_utilization_gpu",
+                "Fraction of time kernels were running (0-1)",
+                &GPU_LABELS,
+            )),
+            memory: PerDevice::new(gauge_vec(
+                "nvml_utilization_memory",
+                "Fraction of time memory was read or written (0-1)",
+                &GPU_LABELS,
+            )),
+        }
+    }
+}
+
+impl DeviceCollector for Utilization {
+    fn name(&self) -> &'static str {
+        "utilization"
+    }
+    fn metrics(&self) -> Vec<(&'static str, &dyn Collector)> {
+        vec![("gauge", &self.gpu), ("gauge", &self.memory)]
+    }
+    fn supported(&self, dev: &MetricDevice) -> bool {
+        supported(dev.gpu().utilization_rates())
+    }
+    fn forget(&self, uuid: &str) {
+        self.gpu.forget(uuid);
+        self.memory.forget(uuid);
+    }
+    fn update(&self, dev: &MetricDevice, errors: &IntCounterVec) -> Result<()> {
+        let rates = dev.query(errors, "utilization_rates", dev.gpu().utilization_rates())?;
+        if let Some(gpu) = dev.gauge("nvml_utilization_gpu", rates.gpu as f64, 0. ..=100.) {
+            self.gpu.get(dev)?.set(gpu / 100.);
+        }
+        if !dev.gpu().memory_utilization() {
+            return Ok(());
+        }
+        let memory = rates.memory as f64;
+        if let Some(memory) = dev.gauge("nvml_utilization_memory", memory, 0. ..=100.) {
+            self.memory.get(dev)?.set(memory / 100.);
+        }
+        Ok(())
+    }
+}
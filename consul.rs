@@ -0,0 +1,77 @@
+//! Registering the exporter with the local Consul agent, for Prometheus' consul_sd_configs
+
+use serde_json::json;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use tracing::info;
+
+use crate::Result;
+
+pub struct Registration {
+    agent: ureq::Agent,
+    url: String,
+    token: Option<String>,
+    id: String,
+}
+
+impl Registration {
+    /// Register a service for the listening address, with a TCP check by the agent
+    pub fn register(
+        url: &str,
+        service: &str,
+        hostname: &str,
+        listening: SocketAddr,
+        token_file: Option<&Path>,
+    ) -> Result<Registration> {
+        let token = match token_file {
+            Some(path) => Some(std::fs::read_to_string(path)?.trim().to_owned()),
+            None => None,
+        };
+        let registration = Registration {
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(5))
+                .build(),
+            url: url.trim_end_matches('/').to_owned(),
+            token,
+            // Unique per agent, in case several exporters share a node
+            id: format!("{service}-{hostname}-{}", listening.port()),
+        };
+        // The agent runs on the same host
+        let check = match listening.ip().is_unspecified() {
+            true => SocketAddr::from(([127, 0, 0, 1], listening.port())),
+            false => listening,
+        };
+        let body = json!({
+            "ID": registration.id,
+            "Name": service,
+            "Port": listening.port(),
+            "Check": {
+                "TCP": check.to_string(),
+                "Interval": "30s",
+                // Clean up after exporters that went away without deregistering
+                "DeregisterCriticalServiceAfter": "1h",
+            },
+        });
+        registration
+            .request("/v1/agent/service/register")
+            .send_string(&body.to_string())?;
+        info!(id = registration.id, "Registered with Consul");
+        Ok(registration)
+    }
+
+    pub fn deregister(self) -> Result<()> {
+        let path = format!("/v1/agent/service/deregister/{}", self.id);
+        self.request(&path).call()?;
+        info!(id = self.id, "Deregistered from Consul");
+        Ok(())
+    }
+
+    fn request(&self, path: &str) -> ureq::Request {
+        let request = self.agent.put(&format!("{}{path}", self.url));
+        match &self.token {
+            Some(token) => request.set("X-Consul-Token", token),
+            None => request,
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Running in the background, for init scripts without a service manager to do it
+//!
+//! The process forks twice, so the daemon is neither a session leader nor the child of the
+//! caller. The caller waits until the daemon is ready, and exits with whether it got there, so
+//! failures on startup still reach it, on stderr and as the exit status.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::Result;
+
+/// Held by the daemon until it is ready
+pub struct Daemon {
+    ready: Option<File>,
+    pid_file: Option<PathBuf>,
+    /// The pid file's directory, if it was created for it
+    pid_dir: Option<PathBuf>,
+}
+
+/// Go to the background, unless `daemonize` is false. Must be called before any threads are
+/// started, as only the calling one survives forking.
+pub fn start(daemonize: bool, pid_file: Option<&Path>) -> Result<Daemon> {
+    let mut daemon = Daemon {
+        ready: None,
+        pid_file: pid_file.map(Path::to_owned),
+        pid_dir: None,
+    };
+    if daemonize {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let (mut read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        if fork()? {
+            drop(write);
+            // Only written to once ready, otherwise closed by the daemon exiting
+            let mut ready = [0];
+            let status = match read.read(&mut ready) {
+                Ok(1) => 0,
+                _ => 1,
+            };
+            std::process::exit(status);
+        }
+        drop(read);
+        if unsafe { libc::setsid() } < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        if fork()? {
+            std::process::exit(0);
+        }
+        let null = File::options().read(true).write(true).open("/dev/null")?;
+        for fd in [0, 1] {
+            unsafe { libc::dup2(null.as_raw_fd(), fd) };
+        }
+        daemon.ready = Some(write);
+    }
+    // Before privileges are dropped, pid files tend to be in /run
+    if let Some(path) = &daemon.pid_file {
+        // Like /run/prometheus-nvml-exporter, for the daemon to remove the file from once
+        // unprivileged
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        if let Some(dir) = dir.filter(|dir| !dir.exists()) {
+            std::fs::create_dir_all(dir)?;
+            daemon.pid_dir = Some(dir.to_owned());
+        }
+        std::fs::write(path, format!("{}\n", std::process::id()))?;
+    }
+    Ok(daemon)
+}
+
+/// Whether this is the parent
+fn fork() -> Result<bool> {
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error().into()),
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+impl Daemon {
+    /// Let the caller exit. From here on, stderr is gone too.
+    pub fn ready(&mut self) -> Result<()> {
+        if let Some(mut ready) = self.ready.take() {
+            // Not to keep a file system busy, now that the configuration has been read
+            std::env::set_current_dir("/")?;
+            let null = File::options().write(true).open("/dev/null")?;
+            unsafe { libc::dup2(null.as_raw_fd(), 2) };
+            ready.write_all(&[1])?;
+        }
+        Ok(())
+    }
+
+    /// Before privileges are dropped, so the pid file can still be removed after
+    pub fn hand_over(&self, user: Option<&str>, group: Option<&str>) -> Result<()> {
+        if let Some(dir) = &self.pid_dir {
+            crate::privileges::chown(dir, user, group)?;
+        }
+        Ok(())
+    }
+
+    pub fn stop(self) {
+        if let Some(path) = &self.pid_file {
+            if let Err(e) = std::fs::remove_file(path) {
+                // Its directory isn't writable after privileges were dropped
+                warn!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use nvml_wrapper::error::NvmlError;
+
+/// A failed NVML query, remembering which function failed
+#[derive(Debug)]
+pub struct QueryError {
+    pub function: &'static str,
+    pub error: NvmlError,
+}
+
+impl std::fmt::Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.function, self.error)
+    }
+}
+
+impl std::error::Error for QueryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Name of the NVML return code behind an error, if it came from NVML
+pub fn nvml_code(e: &(dyn std::error::Error + 'static)) -> Option<&'static str> {
+    match e.downcast_ref::<QueryError>() {
+        Some(e) => Some(code(&e.error)),
+        None => e.downcast_ref::<NvmlError>().map(code),
+    }
+}
+
+pub fn code(e: &NvmlError) -> &'static str {
+    #[allow(deprecated)]
+    match e {
+        NvmlError::Utf8Error(_) => "Utf8Error",
+        NvmlError::NulError(_) => "NulError",
+        NvmlError::LibloadingError(_) => "LibloadingError",
+        NvmlError::FailedToLoadSymbol(_) => "FailedToLoadSymbol",
+        NvmlError::StringTooLong { .. } => "StringTooLong",
+        NvmlError::IncorrectBits(_) => "IncorrectBits",
+        NvmlError::UnexpectedVariant(_) => "UnexpectedVariant",
+        NvmlError::SetReleaseFailed => "SetReleaseFailed",
+        NvmlError::GetPciInfoFailed => "GetPciInfoFailed",
+        NvmlError::PciInfoToCFailed => "PciInfoToCFailed",
+        NvmlError::Uninitialized => "Uninitialized",
+        NvmlError::InvalidArg => "InvalidArg",
+        NvmlError::NotSupported => "NotSupported",
+        NvmlError::NoPermission => "NoPermission",
+        NvmlError::AlreadyInitialized => "AlreadyInitialized",
+        NvmlError::NotFound => "NotFound",
+        NvmlError::InsufficientSize(_) => "InsufficientSize",
+        NvmlError::InsufficientPower => "InsufficientPower",
+        NvmlError::DriverNotLoaded => "DriverNotLoaded",
+        NvmlError::Timeout => "Timeout",
+        NvmlError::IrqIssue => "IrqIssue",
+        NvmlError::LibraryNotFound => "LibraryNotFound",
+        NvmlError::FunctionNotFound => "FunctionNotFound",
+        NvmlError::CorruptedInfoROM => "CorruptedInfoROM",
+        NvmlError::GpuLost => "GpuLost",
+        NvmlError::ResetRequired => "ResetRequired",
+        NvmlError::OperatingSystem => "OperatingSystem",
+        NvmlError::LibRmVersionMismatch => "LibRmVersionMismatch",
+        NvmlError::InUse => "InUse",
+        NvmlError::InsufficientMemory => "InsufficientMemory",
+        NvmlError::NoData => "NoData",
+        NvmlError::VgpuEccNotSupported => "VgpuEccNotSupported",
+        NvmlError::Unknown => "Unknown",
+    }
+}
+
+/// The inverse of [`code`], as far as the error carries no data
+pub fn from_code(code: &str) -> NvmlError {
+    match code {
+        "SetReleaseFailed" => NvmlError::SetReleaseFailed,
+        "GetPciInfoFailed" => NvmlError::GetPciInfoFailed,
+        "PciInfoToCFailed" => NvmlError::PciInfoToCFailed,
+        "Uninitialized" => NvmlError::Uninitialized,
+        "InvalidArg" => NvmlError::InvalidArg,
+        "NotSupported" => NvmlError::NotSupported,
+        "NoPermission" => NvmlError::NoPermission,
+        "NotFound" => NvmlError::NotFound,
+        "InsufficientPower" => NvmlError::InsufficientPower,
+        "DriverNotLoaded" => NvmlError::DriverNotLoaded,
+        "Timeout" => NvmlError::Timeout,
+        "IrqIssue" => NvmlError::IrqIssue,
+        "LibraryNotFound" => NvmlError::LibraryNotFound,
+        "FunctionNotFound" => NvmlError::FunctionNotFound,
+        "CorruptedInfoROM" => NvmlError::CorruptedInfoROM,
+        "GpuLost" => NvmlError::GpuLost,
+        "ResetRequired" => NvmlError::ResetRequired,
+        "OperatingSystem" => NvmlError::OperatingSystem,
+        "LibRmVersionMismatch" => NvmlError::LibRmVersionMismatch,
+        "InUse" => NvmlError::InUse,
+        "InsufficientMemory" => NvmlError::InsufficientMemory,
+        "NoData" => NvmlError::NoData,
+        "VgpuEccNotSupported" => NvmlError::VgpuEccNotSupported,
+        _ => NvmlError::Unknown,
+    }
+}
@@ -0,0 +1,87 @@
+//! Logging to the Windows Event Log, for when running as a service
+
+use std::fmt::Write;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use windows_sys::Win32::Foundation::HANDLE;
+use windows_sys::Win32::System::EventLog::*;
+
+use crate::Result;
+
+const SOURCE: &str = "prometheus-nvml-exporter";
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain([0]).collect()
+}
+
+/// An event source handle, deregistered on drop
+pub struct Layer(HANDLE);
+
+impl Layer {
+    pub fn register() -> Result<Layer> {
+        match unsafe { RegisterEventSourceW(std::ptr::null(), wide(SOURCE).as_ptr()) } {
+            0 => Err(std::io::Error::last_os_error().into()),
+            handle => Ok(Layer(handle)),
+        }
+    }
+}
+
+impl Drop for Layer {
+    fn drop(&mut self) {
+        unsafe { DeregisterEventSource(self.0) };
+    }
+}
+
+impl<S: Subscriber> tracing_subscriber::Layer<S> for Layer {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        let kind = match *event.metadata().level() {
+            Level::ERROR => EVENTLOG_ERROR_TYPE,
+            Level::WARN => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        let mut message = Message::default();
+        event.record(&mut message);
+        let text = wide(&(message.message + &message.fields));
+        let strings = [text.as_ptr()];
+        // Nowhere left to report a failure to
+        unsafe {
+            ReportEventW(
+                self.0,
+                kind,
+                0,
+                0,
+                std::ptr::null_mut(),
+                strings.len() as _,
+                0,
+                strings.as_ptr(),
+                std::ptr::null(),
+            )
+        };
+    }
+}
+
+/// The message, and the other fields as name=value to follow it
+#[derive(Default)]
+struct Message {
+    message: String,
+    fields: String,
+}
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        match field.name() {
+            "message" => write!(self.message, "{:?}", value),
+            name => write!(self.fields, " {}={:?}", name, value),
+        }
+        .ok();
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => write!(self.message, "{}", value),
+            name => write!(self.fields, " {}={:?}", name, value),
+        }
+        .ok();
+    }
+}
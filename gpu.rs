@@ -0,0 +1,231 @@
+//! The device queries the collectors are built on, so devices don't have to come from NVML
+
+use nvml_wrapper::enum_wrappers::device::{
+    Clock, PerformanceState, RetirementCause, TemperatureSensor,
+};
+use nvml_wrapper::enums::device::SampleValue;
+use nvml_wrapper::error::NvmlError;
+use nvml_wrapper::struct_wrappers::device::{
+    MemoryInfo, ProcessInfo, ProcessUtilizationSample, Utilization,
+};
+use nvml_wrapper::structs::device::FieldId;
+use nvml_wrapper::Device;
+use nvml_wrapper_sys::bindings::field_id::{
+    NVML_FI_DEV_PCIE_REPLAY_COUNTER, NVML_FI_DEV_TOTAL_ENERGY_CONSUMPTION,
+};
+
+use crate::raw;
+
+/// The clock domains collected, in this order
+pub static CLOCKS: [Clock; 4] = [Clock::Graphics, Clock::SM, Clock::Memory, Clock::Video];
+
+/// The scalar values fetched in one batch at the start of a collection, see
+/// [`Gpu::field_values`]
+pub static FIELDS: [u32; 2] = [
+    NVML_FI_DEV_TOTAL_ENERGY_CONSUMPTION,
+    NVML_FI_DEV_PCIE_REPLAY_COUNTER,
+];
+
+/// Rows of memory remapped to spares after errors, see [`Gpu::remapped_rows`]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RemappedRows {
+    pub correctable: u32,
+    pub uncorrectable: u32,
+    /// Remapping takes a GPU reset
+    pub pending: bool,
+    /// Remapping failed, the GPU ran out of spare rows
+    pub failed: bool,
+}
+
+/// Pages of memory taken out of use after ECC errors, see [`Gpu::retired_pages`]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RetiredPages {
+    /// After multiple single bit errors
+    pub single_bit: u32,
+    /// After a double bit error
+    pub double_bit: u32,
+    /// Retiring takes a reboot
+    pub pending: bool,
+}
+
+/// A GPU's place in an NVLink fabric of NVSwitches, see [`Gpu::fabric_info`]
+#[derive(Clone, Debug)]
+pub struct FabricInfo {
+    pub cluster_uuid: String,
+    /// Of the GPUs it can reach over NVLink
+    pub clique_id: u32,
+    /// Of joining the fabric: `not_started`, `in_progress`, or `completed`
+    pub state: &'static str,
+    /// Whether it failed, once completed
+    pub failed: bool,
+}
+
+/// Mirrors the methods of [`nvml_wrapper::Device`] of the same name
+pub trait Gpu: Send {
+    fn uuid(&self) -> Result<String, NvmlError>;
+    fn name(&self) -> Result<String, NvmlError>;
+    fn pci_bus_id(&self) -> Result<String, NvmlError>;
+    fn index(&self) -> Result<u32, NvmlError>;
+    fn mig_mode(&self) -> Result<bool, NvmlError>;
+    fn memory_info(&self) -> Result<MemoryInfo, NvmlError>;
+    fn fan_speed(&self, fan: u32) -> Result<u32, NvmlError>;
+    fn temperature(&self, sensor: TemperatureSensor) -> Result<u32, NvmlError>;
+    fn performance_state(&self) -> Result<PerformanceState, NvmlError>;
+    fn power_usage(&self) -> Result<u32, NvmlError>;
+    fn enforced_power_limit(&self) -> Result<u32, NvmlError>;
+    fn total_energy_consumption(&self) -> Result<u64, NvmlError>;
+    fn pcie_replay_counter(&self) -> Result<u32, NvmlError>;
+    fn utilization_rates(&self) -> Result<Utilization, NvmlError>;
+    /// Whether [`Gpu::utilization_rates`] has the memory's utilization, rather than 0 where the
+    /// backend only knows the GPU's
+    fn memory_utilization(&self) -> bool {
+        true
+    }
+    /// In MHz
+    fn clock_info(&self, clock: Clock) -> Result<u32, NvmlError>;
+    fn running_compute_processes(&self) -> Result<Vec<ProcessInfo>, NvmlError>;
+    fn running_graphics_processes(&self) -> Result<Vec<ProcessInfo>, NvmlError>;
+    /// Several of the scalar values above in one driver call, by NVML field id
+    /// (`nvmlDeviceGetFieldValues`). Without such a call, they are queried one by one.
+    fn field_values(&self, _fields: &[u32]) -> Result<Vec<Result<u64, NvmlError>>, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    /// Per process SM, memory, encoder, and decoder utilization, as sampled by the driver
+    fn process_utilization_stats(&self) -> Result<Vec<ProcessUtilizationSample>, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn vbios_version(&self) -> Result<String, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    /// Of the driver the device is run by (`nvmlSystemGetDriverVersion`)
+    fn driver_version(&self) -> Result<String, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    /// The uuids of the MIG devices the GPU is partitioned into, with their profile, e.g.
+    /// `1g.10gb`
+    fn mig_profiles(&self) -> Result<Vec<(String, String)>, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    /// Of Ampere and later GPUs, with drivers since R450 (`nvmlDeviceGetRemappedRows`)
+    fn remapped_rows(&self) -> Result<RemappedRows, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    /// Of GPUs before Ampere, which retire pages instead of remapping rows
+    fn retired_pages(&self) -> Result<RetiredPages, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    /// Of Hopper and later, with drivers since R520: the given GPM metrics, by id, averaged
+    /// since the previous call (`nvmlGpmMetricsGet`). The first call has `NoData`.
+    fn gpm_metrics(&self, _metrics: &[u32]) -> Result<Vec<Result<f64, NvmlError>>, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    /// Of GPUs on NVSwitches, with drivers since R525 (`nvmlDeviceGetGpuFabricInfo`)
+    fn fabric_info(&self) -> Result<FabricInfo, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    /// Of Grace Hopper, with drivers since R550: whether the chip-to-chip link is enabled
+    /// (`nvmlDeviceGetC2cModeInfoV`)
+    fn c2c_mode(&self) -> Result<bool, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+}
+
+impl Gpu for Device<'_> {
+    fn uuid(&self) -> Result<String, NvmlError> {
+        Device::uuid(self)
+    }
+    fn name(&self) -> Result<String, NvmlError> {
+        Device::name(self)
+    }
+    fn pci_bus_id(&self) -> Result<String, NvmlError> {
+        Ok(Device::pci_info(self)?.bus_id)
+    }
+    fn index(&self) -> Result<u32, NvmlError> {
+        Device::index(self)
+    }
+    fn mig_mode(&self) -> Result<bool, NvmlError> {
+        raw::mig_mode(self)
+    }
+    fn memory_info(&self) -> Result<MemoryInfo, NvmlError> {
+        Device::memory_info(self)
+    }
+    fn fan_speed(&self, fan: u32) -> Result<u32, NvmlError> {
+        Device::fan_speed(self, fan)
+    }
+    fn temperature(&self, sensor: TemperatureSensor) -> Result<u32, NvmlError> {
+        Device::temperature(self, sensor)
+    }
+    fn performance_state(&self) -> Result<PerformanceState, NvmlError> {
+        Device::performance_state(self)
+    }
+    fn power_usage(&self) -> Result<u32, NvmlError> {
+        Device::power_usage(self)
+    }
+    fn enforced_power_limit(&self) -> Result<u32, NvmlError> {
+        Device::enforced_power_limit(self)
+    }
+    fn total_energy_consumption(&self) -> Result<u64, NvmlError> {
+        Device::total_energy_consumption(self)
+    }
+    fn pcie_replay_counter(&self) -> Result<u32, NvmlError> {
+        Device::pcie_replay_counter(self)
+    }
+    fn utilization_rates(&self) -> Result<Utilization, NvmlError> {
+        Device::utilization_rates(self)
+    }
+    fn clock_info(&self, clock: Clock) -> Result<u32, NvmlError> {
+        Device::clock_info(self, clock)
+    }
+    fn running_compute_processes(&self) -> Result<Vec<ProcessInfo>, NvmlError> {
+        Device::running_compute_processes(self)
+    }
+    fn running_graphics_processes(&self) -> Result<Vec<ProcessInfo>, NvmlError> {
+        Device::running_graphics_processes(self)
+    }
+    fn process_utilization_stats(&self) -> Result<Vec<ProcessUtilizationSample>, NvmlError> {
+        // All samples still buffered, not only those since a timestamp
+        Device::process_utilization_stats(self, None)
+    }
+    fn vbios_version(&self) -> Result<String, NvmlError> {
+        Device::vbios_version(self)
+    }
+    fn driver_version(&self) -> Result<String, NvmlError> {
+        self.nvml().sys_driver_version()
+    }
+    fn mig_profiles(&self) -> Result<Vec<(String, String)>, NvmlError> {
+        raw::mig_profiles(self)
+    }
+    fn remapped_rows(&self) -> Result<RemappedRows, NvmlError> {
+        raw::remapped_rows(self)
+    }
+    fn retired_pages(&self) -> Result<RetiredPages, NvmlError> {
+        let count = |cause| Device::retired_pages(self, cause).map(|pages| pages.len() as u32);
+        Ok(RetiredPages {
+            single_bit: count(RetirementCause::MultipleSingleBitEccErrors)?,
+            double_bit: count(RetirementCause::DoubleBitEccError)?,
+            pending: Device::are_pages_pending_retired(self)?,
+        })
+    }
+    fn gpm_metrics(&self, metrics: &[u32]) -> Result<Vec<Result<f64, NvmlError>>, NvmlError> {
+        raw::gpm_metrics(self, metrics)
+    }
+    fn fabric_info(&self) -> Result<FabricInfo, NvmlError> {
+        raw::fabric_info(self)
+    }
+    fn c2c_mode(&self) -> Result<bool, NvmlError> {
+        raw::c2c_mode(self)
+    }
+    fn field_values(&self, fields: &[u32]) -> Result<Vec<Result<u64, NvmlError>>, NvmlError> {
+        let ids = fields.iter().map(|&id| FieldId(id)).collect::<Vec<_>>();
+        let samples = Device::field_values_for(self, &ids)?;
+        Ok(samples
+            .into_iter()
+            .map(|sample| match sample?.value? {
+                SampleValue::U32(v) => Ok(v.into()),
+                SampleValue::U64(v) => Ok(v),
+                SampleValue::I64(v) => v.try_into().map_err(|_| NvmlError::Unknown),
+                SampleValue::F64(_) => Err(NvmlError::Unknown),
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,197 @@
+//! A Grafana dashboard for this exporter's metrics, with the names and labels they are exported
+//! with, so it doesn't drift from the metrics
+
+use prometheus::core::Collector;
+use serde_json::{json, Value};
+
+use crate::groups::Groups;
+use crate::relabel::Rules;
+use crate::GPU_LABELS;
+use nvml_exporter::collectors::{Memory, Power, Thermal, Utilization};
+
+/// How the metrics are exported, from the serving options
+pub struct Schema<'a> {
+    pub hostname: bool,
+    pub aliases: bool,
+    pub groups: Option<&'a Groups>,
+    pub rules: Option<&'a Rules>,
+}
+
+/// A metric as exported
+struct Metric {
+    name: String,
+    /// The labels it has before relabeling, and their names after, if they're kept
+    labels: Vec<(String, Option<String>)>,
+}
+
+impl Metric {
+    /// What the label is called after relabeling
+    fn label(&self, label: &str) -> Option<String> {
+        let (_, renamed) = self.labels.iter().find(|(l, _)| l == label)?;
+        renamed.clone()
+    }
+}
+
+impl Schema<'_> {
+    /// The metric after relabeling, none if the rules drop it
+    fn metric(&self, metric: &dyn Collector) -> Option<Metric> {
+        let desc = &metric.desc()[0];
+        let mut labels = desc.variable_labels.clone();
+        labels.push("instance".into());
+        if self.hostname {
+            labels.push("hostname".into());
+        }
+        if self.aliases {
+            labels.push("alias".into());
+        }
+        labels.extend(
+            self.groups
+                .into_iter()
+                .flat_map(Groups::labels)
+                .map(Into::into),
+        );
+        let (name, renamed) = match self.rules {
+            Some(rules) => {
+                let labels = labels.iter().map(String::as_str).collect::<Vec<_>>();
+                rules.schema(&desc.fq_name, &labels)?
+            }
+            None => (
+                desc.fq_name.clone(),
+                labels.iter().cloned().map(Some).collect(),
+            ),
+        };
+        Some(Metric {
+            name,
+            labels: labels.into_iter().zip(renamed).collect(),
+        })
+    }
+
+    /// The labels to pick GPUs by, the first being shown in legends
+    fn selectors(&self) -> Vec<&str> {
+        let mut selectors = vec![match self.hostname {
+            true => "hostname",
+            false => "instance",
+        }];
+        selectors.extend(self.groups.into_iter().flat_map(Groups::labels));
+        selectors.push(GPU_LABELS[0]);
+        selectors
+    }
+}
+
+fn variable(name: &str, metric: &Metric, label: &str) -> Value {
+    let query = format!("label_values({}, {})", metric.name, label);
+    json!({
+        "name": name,
+        "label": name,
+        "type": "query",
+        "datasource": {"type": "prometheus", "uid": "$datasource"},
+        "query": {"query": query, "refId": name},
+        "definition": query,
+        "refresh": 2,
+        "multi": true,
+        "includeAll": true,
+        // So that "All" also matches series without the label, like GPUs in no group
+        "allValue": ".*",
+        "current": {"text": "All", "value": "$__all"},
+        "sort": 1,
+    })
+}
+
+fn panel(id: usize, title: &str, unit: &str, expr: String, legend: String) -> Value {
+    json!({
+        "id": id,
+        "title": title,
+        "type": "timeseries",
+        "datasource": {"type": "prometheus", "uid": "$datasource"},
+        "gridPos": {"h": 8, "w": 12, "x": (id - 1) % 2 * 12, "y": (id - 1) / 2 * 8},
+        "fieldConfig": {"defaults": {"unit": unit}, "overrides": []},
+        "options": {"legend": {"displayMode": "list", "placement": "bottom"}},
+        "targets": [{"expr": expr, "legendFormat": legend, "refId": "A"}],
+    })
+}
+
+/// The dashboard as JSON, for importing. Panels of metrics the rules drop, or of which they drop
+/// the labels needed to tell the GPUs apart, are left out.
+pub fn generate(schema: &Schema) -> crate::Result<String> {
+    let (utilization, memory, thermal, power) = (
+        Utilization::default(),
+        Memory::default(),
+        Thermal::default(),
+        Power::default(),
+    );
+    let panels: [(&str, &str, &dyn Collector, Option<&str>); 6] = [
+        ("GPU utilization", "percentunit", &utilization.gpu, None),
+        (
+            "Memory utilization",
+            "percentunit",
+            &utilization.memory,
+            None,
+        ),
+        ("Memory used", "bytes", &memory.used, None),
+        ("Temperature", "celsius", &thermal.temperature, None),
+        ("Power", "mwatt", &power.usage, None),
+        ("Fan speed", "percentunit", &thermal.fan_speed, Some("fan")),
+    ];
+    let selectors = schema.selectors();
+    let Some(reference) = schema.metric(&thermal.temperature) else {
+        return Err(
+            "The relabeling rules drop the temperature, which the dashboard needs to \
+            list the GPUs"
+                .into(),
+        );
+    };
+    let mut variables = vec![json!({
+        "name": "datasource",
+        "label": "Data source",
+        "type": "datasource",
+        "query": "prometheus",
+    })];
+    for &selector in &selectors {
+        let Some(label) = reference.label(selector) else {
+            return Err(format!("The relabeling rules drop the {selector} label").into());
+        };
+        variables.push(variable(selector, &reference, &label));
+    }
+    let mut rendered = vec![];
+    for (title, unit, metric, extra) in panels {
+        let Some(metric) = schema.metric(metric) else {
+            continue;
+        };
+        let labels = selectors
+            .iter()
+            .map(|&selector| Some((metric.label(selector)?, selector)))
+            .collect::<Option<Vec<_>>>();
+        let Some(labels) = labels else {
+            continue;
+        };
+        let matchers = labels
+            .iter()
+            .map(|(label, variable)| format!("{label}=~\"${variable}\""))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let gpu = match schema.aliases {
+            true => metric
+                .label("alias")
+                .unwrap_or_else(|| labels[labels.len() - 1].0.clone()),
+            false => labels[labels.len() - 1].0.clone(),
+        };
+        let mut legend = format!("{{{{{}}}}} {{{{{}}}}}", labels[0].0, gpu);
+        if let Some(extra) = extra.and_then(|extra| metric.label(extra)) {
+            legend.push_str(&format!(" {extra} {{{{{extra}}}}}"));
+        }
+        let expr = format!("{}{{{}}}", metric.name, matchers);
+        rendered.push(panel(rendered.len() + 1, title, unit, expr, legend));
+    }
+    let dashboard = json!({
+        "title": "NVIDIA GPUs",
+        "uid": "nvml-exporter",
+        "tags": ["nvml", "gpu"],
+        "editable": true,
+        "schemaVersion": 39,
+        "time": {"from": "now-1h", "to": "now"},
+        "refresh": "30s",
+        "templating": {"list": variables},
+        "panels": rendered,
+    });
+    Ok(serde_json::to_string_pretty(&dashboard)?)
+}
@@ -0,0 +1,96 @@
+//! Grouping GPUs, e.g. into pools, with a label per grouping, for aggregating by group
+
+use prometheus::proto::{LabelPair, MetricFamily};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::aliases::key;
+use crate::{Result, GPU_LABELS};
+
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+enum Member {
+    Index(usize),
+    /// A uuid, a PCI address, or a range of indices like `4-7`
+    Gpu(String),
+}
+
+impl Member {
+    /// Whether it is the GPU with the index and label values
+    fn is(&self, index: Option<usize>, [uuid, _, pci]: &[String; 3]) -> bool {
+        match self {
+            Member::Index(i) => index == Some(*i),
+            Member::Gpu(gpu) => match gpu
+                .split_once('-')
+                .map(|(from, to)| (from.parse(), to.parse()))
+            {
+                Some((Ok(from), Ok(to))) => index.is_some_and(|i: usize| (from..=to).contains(&i)),
+                _ => key(gpu) == *uuid || key(gpu) == key(pci),
+            },
+        }
+    }
+}
+
+/// By label, and by the label's value, the members of the group
+#[derive(Clone)]
+pub struct Groups(BTreeMap<String, BTreeMap<String, Vec<Member>>>);
+
+impl Groups {
+    /// A YAML mapping of label names to groups, which map the label's values to lists of GPU
+    /// indices, index ranges, uuids, or PCI addresses, e.g. `pool: {inference: [0-3]}`
+    pub fn load(path: &str) -> Result<Groups> {
+        let path = Path::new(path);
+        let groups: BTreeMap<String, BTreeMap<String, Vec<Member>>> =
+            serde_yaml::from_str(&std::fs::read_to_string(path)?)
+                .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+        for label in groups.keys() {
+            let mut chars = label.chars();
+            let valid = chars
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid || GPU_LABELS.contains(&label.as_str()) || label == "alias" {
+                return Err(format!("Can't group by {label:?} in {}", path.display()).into());
+            }
+        }
+        Ok(Groups(groups))
+    }
+
+    /// The names of the labels the groups are exported as
+    pub fn labels(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
+
+    /// Label the series of the devices, listed in the order of their indices, with their groups.
+    /// Where a GPU is in more than one group of a label, the first by name counts.
+    pub fn label(&self, families: &mut [MetricFamily], devices: &[[String; 3]]) {
+        for metric in families
+            .iter_mut()
+            .flat_map(|mf| mf.mut_metric().iter_mut())
+        {
+            let uuid = metric
+                .get_label()
+                .iter()
+                .find(|l| l.get_name() == GPU_LABELS[0])
+                .map(|l| l.get_value().to_owned());
+            let Some(uuid) = uuid else {
+                continue;
+            };
+            let index = devices.iter().position(|[u, ..]| *u == uuid);
+            let unknown = [uuid, String::new(), String::new()];
+            let labels = index.map_or(&unknown, |i| &devices[i]);
+            for (label, groups) in &self.0 {
+                let group = groups
+                    .iter()
+                    .find(|(_, members)| members.iter().any(|m| m.is(index, labels)));
+                if let Some((value, _)) = group {
+                    let mut pair = LabelPair::default();
+                    pair.set_name(label.clone());
+                    pair.set_value(value.clone());
+                    metric.mut_label().push(pair);
+                }
+            }
+        }
+    }
+}
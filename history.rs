@@ -0,0 +1,334 @@
+//! Short term history in a local SQLite database, for workstations without Prometheus, and the
+//! query subcommand reading it back. libsqlite3 is loaded at runtime, like NVML.
+
+use libloading::{Library, Symbol};
+use prometheus::proto::MetricFamily;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::path::Path;
+use std::ptr::{null, null_mut};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::push::{csv_field, samples, Sink};
+use crate::{Result, GPU_LABELS};
+
+#[cfg(target_os = "linux")]
+const LIBRARY: &str = "libsqlite3.so.0";
+#[cfg(target_os = "macos")]
+const LIBRARY: &str = "libsqlite3.dylib";
+#[cfg(windows)]
+const LIBRARY: &str = "sqlite3.dll";
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+const LIBRARY: &str = "libsqlite3.so";
+
+const SQLITE_OK: c_int = 0;
+const SQLITE_ROW: c_int = 100;
+const SQLITE_DONE: c_int = 101;
+const SQLITE_OPEN_READONLY: c_int = 0x1;
+const SQLITE_OPEN_READWRITE: c_int = 0x2;
+const SQLITE_OPEN_CREATE: c_int = 0x4;
+/// Have SQLite copy bound strings
+const SQLITE_TRANSIENT: isize = -1;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS samples (
+        -- Milliseconds since the epoch
+        timestamp INTEGER NOT NULL,
+        metric TEXT NOT NULL,
+        uuid TEXT NOT NULL,
+        -- Those beyond the GPU's, as label=value;…
+        labels TEXT NOT NULL,
+        value REAL NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS samples_by_time ON samples (timestamp);
+    CREATE INDEX IF NOT EXISTS samples_by_metric ON samples (metric, timestamp);
+";
+
+/// An open database, closed on drop
+struct Database {
+    lib: Library,
+    db: *mut c_void,
+}
+
+// The connection is only ever used from one thread at a time
+unsafe impl Send for Database {}
+
+impl Database {
+    fn open(path: &Path, writable: bool) -> Result<Database> {
+        let lib = unsafe { Library::new(LIBRARY) }
+            .map_err(|e| format!("Failed to load {LIBRARY}: {e}"))?;
+        let mut database = Database {
+            lib,
+            db: null_mut(),
+        };
+        let open: Symbol<
+            unsafe extern "C" fn(*const c_char, *mut *mut c_void, c_int, *const c_char) -> c_int,
+        > = database.sym(b"sqlite3_open_v2\0")?;
+        let flags = match writable {
+            true => SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE,
+            false => SQLITE_OPEN_READONLY,
+        };
+        let path = CString::new(path.to_string_lossy().as_bytes())?;
+        let status = unsafe { open(path.as_ptr(), &mut database.db, flags, null()) };
+        database.check(status)?;
+        Ok(database)
+    }
+
+    fn sym<F>(&self, name: &[u8]) -> Result<Symbol<'_, F>> {
+        Ok(unsafe { self.lib.get(name) }?)
+    }
+
+    fn check(&self, status: c_int) -> Result<()> {
+        if status == SQLITE_OK || status == SQLITE_ROW || status == SQLITE_DONE {
+            return Ok(());
+        }
+        let message = self
+            .sym::<unsafe extern "C" fn(*mut c_void) -> *const c_char>(b"sqlite3_errmsg\0")
+            .ok()
+            .filter(|_| !self.db.is_null())
+            .map(|errmsg| {
+                unsafe { CStr::from_ptr(errmsg(self.db)) }
+                    .to_string_lossy()
+                    .into_owned()
+            });
+        Err(format!("SQLite error {status}: {}", message.unwrap_or_default()).into())
+    }
+
+    /// Statements without results
+    fn execute(&self, sql: &str) -> Result<()> {
+        let exec: Symbol<
+            unsafe extern "C" fn(
+                *mut c_void,
+                *const c_char,
+                *const c_void,
+                *mut c_void,
+                *mut c_void,
+            ) -> c_int,
+        > = self.sym(b"sqlite3_exec\0")?;
+        let sql = CString::new(sql)?;
+        let status = unsafe { exec(self.db, sql.as_ptr(), null(), null_mut(), null_mut()) };
+        self.check(status)
+    }
+
+    fn prepare(&self, sql: &str) -> Result<Statement<'_>> {
+        let prepare: Symbol<
+            unsafe extern "C" fn(
+                *mut c_void,
+                *const c_char,
+                c_int,
+                *mut *mut c_void,
+                *mut c_void,
+            ) -> c_int,
+        > = self.sym(b"sqlite3_prepare_v2\0")?;
+        let sql = CString::new(sql)?;
+        let mut statement = null_mut();
+        let status = unsafe { prepare(self.db, sql.as_ptr(), -1, &mut statement, null_mut()) };
+        self.check(status)?;
+        Ok(Statement {
+            db: self,
+            statement,
+        })
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        if let Ok(close) =
+            self.sym::<unsafe extern "C" fn(*mut c_void) -> c_int>(b"sqlite3_close\0")
+        {
+            unsafe { close(self.db) };
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Value<'a> {
+    Integer(i64),
+    Real(f64),
+    Text(&'a str),
+}
+
+/// A prepared statement, finalized on drop
+struct Statement<'db> {
+    db: &'db Database,
+    statement: *mut c_void,
+}
+
+impl Statement<'_> {
+    /// Reset, and bind the parameters, from 1 on
+    fn bind(&mut self, values: &[Value]) -> Result<()> {
+        let reset: Symbol<unsafe extern "C" fn(*mut c_void) -> c_int> =
+            self.db.sym(b"sqlite3_reset\0")?;
+        self.db.check(unsafe { reset(self.statement) })?;
+        for (i, value) in values.iter().enumerate() {
+            let i = i as c_int + 1;
+            let status = match *value {
+                Value::Integer(value) => {
+                    let bind: Symbol<unsafe extern "C" fn(*mut c_void, c_int, i64) -> c_int> =
+                        self.db.sym(b"sqlite3_bind_int64\0")?;
+                    unsafe { bind(self.statement, i, value) }
+                }
+                Value::Real(value) => {
+                    let bind: Symbol<unsafe extern "C" fn(*mut c_void, c_int, f64) -> c_int> =
+                        self.db.sym(b"sqlite3_bind_double\0")?;
+                    unsafe { bind(self.statement, i, value) }
+                }
+                Value::Text(value) => {
+                    let bind: Symbol<
+                        unsafe extern "C" fn(
+                            *mut c_void,
+                            c_int,
+                            *const c_char,
+                            c_int,
+                            isize,
+                        ) -> c_int,
+                    > = self.db.sym(b"sqlite3_bind_text\0")?;
+                    let len = value.len() as c_int;
+                    unsafe {
+                        bind(
+                            self.statement,
+                            i,
+                            value.as_ptr() as _,
+                            len,
+                            SQLITE_TRANSIENT,
+                        )
+                    }
+                }
+            };
+            self.db.check(status)?;
+        }
+        Ok(())
+    }
+
+    /// Whether there is a row to read columns of
+    fn step(&mut self) -> Result<bool> {
+        let step: Symbol<unsafe extern "C" fn(*mut c_void) -> c_int> =
+            self.db.sym(b"sqlite3_step\0")?;
+        let status = unsafe { step(self.statement) };
+        self.db.check(status)?;
+        Ok(status == SQLITE_ROW)
+    }
+
+    fn integer(&self, column: c_int) -> Result<i64> {
+        let get: Symbol<unsafe extern "C" fn(*mut c_void, c_int) -> i64> =
+            self.db.sym(b"sqlite3_column_int64\0")?;
+        Ok(unsafe { get(self.statement, column) })
+    }
+
+    fn real(&self, column: c_int) -> Result<f64> {
+        let get: Symbol<unsafe extern "C" fn(*mut c_void, c_int) -> f64> =
+            self.db.sym(b"sqlite3_column_double\0")?;
+        Ok(unsafe { get(self.statement, column) })
+    }
+
+    fn text(&self, column: c_int) -> Result<String> {
+        let get: Symbol<unsafe extern "C" fn(*mut c_void, c_int) -> *const c_char> =
+            self.db.sym(b"sqlite3_column_text\0")?;
+        let text = unsafe { get(self.statement, column) };
+        Ok(match text.is_null() {
+            true => String::new(),
+            false => unsafe { CStr::from_ptr(text) }
+                .to_string_lossy()
+                .into_owned(),
+        })
+    }
+}
+
+impl Drop for Statement<'_> {
+    fn drop(&mut self) {
+        let finalize = self
+            .db
+            .sym::<unsafe extern "C" fn(*mut c_void) -> c_int>(b"sqlite3_finalize\0");
+        if let Ok(finalize) = finalize {
+            unsafe { finalize(self.statement) };
+        }
+    }
+}
+
+/// Records every push, and deletes what's older than the retention
+pub struct History {
+    db: Database,
+    retention: Duration,
+}
+
+impl History {
+    pub fn open(path: &Path, retention: Duration) -> Result<History> {
+        let db = Database::open(path, true)?;
+        db.execute(SCHEMA)?;
+        Ok(History { db, retention })
+    }
+}
+
+impl Sink for History {
+    fn name(&self) -> &'static str {
+        "history"
+    }
+
+    fn push(&mut self, families: &[MetricFamily]) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let timestamp = now.as_millis() as i64;
+        self.db.execute("BEGIN")?;
+        let mut insert = self
+            .db
+            .prepare("INSERT INTO samples VALUES (?, ?, ?, ?, ?)")?;
+        for sample in samples(families) {
+            let uuid = sample.labels.iter().find(|(name, _)| name == GPU_LABELS[0]);
+            let labels = sample
+                .labels
+                .iter()
+                .filter(|(name, _)| !GPU_LABELS.contains(&name.as_str()))
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect::<Vec<_>>()
+                .join(";");
+            insert.bind(&[
+                Value::Integer(timestamp),
+                Value::Text(&sample.name),
+                Value::Text(uuid.map_or("", |(_, uuid)| uuid)),
+                Value::Text(&labels),
+                Value::Real(sample.value),
+            ])?;
+            insert.step()?;
+        }
+        drop(insert);
+        let mut expire = self.db.prepare("DELETE FROM samples WHERE timestamp < ?")?;
+        let expired = now.saturating_sub(self.retention).as_millis() as i64;
+        expire.bind(&[Value::Integer(expired)])?;
+        expire.step()?;
+        drop(expire);
+        self.db.execute("COMMIT")
+    }
+}
+
+/// Print the samples recorded since then, as CSV, optionally only of the metric and GPU
+pub fn query(path: &Path, since: Duration, metric: Option<&str>, gpu: Option<&str>) -> Result<()> {
+    let db = Database::open(path, false)?;
+    let mut select = db.prepare(
+        "SELECT timestamp, metric, uuid, labels, value FROM samples
+        WHERE timestamp >= ? AND (? = '' OR metric = ?) AND (? = '' OR uuid = ?)
+        ORDER BY timestamp, metric, uuid, labels",
+    )?;
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .saturating_sub(since);
+    let metric = Value::Text(metric.unwrap_or_default());
+    let gpu = Value::Text(gpu.unwrap_or_default());
+    select.bind(&[
+        Value::Integer(since.as_millis() as i64),
+        metric,
+        metric,
+        gpu,
+        gpu,
+    ])?;
+    println!("timestamp,metric,uuid,labels,value");
+    while select.step()? {
+        let timestamp = UNIX_EPOCH + Duration::from_millis(select.integer(0)? as u64);
+        let fields = [
+            humantime::format_rfc3339_millis(timestamp).to_string(),
+            select.text(1)?,
+            select.text(2)?,
+            select.text(3)?,
+            select.real(4)?.to_string(),
+        ];
+        println!("{}", fields.map(|field| csv_field(&field)).join(","));
+    }
+    Ok(())
+}
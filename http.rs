@@ -0,0 +1,425 @@
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use prometheus::proto::MetricFamily;
+use prometheus::{Encoder, TextEncoder};
+use serde_json::json;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tiny_http::{Header, ListenAddr, Listener, Request, Response};
+
+use crate::openmetrics::OpenMetricsEncoder;
+use crate::{Result, GPU_LABELS};
+
+const METRICS_PATH: &str = "/metrics";
+const PROBE_PATH: &str = "/probe";
+const SD_PATH: &str = "/sd";
+const DEVICES_PATH: &str = "/api/v1/devices";
+const DASHBOARD_PATH: &str = "/dashboard";
+const POWER_LIMIT_PATH: &str = "/api/v1/admin/power-limit";
+const LOCKED_CLOCKS_PATH: &str = "/api/v1/admin/locked-clocks";
+const RESET_COUNTERS_PATH: &str = "/api/v1/admin/reset-counters";
+/// Charts of the devices' main metrics, from the devices as JSON
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+pub enum Route {
+    Metrics,
+    /// Metrics of a single device, multi-target exporter style
+    Probe,
+    /// The devices as probe targets, for Prometheus' HTTP service discovery
+    ServiceDiscovery,
+    /// The devices and their metrics as JSON, for consumers other than Prometheus
+    Devices,
+    Dashboard,
+    /// Setting a GPU's power limit, with `--enable-admin-api`
+    PowerLimit,
+    /// Locking or unlocking a GPU's graphics clock, with `--enable-admin-api`
+    LockedClocks,
+    /// Clearing a GPU's ECC and NVLink error counters, with `--enable-admin-api`
+    ResetCounters,
+    Other,
+}
+
+/// Where to listen: a TCP address, or `unix:` followed by a socket path
+#[derive(Clone)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Listen {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.strip_prefix("unix:") {
+            Some(path) => Listen::Unix(path.into()),
+            None => Listen::Tcp(s.parse()?),
+        })
+    }
+}
+
+impl Listen {
+    pub fn listener(&self) -> Result<Listener> {
+        match self {
+            Listen::Tcp(addr) => Ok(std::net::TcpListener::bind(addr)?.into()),
+            Listen::Unix(path) => {
+                // Clean up after a previous instance, but don't clobber anything else
+                #[cfg(unix)]
+                if let Ok(meta) = std::fs::symlink_metadata(path) {
+                    use std::os::unix::fs::FileTypeExt;
+                    if meta.file_type().is_socket() {
+                        std::fs::remove_file(path)?;
+                    }
+                }
+                #[cfg(unix)]
+                return Ok(std::os::unix::net::UnixListener::bind(path)?.into());
+                #[cfg(not(unix))]
+                return Err(format!("Can't listen on {}, no unix sockets", path.display()).into());
+            }
+        }
+    }
+}
+
+/// Where the listener listens, before tiny_http serves it and could tell
+pub fn listen_addr(listener: &Listener) -> Result<ListenAddr> {
+    Ok(match listener {
+        Listener::Tcp(listener) => ListenAddr::IP(listener.local_addr()?),
+        #[cfg(unix)]
+        Listener::Unix(listener) => ListenAddr::Unix(listener.local_addr()?),
+    })
+}
+
+/// Added to every response, from the web config's http_server_config.headers
+static HEADERS: OnceLock<Vec<Header>> = OnceLock::new();
+
+pub fn set_headers(headers: Vec<Header>) {
+    HEADERS.set(headers).ok();
+}
+
+/// Respond, with the configured headers
+pub fn respond<R: Read>(request: Request, mut response: Response<R>) -> std::io::Result<()> {
+    for header in HEADERS.get().into_iter().flatten() {
+        response.add_header(header.clone());
+    }
+    request.respond(response)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ContentEncoding {
+    Identity,
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    /// Pick the best encoding the client is willing to accept, preferring gzip
+    fn negotiate(request: &Request) -> ContentEncoding {
+        let Some(accept) = header(request, "Accept-Encoding") else {
+            return ContentEncoding::Identity;
+        };
+        let (mut gzip, mut deflate) = (0., 0.);
+        for entry in accept.split(',') {
+            let mut parts = entry.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let q = parts
+                .find_map(|p| p.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.);
+            if name.eq_ignore_ascii_case("gzip") {
+                gzip = q;
+            } else if name.eq_ignore_ascii_case("deflate") {
+                deflate = q;
+            }
+        }
+        if gzip > 0. && gzip >= deflate {
+            ContentEncoding::Gzip
+        } else if deflate > 0. {
+            ContentEncoding::Deflate
+        } else {
+            ContentEncoding::Identity
+        }
+    }
+
+    fn encode(self, body: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(match self {
+            ContentEncoding::Identity => body,
+            ContentEncoding::Gzip => {
+                let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+                enc.write_all(&body)?;
+                enc.finish()?
+            }
+            ContentEncoding::Deflate => {
+                let mut enc = ZlibEncoder::new(Vec::new(), Compression::default());
+                enc.write_all(&body)?;
+                enc.finish()?
+            }
+        })
+    }
+
+    fn header_value(self) -> Option<&'static str> {
+        match self {
+            ContentEncoding::Identity => None,
+            ContentEncoding::Gzip => Some("gzip"),
+            ContentEncoding::Deflate => Some("deflate"),
+        }
+    }
+}
+
+/// Whether the client asked for OpenMetrics (with non-zero quality) in its `Accept` header
+fn wants_openmetrics(request: &Request) -> bool {
+    header(request, "Accept").is_some_and(|accept| {
+        accept.split(',').any(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            parts.next() == Some("application/openmetrics-text")
+                && parts
+                    .find_map(|p| p.strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_none_or(|q| q > 0.)
+        })
+    })
+}
+
+/// The time Prometheus is going to wait for us, as announced in its request
+pub fn scrape_timeout(request: &Request) -> Option<Duration> {
+    header(request, "X-Prometheus-Scrape-Timeout-Seconds")
+        .and_then(|timeout| timeout.parse().ok())
+        .and_then(|timeout| Duration::try_from_secs_f64(timeout).ok())
+}
+
+fn header<'r>(request: &'r Request, name: &'static str) -> Option<&'r str> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str())
+}
+
+fn path(request: &Request) -> &str {
+    request.url().split('?').next().unwrap_or_default()
+}
+
+pub fn route(request: &Request) -> Route {
+    match path(request) {
+        METRICS_PATH => Route::Metrics,
+        PROBE_PATH => Route::Probe,
+        SD_PATH => Route::ServiceDiscovery,
+        DEVICES_PATH => Route::Devices,
+        DASHBOARD_PATH => Route::Dashboard,
+        POWER_LIMIT_PATH => Route::PowerLimit,
+        LOCKED_CLOCKS_PATH => Route::LockedClocks,
+        RESET_COUNTERS_PATH => Route::ResetCounters,
+        _ => Route::Other,
+    }
+}
+
+/// First value of a query string parameter
+pub fn query_param(request: &Request, name: &str) -> Option<String> {
+    let (_, query) = request.url().split_once('?')?;
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// All values of a query string parameter
+pub fn query_params(request: &Request, name: &str) -> Vec<String> {
+    let Some((_, query)) = request.url().split_once('?') else {
+        return vec![];
+    };
+    form_urlencoded::parse(query.as_bytes())
+        .filter(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+        .collect()
+}
+
+pub fn error(request: Request, status: u16, message: &str) -> Result<()> {
+    respond(
+        request,
+        Response::from_string(format!("{}\n", message)).with_status_code(status),
+    )?;
+    Ok(())
+}
+
+pub fn redirect(request: Request) -> Result<()> {
+    let response = Response::from_string(format!("try {} for metrics\n", METRICS_PATH))
+        .with_status_code(301)
+        .with_header(Header::from_bytes("Location", METRICS_PATH).unwrap());
+    respond(request, response)?;
+    Ok(())
+}
+
+/// Respond with a target group per device, scraped from `address` at its probe path. Without
+/// one, the address the request was made to, from its `Host` header.
+pub fn service_discovery(
+    request: Request,
+    devices: Vec<[String; 3]>,
+    address: Option<&str>,
+    https: bool,
+) -> Result<()> {
+    let Some(address) = address.or_else(|| header(&request, "Host")) else {
+        return error(request, 400, "Missing Host header");
+    };
+    let groups = devices
+        .iter()
+        .enumerate()
+        .map(|(index, [uuid, name, pci])| {
+            // Not the metrics' own label names, which would clash with those
+            json!({
+                "targets": [address],
+                "labels": {
+                    "__metrics_path__": PROBE_PATH,
+                    "__param_gpu": uuid,
+                    "__scheme__": if https { "https" } else { "http" },
+                    "gpu": uuid,
+                    "gpu_index": index.to_string(),
+                    "gpu_model": name,
+                    "gpu_pci": pci,
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+    let response = Response::from_string(serde_json::Value::from(groups).to_string())
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+    respond(request, response)?;
+    Ok(())
+}
+
+/// Respond with the devices, in the order of their indices, each with the values of its metrics:
+/// a number for metrics without labels beyond the device's, otherwise a list of `labels` and
+/// `value`. Host wide metrics are under `host`.
+pub fn devices(
+    request: Request,
+    devices: Vec<[String; 3]>,
+    families: &[MetricFamily],
+    collected: SystemTime,
+) -> Result<()> {
+    let mut host = serde_json::Map::new();
+    let mut metrics = vec![serde_json::Map::new(); devices.len()];
+    for sample in crate::push::samples(families) {
+        let uuid = sample.labels.iter().find(|(name, _)| name == GPU_LABELS[0]);
+        let metrics = match uuid {
+            Some((_, uuid)) => match devices.iter().position(|[u, ..]| u == uuid) {
+                Some(index) => &mut metrics[index],
+                // Gone since listing the devices
+                None => continue,
+            },
+            None => &mut host,
+        };
+        let labels = sample
+            .labels
+            .into_iter()
+            .filter(|(name, _)| !GPU_LABELS.contains(&name.as_str()))
+            .map(|(name, value)| (name, value.into()))
+            .collect::<serde_json::Map<_, _>>();
+        if labels.is_empty() {
+            metrics.insert(sample.name, sample.value.into());
+        } else {
+            let series = metrics.entry(sample.name).or_insert_with(|| json!([]));
+            if let Some(series) = series.as_array_mut() {
+                series.push(json!({"labels": labels, "value": sample.value}));
+            }
+        }
+    }
+    let devices = devices
+        .into_iter()
+        .zip(metrics)
+        .enumerate()
+        .map(|(index, ([uuid, name, pci], metrics))| {
+            json!({
+                "index": index,
+                "uuid": uuid,
+                "name": name,
+                "pci": pci,
+                "metrics": metrics,
+            })
+        })
+        .collect::<Vec<_>>();
+    let document = json!({
+        "timestamp": collected.duration_since(UNIX_EPOCH)?.as_secs_f64(),
+        "devices": devices,
+        "host": host,
+    });
+    let response = Response::from_string(document.to_string())
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+    respond(request, response)?;
+    Ok(())
+}
+
+pub fn dashboard(request: Request) -> Result<()> {
+    let response = Response::from_string(DASHBOARD)
+        .with_header(Header::from_bytes("Content-Type", "text/html; charset=utf-8").unwrap());
+    respond(request, response)?;
+    Ok(())
+}
+
+/// Respond with a setting's value before and after changing it
+pub fn changed(
+    request: Request,
+    gpu: &str,
+    setting: &str,
+    (before, after): (u32, u32),
+) -> Result<()> {
+    let document = json!({
+        "gpu": gpu,
+        "setting": setting,
+        "before": before,
+        "after": after,
+    });
+    let response = Response::from_string(document.to_string())
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+    respond(request, response)?;
+    Ok(())
+}
+
+/// Respond with the counters that were reset
+pub fn reset(request: Request, gpu: &str, counters: &[&str]) -> Result<()> {
+    let document = json!({
+        "gpu": gpu,
+        "reset": counters,
+    });
+    let response = Response::from_string(document.to_string())
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+    respond(request, response)?;
+    Ok(())
+}
+
+/// Respond with the given metrics, optionally stamping every sample with the time it was
+/// `collected`
+pub fn metrics(
+    request: Request,
+    mut families: Vec<MetricFamily>,
+    collected: Option<SystemTime>,
+) -> Result<()> {
+    if let Some(collected) = collected {
+        let timestamp_ms = collected
+            .duration_since(UNIX_EPOCH)?
+            .as_millis()
+            .try_into()?;
+        for mf in &mut families {
+            for m in mf.mut_metric().iter_mut() {
+                m.set_timestamp_ms(timestamp_ms);
+            }
+        }
+    }
+    let mut body = vec![];
+    let content_type = if wants_openmetrics(&request) {
+        let encoder = OpenMetricsEncoder;
+        encoder.encode(&families, &mut body)?;
+        encoder.format_type().to_owned()
+    } else {
+        let encoder = TextEncoder::new();
+        encoder.encode(&families, &mut body)?;
+        encoder.format_type().to_owned()
+    };
+    let encoding = ContentEncoding::negotiate(&request);
+    let mut response = Response::from_data(encoding.encode(body)?)
+        .with_header(Header::from_bytes("Content-Type", content_type).unwrap())
+        .with_header(Header::from_bytes("Vary", "Accept, Accept-Encoding").unwrap());
+    if let Some(value) = encoding.header_value() {
+        response.add_header(Header::from_bytes("Content-Encoding", value).unwrap());
+    }
+    respond(request, response)?;
+    Ok(())
+}
@@ -0,0 +1,292 @@
+//! Intel GPUs driven by i915 or xe, read from sysfs and hwmon
+//!
+//! Device memory comes from the i915 memory region query, which reports the unallocated size only
+//! with CAP_PERFMON, and isn't available for xe yet. Without a power sensor, power is averaged
+//! from the energy counter since the previous query.
+
+use nvml_wrapper::enum_wrappers::device::{Clock, PerformanceState, TemperatureSensor};
+use nvml_wrapper::error::NvmlError;
+use nvml_wrapper::struct_wrappers::device::{MemoryInfo, ProcessInfo, Utilization};
+use std::fs;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::gpu::Gpu;
+
+/// A card's sysfs directories, found once
+#[derive(Clone)]
+pub struct IntelGpu {
+    index: u32,
+    /// PCI address as sysfs has it, e.g. 0000:03:00.0
+    pci: String,
+    /// PCI device id, e.g. 0x56a0
+    product: String,
+    card: PathBuf,
+    xe: bool,
+    hwmon: Option<PathBuf>,
+    render_node: Option<PathBuf>,
+    /// For power without a power sensor
+    energy: Arc<Mutex<Option<EnergySample>>>,
+}
+
+struct EnergySample {
+    at: Instant,
+    energy: u64,
+    /// Average since the previous sample
+    power: Option<u32>,
+}
+
+fn read(path: &Path) -> Option<String> {
+    Some(fs::read_to_string(path).ok()?.trim().to_owned())
+}
+
+fn number(path: &Path) -> Result<u64, NvmlError> {
+    read(path)
+        .ok_or(NvmlError::NotSupported)?
+        .parse()
+        .map_err(|_| NvmlError::Unknown)
+}
+
+fn entries(dir: impl AsRef<Path>) -> impl Iterator<Item = PathBuf> {
+    let mut entries = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    entries.sort();
+    entries.into_iter()
+}
+
+fn file_name(path: &Path) -> Option<String> {
+    Some(path.file_name()?.to_str()?.to_owned())
+}
+
+/// All Intel GPUs with a supported driver
+pub fn find() -> Vec<IntelGpu> {
+    let cards = entries("/sys/class/drm").filter(|card| {
+        // Not the connectors, card0-DP-1 and the like
+        file_name(card).is_some_and(|name| name.starts_with("card") && !name.contains('-'))
+    });
+    let mut gpus = vec![];
+    for card in cards {
+        let device = card.join("device");
+        if read(&device.join("vendor")).as_deref() != Some("0x8086") {
+            continue;
+        }
+        let Some(driver) = fs::read_link(device.join("driver"))
+            .ok()
+            .and_then(|driver| file_name(&driver))
+        else {
+            continue;
+        };
+        if driver != "i915" && driver != "xe" {
+            continue;
+        }
+        let (Some(pci), Some(product)) = (
+            fs::canonicalize(&device).ok().and_then(|d| file_name(&d)),
+            read(&device.join("device")),
+        ) else {
+            continue;
+        };
+        let gpu = IntelGpu {
+            index: gpus.len() as u32,
+            pci,
+            product,
+            xe: driver == "xe",
+            hwmon: entries(device.join("hwmon")).next(),
+            render_node: entries(device.join("drm"))
+                .filter_map(|node| file_name(&node))
+                .find(|node| node.starts_with("renderD"))
+                .map(|node| Path::new("/dev/dri").join(node)),
+            energy: Default::default(),
+            card,
+        };
+        // So there is power to report from the first query on
+        gpu.power_usage().ok();
+        gpus.push(gpu);
+    }
+    gpus
+}
+
+impl IntelGpu {
+    fn hwmon(&self, file: &str) -> Result<PathBuf, NvmlError> {
+        let path = self
+            .hwmon
+            .as_ref()
+            .ok_or(NvmlError::NotSupported)?
+            .join(file);
+        match path.exists() {
+            true => Ok(path),
+            false => Err(NvmlError::NotSupported),
+        }
+    }
+
+    fn frequency(&self) -> Result<u32, NvmlError> {
+        let path = match self.xe {
+            true => self.card.join("device/tile0/gt0/freq0/act_freq"),
+            false => self.card.join("gt_act_freq_mhz"),
+        };
+        Ok(number(&path)? as u32)
+    }
+}
+
+#[repr(C)]
+struct I915Query {
+    num_items: u32,
+    flags: u32,
+    items_ptr: u64,
+}
+
+#[repr(C)]
+struct I915QueryItem {
+    query_id: u64,
+    length: i32,
+    flags: u32,
+    data_ptr: u64,
+}
+
+#[repr(C)]
+struct I915MemoryRegionInfo {
+    memory_class: u16,
+    memory_instance: u16,
+    rsvd0: u32,
+    probed_size: u64,
+    unallocated_size: u64,
+    rsvd1: [u64; 8],
+}
+
+const DRM_IOCTL_I915_QUERY: u64 = 0xc010_6479;
+const DRM_I915_QUERY_MEMORY_REGIONS: u64 = 4;
+const I915_MEMORY_CLASS_DEVICE: u16 = 1;
+
+/// Total and unallocated size of the device memory regions
+fn i915_memory(render_node: &Path) -> Result<(u64, u64), NvmlError> {
+    let node = fs::File::open(render_node).map_err(|_| NvmlError::NoPermission)?;
+    // The length of the data, or a negative error
+    let query = |length: i32, data: *mut u64| {
+        let mut item = I915QueryItem {
+            query_id: DRM_I915_QUERY_MEMORY_REGIONS,
+            length,
+            flags: 0,
+            data_ptr: data as u64,
+        };
+        let mut query = I915Query {
+            num_items: 1,
+            flags: 0,
+            items_ptr: &mut item as *mut _ as u64,
+        };
+        match unsafe { libc::ioctl(node.as_raw_fd(), DRM_IOCTL_I915_QUERY as _, &mut query) } {
+            0 if item.length > 0 => Ok(item.length),
+            _ => Err(NvmlError::NotSupported),
+        }
+    };
+    // Once for the size, once for the data
+    let length = query(0, std::ptr::null_mut())?;
+    let mut data = vec![0u64; (length as usize).div_ceil(8)];
+    query(length, data.as_mut_ptr())?;
+    // num_regions, rsvd[3], then the regions
+    let fit = (length as usize).saturating_sub(16) / std::mem::size_of::<I915MemoryRegionInfo>();
+    let count = (data[0] as u32 as usize).min(fit);
+    let regions = unsafe {
+        std::slice::from_raw_parts(data[2..].as_ptr() as *const I915MemoryRegionInfo, count)
+    };
+    let device = regions
+        .iter()
+        .filter(|region| region.memory_class == I915_MEMORY_CLASS_DEVICE)
+        .map(|region| (region.probed_size, region.unallocated_size))
+        .reduce(|(a, b), (c, d)| (a + c, b + d));
+    device.ok_or(NvmlError::NotSupported)
+}
+
+impl Gpu for IntelGpu {
+    fn uuid(&self) -> Result<String, NvmlError> {
+        // There is none, the PCI address is the next best thing
+        Ok(format!("intel-{}", self.pci))
+    }
+    fn name(&self) -> Result<String, NvmlError> {
+        Ok(format!("Intel GPU {}", self.product))
+    }
+    fn pci_bus_id(&self) -> Result<String, NvmlError> {
+        // As NVML formats it, with an 8 digit domain
+        Ok(format!("0000{}", self.pci.to_uppercase()))
+    }
+    fn index(&self) -> Result<u32, NvmlError> {
+        Ok(self.index)
+    }
+    fn mig_mode(&self) -> Result<bool, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn memory_info(&self) -> Result<MemoryInfo, NvmlError> {
+        if self.xe {
+            return Err(NvmlError::NotSupported);
+        }
+        let node = self.render_node.as_ref().ok_or(NvmlError::NotSupported)?;
+        let (total, free) = i915_memory(node)?;
+        Ok(MemoryInfo {
+            free,
+            total,
+            used: total - free,
+        })
+    }
+    fn fan_speed(&self, _: u32) -> Result<u32, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn temperature(&self, _: TemperatureSensor) -> Result<u32, NvmlError> {
+        let millidegrees = (1..=3)
+            .find_map(|i| self.hwmon(&format!("temp{i}_input")).ok())
+            .ok_or(NvmlError::NotSupported)?;
+        Ok((number(&millidegrees)? / 1000) as u32)
+    }
+    fn performance_state(&self) -> Result<PerformanceState, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn power_usage(&self) -> Result<u32, NvmlError> {
+        if let Ok(microwatts) = self.hwmon("power1_input") {
+            return Ok((number(&microwatts)? / 1000) as u32);
+        }
+        let mut sample = self.energy.lock().unwrap();
+        let power = match &*sample {
+            // Too short to average over, e.g. queried twice in one collection
+            Some(last) if last.at.elapsed().as_secs() < 1 => last.power,
+            last => {
+                let (at, energy) = (Instant::now(), self.total_energy_consumption()?);
+                let power = last.as_ref().map(|last| {
+                    let elapsed = at.duration_since(last.at).as_secs_f64();
+                    // mJ / s = mW
+                    (energy.saturating_sub(last.energy) as f64 / elapsed) as u32
+                });
+                *sample = Some(EnergySample { at, energy, power });
+                power
+            }
+        };
+        power.ok_or(NvmlError::NoData)
+    }
+    fn enforced_power_limit(&self) -> Result<u32, NvmlError> {
+        Ok((number(&self.hwmon("power1_max")?)? / 1000) as u32)
+    }
+    fn total_energy_consumption(&self) -> Result<u64, NvmlError> {
+        // µJ, NVML counts mJ
+        Ok(number(&self.hwmon("energy1_input")?)? / 1000)
+    }
+    fn pcie_replay_counter(&self) -> Result<u32, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn utilization_rates(&self) -> Result<Utilization, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn clock_info(&self, clock: Clock) -> Result<u32, NvmlError> {
+        match clock {
+            Clock::Graphics | Clock::SM => self.frequency(),
+            _ => Err(NvmlError::NotSupported),
+        }
+    }
+    fn running_compute_processes(&self) -> Result<Vec<ProcessInfo>, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn running_graphics_processes(&self) -> Result<Vec<ProcessInfo>, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+}
@@ -0,0 +1,96 @@
+//! Logging to journald with its native protocol, so fields stay fields
+//!
+//! Each event is a datagram of `KEY=value` lines to journald's socket, see systemd's
+//! `journal-native-protocol`.
+
+use std::fmt::Write;
+use std::os::unix::net::UnixDatagram;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+
+use crate::Result;
+
+const SOCKET: &str = "/run/systemd/journal/socket";
+
+pub struct Layer {
+    socket: UnixDatagram,
+}
+
+impl Layer {
+    pub fn connect() -> Result<Layer> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(SOCKET)?;
+        Ok(Layer { socket })
+    }
+}
+
+impl<S: Subscriber> tracing_subscriber::Layer<S> for Layer {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        let meta = event.metadata();
+        let priority = match *meta.level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+        let mut entry = Entry::default();
+        entry.field("PRIORITY", &priority.to_string());
+        entry.field("SYSLOG_IDENTIFIER", env!("CARGO_PKG_NAME"));
+        entry.field("TARGET", meta.target());
+        if let Some(file) = meta.file() {
+            entry.field("CODE_FILE", file);
+        }
+        if let Some(line) = meta.line() {
+            entry.field("CODE_LINE", &line.to_string());
+        }
+        event.record(&mut entry);
+        // Nowhere left to report a failure to
+        self.socket.send(&entry.0).ok();
+    }
+}
+
+#[derive(Default)]
+struct Entry(Vec<u8>);
+
+impl Entry {
+    fn field(&mut self, name: &str, value: &str) {
+        self.0.extend(name.as_bytes());
+        if value.contains('\n') {
+            // Binary safe: the name, a newline, the length, and the value
+            self.0.push(b'\n');
+            self.0.extend((value.len() as u64).to_le_bytes());
+        } else {
+            self.0.push(b'=');
+        }
+        self.0.extend(value.as_bytes());
+        self.0.push(b'\n');
+    }
+}
+
+impl Visit for Entry {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        let mut formatted = String::new();
+        write!(formatted, "{:?}", value).ok();
+        self.record_str(field, &formatted);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let name = match field.name() {
+            "message" => "MESSAGE".to_owned(),
+            "uuid" => "DEVICE_UUID".to_owned(),
+            "code" => "NVML_CODE".to_owned(),
+            // Journal field names are upper case letters, digits, and underscores
+            name => name
+                .chars()
+                .map(|c| match c {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+                    _ => '_',
+                })
+                .collect::<String>()
+                .trim_start_matches('_')
+                .to_owned(),
+        };
+        self.field(&name, value);
+    }
+}
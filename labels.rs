@@ -0,0 +1,41 @@
+//! Label values that downstream tools cope with, and that match other exporters'
+
+/// Beyond sanitizing, which always happens
+#[derive(Clone, Copy, Default)]
+pub struct Normalization {
+    /// Like the kernel and most exporters have them, e.g. `00000000:af:00.0`
+    pub lowercase_pci: bool,
+    /// `GeForce RTX 3080` rather than `NVIDIA GeForce RTX 3080`
+    pub trim_vendor: bool,
+}
+
+const VENDOR: &str = "NVIDIA ";
+
+/// Control characters, like newlines in command lines, replaced by spaces, and surrounding
+/// whitespace trimmed
+pub fn sanitize(value: &str) -> String {
+    let value = value.replace(char::is_control, " ");
+    value.trim().to_owned()
+}
+
+impl Normalization {
+    /// Of [`crate::GPU_LABELS`]
+    pub fn apply(&self, labels: &mut [String; 3]) {
+        let [uuid, name, pci] = labels;
+        *uuid = sanitize(uuid);
+        *name = sanitize(name);
+        if self.trim_vendor {
+            if let Some(trimmed) = name.strip_prefix(VENDOR) {
+                *name = trimmed.to_owned();
+            }
+        }
+        *pci = self.pci(pci);
+    }
+
+    pub fn pci(&self, bus_id: &str) -> String {
+        match self.lowercase_pci {
+            true => sanitize(bus_id).to_lowercase(),
+            false => sanitize(bus_id),
+        }
+    }
+}
@@ -0,0 +1,1189 @@
+//! GPU metrics from NVML for Prometheus registries
+//!
+//! Get a [`Backend`] and register an [`NvmlCollector`] for it.
+
+use nvml_wrapper::bitmasks::InitFlags;
+use nvml_wrapper::enum_wrappers::device::{Clock, EccCounter};
+use nvml_wrapper::enums::device::GpuLockedClocksSetting;
+use nvml_wrapper::error::NvmlError;
+use nvml_wrapper::Nvml;
+use nvml_wrapper_sys::bindings::NVML_NVLINK_MAX_LINKS;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{LabelPair, MetricFamily};
+use prometheus::{Gauge, GaugeVec, IntCounterVec, IntGauge, IntGaugeVec};
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info, warn};
+
+pub mod capabilities;
+pub mod cgroup;
+pub mod collectors;
+pub mod errors;
+pub mod gpu;
+#[cfg(feature = "intel")]
+pub mod intel;
+pub mod labels;
+pub mod mock;
+pub mod procfs;
+mod raw;
+pub mod record;
+#[cfg(feature = "rocm")]
+pub mod rocm;
+pub mod runtime_pm;
+pub mod sanity;
+pub mod smi;
+#[cfg(feature = "tegra")]
+pub mod tegra;
+
+use collectors::DeviceCollector;
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+pub static GPU_LABELS: [&str; 3] = ["uuid", "name", "pci"];
+
+fn int_gauge_vec(name: &str, help: &str, labels: &[&str]) -> IntGaugeVec {
+    IntGaugeVec::new(prometheus::Opts::new(name, help), labels).unwrap()
+}
+
+fn gauge_vec(name: &str, help: &str, labels: &[&str]) -> GaugeVec {
+    GaugeVec::new(prometheus::Opts::new(name, help), labels).unwrap()
+}
+
+fn int_counter_vec(name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    IntCounterVec::new(prometheus::Opts::new(name, help), labels).unwrap()
+}
+
+/// Collects from all devices of a [`Backend`] whenever it is gathered
+pub struct NvmlCollector {
+    /// Locked for the duration of a collection, as collections reset the collectors' metrics and
+    /// fill them again. Declared before the backend, which NVML's devices borrow from, so it's
+    /// dropped first.
+    devices: Mutex<Devices>,
+    backend: Backend,
+    collectors: Vec<Box<dyn DeviceCollector>>,
+    /// Unlike the collectors' metrics, this keeps counting across collections
+    errors: IntCounterVec,
+    /// When all collectors last succeeded, on all devices, and by device
+    last_collect: Gauge,
+    device_last_collect: GaugeVec,
+    /// Whether each collector supports each device found, by uuid. Probed when a device is
+    /// first found, so metrics it can't report aren't queried, let alone exported.
+    support: Mutex<HashMap<String, Vec<bool>>>,
+    /// Whether each metric had a series for the device in its last collection
+    metric_supported: IntGaugeVec,
+    /// Whether a failed collector fails the gathering, or only leaves out its metrics
+    tolerant: bool,
+    /// Whether the backend could list the devices, and how many it found
+    up: IntGauge,
+    device_count: IntGauge,
+    /// Sums over the devices collected from, without labels so there's no need to sum by
+    host_memory_used: IntGaugeVec,
+    host_power_usage: GaugeVec,
+    host_gpu_count: IntGaugeVec,
+    /// Which GPUs are suspended, and not queried until they wake up. Their label values are from
+    /// when they were last awake, by bus id.
+    suspended: IntGaugeVec,
+    labels: Mutex<HashMap<String, [String; 3]>>,
+    /// Shared with the devices, which check their readings with it
+    sanity: Arc<sanity::Sanity>,
+    normalization: labels::Normalization,
+    /// Whether the deadline passed before each selected collector got to all devices
+    timed_out: IntGaugeVec,
+}
+
+impl NvmlCollector {
+    /// With all collectors
+    pub fn new(backend: Backend) -> NvmlCollector {
+        NvmlCollector::with_collectors(backend, collectors::all())
+    }
+
+    pub fn with_collectors(
+        backend: Backend,
+        collectors: Vec<Box<dyn DeviceCollector>>,
+    ) -> NvmlCollector {
+        NvmlCollector {
+            devices: Default::default(),
+            backend,
+            collectors,
+            errors: int_counter_vec(
+                "nvml_errors_total",
+                "Failed NVML queries by function and return code",
+                &["uuid", "function", "code"],
+            ),
+            last_collect: Gauge::new(
+                "nvml_exporter_last_collect_timestamp_seconds",
+                "When all devices were last collected from successfully",
+            )
+            .unwrap(),
+            device_last_collect: gauge_vec(
+                "nvml_exporter_device_last_collect_timestamp_seconds",
+                "When the device was last collected from successfully",
+                &GPU_LABELS,
+            ),
+            support: Default::default(),
+            metric_supported: int_gauge_vec(
+                "nvml_metric_supported",
+                "Whether the device reports the metric (1) or can't (0)",
+                &[&GPU_LABELS[..], &["metric"][..]].concat(),
+            ),
+            tolerant: false,
+            up: IntGauge::new("nvml_up", "Whether the GPU driver could be queried").unwrap(),
+            device_count: IntGauge::new("nvml_device_count", "GPUs found").unwrap(),
+            host_memory_used: int_gauge_vec(
+                "nvml_host_memory_used_bytes",
+                "Memory used on all GPUs",
+                &[],
+            ),
+            host_power_usage: gauge_vec(
+                "nvml_host_power_usage_watts",
+                "Current power usage of all GPUs",
+                &[],
+            ),
+            host_gpu_count: int_gauge_vec(
+                "nvml_host_gpu_count",
+                "GPUs collected from for the nvml_host_ sums",
+                &[],
+            ),
+            suspended: int_gauge_vec(
+                "nvml_gpu_suspended",
+                "Whether the GPU is suspended by runtime power management, and not queried",
+                &GPU_LABELS,
+            ),
+            labels: Default::default(),
+            sanity: Default::default(),
+            normalization: Default::default(),
+            timed_out: int_gauge_vec(
+                "nvml_collector_timed_out",
+                "Whether the collector left out devices in the last collection, for lack of time",
+                &["collector"],
+            ),
+        }
+    }
+
+    pub fn backend(&self) -> &Backend {
+        &self.backend
+    }
+
+    /// Switch to a new backend, e.g. after reinitializing NVML, and return the old one
+    pub fn set_backend(&mut self, backend: Backend) -> Backend {
+        // They borrow from the old one
+        *self.devices.get_mut().unwrap() = Devices::default();
+        std::mem::replace(&mut self.backend, backend)
+    }
+
+    /// Have [`Self::gather`] succeed even if collectors fail, without their metrics. The
+    /// failures are logged and NVML's counted in `nvml_errors_total` either way.
+    pub fn set_tolerant(&mut self, tolerant: bool) {
+        self.tolerant = tolerant;
+    }
+
+    /// How to normalize the label values of the devices
+    pub fn set_normalization(&mut self, normalization: labels::Normalization) {
+        self.normalization = normalization;
+    }
+
+    pub fn into_backend(self) -> Backend {
+        drop(self.devices);
+        self.backend
+    }
+
+    pub fn collectors(&self) -> &[Box<dyn DeviceCollector>] {
+        &self.collectors
+    }
+
+    /// Failed NVML queries, by uuid, function, and error code
+    pub fn errors(&self) -> &IntCounterVec {
+        &self.errors
+    }
+
+    /// Query the selected devices with the selected collectors until the deadline passes. `None`
+    /// if a selected device doesn't exist. Fails if a collector does, unless
+    /// [tolerant](Self::set_tolerant).
+    pub fn gather(
+        &self,
+        selection: &Selection,
+        deadline: Option<Instant>,
+    ) -> Result<Option<Vec<MetricFamily>>> {
+        let mut found = self.devices.lock().unwrap();
+        self.discover(&mut found)?;
+        let Devices { layout, list } = &mut *found;
+        let mut devices = list.iter().collect::<Vec<_>>();
+        self.set_up(&devices);
+        if let Some(targets) = &selection.devices {
+            let mut selected = vec![false; devices.len()];
+            for target in targets {
+                let found = devices
+                    .iter()
+                    .position(|dev| dev.uuid() == target)
+                    .or_else(|| target.parse().ok().filter(|&i: &usize| i < devices.len()));
+                let Some(found) = found else {
+                    return Ok(None);
+                };
+                selected[found] = true;
+            }
+            let mut selected = selected.into_iter();
+            devices.retain(|_| selected.next() == Some(true));
+        }
+        let collected = self.collect_devices(&devices, selection, deadline);
+        if collected.is_err() {
+            // E.g. a device fell off the bus
+            *layout = None;
+        }
+        if !self.tolerant {
+            collected?;
+        }
+        let mut families = self.families(selection);
+        if selection.devices.is_some() {
+            // Leave out the errors of other devices
+            let uuids = devices.iter().map(|dev| dev.uuid()).collect::<Vec<_>>();
+            for mf in &mut families {
+                let metrics = mf
+                    .take_metric()
+                    .into_iter()
+                    .filter(|m| {
+                        m.get_label().iter().any(|l| {
+                            l.get_name() == GPU_LABELS[0] && uuids.contains(&l.get_value())
+                        })
+                    })
+                    .collect();
+                mf.set_metric(metrics);
+            }
+            families.retain(|mf| !mf.get_metric().is_empty());
+        }
+        Ok(Some(families))
+    }
+
+    /// The devices' label values, in the order of their indices
+    pub fn devices(&self) -> Result<Vec<[String; 3]>> {
+        let mut found = self.devices.lock().unwrap();
+        self.discover(&mut found)?;
+        Ok(found.list.iter().map(|dev| dev.labels.clone()).collect())
+    }
+
+    /// Have the collectors that aggregate between collections take a reading of every device
+    pub fn sample(&self) -> Result<()> {
+        let mut found = self.devices.lock().unwrap();
+        // Labeled and checked like those collected from
+        self.discover(&mut found)?;
+        let devices = found.list.iter().collect::<Vec<_>>();
+        self.set_up(&devices);
+        for dev in devices {
+            for collector in self.supported(dev) {
+                collector.sample(dev);
+            }
+        }
+        Ok(())
+    }
+
+    /// Bring the backend's devices up to date, keeping track of whether there are any. They're
+    /// only set up again when the backend's [layout](Backend::layout) changed, or after a failed
+    /// collection.
+    fn discover(&self, found: &mut Devices) -> Result<()> {
+        let down = |_: &_| {
+            self.up.set(0);
+            self.device_count.set(0);
+        };
+        let pci = self.backend.pci_gpus();
+        let layout = self.backend.layout(&pci).inspect_err(down)?;
+        if layout.is_none() || layout != found.layout {
+            let mut devices = self.backend.discover_among(&pci).inspect_err(down)?;
+            for dev in &mut devices {
+                dev.sanity = Some(self.sanity.clone());
+                self.normalization.apply(&mut dev.labels);
+            }
+            // Safety: only NVML's devices borrow from the backend, which outlives them, as they
+            // are dropped first, and whenever it is replaced
+            found.list = unsafe {
+                std::mem::transmute::<Vec<MetricDevice<'_>>, Vec<MetricDevice<'static>>>(devices)
+            };
+            found.layout = layout;
+        }
+        let devices = &found.list;
+        self.up
+            .set((!matches!(self.backend, Backend::Absent)).into());
+        self.device_count.set(devices.len() as i64);
+        let mut labels = self.labels.lock().unwrap();
+        for dev in devices {
+            labels.insert(dev.labels[2].clone(), dev.labels.clone());
+        }
+        self.suspended.reset();
+        for gpu in pci {
+            let bus_id = self.normalization.pci(&gpu.bus_id);
+            let unknown = [String::new(), String::new(), bus_id.clone()];
+            let [uuid, name, pci] = labels.get(&bus_id).unwrap_or(&unknown);
+            self.suspended
+                .with_label_values(&[uuid, name, pci])
+                .set(gpu.suspended.into());
+        }
+        Ok(())
+    }
+
+    fn forget(&self, uuid: &str) {
+        for collector in &self.collectors {
+            collector.forget(uuid);
+        }
+        self.sanity.forget(uuid);
+        let series = self.metric_supported.collect();
+        for metric in series.iter().flat_map(|mf| mf.get_metric()) {
+            let labels = metric
+                .get_label()
+                .iter()
+                .map(|l| (l.get_name(), l.get_value()))
+                .collect::<HashMap<_, _>>();
+            if labels.get(GPU_LABELS[0]) == Some(&uuid) {
+                self.metric_supported.remove(&labels).ok();
+            }
+        }
+    }
+
+    /// Probe what new devices support, and forget the devices that are gone since last time
+    fn set_up(&self, devices: &[&MetricDevice]) {
+        let mut support = self.support.lock().unwrap();
+        support.retain(|uuid, _| {
+            let present = devices.iter().any(|dev| dev.uuid() == uuid);
+            if !present {
+                self.forget(uuid);
+            }
+            present
+        });
+        for dev in devices {
+            if !support.contains_key(dev.uuid()) {
+                let supported = self.collectors.iter().map(|c| c.supported(dev)).collect();
+                support.insert(dev.uuid().to_owned(), supported);
+            }
+        }
+    }
+
+    /// The collectors that support the device
+    fn supported(&self, dev: &MetricDevice) -> Vec<&dyn DeviceCollector> {
+        let support = self.support.lock().unwrap();
+        let support = support.get(dev.uuid()).map_or(&[][..], Vec::as_slice);
+        self.collectors
+            .iter()
+            .zip(support)
+            .filter(|(_, &supported)| supported)
+            .map(|(collector, _)| &**collector)
+            .collect()
+    }
+
+    /// Run the selected collectors on the given devices, until the deadline passes. All
+    /// collectors run even if some fail, the first failure is returned. If nothing was left out,
+    /// a success counts for the overall last collection timestamp.
+    fn collect_devices(
+        &self,
+        devices: &[&MetricDevice],
+        selection: &Selection,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        self.timed_out.reset();
+        for collector in &self.collectors {
+            collector.reset();
+            if selection.collects(collector.name()) {
+                self.timed_out.with_label_values(&[collector.name()]).set(0);
+            }
+        }
+        let timed_out = |collector: &dyn DeviceCollector| {
+            self.timed_out.with_label_values(&[collector.name()]).set(1);
+        };
+        let mut result = Ok(());
+        let mut collected = devices.len();
+        let mut complete = selection.devices.is_none() && selection.collectors.is_none();
+        for (i, dev) in devices.iter().enumerate() {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                warn!(
+                    "Scrape timeout reached, leaving out {} devices",
+                    devices.len() - i
+                );
+                complete = false;
+                collected = i;
+                // Rather than export what they had last time
+                for dev in &devices[i..] {
+                    self.forget(dev.uuid());
+                }
+                let collectors = self.collectors.iter();
+                collectors
+                    .filter(|c| selection.collects(c.name()))
+                    .for_each(|c| timed_out(&**c));
+                break;
+            }
+            let mut failed = false;
+            let mut cut_short = false;
+            dev.prefetch();
+            let collectors = self.supported(dev).into_iter();
+            for collector in collectors.filter(|c| selection.collects(c.name())) {
+                // A single slow device gets the others left out, but not everything
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    warn!(
+                        uuid = dev.uuid(),
+                        collector = collector.name(),
+                        "Scrape timeout reached, leaving out the collector"
+                    );
+                    collector.forget(dev.uuid());
+                    timed_out(collector);
+                    complete = false;
+                    cut_short = true;
+                    continue;
+                }
+                let started = Instant::now();
+                let updated = collector.update(dev, &self.errors);
+                let elapsed = started.elapsed();
+                let name = collector.name();
+                match updated {
+                    Ok(()) => debug!(uuid = dev.uuid(), collector = name, ?elapsed, "Collected"),
+                    Err(e) => {
+                        let code = errors::nvml_code(&*e);
+                        error!(
+                            uuid = dev.uuid(),
+                            collector = name,
+                            code,
+                            "Collection failed: {}",
+                            e
+                        );
+                        // Whatever it had collected before the failure
+                        collector.forget(dev.uuid());
+                        failed = true;
+                        if result.is_ok() {
+                            result = Err(e);
+                        }
+                    }
+                }
+            }
+            // A failure isn't for lack of support
+            if !failed && !cut_short {
+                self.update_metric_supported(dev, selection);
+            }
+            if !failed && !cut_short && selection.collectors.is_none() {
+                self.device_last_collect
+                    .with_label_values(&dev.labels())
+                    .set(unix_time());
+            }
+        }
+        if complete && result.is_ok() {
+            self.last_collect.set(unix_time());
+        }
+        self.update_host(collected);
+        result
+    }
+
+    /// Sum up what the collectors have for all devices. Left out if no device has a reading,
+    /// e.g. when the collector isn't selected.
+    fn update_host(&self, collected: usize) {
+        self.host_memory_used.reset();
+        self.host_power_usage.reset();
+        self.host_gpu_count.reset();
+        let families = self
+            .collectors
+            .iter()
+            .flat_map(|collector| collector.metrics())
+            .flat_map(|(_, metric)| metric.collect())
+            .collect::<Vec<_>>();
+        let sum = |name: &str| {
+            let metrics = families
+                .iter()
+                .filter(|mf| mf.get_name() == name)
+                .flat_map(|mf| mf.get_metric())
+                .collect::<Vec<_>>();
+            let sum: f64 = metrics.iter().map(|m| m.get_gauge().get_value()).sum();
+            (!metrics.is_empty()).then_some(sum)
+        };
+        if let Some(used) = sum("nvml_memory_used_bytes") {
+            self.host_memory_used
+                .with_label_values(&[])
+                .set(used as i64);
+        }
+        if let Some(usage) = sum("nvml_power_usage_current_mw") {
+            self.host_power_usage
+                .with_label_values(&[])
+                .set(usage / 1000.);
+        }
+        self.host_gpu_count
+            .with_label_values(&[])
+            .set(collected as i64);
+    }
+
+    /// Set [`Self::metric_supported`] for the metrics of the selected collectors, from whether
+    /// they now have a series for the device. Per process metrics are left out, as they only exist
+    /// while processes run.
+    fn update_metric_supported(&self, dev: &MetricDevice, selection: &Selection) {
+        let support = self.support.lock().unwrap();
+        let support = support.get(dev.uuid()).map_or(&[][..], Vec::as_slice);
+        let collectors = self.collectors.iter().zip(support);
+        for (collector, &supported) in collectors.filter(|(c, _)| selection.collects(c.name())) {
+            for (_, metric) in collector.metrics() {
+                let families = metric.collect();
+                for desc in metric.desc() {
+                    if desc.variable_labels.iter().any(|label| label == "pid") {
+                        continue;
+                    }
+                    let present = supported
+                        && families
+                            .iter()
+                            .filter(|mf| mf.get_name() == desc.fq_name)
+                            .flat_map(|mf| mf.get_metric())
+                            .flat_map(|m| m.get_label())
+                            .any(|l| l.get_name() == GPU_LABELS[0] && l.get_value() == dev.uuid());
+                    let [uuid, name, pci] = dev.labels();
+                    self.metric_supported
+                        .with_label_values(&[uuid, name, pci, &desc.fq_name])
+                        .set(present.into());
+                }
+            }
+        }
+    }
+
+    fn families(&self, selection: &Selection) -> Vec<MetricFamily> {
+        let mut families = self
+            .collectors
+            .iter()
+            .filter(|collector| selection.collects(collector.name()))
+            .flat_map(|collector| collector.metrics())
+            .flat_map(|(_, metric)| metric.collect())
+            .chain(self.errors.collect())
+            .chain(self.sanity.invalid.collect())
+            .chain(self.last_collect.collect())
+            .chain(self.device_last_collect.collect())
+            .chain(self.metric_supported.collect())
+            .chain(self.up.collect())
+            .chain(self.device_count.collect())
+            .chain(self.host_memory_used.collect())
+            .chain(self.host_power_usage.collect())
+            .chain(self.host_gpu_count.collect())
+            .chain(self.suspended.collect())
+            .chain(self.timed_out.collect())
+            .filter(|mf| !mf.get_metric().is_empty())
+            .collect::<Vec<_>>();
+        families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        for (name, value) in self.backend.labels() {
+            add_label(&mut families, name, value);
+        }
+        families
+    }
+}
+
+fn unix_time() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Add a label with the same value to every metric
+pub fn add_label(families: &mut [MetricFamily], name: &str, value: &str) {
+    for metric in families
+        .iter_mut()
+        .flat_map(|mf| mf.mut_metric().iter_mut())
+    {
+        let mut label = LabelPair::default();
+        label.set_name(name.into());
+        label.set_value(value.into());
+        metric.mut_label().push(label);
+    }
+}
+
+impl Collector for NvmlCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.collectors
+            .iter()
+            .flat_map(|collector| collector.metrics())
+            .flat_map(|(_, metric)| metric.desc())
+            .chain(self.errors.desc())
+            .chain(self.sanity.invalid.desc())
+            .chain(self.last_collect.desc())
+            .chain(self.device_last_collect.desc())
+            .chain(self.metric_supported.desc())
+            .chain(self.up.desc())
+            .chain(self.device_count.desc())
+            .chain(self.host_memory_used.desc())
+            .chain(self.host_power_usage.desc())
+            .chain(self.host_gpu_count.desc())
+            .chain(self.suspended.desc())
+            .chain(self.timed_out.desc())
+            .collect()
+    }
+
+    /// Failures are logged, and whatever was collected is returned
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut found = self.devices.lock().unwrap();
+        if self.discover(&mut found).is_ok() {
+            let devices = found.list.iter().collect::<Vec<_>>();
+            self.set_up(&devices);
+            if self
+                .collect_devices(&devices, &Selection::default(), None)
+                .is_err()
+            {
+                found.layout = None;
+            }
+        }
+        self.families(&Selection::default())
+    }
+}
+
+/// The devices an [`NvmlCollector`] found, and the backend's layout when it did
+#[derive(Default)]
+struct Devices {
+    /// `None` to find them again
+    layout: Option<Layout>,
+    list: Vec<MetricDevice<'static>>,
+}
+
+/// What changes when devices come or go: how many there are, and the bus ids of those awake
+type Layout = (usize, Vec<String>);
+
+/// What to collect in [`NvmlCollector::gather`]
+#[derive(Clone, Default)]
+pub struct Selection {
+    /// Uuids or indices, all devices if `None`
+    pub devices: Option<Vec<String>>,
+    /// Collector names, all collectors if `None`
+    pub collectors: Option<Vec<String>>,
+}
+
+impl Selection {
+    pub fn devices(devices: Vec<String>) -> Selection {
+        Selection {
+            devices: Some(devices),
+            collectors: None,
+        }
+    }
+
+    pub fn collects(&self, collector: &str) -> bool {
+        self.collectors
+            .as_ref()
+            .is_none_or(|collectors| collectors.iter().any(|c| c == collector))
+    }
+}
+
+/// A device and the label values of its metrics
+pub struct MetricDevice<'a> {
+    device: Box<dyn gpu::Gpu + 'a>,
+    labels: [String; 3],
+    fan_count: u32,
+    /// Values of [`gpu::FIELDS`] fetched for the current collection, by field id
+    fields: Mutex<HashMap<u32, u64>>,
+    /// Where readings are checked, if they are
+    sanity: Option<Arc<sanity::Sanity>>,
+    capabilities: capabilities::Capabilities,
+}
+
+impl MetricDevice<'_> {
+    pub fn new(device: Box<dyn gpu::Gpu + '_>) -> Result<MetricDevice<'_>> {
+        let mut i: u32 = 0;
+        Ok(MetricDevice {
+            fan_count: loop {
+                if i > 10_000 || device.fan_speed(i).is_err() {
+                    break i;
+                };
+                i += 1;
+            },
+            labels: [device.uuid()?, device.name()?, device.pci_bus_id()?],
+            capabilities: capabilities::Capabilities::probe(&*device),
+            device,
+            fields: Default::default(),
+            sanity: None,
+        })
+    }
+    pub fn gpu(&self) -> &dyn gpu::Gpu {
+        &*self.device
+    }
+    pub fn uuid(&self) -> &str {
+        &self.labels[0]
+    }
+    /// Values for [`GPU_LABELS`]
+    pub fn labels(&self) -> [&str; 3] {
+        self.labels.each_ref().map(String::as_str)
+    }
+    pub fn fan_count(&self) -> u32 {
+        self.fan_count
+    }
+    /// What the device's driver can be asked
+    pub fn capabilities(&self) -> &capabilities::Capabilities {
+        &self.capabilities
+    }
+    /// Fetch [`gpu::FIELDS`] in one driver call, replacing those of the previous collection
+    fn prefetch(&self) {
+        let values = self.device.field_values(&gpu::FIELDS).unwrap_or_default();
+        let fields = gpu::FIELDS.into_iter().zip(values);
+        let fields = fields.filter_map(|(id, value)| Some((id, value.ok()?)));
+        *self.fields.lock().unwrap() = fields.collect();
+    }
+    /// A prefetched field value, or, if it wasn't available that way, that of `query`, so failures
+    /// are reported as before
+    pub fn field<T: TryFrom<u64>>(
+        &self,
+        id: u32,
+        query: impl FnOnce() -> std::result::Result<T, NvmlError>,
+    ) -> std::result::Result<T, NvmlError> {
+        let value = self.fields.lock().unwrap().remove(&id);
+        match value.and_then(|value| T::try_from(value).ok()) {
+            Some(value) => Ok(value),
+            None => query(),
+        }
+    }
+    /// The reading of a gauge, if it is within `valid`, see [`sanity`]
+    pub fn gauge(
+        &self,
+        metric: &'static str,
+        value: f64,
+        valid: RangeInclusive<f64>,
+    ) -> Option<f64> {
+        match &self.sanity {
+            Some(sanity) => sanity.gauge(self.uuid(), metric, value, valid),
+            None => Some(value),
+        }
+    }
+    /// The reading of a counter, if it didn't jump, see [`sanity`]
+    pub fn counter(&self, metric: &'static str, value: u64) -> Option<u64> {
+        match &self.sanity {
+            Some(sanity) => sanity.counter(self.uuid(), metric, value),
+            None => Some(value),
+        }
+    }
+    /// Count and annotate a failed NVML query
+    pub fn query<T>(
+        &self,
+        errors: &IntCounterVec,
+        function: &'static str,
+        result: std::result::Result<T, NvmlError>,
+    ) -> std::result::Result<T, errors::QueryError> {
+        result.map_err(|error| {
+            errors
+                .with_label_values(&[&self.labels[0], function, errors::code(&error)])
+                .inc();
+            errors::QueryError { function, error }
+        })
+    }
+}
+
+/// Where to try loading NVML from: the given library path, or the well-known ones that exist,
+/// followed by the bare library names for the dynamic linker's search path
+pub fn nvml_library_paths(path: Option<&Path>) -> Vec<PathBuf> {
+    if let Some(path) = path {
+        return vec![path.into()];
+    }
+    let paths = [
+        "/usr/lib/libnvidia-ml.so",
+        "/run/opengl-driver/lib/libnvidia-ml.so",
+        "/usr/lib/x86_64-linux-gnu/libnvidia-ml.so.1",
+        "/usr/lib64/libnvidia-ml.so.1",
+        "/usr/local/nvidia/lib64/libnvidia-ml.so.1",
+        "/usr/lib/wsl/lib/libnvidia-ml.so.1",
+    ];
+    paths
+        .into_iter()
+        .map(Path::new)
+        .filter(|path| path.exists())
+        // Without the development package, only the versioned name exists
+        .chain([Path::new("libnvidia-ml.so"), Path::new("libnvidia-ml.so.1")])
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Where devices come from
+pub enum Backend {
+    Nvml(Box<Nvml>),
+    Mock(u32),
+    Replay(Arc<record::Recording>),
+    /// Parsed from `nvidia-smi` output, marked with a `source="nvidia-smi"` label
+    Smi,
+    #[cfg(feature = "tegra")]
+    Tegra(tegra::TegraGpu),
+    /// Marked with a `vendor="amd"` label
+    #[cfg(feature = "rocm")]
+    Rocm(Arc<rocm::Rocm>),
+    /// Marked with a `vendor="intel"` label
+    #[cfg(feature = "intel")]
+    Intel(Vec<intel::IntelGpu>),
+    /// No driver to query, for hosts without GPUs. There are no devices, and `nvml_up` is 0.
+    Absent,
+}
+
+impl Backend {
+    /// Load and initialize NVML, from the given path or a well-known location
+    pub fn nvml(library_path: Option<&Path>, flags: InitFlags) -> Result<Backend> {
+        Ok(Backend::Nvml(Box::new(init_nvml(library_path, flags)?)))
+    }
+
+    /// `nvidia-smi`, if it works
+    pub fn smi() -> Result<Backend> {
+        smi::query().inspect_err(|e| debug!("Failed to run nvidia-smi: {}", e))?;
+        Ok(Backend::Smi)
+    }
+
+    /// The integrated GPU of a Jetson board, if this is one
+    #[cfg(feature = "tegra")]
+    pub fn tegra() -> Result<Backend> {
+        let gpu = tegra::TegraGpu::find().ok_or("No Tegra GPU found")?;
+        Ok(Backend::Tegra(gpu))
+    }
+
+    /// AMD GPUs through ROCm SMI, from the given path or a well-known location
+    #[cfg(feature = "rocm")]
+    pub fn rocm(library_path: Option<&Path>) -> Result<Backend> {
+        let path = library_path.unwrap_or(Path::new("/opt/rocm/lib/librocm_smi64.so"));
+        let path = match path.exists() {
+            true => path,
+            false => Path::new("librocm_smi64.so"),
+        };
+        let rocm = rocm::Rocm::init(path)
+            .inspect_err(|e| debug!(path = %path.display(), "Failed to load ROCm SMI: {}", e))?;
+        info!(path = %path.display(), "Loaded ROCm SMI");
+        Ok(Backend::Rocm(Arc::new(rocm)))
+    }
+
+    /// Intel GPUs, if there are any
+    #[cfg(feature = "intel")]
+    pub fn intel() -> Result<Backend> {
+        let gpus = intel::find();
+        if gpus.is_empty() {
+            return Err("No Intel GPU found".into());
+        }
+        info!(count = gpus.len(), "Found Intel GPUs");
+        Ok(Backend::Intel(gpus))
+    }
+
+    /// Added to all metrics, for devices that aren't NVIDIA's or don't come from NVML directly
+    pub fn labels(&self) -> Vec<(&'static str, &'static str)> {
+        match self {
+            Backend::Smi => vec![("source", "nvidia-smi")],
+            #[cfg(feature = "rocm")]
+            Backend::Rocm(_) => vec![("vendor", "amd")],
+            #[cfg(feature = "intel")]
+            Backend::Intel(_) => vec![("vendor", "intel")],
+            _ => vec![],
+        }
+    }
+
+    /// What changes when devices come or go, cheaper to check than finding them again. `None`
+    /// if the devices' readings are taken when they're found, so they have to be every time.
+    fn layout(&self, pci: &[runtime_pm::PciGpu]) -> Result<Option<Layout>> {
+        let count = match self {
+            Backend::Nvml(nvml) => nvml.device_count()? as usize,
+            Backend::Mock(count) => *count as usize,
+            Backend::Replay(recording) => recording.device_count(),
+            Backend::Smi => return Ok(None),
+            #[cfg(feature = "tegra")]
+            Backend::Tegra(_) => 1,
+            #[cfg(feature = "rocm")]
+            Backend::Rocm(rocm) => rocm.device_count()? as usize,
+            #[cfg(feature = "intel")]
+            Backend::Intel(gpus) => gpus.len(),
+            Backend::Absent => 0,
+        };
+        let awake = pci.iter().filter(|gpu| !gpu.suspended);
+        Ok(Some((count, awake.map(|gpu| gpu.bus_id.clone()).collect())))
+    }
+
+    pub fn discover(&self) -> Result<Vec<MetricDevice<'_>>> {
+        self.discover_among(&self.pci_gpus())
+    }
+
+    /// With the GPUs on the PCI bus already looked up
+    fn discover_among(&self, pci: &[runtime_pm::PciGpu]) -> Result<Vec<MetricDevice<'_>>> {
+        let gpus: Vec<Box<dyn gpu::Gpu>> = match self {
+            Backend::Nvml(nvml) => {
+                if pci.iter().any(|gpu| gpu.suspended) {
+                    // Only the others, by bus id, not to wake these up
+                    pci.iter()
+                        .filter(|gpu| !gpu.suspended)
+                        .filter_map(|gpu| match nvml.device_by_pci_bus_id(gpu.bus_id.as_str()) {
+                            // E.g. bound to vfio for a VM
+                            Err(NvmlError::NotFound) => None,
+                            device => Some(device.map(|device| Box::new(device) as _)),
+                        })
+                        .collect::<std::result::Result<_, NvmlError>>()?
+                } else {
+                    (0..(nvml.device_count()?))
+                        .map(|idx| Ok(Box::new(nvml.device_by_index(idx)?) as _))
+                        .collect::<std::result::Result<_, NvmlError>>()?
+                }
+            }
+            Backend::Mock(count) => (0..*count)
+                .map(|idx| Box::new(mock::MockGpu::new(idx)) as _)
+                .collect(),
+            Backend::Replay(recording) => (0..recording.device_count())
+                .map(|idx| Box::new(record::ReplayGpu::new(recording.clone(), idx)) as _)
+                .collect(),
+            Backend::Smi => smi::query()?
+                .into_iter()
+                .map(|gpu| Box::new(gpu) as _)
+                .collect(),
+            #[cfg(feature = "tegra")]
+            Backend::Tegra(gpu) => vec![Box::new(gpu.clone())],
+            #[cfg(feature = "rocm")]
+            Backend::Rocm(rocm) => (0..rocm.device_count()?)
+                .map(|idx| Box::new(rocm::RocmGpu::new(rocm.clone(), idx)) as _)
+                .collect(),
+            #[cfg(feature = "intel")]
+            Backend::Intel(gpus) => gpus.iter().map(|gpu| Box::new(gpu.clone()) as _).collect(),
+            Backend::Absent => vec![],
+        };
+        let devices = gpus
+            .into_iter()
+            .map(MetricDevice::new)
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .inspect_err(|e| {
+                error!(
+                    code = errors::nvml_code(&**e),
+                    "Failed to set up devices: {}", e
+                )
+            })?;
+        for dev in &devices {
+            let [uuid, name, pci] = &dev.labels;
+            let driver = dev
+                .capabilities
+                .driver()
+                .map(|v| format!("{}.{}", v.0, v.1));
+            debug!(
+                uuid,
+                name,
+                pci,
+                fans = dev.fan_count,
+                driver,
+                "Found device"
+            );
+        }
+        Ok(devices)
+    }
+
+    /// The GPUs on the PCI bus, and whether they are suspended, for the backends that wake them
+    /// up
+    pub fn pci_gpus(&self) -> Vec<runtime_pm::PciGpu> {
+        match self {
+            Backend::Nvml(_) => runtime_pm::nvidia_gpus(),
+            _ => vec![],
+        }
+    }
+
+    /// Turn on NVML accounting mode on all devices that support it, so the driver keeps
+    /// statistics of processes after they exit. Needs root, failures are logged.
+    pub fn enable_accounting(&self) {
+        let Backend::Nvml(nvml) = self else {
+            return;
+        };
+        let count = match nvml.device_count() {
+            Ok(count) => count,
+            Err(e) => return warn!("Not enabling accounting mode: {}", e),
+        };
+        for idx in 0..count {
+            let mut device = match nvml.device_by_index(idx) {
+                Ok(device) => device,
+                Err(e) => return warn!("Not enabling accounting mode: {}", e),
+            };
+            if device.is_accounting_enabled().unwrap_or(false) {
+                continue;
+            }
+            match device.set_accounting(true) {
+                Ok(()) => {
+                    let buffer = device.accounting_buffer_size().unwrap_or_default();
+                    info!(idx, buffer, "Enabled accounting mode");
+                }
+                Err(NvmlError::NotSupported) => debug!(idx, "No accounting mode"),
+                Err(NvmlError::NoPermission) => {
+                    return warn!("Not enabling accounting mode, that needs root");
+                }
+                Err(e) => warn!(idx, "Failed to enable accounting mode: {}", e),
+            }
+        }
+    }
+
+    /// Set the power limit of the GPU, by index or uuid, returning the enforced limit before and
+    /// after, in mW, or none if there is no such GPU. Needs root, and only NVML can.
+    pub fn set_power_limit(&self, gpu: &str, milliwatts: u32) -> Result<Option<(u32, u32)>> {
+        let Some(mut device) = self.nvml_device(gpu)? else {
+            return Ok(None);
+        };
+        let constraints = device.power_management_limit_constraints()?;
+        if !(constraints.min_limit..=constraints.max_limit).contains(&milliwatts) {
+            return Err(format!(
+                "The power limit has to be within {} to {} mW",
+                constraints.min_limit, constraints.max_limit
+            )
+            .into());
+        }
+        let before = device.enforced_power_limit()?;
+        device.set_power_management_limit(milliwatts)?;
+        Ok(Some((before, device.enforced_power_limit()?)))
+    }
+
+    /// Lock the graphics clock of the GPU, by index or uuid, to between the given MHz, or unlock
+    /// it, returning the graphics clock before and after, or none if there is no such GPU. Needs
+    /// root, and only NVML can.
+    pub fn lock_clocks(&self, gpu: &str, clocks: Option<(u32, u32)>) -> Result<Option<(u32, u32)>> {
+        let Some(mut device) = self.nvml_device(gpu)? else {
+            return Ok(None);
+        };
+        let before = device.clock_info(Clock::Graphics)?;
+        match clocks {
+            Some((min_clock_mhz, max_clock_mhz)) => {
+                device.set_gpu_locked_clocks(GpuLockedClocksSetting::Numeric {
+                    min_clock_mhz,
+                    max_clock_mhz,
+                })?
+            }
+            None => device.reset_gpu_locked_clocks()?,
+        }
+        Ok(Some((before, device.clock_info(Clock::Graphics)?)))
+    }
+
+    /// Clear the GPU's ECC error counts, volatile and aggregate, and the error counters of its
+    /// active NVLinks, as asked for, e.g. after maintenance. Returns the GPU's uuid and the
+    /// counters it has that were reset, or none if there is no such GPU. Needs root, and only
+    /// NVML can.
+    pub fn reset_counters(
+        &self,
+        gpu: &str,
+        ecc: bool,
+        nvlink: bool,
+    ) -> Result<Option<(String, Vec<&'static str>)>> {
+        let Some(mut device) = self.nvml_device(gpu)? else {
+            return Ok(None);
+        };
+        let mut reset = vec![];
+        if ecc {
+            let cleared = device
+                .clear_ecc_error_counts(EccCounter::Volatile)
+                .and_then(|()| device.clear_ecc_error_counts(EccCounter::Aggregate));
+            match cleared {
+                Ok(()) => reset.push("ecc"),
+                Err(NvmlError::NotSupported) => debug!(gpu, "No ECC"),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if nvlink {
+            let mut links = 0;
+            for link in 0..NVML_NVLINK_MAX_LINKS {
+                let mut link = device.link_wrapper_for(link);
+                match link.is_active() {
+                    Ok(true) => link.reset_error_counters()?,
+                    Ok(false) => continue,
+                    Err(NvmlError::NotSupported | NvmlError::InvalidArg) => break,
+                    Err(e) => return Err(e.into()),
+                }
+                links += 1;
+            }
+            match links {
+                0 => debug!(gpu, "No active NVLinks"),
+                _ => reset.push("nvlink"),
+            }
+        }
+        Ok(Some((device.uuid()?, reset)))
+    }
+
+    /// A GPU to change settings of, by index or uuid
+    fn nvml_device(&self, gpu: &str) -> Result<Option<nvml_wrapper::Device<'_>>> {
+        let Backend::Nvml(nvml) = self else {
+            return Err("Only NVML can change GPU settings".into());
+        };
+        let device = match gpu.parse() {
+            Ok(idx) => nvml.device_by_index(idx),
+            Err(_) => nvml.device_by_uuid(gpu),
+        };
+        match device {
+            Ok(device) => Ok(Some(device)),
+            Err(NvmlError::NotFound | NvmlError::InvalidArg) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn shutdown(self) -> Result<()> {
+        if let Backend::Nvml(nvml) = self {
+            nvml.shutdown()?;
+        }
+        Ok(())
+    }
+}
+
+fn init_nvml(library_path: Option<&Path>, flags: InitFlags) -> Result<Nvml> {
+    let paths = nvml_library_paths(library_path);
+    let mut result = Err(NvmlError::Unknown);
+    for path in &paths {
+        result = Nvml::builder()
+            .lib_path(path.as_os_str())
+            .flags(flags)
+            .init();
+        match &result {
+            Err(NvmlError::LibloadingError(e)) => {
+                debug!(path = %path.display(), "Failed to load NVML: {}", e)
+            }
+            Ok(_) => {
+                raw::load(path);
+                info!(path = %path.display(), "Loaded NVML");
+                break;
+            }
+            Err(_) => break,
+        }
+    }
+    let nvml = result.inspect_err(|e| match e {
+        NvmlError::LibloadingError(_) => error!(
+            code = errors::nvml_code(e),
+            tried = ?paths,
+            "Failed to load NVML, is the NVIDIA driver installed? Specify the library path if it is in an unusual location"
+        ),
+        _ => error!(
+            code = errors::nvml_code(e),
+            "Failed to initialize NVML: {}", e
+        ),
+    })?;
+    info!(
+        driver = nvml.sys_driver_version().ok(),
+        nvml = nvml.sys_nvml_version().ok(),
+        "Initialized NVML"
+    );
+    Ok(nvml)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where the devices' handles are, which stay put while they're kept
+    fn handles(collector: &NvmlCollector) -> Vec<*const u8> {
+        let devices = collector.devices.lock().unwrap();
+        let handles = devices
+            .list
+            .iter()
+            .map(|dev| &*dev.device as *const _ as *const u8);
+        handles.collect()
+    }
+
+    #[test]
+    fn devices_are_kept() {
+        let collector = NvmlCollector::new(Backend::Mock(2));
+        collector.gather(&Selection::default(), None).unwrap();
+        let before = handles(&collector);
+        assert_eq!(before.len(), 2);
+        collector.sample().unwrap();
+        collector.devices().unwrap();
+        collector.gather(&Selection::default(), None).unwrap();
+        assert_eq!(handles(&collector), before);
+    }
+
+    #[test]
+    fn sampled_devices_are_normalized() {
+        let histograms = collectors::Histograms::new(&[collectors::histograms::Signal::Power]);
+        // Up to 00000000:0B:00.0
+        let mut collector =
+            NvmlCollector::with_collectors(Backend::Mock(11), vec![Box::new(histograms)]);
+        collector.set_normalization(labels::Normalization {
+            lowercase_pci: true,
+            trim_vendor: false,
+        });
+        collector.sample().unwrap();
+        let families = collector.gather(&Selection::default(), None).unwrap();
+        let pci = families
+            .unwrap()
+            .iter()
+            .filter(|mf| mf.get_name() == "nvml_sampled_power_usage_mw")
+            .flat_map(|mf| mf.get_metric())
+            .flat_map(|m| m.get_label())
+            .filter(|l| l.get_name() == "pci")
+            .map(|l| l.get_value().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(pci.len(), 11);
+        assert!(pci.contains(&"00000000:0b:00.0".to_owned()));
+        assert!(pci.iter().all(|pci| *pci == pci.to_lowercase()));
+    }
+}
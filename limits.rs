@@ -0,0 +1,94 @@
+//! Keeping misbehaving scrapers and port scanners from stacking up collections
+//!
+//! Requests are answered one after the other, so the ones waiting are what would pile up.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::Instant;
+use tiny_http::{Request, Server};
+use tracing::warn;
+
+use crate::http;
+
+/// Clients remembered before forgetting those that haven't been heard from in a minute
+const MAX_CLIENTS: usize = 1024;
+
+/// Token bucket, holding up to a minute's worth of requests
+struct Bucket {
+    tokens: f64,
+    at: Instant,
+}
+
+pub struct Limits {
+    max_waiting: usize,
+    /// Requests per minute and client address
+    per_client: Option<u32>,
+    clients: HashMap<IpAddr, Bucket>,
+    waiting: VecDeque<Request>,
+}
+
+impl Limits {
+    pub fn new(max_waiting: usize, per_client: Option<u32>) -> Limits {
+        Limits {
+            max_waiting,
+            per_client,
+            clients: HashMap::new(),
+            waiting: VecDeque::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+
+    /// Queue a request, or reject it right away if it is over a limit
+    pub fn admit(&mut self, request: Request) {
+        if let Some(addr) = request.remote_addr() {
+            if !self.allow(addr.ip()) {
+                warn!(remote = %addr, "Rate limited request");
+                http::error(request, 429, "Too many requests").ok();
+                return;
+            }
+        }
+        if self.waiting.len() >= self.max_waiting {
+            warn!(remote = ?request.remote_addr(), "Rejected request, too many waiting");
+            http::error(request, 503, "Too many concurrent scrapes").ok();
+            return;
+        }
+        self.waiting.push_back(request);
+    }
+
+    /// Admit all requests that arrived in the meantime
+    pub fn accept(&mut self, server: &Server) {
+        while let Ok(Some(request)) = server.try_recv() {
+            self.admit(request);
+        }
+    }
+
+    pub fn next(&mut self) -> Option<Request> {
+        self.waiting.pop_front()
+    }
+
+    fn allow(&mut self, ip: IpAddr) -> bool {
+        let Some(per_minute) = self.per_client else {
+            return true;
+        };
+        let (now, capacity) = (Instant::now(), per_minute as f64);
+        if self.clients.len() >= MAX_CLIENTS {
+            self.clients
+                .retain(|_, bucket| now.duration_since(bucket.at).as_secs() < 60);
+        }
+        let bucket = self.clients.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            at: now,
+        });
+        let refill = now.duration_since(bucket.at).as_secs_f64() * capacity / 60.;
+        bucket.tokens = (bucket.tokens + refill).min(capacity);
+        bucket.at = now;
+        if bucket.tokens < 1. {
+            return false;
+        }
+        bucket.tokens -= 1.;
+        true
+    }
+}
@@ -0,0 +1,56 @@
+use tracing::Level;
+
+use crate::Result;
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Format {
+    Text,
+    /// One JSON object per line, e.g. for Loki or ELK
+    Json,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Target {
+    Stderr,
+    /// The systemd journal, with fields like DEVICE_UUID and NVML_CODE (--log-format is ignored)
+    #[cfg(target_os = "linux")]
+    Journald,
+    /// The Windows Event Log, as the Application log's prometheus-nvml-exporter source
+    #[cfg(windows)]
+    Eventlog,
+}
+
+pub fn init(level: Level, format: Format, target: Target) -> Result<()> {
+    match target {
+        Target::Stderr => (),
+        #[cfg(target_os = "linux")]
+        Target::Journald => {
+            use tracing_subscriber::layer::SubscriberExt;
+            use tracing_subscriber::util::SubscriberInitExt;
+            use tracing_subscriber::Layer;
+            let journald = crate::journald::Layer::connect()?;
+            let filter = tracing_subscriber::filter::LevelFilter::from_level(level);
+            return Ok(tracing_subscriber::registry()
+                .with(journald.with_filter(filter))
+                .try_init()?);
+        }
+        #[cfg(windows)]
+        Target::Eventlog => {
+            use tracing_subscriber::layer::SubscriberExt;
+            use tracing_subscriber::util::SubscriberInitExt;
+            use tracing_subscriber::Layer;
+            let eventlog = crate::eventlog::Layer::register()?;
+            let filter = tracing_subscriber::filter::LevelFilter::from_level(level);
+            return Ok(tracing_subscriber::registry()
+                .with(eventlog.with_filter(filter))
+                .try_init()?);
+        }
+    }
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr);
+    match format {
+        Format::Text => builder.try_init(),
+        Format::Json => builder.json().try_init(),
+    }
+}
@@ -1,214 +1,1521 @@
-use nvml_wrapper::{Device, Nvml};
-use prometheus::{
-    register_gauge_vec, register_int_counter_vec, register_int_gauge_vec, GaugeVec, IntCounterVec,
-    IntGaugeVec,
-};
-use std::cmp;
-use std::net::SocketAddr;
-use std::path::{Path, PathBuf};
-use std::time::{Duration, Instant};
+use nvml_exporter::collectors::histograms;
+use nvml_exporter::{record, Backend, MetricDevice, NvmlCollector, Result, Selection, GPU_LABELS};
+use nvml_wrapper::bitmasks::InitFlags;
+use nvml_wrapper::error::NvmlError;
+use prometheus::{Encoder, TextEncoder};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, info, warn};
+
+mod aliases;
+mod auth;
+mod cache;
+mod cloud;
+mod consul;
+#[cfg(unix)]
+mod daemon;
+#[cfg(windows)]
+mod eventlog;
+mod grafana;
+mod groups;
+#[cfg(feature = "sqlite")]
+mod history;
+mod http;
+#[cfg(target_os = "linux")]
+mod journald;
+mod limits;
+mod logging;
+#[cfg(unix)]
+mod mdns;
+mod openmetrics;
+#[cfg(unix)]
+mod privileges;
+mod push;
+mod regex;
+mod relabel;
+mod rules;
+#[cfg(target_os = "linux")]
+mod sandbox;
+#[cfg(windows)]
+mod service;
+mod snapshot;
+mod systemd;
+#[cfg(unix)]
+mod tls;
+mod watch;
+mod webconfig;
 
 #[derive(clap::Parser)]
 #[clap(author, version, about)]
 struct Opts {
-    /// Listen address/port
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Listen address/port, or unix:/path/to/socket. Ignored when socket activated.
     #[structopt(short = 'l', long = "listen", default_value = "[::]:9144", env)]
-    listen: SocketAddr,
+    listen: http::Listen,
     /// Specify where to load nvml library from
     // runtime loading, so we can't use the normal linker magic
-    #[structopt(long, env)]
+    #[structopt(long, env, global = true, visible_alias = "nvml-lib-path")]
     nvml_library_path: Option<PathBuf>,
+    /// Initialize NVML without attaching to the devices (NVML_INIT_FLAG_NO_ATTACH)
+    #[structopt(long, env, global = true)]
+    nvml_no_attach: bool,
+    /// Initialize NVML even if no devices are found, e.g. to serve only the process metrics
+    #[structopt(long, env, global = true)]
+    nvml_no_gpus: bool,
+    /// Lower case PCI bus ids in labels, like the kernel has them
+    #[structopt(long, env)]
+    lowercase_pci: bool,
+    /// Leave "NVIDIA " out of the beginning of device names in labels
+    #[structopt(long, env)]
+    trim_vendor: bool,
+    /// Turn on NVML accounting mode on startup, for per process statistics that outlive the
+    /// processes (needs root). The number of processes the driver keeps them for is logged, NVML
+    /// has no way to set it.
+    #[structopt(long, env)]
+    enable_accounting: bool,
+    /// Keep running if no GPU driver can be loaded, e.g. on the GPU-less hosts of a fleet,
+    /// exporting nvml_up 0 and looking for one again every 30s
+    #[structopt(long, env, global = true)]
+    allow_no_gpus: bool,
+    /// Don't use NVML, make up this many devices with synthetic values instead
+    #[structopt(long, env, global = true, conflicts_with = "nvml_library_path")]
+    mock_gpus: Option<u32>,
+    /// PEM certificate chain to serve metrics over HTTPS with
+    #[structopt(long, env, requires = "tls_key", conflicts_with = "web_config_file")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[structopt(long, env, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Require HTTP basic auth, given as user:bcrypt-hash (may be repeated)
+    #[structopt(long, env, value_delimiter = ',')]
+    basic_auth: Vec<String>,
+    /// Stop checking the basic auth credentials of a client address for a minute after this many
+    /// failed, as checking the bcrypt hashes holds up scrapes
+    #[structopt(long, env, default_value = "10")]
+    max_auth_failures: usize,
+    /// exporter-toolkit style web configuration (TLS, basic auth)
+    #[structopt(long = "web.config.file", env = "WEB_CONFIG_FILE")]
+    web_config_file: Option<PathBuf>,
+    /// Switch to this user (name or uid) once the listener is bound
+    #[cfg(unix)]
+    #[structopt(long)]
+    user: Option<String>,
+    /// Switch to this group (name or gid) once the listener is bound
+    #[cfg(unix)]
+    #[structopt(long)]
+    group: Option<String>,
+    /// Run in the background, once started up successfully
+    #[cfg(unix)]
+    #[structopt(long)]
+    daemon: bool,
+    /// Write the process id to this file, removed on shutdown
+    #[cfg(unix)]
+    #[structopt(long, env)]
+    pid_file: Option<PathBuf>,
+    /// Once set up, deny the process system calls and file system access it doesn't need, with
+    /// seccomp and Landlock
+    #[cfg(target_os = "linux")]
+    #[structopt(long, env)]
+    sandbox: bool,
+    /// Install or uninstall as a Windows service, or run as one
+    #[cfg(windows)]
+    #[structopt(long, value_enum)]
+    service: Option<service::Action>,
+    /// Log verbosity (error, warn, info, debug, trace)
+    #[structopt(long, env, default_value = "info", global = true)]
+    log_level: tracing::Level,
+    /// Log output format
+    #[structopt(long, env, value_enum, default_value = "text", global = true)]
+    log_format: logging::Format,
+    /// Where to log to
+    #[structopt(long, env, value_enum, default_value = "stderr", global = true)]
+    log_target: logging::Target,
+    /// Safety margin subtracted from the scrape timeout announced by Prometheus
+    #[structopt(long, env, default_value = "500ms")]
+    scrape_timeout_offset: humantime::Duration,
+    /// Longest a collection may take, leaving out the devices and collectors that didn't make it.
+    /// Also applies if Prometheus announces a longer scrape timeout, or none.
+    #[structopt(long, env)]
+    scrape_deadline: Option<humantime::Duration>,
+    /// Reject scrapes with 503 while this many are waiting for the one being collected
+    #[structopt(long, env, default_value = "4")]
+    max_concurrent_scrapes: usize,
+    /// Reject more than this many requests per minute from one client address with 429
+    #[structopt(long, env)]
+    client_rate_limit: Option<u32>,
+    /// Don't serve metrics over HTTP, only push them
+    #[structopt(long, env)]
+    no_listen: bool,
+    /// Push metrics to this Pushgateway group URL, e.g. http://pushgateway:9091/metrics/job/nvml
+    #[structopt(long, env)]
+    push_url: Option<String>,
+    /// Push metrics via the Prometheus remote_write protocol to this URL
+    #[structopt(long, env)]
+    remote_write_url: Option<String>,
+    /// Basic auth user for --remote-write-url
+    #[structopt(long, env, conflicts_with = "remote_write_bearer_token_file")]
+    remote_write_username: Option<String>,
+    /// File containing the basic auth password for --remote-write-url
+    #[structopt(long, env, requires = "remote_write_username")]
+    remote_write_password_file: Option<PathBuf>,
+    /// File containing a bearer token for --remote-write-url
+    #[structopt(long, env)]
+    remote_write_bearer_token_file: Option<PathBuf>,
+    /// Extra name=value header for --remote-write-url, e.g. X-Scope-OrgID=gpus (may be repeated)
+    #[structopt(long)]
+    remote_write_header: Vec<String>,
+    /// Push metrics to this OpenTelemetry collector via OTLP/HTTP, e.g. http://collector:4318
+    #[structopt(long, env)]
+    otlp_endpoint: Option<String>,
+    /// Extra name=value header for --otlp-endpoint (may be repeated)
+    #[structopt(long)]
+    otlp_header: Vec<String>,
+    /// Push metrics to this VictoriaMetrics' import API, e.g. http://victoriametrics:8428
+    #[structopt(long, env)]
+    victoriametrics_url: Option<String>,
+    /// Tenant for --victoriametrics-url, as account[:project]
+    #[structopt(long, env)]
+    victoriametrics_tenant: Option<String>,
+    /// Extra name=value header for --victoriametrics-url (may be repeated)
+    #[structopt(long)]
+    victoriametrics_header: Vec<String>,
+    /// Publish metrics to Kafka, via these brokers (host:port,…)
+    #[structopt(long, env, value_delimiter = ',', requires = "kafka_topic")]
+    kafka_brokers: Vec<String>,
+    /// Topic for --kafka-brokers, written to partition 0
+    #[structopt(long, env)]
+    kafka_topic: Option<String>,
+    /// Message format for --kafka-brokers
+    #[structopt(long, env, value_enum, default_value = "json")]
+    kafka_format: push::KafkaFormat,
+    /// Publish metrics to this MQTT broker (host:port), a message per sample
+    #[structopt(long, env)]
+    mqtt_broker: Option<String>,
+    /// Topics for --mqtt-broker are <prefix>/<uuid>/<metric>, followed by other label values
+    #[structopt(long, env, default_value = "nvml")]
+    mqtt_topic_prefix: String,
+    /// Quality of service for --mqtt-broker
+    #[structopt(long, env, default_value = "0", value_parser = clap::value_parser!(u8).range(0..=2))]
+    mqtt_qos: u8,
+    /// Announce temperature, power, and fan sensors to Home Assistant via --mqtt-broker, with
+    /// discovery messages under this prefix, usually "homeassistant"
+    #[structopt(long, env)]
+    mqtt_homeassistant_discovery: Option<String>,
+    /// User for --mqtt-broker
+    #[structopt(long, env)]
+    mqtt_username: Option<String>,
+    /// File containing the password for --mqtt-username
+    #[structopt(long, env, requires = "mqtt_username")]
+    mqtt_password_file: Option<PathBuf>,
+    /// Send metrics to this Zabbix server's or proxy's trapper (host[:port]), as zabbix_sender
+    #[structopt(long, env)]
+    zabbix_server: Option<String>,
+    /// Zabbix host the values are for, with {hostname}, {metric}, or {<label>} replaced
+    #[structopt(long, env, default_value = "{hostname}")]
+    zabbix_host: String,
+    /// Zabbix item key, like --zabbix-host, with {params} for the label values beyond the GPU's
+    /// name and PCI address
+    #[structopt(long, env, default_value = "{metric}[{params}]")]
+    zabbix_key: String,
+    /// Send metrics to this StatsD server (host:port, UDP)
+    #[structopt(long, env)]
+    statsd_addr: Option<String>,
+    /// Prefix for StatsD metric names
+    #[structopt(long, env, default_value = "")]
+    statsd_prefix: String,
+    /// How to convey labels to StatsD
+    #[structopt(long, env, value_enum, default_value = "dogstatsd")]
+    statsd_tags: push::StatsDTags,
+    /// Send metrics to this Graphite/Carbon server (host:port, plaintext protocol)
+    #[structopt(long, env)]
+    graphite_addr: Option<String>,
+    /// Prefix for Graphite metric paths, e.g. "gpus."
+    #[structopt(long, env, default_value = "")]
+    graphite_prefix: String,
+    /// How to convey labels to Graphite
+    #[structopt(long, env, value_enum, default_value = "tagged")]
+    graphite_tags: push::GraphiteTags,
+    /// How often to send to Graphite, if different from --push-interval
+    #[structopt(long, env)]
+    graphite_interval: Option<humantime::Duration>,
+    /// Periodically write nvml.prom into this directory for node_exporter's textfile collector
+    /// (combine with --no-listen to not open a port)
+    #[structopt(long, env)]
+    textfile_dir: Option<PathBuf>,
+    /// Append the metrics to stdout or --log-metrics-file this often
+    #[structopt(long, env)]
+    log_metrics_interval: Option<humantime::Duration>,
+    /// Format for --log-metrics-interval
+    #[structopt(long, env, value_enum, default_value = "text")]
+    log_metrics_format: push::MetricsLogFormat,
+    /// Only log this metric with --log-metrics-interval (may be repeated)
+    #[structopt(long)]
+    log_metric: Vec<String>,
+    /// File for --log-metrics-interval, rotated at --log-metrics-max-bytes, instead of stdout
+    #[structopt(long, env)]
+    log_metrics_file: Option<PathBuf>,
+    /// Size at which --log-metrics-file is rotated
+    #[structopt(long, env, default_value = "104857600")]
+    log_metrics_max_bytes: u64,
+    /// Record the metrics every --push-interval to this SQLite database, for the query subcommand
+    #[cfg(feature = "sqlite")]
+    #[structopt(long, env)]
+    history_file: Option<PathBuf>,
+    /// How long to keep samples in --history-file
+    #[cfg(feature = "sqlite")]
+    #[structopt(long, env, default_value = "7d")]
+    history_retention: humantime::Duration,
+    /// Alert when a series crosses this threshold, e.g. nvml_temp>85 (may be repeated)
+    #[structopt(long)]
+    alert: Vec<push::Threshold>,
+    /// POST alerts to this URL as JSON, when they fire and when they resolve
+    #[structopt(long, env)]
+    alert_webhook: Option<String>,
+    /// Run this shell command for alerts, with NVML_ALERT, NVML_ALERT_STATUS, NVML_ALERT_VALUE,
+    /// and NVML_ALERT_LABEL_<NAME> set
+    #[structopt(long, env)]
+    alert_command: Option<String>,
+    /// How often to push metrics to push targets
+    #[structopt(long, env, default_value = "15s")]
+    push_interval: humantime::Duration,
+    /// Give up on a push to an HTTP push target after this long, not to hold up scrapes
+    #[structopt(long, env, default_value = "10s")]
+    push_timeout: humantime::Duration,
+    /// Attach the time of collection to each exported sample
+    #[structopt(long, env)]
+    metric_timestamps: bool,
+    /// Serve full /metrics scrapes from the last collection while it's younger than this,
+    /// unless ?fresh=1 is given
+    #[structopt(long, env)]
+    cache_ttl: Option<humantime::Duration>,
+    /// Look up the cloud instance at startup and export it as nvml_exporter_cloud_info
+    #[structopt(long, env, value_enum)]
+    cloud_metadata: Option<cloud::Provider>,
+    /// Register with the Consul agent at this URL, e.g. http://127.0.0.1:8500, while running
+    #[structopt(long, env)]
+    consul_url: Option<String>,
+    /// Service name to register with Consul
+    #[structopt(long, env, default_value = "nvml-exporter")]
+    consul_service: String,
+    /// File containing the ACL token for --consul-url
+    #[structopt(long, env)]
+    consul_token_file: Option<PathBuf>,
+    /// Serve /dashboard, charts of the GPUs' utilization, memory, temperature, and power
+    #[structopt(long, env)]
+    dashboard: bool,
+    /// Serve POST /api/v1/admin/power-limit?gpu=…&milliwatts=… and
+    /// /api/v1/admin/locked-clocks?gpu=…&min=…&max=… (MHz, neither to unlock), to change the
+    /// GPUs' settings, and /api/v1/admin/reset-counters?gpu=…&counters=ecc,nvlink, to clear
+    /// their error counters. Needs --admin-user, and root.
+    #[structopt(long, env)]
+    enable_admin_api: bool,
+    /// Basic auth user allowed to use the admin API, unlike the others (may be repeated)
+    #[structopt(long, env, value_delimiter = ',')]
+    admin_user: Vec<String>,
+    /// Serve /sd, listing the GPUs as /probe targets for Prometheus' HTTP service discovery
+    #[structopt(long, env)]
+    service_discovery: bool,
+    /// Address (host:port) the /sd targets are scraped at, instead of the one requested
+    #[structopt(long, env, requires = "service_discovery")]
+    sd_address: Option<String>,
+    /// Announce the exporter via mDNS as a _prometheus-http._tcp service
+    #[cfg(unix)]
+    #[structopt(long, env)]
+    mdns: bool,
+    /// Label all metrics with hostname="<NODE_NAME or the hostname>", for when pushing, where no
+    /// scraper adds the instance label
+    #[structopt(long, env)]
+    add_hostname_label: bool,
+    /// Label the GPUs' series with alias="…", from this YAML file mapping uuids or PCI addresses
+    /// to aliases
+    #[structopt(long, env, value_parser = aliases::Aliases::load)]
+    gpu_aliases: Option<aliases::Aliases>,
+    /// Label the GPUs' series with the groups they're in, from this YAML file mapping label names
+    /// to groups, e.g. pool: {inference: [0-3], training: [4-7]}
+    #[structopt(long, env, value_parser = groups::Groups::load)]
+    gpu_groups: Option<groups::Groups>,
+    /// Rewrite metrics by the rules in this YAML file, a list like Prometheus'
+    /// metric_relabel_configs, before serving or pushing them
+    #[structopt(long, env, value_parser = relabel::Rules::load)]
+    relabel_config: Option<relabel::Rules>,
+    /// Export at most this many processes per GPU, those using the most memory, and sum up the
+    /// rest as pid="other" (0 for no limit)
+    #[structopt(long, env, default_value = "64")]
+    process_limit: usize,
+    /// Read power, temperature, and utilization every --sample-interval, and export their
+    /// minimum, maximum, and average since the previous scrape as _min, _max, and _avg
+    #[structopt(long, env)]
+    aggregates: bool,
+    /// Export a histogram of every reading of these signals, taken every --sample-interval
+    #[structopt(long, env, value_enum, value_delimiter = ',')]
+    histogram: Vec<HistogramSignal>,
+    /// How often to take readings for --aggregates and --histogram, from 100ms to 5s
+    #[structopt(long, env, default_value = "1s", value_parser = sample_interval)]
+    sample_interval: Duration,
+    /// What a failed NVML query does: strict fails the scrape, or the exporter, tolerant leaves
+    /// out the metrics that couldn't be collected, counted in nvml_errors_total
+    #[structopt(long, env, value_enum, default_value = "strict")]
+    error_mode: ErrorMode,
 }
 
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
-
-static GPU_LABELS: [&str; 3] = ["uuid", "name", "pci"];
-lazy_static::lazy_static! {
-    static ref MEMORY_FREE: IntGaugeVec =
-        register_int_gauge_vec!("nvml_memory_free_bytes", "Free Memory", &GPU_LABELS).unwrap();
-    static ref MEMORY_USED: IntGaugeVec =
-        register_int_gauge_vec!("nvml_memory_used_bytes", "Used Memory", &GPU_LABELS).unwrap();
-    static ref MEMORY_TOTAL: IntGaugeVec =
-        register_int_gauge_vec!("nvml_memory_total_bytes", "Total Memory", &GPU_LABELS).unwrap();
-    static ref FAN_SPEED: GaugeVec = register_gauge_vec!(
-        "nvml_fan_speed",
-        "Fan speed (0-1)",
-        &[&GPU_LABELS[..], &["fan"][..]].concat()
-    )
-    .unwrap();
-    static ref TEMPERATURE: GaugeVec =
-        register_gauge_vec!("nvml_temp", "Temperature degC", &GPU_LABELS).unwrap();
-    static ref PERFORMANCE_STATE: IntGaugeVec = register_int_gauge_vec!(
-        "nvml_performance_state",
-        "Performance State (between 15 (low) and 0 (high))",
-        &GPU_LABELS
-    )
-    .unwrap();
-    static ref POWER_USAGE: IntGaugeVec = register_int_gauge_vec!(
-        "nvml_power_usage_current_mw",
-        "Current power usage (mW)",
-        &GPU_LABELS
-    )
-    .unwrap();
-    static ref POWER_MAX: IntGaugeVec = register_int_gauge_vec!(
-        "nvml_power_usage_max_mw",
-        "Enforced power limit (mW)",
-        &GPU_LABELS
-    )
-    .unwrap();
-    static ref ENERGY_USED: IntCounterVec = register_int_counter_vec!(
-        "nvml_power_used_total_mj",
-        "Energy used in total",
-        &GPU_LABELS
-    )
-    .unwrap();
-    static ref PCI_REPLAY: IntCounterVec =
-        register_int_counter_vec!("nvml_pci_replay", "Energy used in total", &GPU_LABELS).unwrap();
-}
-
-struct MetricDevice<'a> {
-    device: Device<'a>,
-    labels: [String; 3],
-    fan_count: u32,
-}
-
-impl MetricDevice<'_> {
-    fn new(device: Device) -> Result<MetricDevice<'_>> {
-        let mut i: u32 = 0;
-        Ok(MetricDevice {
-            fan_count: loop {
-                if i > 10_000 || device.fan_speed(i).is_err() {
-                    break i;
-                };
-                i += 1;
-            },
-            labels: [device.uuid()?, device.name()?, device.pci_info()?.bus_id],
-            device,
-        })
-    }
-    fn labels(&self) -> Vec<&str> {
-        self.labels.iter().map(|x| x.as_ref()).collect()
-    }
-    fn performance_state(&self) -> Result<i64> {
-        use nvml_wrapper::enum_wrappers::device::PerformanceState::*;
-        Ok(match self.device.performance_state()? {
-            Zero => 0,
-            One => 1,
-            Two => 2,
-            Three => 3,
-            Four => 4,
-            Five => 5,
-            Six => 6,
-            Seven => 7,
-            Eight => 8,
-            Nine => 9,
-            Ten => 10,
-            Eleven => 11,
-            Twelve => 12,
-            Thirteen => 13,
-            Fourteen => 14,
-            Fifteen => 15,
-            Unknown => -1,
-        })
-    }
-    fn update(&self) -> Result<()> {
-        let meminfo = self.device.memory_info()?;
-        MEMORY_FREE
-            .get_metric_with_label_values(&self.labels())?
-            .set(meminfo.free.try_into()?);
-        MEMORY_USED
-            .get_metric_with_label_values(&self.labels())?
-            .set(meminfo.used.try_into()?);
-        MEMORY_TOTAL
-            .get_metric_with_label_values(&self.labels())?
-            .set(meminfo.total.try_into()?);
-        for i in 0..self.fan_count {
-            FAN_SPEED
-                .get_metric_with_label_values(
-                    &[&self.labels()[..], &[format!("{}", i).as_ref()][..]].concat(),
-                )?
-                .set(self.device.fan_speed(i)? as f64 / 100.);
-        }
-        TEMPERATURE
-            .get_metric_with_label_values(&self.labels())?
-            .set(
-                self.device
-                    .temperature(nvml_wrapper::enum_wrappers::device::TemperatureSensor::Gpu)?
-                    as f64,
-            );
-        PERFORMANCE_STATE
-            .get_metric_with_label_values(&self.labels())?
-            .set(self.performance_state()?);
-        POWER_USAGE
-            .get_metric_with_label_values(&self.labels())?
-            .set(self.device.power_usage()? as i64);
-        POWER_MAX
-            .get_metric_with_label_values(&self.labels())?
-            .set(self.device.enforced_power_limit()? as i64);
-        let energy_prev = ENERGY_USED
-            .get_metric_with_label_values(&self.labels())?
-            .get();
-        let energy_current: u64 = self.device.total_energy_consumption()?.try_into()?;
-        ENERGY_USED
-            .get_metric_with_label_values(&self.labels())?
-            .inc_by(energy_current - energy_prev);
-        let replay_prev = PCI_REPLAY
-            .get_metric_with_label_values(&self.labels())?
-            .get();
-        let replay_current: u64 = self.device.pcie_replay_counter()?.try_into()?;
-        PCI_REPLAY
-            .get_metric_with_label_values(&self.labels())?
-            .inc_by(replay_current - replay_prev);
-        Ok(())
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum ErrorMode {
+    Strict,
+    Tolerant,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum HistogramSignal {
+    Power,
+    SmUtilization,
+}
+
+fn sample_interval(s: &str) -> std::result::Result<Duration, String> {
+    let interval = *s
+        .parse::<humantime::Duration>()
+        .map_err(|e| e.to_string())?;
+    match interval {
+        interval if interval < Duration::from_millis(100) => Err("at least 100ms".into()),
+        interval if interval > Duration::from_secs(5) => Err("at most 5s".into()),
+        interval => Ok(interval),
+    }
+}
+
+impl Opts {
+    fn collector(&self) -> Result<NvmlCollector> {
+        let options = nvml_exporter::collectors::Options {
+            process_limit: (self.process_limit > 0).then_some(self.process_limit),
+            aggregates: self.aggregates,
+            histograms: self
+                .histogram
+                .iter()
+                .map(|signal| match signal {
+                    HistogramSignal::Power => histograms::Signal::Power,
+                    HistogramSignal::SmUtilization => histograms::Signal::SmUtilization,
+                })
+                .collect(),
+        };
+        let collectors = nvml_exporter::collectors::with_options(&options);
+        let mut collector = NvmlCollector::with_collectors(backend(self)?, collectors);
+        collector.set_tolerant(self.error_mode == ErrorMode::Tolerant);
+        collector.set_normalization(nvml_exporter::labels::Normalization {
+            lowercase_pci: self.lowercase_pci,
+            trim_vendor: self.trim_vendor,
+        });
+        Ok(collector)
+    }
+
+    /// When a collection has to be done by, from the scrape timeout announced by Prometheus, if
+    /// any, and --scrape-deadline
+    fn deadline(&self, scrape_timeout: Option<Duration>) -> Option<Instant> {
+        let announced =
+            scrape_timeout.map(|timeout| timeout.saturating_sub(*self.scrape_timeout_offset));
+        let limit = self.scrape_deadline.map(Duration::from);
+        let timeout = announced.into_iter().chain(limit).min()?;
+        Some(Instant::now() + timeout)
+    }
+
+    /// In tolerant mode, log a failure rather than pass it on
+    fn tolerate<T>(&self, result: Result<T>) -> Result<Option<T>> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if self.error_mode == ErrorMode::Tolerant => {
+                warn!("Collection failed: {}", e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
     }
 }
 
+/// Without a subcommand, metrics are served over HTTP and/or pushed
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Collect once, print the metrics in the text exposition format, and exit
+    Print,
+    /// Collect once, print all values as JSON, per device and metric, and exit
+    Json,
+    /// Exit successfully only if the GPUs can be found and collected from, e.g. for a
+    /// container HEALTHCHECK
+    Check,
+    /// List the GPUs found, with the collectors each of them supports
+    ListDevices,
+    /// List the metrics this exporter can emit, and whether the local GPUs support them
+    ListMetrics,
+    /// Print Prometheus alerting and recording rules for this exporter's metrics
+    GenRules(rules::Thresholds),
+    /// Print a Grafana dashboard for this exporter's metrics, as named and labeled with the
+    /// given --add-hostname-label, --gpu-aliases, --gpu-groups, and --relabel-config
+    GenDashboard,
+    /// Print a shell completion script
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Write raw device readings to a file at regular intervals, e.g. for bug reports
+    Record {
+        output: PathBuf,
+        /// Time between readings
+        #[arg(long, default_value = "1s")]
+        interval: humantime::Duration,
+        /// Stop after this long, instead of running until interrupted
+        #[arg(long)]
+        duration: Option<humantime::Duration>,
+    },
+    /// Serve metrics from a file written by record, as configured by the options before it
+    Replay { file: PathBuf },
+    /// Show the main readings of each GPU in a table, updated until interrupted
+    Watch {
+        /// Time between updates
+        #[arg(long, default_value = "1s")]
+        interval: humantime::Duration,
+    },
+    /// Sample the metrics for a while, and write them as CSV, e.g. for benchmark reports
+    Csv {
+        /// Time between samples
+        #[arg(long, default_value = "1s")]
+        interval: humantime::Duration,
+        /// How long to sample for
+        #[arg(long)]
+        duration: humantime::Duration,
+        /// Write to this file, replacing it, instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Only sample this metric (may be repeated)
+        #[arg(long)]
+        metric: Vec<String>,
+    },
+    /// Print samples from --history-file as CSV
+    #[cfg(feature = "sqlite")]
+    Query {
+        /// How far back
+        #[arg(long, default_value = "1h")]
+        since: humantime::Duration,
+        /// Only this metric
+        #[arg(long)]
+        metric: Option<String>,
+        /// Only the GPU with this uuid
+        #[arg(long)]
+        gpu: Option<String>,
+    },
+    /// Print a man page in roff format
+    #[command(hide = true)]
+    GenMan,
+}
+
 fn main() -> Result<()> {
     let opts: Opts = clap::Parser::parse();
+    logging::init(opts.log_level, opts.log_format, opts.log_target)?;
 
-    let exporter = prometheus_exporter::start(opts.listen)?;
+    #[cfg(windows)]
+    match opts.service {
+        Some(service::Action::Install) => return service::install(),
+        Some(service::Action::Uninstall) => return service::uninstall(),
+        Some(service::Action::Run) => return service::run(move || serve(&opts)),
+        None => (),
+    }
+    match opts.command {
+        None => serve(&opts),
+        Some(Command::Print) => print(&opts),
+        Some(Command::Json) => json(&opts),
+        Some(Command::Check) => check(&opts),
+        Some(Command::ListDevices) => list_devices(&opts),
+        Some(Command::ListMetrics) => list_metrics(&opts),
+        Some(Command::GenRules(thresholds)) => {
+            print!("{}", rules::generate(&thresholds)?);
+            Ok(())
+        }
+        Some(Command::GenDashboard) => {
+            let schema = grafana::Schema {
+                hostname: opts.add_hostname_label,
+                aliases: opts.gpu_aliases.is_some(),
+                groups: opts.gpu_groups.as_ref(),
+                rules: opts.relabel_config.as_ref(),
+            };
+            println!("{}", grafana::generate(&schema)?);
+            Ok(())
+        }
+        Some(Command::Completions { shell }) => {
+            let mut cmd = <Opts as clap::CommandFactory>::command();
+            let name = cmd.get_name().to_owned();
+            clap_complete::generate(shell, &mut cmd, name, &mut std::io::stdout());
+            Ok(())
+        }
+        Some(Command::Record {
+            ref output,
+            interval,
+            duration,
+        }) => {
+            let backend = backend(&opts)?;
+            let devices = backend.discover()?;
+            let gpus = devices.iter().map(MetricDevice::gpu).collect::<Vec<_>>();
+            record::record(&gpus, output, *interval, duration.map(Into::into))
+        }
+        Some(Command::Replay { .. }) => serve(&opts),
+        Some(Command::Watch { interval }) => watch(&opts, *interval),
+        #[cfg(feature = "sqlite")]
+        Some(Command::Query {
+            since,
+            ref metric,
+            ref gpu,
+        }) => {
+            let file = opts
+                .history_file
+                .as_deref()
+                .ok_or("query needs --history-file")?;
+            history::query(file, *since, metric.as_deref(), gpu.as_deref())
+        }
+        Some(Command::Csv {
+            interval,
+            duration,
+            ref output,
+            ref metric,
+        }) => csv(&opts, *interval, *duration, output.clone(), metric.clone()),
+        Some(Command::GenMan) => {
+            let cmd = <Opts as clap::CommandFactory>::command();
+            clap_mangen::Man::new(cmd).render(&mut std::io::stdout())?;
+            Ok(())
+        }
+    }
+}
 
-    let mut lastdevices = 0;
-    let mut refresh_interval = Duration::from_secs(30);
+fn json(opts: &Opts) -> Result<()> {
+    let collector = opts.collector()?;
+    let snapshot = snapshot::devices(&gather(opts, &collector, None)?);
+    serde_json::to_writer_pretty(std::io::stdout().lock(), &snapshot)?;
+    println!();
+    Ok(())
+}
 
+fn watch(opts: &Opts, interval: Duration) -> Result<()> {
+    let collector = opts.collector()?;
     loop {
-        let mut nvml = Nvml::builder();
-        match &opts.nvml_library_path {
-            Some(path) => {
-                nvml.lib_path(path.as_os_str());
+        let families = gather(opts, &collector, None)?;
+        let mut rows = vec![watch::HEADER.map(String::from)];
+        rows.extend(watch::rows(&families));
+        print!("{}", watch::CLEAR);
+        print_table(rows);
+        std::thread::sleep(interval);
+    }
+}
+
+fn csv(
+    opts: &Opts,
+    interval: Duration,
+    duration: Duration,
+    output: Option<PathBuf>,
+    metrics: Vec<String>,
+) -> Result<()> {
+    use push::Sink;
+    let collector = opts.collector()?;
+    if let Some(output) = &output {
+        if output.exists() {
+            std::fs::remove_file(output)?;
+        }
+    }
+    let file = output.map(|output| (output, u64::MAX));
+    let mut csv = push::MetricsLog::new(push::MetricsLogFormat::Csv, metrics, file);
+    let start = Instant::now();
+    let mut next = start;
+    while next - start <= duration {
+        csv.push(&gather(opts, &collector, None)?)?;
+        next += interval;
+        std::thread::sleep(next.saturating_duration_since(Instant::now()));
+    }
+    Ok(())
+}
+
+fn check(opts: &Opts) -> Result<()> {
+    let collector = opts.collector()?;
+    let devices = collector.backend().discover()?.len();
+    if devices == 0 {
+        return Err("No GPUs found".into());
+    }
+    collector.gather(&Selection::default(), None)?;
+    println!("OK, collected from {} GPUs", devices);
+    Ok(())
+}
+
+fn list_devices(opts: &Opts) -> Result<()> {
+    let backend = backend(opts)?;
+    let devices = backend.discover()?;
+    let collectors = nvml_exporter::collectors::all();
+    let mut rows = vec![["INDEX", "UUID", "NAME", "PCI", "MIG", "COLLECTORS"].map(String::from)];
+    for dev in &devices {
+        let [uuid, name, pci] = [0, 1, 2].map(|i| dev.labels()[i].to_owned());
+        let mig = match dev.gpu().mig_mode() {
+            Ok(true) => "enabled",
+            Ok(false) => "disabled",
+            Err(NvmlError::NotSupported) => "-",
+            Err(_) => "unknown",
+        };
+        let collectors = collectors
+            .iter()
+            .filter(|collector| collector.supported(dev))
+            .map(|collector| collector.name())
+            .collect::<Vec<_>>()
+            .join(",");
+        let index = dev.gpu().index().map_or("?".into(), |i| i.to_string());
+        rows.push([index, uuid, name, pci, mig.into(), collectors]);
+    }
+    print_table(rows);
+    Ok(())
+}
+
+/// Print rows as columns aligned to their widest cell
+fn print_table<const N: usize>(rows: Vec<[String; N]>) {
+    let widths = (0..N)
+        .map(|col| rows.iter().map(|row| row[col].len()).max().unwrap_or(0))
+        .collect::<Vec<_>>();
+    for row in rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
+
+fn list_metrics(opts: &Opts) -> Result<()> {
+    // Still useful as a reference on machines without (working) GPUs
+    let backend = backend(opts).ok();
+    let devices = match &backend {
+        Some(backend) => backend.discover().ok(),
+        None => None,
+    };
+    let collector = NvmlCollector::new(Backend::Mock(0));
+    let mut metrics = vec![];
+    for c in collector.collectors() {
+        let supported = match &devices {
+            None => "unknown",
+            Some(devices) => match devices.iter().filter(|dev| c.supported(dev)).count() {
+                0 => "no",
+                n if n == devices.len() => "yes",
+                _ => "some",
+            },
+        };
+        for (kind, metric) in c.metrics() {
+            metrics.push((metric, kind, c.name(), supported));
+        }
+    }
+    metrics.push((collector.errors(), "counter", "exporter", "yes"));
+    let mut rows =
+        vec![["NAME", "TYPE", "LABELS", "COLLECTOR", "SUPPORTED", "HELP"].map(String::from)];
+    for (metric, kind, collector, supported) in metrics {
+        for desc in metric.desc() {
+            rows.push([
+                desc.fq_name.clone(),
+                kind.into(),
+                desc.variable_labels.join(","),
+                collector.into(),
+                supported.into(),
+                desc.help.clone(),
+            ]);
+        }
+    }
+    print_table(rows);
+    Ok(())
+}
+
+fn print(opts: &Opts) -> Result<()> {
+    if let Some(provider) = opts.cloud_metadata {
+        cloud::register(provider);
+    }
+    let collector = opts.collector()?;
+    TextEncoder::new().encode(
+        &gather(opts, &collector, None)?,
+        &mut std::io::stdout().lock(),
+    )?;
+    Ok(())
+}
+
+/// The metrics of all devices, and the exporter's own from the default registry
+fn gather(
+    opts: &Opts,
+    collector: &NvmlCollector,
+    deadline: Option<Instant>,
+) -> Result<Vec<prometheus::proto::MetricFamily>> {
+    let families = gather_selected(opts, collector, &Selection::default(), deadline)?;
+    Ok(families.unwrap_or_default())
+}
+
+/// `None` if a selected device doesn't exist
+fn gather_selected(
+    opts: &Opts,
+    collector: &NvmlCollector,
+    selection: &Selection,
+    deadline: Option<Instant>,
+) -> Result<Option<Vec<prometheus::proto::MetricFamily>>> {
+    let Some(nvml) = collector.gather(selection, deadline)? else {
+        return Ok(None);
+    };
+    let mut families = prometheus::gather();
+    families.extend(nvml);
+    families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+    relabel(opts, collector, &mut families);
+    Ok(Some(families))
+}
+
+/// Change a GPU's setting, or reset its counters, as requested from the admin API
+fn admin(
+    collector: &NvmlCollector,
+    request: tiny_http::Request,
+    route: http::Route,
+    counter_resets: &prometheus::IntCounterVec,
+) -> Result<()> {
+    if *request.method() != tiny_http::Method::Post {
+        return http::error(request, 405, "Changing settings needs POST");
+    }
+    let Some(gpu) = http::query_param(&request, "gpu") else {
+        return http::error(request, 400, "Missing gpu parameter");
+    };
+    let number = |name| http::query_param(&request, name).map(|value| value.parse::<u32>());
+    let (setting, changed) = match route {
+        http::Route::PowerLimit => {
+            let Some(Ok(milliwatts)) = number("milliwatts") else {
+                return http::error(request, 400, "Missing or invalid milliwatts parameter");
+            };
+            info!(gpu, milliwatts, remote = ?request.remote_addr(), "Setting the power limit");
+            let changed = collector.backend().set_power_limit(&gpu, milliwatts);
+            ("power_limit_mw", changed)
+        }
+        http::Route::ResetCounters => {
+            let counters = http::query_param(&request, "counters");
+            let counters = counters.as_deref().unwrap_or("ecc,nvlink").split(',');
+            let (mut ecc, mut nvlink) = (false, false);
+            for counter in counters {
+                match counter {
+                    "ecc" => ecc = true,
+                    "nvlink" => nvlink = true,
+                    _ => return http::error(request, 400, &format!("Unknown counter {counter}")),
+                }
             }
-            None => {
-                let paths = [
-                    Path::new("/usr/lib/libnvidia-ml.so"),
-                    Path::new("/run/opengl-driver/lib/libnvidia-ml.so"),
-                ];
-                for path in paths {
-                    if path.exists() {
-                        nvml.lib_path(path.as_os_str());
-                        break;
+            info!(gpu, ecc, nvlink, remote = ?request.remote_addr(), "Resetting counters");
+            return match collector.backend().reset_counters(&gpu, ecc, nvlink) {
+                Ok(Some((uuid, reset))) => {
+                    for counter in &reset {
+                        counter_resets.with_label_values(&[&uuid, counter]).inc();
                     }
+                    http::reset(request, &uuid, &reset)
+                }
+                Ok(None) => http::error(request, 404, "No such gpu"),
+                Err(e) => {
+                    warn!(gpu, "Failed to reset counters: {}", e);
+                    http::error(request, 500, &format!("Failed to reset counters: {e}"))
                 }
+            };
+        }
+        http::Route::LockedClocks => {
+            let clocks = match (number("min"), number("max")) {
+                (Some(Ok(min)), Some(Ok(max))) if min <= max => Some((min, max)),
+                (None, None) => None,
+                _ => return http::error(request, 400, "Invalid min and max parameters"),
+            };
+            info!(gpu, ?clocks, remote = ?request.remote_addr(), "Locking the graphics clock");
+            let changed = collector.backend().lock_clocks(&gpu, clocks);
+            ("graphics_clock_mhz", changed)
+        }
+        http::Route::Metrics
+        | http::Route::Probe
+        | http::Route::ServiceDiscovery
+        | http::Route::Devices
+        | http::Route::Dashboard
+        | http::Route::Other => return http::error(request, 404, "Not an admin API"),
+    };
+    match changed {
+        Ok(Some(changed)) => http::changed(request, &gpu, setting, changed),
+        Ok(None) => http::error(request, 404, "No such gpu"),
+        Err(e) => {
+            warn!(gpu, "Failed to change the {}: {}", setting, e);
+            http::error(
+                request,
+                500,
+                &format!("Failed to change the {setting}: {e}"),
+            )
+        }
+    }
+}
+
+/// Add the hostname, alias, and group labels, then apply the relabeling rules
+fn relabel(
+    opts: &Opts,
+    collector: &NvmlCollector,
+    families: &mut Vec<prometheus::proto::MetricFamily>,
+) {
+    static HOSTNAME: OnceLock<String> = OnceLock::new();
+    if opts.add_hostname_label {
+        let hostname = HOSTNAME.get_or_init(hostname);
+        nvml_exporter::add_label(families, "hostname", hostname);
+    }
+    if let Some(aliases) = &opts.gpu_aliases {
+        aliases.label(families);
+    }
+    if let Some(groups) = &opts.gpu_groups {
+        match collector.devices() {
+            Ok(devices) => groups.label(families, &devices),
+            Err(e) => warn!("Failed to list the devices for their groups: {}", e),
+        }
+    }
+    if let Some(rules) = &opts.relabel_config {
+        rules.relabel(families);
+    }
+}
+
+/// NODE_NAME, as Kubernetes deployments commonly set from spec.nodeName, or the system's
+fn hostname() -> String {
+    if let Ok(name) = std::env::var("NODE_NAME") {
+        return name;
+    }
+    #[cfg(unix)]
+    {
+        let mut name = [0u8; 256];
+        if unsafe { libc::gethostname(name.as_mut_ptr() as _, name.len()) } == 0 {
+            let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            return String::from_utf8_lossy(&name[..len]).into_owned();
+        }
+    }
+    #[cfg(windows)]
+    if let Ok(name) = std::env::var("COMPUTERNAME") {
+        return name;
+    }
+    warn!("Failed to get the hostname");
+    String::new()
+}
+
+fn backend(opts: &Opts) -> Result<Backend> {
+    Ok(match (&opts.command, opts.mock_gpus) {
+        (Some(Command::Replay { file }), _) => Backend::Replay(record::Recording::load(file)?),
+        (_, Some(count)) => Backend::Mock(count),
+        (_, None) => {
+            let mut flags = InitFlags::empty();
+            flags.set(InitFlags::NO_ATTACH, opts.nvml_no_attach);
+            flags.set(InitFlags::NO_GPUS, opts.nvml_no_gpus);
+            let backend = match Backend::nvml(opts.nvml_library_path.as_deref(), flags) {
+                Err(e) if matches!(e.downcast_ref(), Some(NvmlError::LibloadingError(_))) => {
+                    fallback_backend().ok_or(e)
+                }
+                backend => backend,
+            };
+            match backend {
+                Err(e) if opts.allow_no_gpus => {
+                    warn!("No GPUs to export: {}", e);
+                    Backend::Absent
+                }
+                backend => backend?,
             }
+        }
+    })
+}
+
+/// For when NVML can't be loaded: a Jetson GPU, AMD or Intel GPUs, or nvidia-smi
+fn fallback_backend() -> Option<Backend> {
+    #[cfg(feature = "tegra")]
+    if let Ok(backend) = Backend::tegra() {
+        info!("Found a Tegra GPU, reading it from sysfs");
+        return Some(backend);
+    }
+    #[cfg(feature = "rocm")]
+    if let Ok(backend) = Backend::rocm(None) {
+        return Some(backend);
+    }
+    #[cfg(feature = "intel")]
+    if let Ok(backend) = Backend::intel() {
+        return Some(backend);
+    }
+    let backend = Backend::smi().ok()?;
+    warn!("Falling back to nvidia-smi, only the core metrics will be available");
+    Some(backend)
+}
+
+/// How often to check for changes of the device list
+const DEVICE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+fn serve(opts: &Opts) -> Result<()> {
+    // Before the first thread is started
+    #[cfg(unix)]
+    let mut daemon = daemon::start(opts.daemon, opts.pid_file.as_deref())?;
+    if let Some(provider) = opts.cloud_metadata {
+        cloud::register(provider);
+    }
+    let start_time = prometheus::Gauge::new(
+        "nvml_exporter_start_time_seconds",
+        "When the exporter started, in seconds since the epoch",
+    )?;
+    start_time.set(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs_f64(),
+    );
+    prometheus::register(Box::new(start_time))?;
+    let reinitializations = prometheus::IntCounter::new(
+        "nvml_exporter_reinitializations_total",
+        "Times the driver connection was reinitialized after losing it",
+    )?;
+    prometheus::register(Box::new(reinitializations.clone()))?;
+    let counter_resets = prometheus::IntCounterVec::new(
+        prometheus::Opts::new(
+            "nvml_counter_resets_total",
+            "Times the GPU's error counters were reset through the admin API",
+        ),
+        &[GPU_LABELS[0], "counter"],
+    )?;
+    prometheus::register(Box::new(counter_resets.clone()))?;
+    let web_config = match &opts.web_config_file {
+        Some(path) => webconfig::WebConfig::load(path)?,
+        None => Default::default(),
+    };
+    let mut users = auth::BasicAuth::parse_users(&opts.basic_auth)?;
+    http::set_headers(web_config.headers()?);
+    users.extend(web_config.basic_auth_users);
+    let auth = auth::BasicAuth::new(users)
+        .with_admins(&opts.admin_user)?
+        .with_failure_limit(opts.max_auth_failures);
+    if opts.enable_admin_api && !auth.admins() {
+        return Err("--enable-admin-api needs --admin-user, with basic auth from --basic-auth or --web.config.file".into());
+    }
+    let (tls, rustls) = match web_config.tls_server_config {
+        Some(tls) => (Some((tls.cert_file, tls.key_file)), tls.rustls),
+        None => (opts.tls_cert.clone().zip(opts.tls_key.clone()), None),
+    };
+    let https = tls.is_some();
+    let ssl = match (tls, &rustls) {
+        (Some((cert, key)), None) => Some(tiny_http::SslConfig {
+            certificate: std::fs::read(cert)?,
+            private_key: std::fs::read(key)?,
+        }),
+        _ => None,
+    };
+    let mut cache = opts.cache_ttl.map(|ttl| cache::Cache::new(*ttl));
+    #[cfg(unix)]
+    let (listener, activated) = match (opts.no_listen, systemd::listener()?) {
+        (true, _) => (None, false),
+        (false, Some(listener)) => (Some(listener), true),
+        (false, None) => (Some(opts.listen.listener()?), false),
+    };
+    #[cfg(not(unix))]
+    let (listener, activated) = match opts.no_listen {
+        true => (None, false),
+        false => (Some(opts.listen.listener()?), false),
+    };
+    let addr = listener.as_ref().map(http::listen_addr).transpose()?;
+    // Only served once sandboxed, as serving starts threads
+    #[cfg(unix)]
+    let (listener, mut terminator) = match (listener, &rustls) {
+        (Some(listener), Some(_)) => (None, Some(tls::Terminator::new(listener)?)),
+        (listener, _) => (listener, None),
+    };
+    #[cfg(not(unix))]
+    if rustls.is_some() {
+        return Err("This web config's TLS settings need unix sockets".into());
+    }
+    if let Some(addr) = &addr {
+        info!(%addr, "Listening");
+    }
+    let ip = addr.clone().and_then(tiny_http::ListenAddr::to_ip);
+    let consul = match (&opts.consul_url, ip) {
+        (Some(url), Some(addr)) => consul::Registration::register(
+            url,
+            &opts.consul_service,
+            &hostname(),
+            addr,
+            opts.consul_token_file.as_deref(),
+        )
+        .inspect_err(|e| warn!("Failed to register with Consul: {}", e))
+        .ok(),
+        (Some(_), None) => {
+            warn!("Not registering with Consul without a TCP listener");
+            None
+        }
+        (None, _) => None,
+    };
+    let mut limits = limits::Limits::new(opts.max_concurrent_scrapes, opts.client_rate_limit);
+    // Before dropping privileges, for accounting mode
+    let mut collector = opts.collector()?;
+    if opts.enable_accounting {
+        collector.backend().enable_accounting();
+    }
+    #[cfg(unix)]
+    if let Some(terminator) = &terminator {
+        privileges::chown(
+            terminator.dir(),
+            opts.user.as_deref(),
+            opts.group.as_deref(),
+        )?;
+    }
+    #[cfg(unix)]
+    daemon.hand_over(opts.user.as_deref(), opts.group.as_deref())?;
+    #[cfg(unix)]
+    privileges::drop_to(opts.user.as_deref(), opts.group.as_deref())?;
+
+    let mut outputs = push::Outputs::default();
+    if let Some(url) = &opts.push_url {
+        outputs.add(
+            push::Pushgateway::new(url.clone(), *opts.push_timeout),
+            *opts.push_interval,
+        );
+    }
+    if let Some(url) = &opts.remote_write_url {
+        let headers = push::auth_headers(
+            opts.remote_write_username.as_deref(),
+            opts.remote_write_password_file.as_deref(),
+            opts.remote_write_bearer_token_file.as_deref(),
+            &opts.remote_write_header,
+        )?;
+        outputs.add(
+            push::RemoteWrite::new(url.clone(), headers, *opts.push_timeout),
+            *opts.push_interval,
+        );
+    }
+    if let Some(endpoint) = &opts.otlp_endpoint {
+        let headers = push::auth_headers(None, None, None, &opts.otlp_header)?;
+        outputs.add(
+            push::Otlp::new(endpoint, headers, *opts.push_timeout),
+            *opts.push_interval,
+        );
+    }
+    if let Some(url) = &opts.victoriametrics_url {
+        let headers = push::auth_headers(None, None, None, &opts.victoriametrics_header)?;
+        let tenant = opts.victoriametrics_tenant.as_deref();
+        let sink = push::VictoriaMetrics::new(url, tenant, headers, *opts.push_timeout);
+        outputs.add(sink, *opts.push_interval);
+    }
+    if let (false, Some(topic)) = (opts.kafka_brokers.is_empty(), &opts.kafka_topic) {
+        let kafka = push::Kafka::new(opts.kafka_brokers.clone(), topic.clone(), opts.kafka_format);
+        outputs.add(kafka, *opts.push_interval);
+    }
+    if let Some(broker) = &opts.mqtt_broker {
+        let credentials = match &opts.mqtt_username {
+            Some(username) => {
+                let password = match &opts.mqtt_password_file {
+                    Some(path) => std::fs::read_to_string(path)?.trim_end().to_owned(),
+                    None => String::new(),
+                };
+                Some((username.clone(), password))
+            }
+            None => None,
         };
-        let nvml = nvml.init()?;
-        let devices = (0..(nvml.device_count()?))
-            .map(|idx| nvml.device_by_index(idx))
-            .collect::<std::result::Result<Vec<_>, _>>()?
-            .into_iter()
-            .map(MetricDevice::new)
-            .collect::<std::result::Result<Vec<_>, _>>()?;
-        refresh_interval = match lastdevices == devices.len() {
-            false => Duration::from_secs(30),
-            true => cmp::min(refresh_interval * 2, Duration::from_secs(3600)),
+        let mut mqtt = push::Mqtt::new(
+            broker.clone(),
+            opts.mqtt_topic_prefix.clone(),
+            opts.mqtt_qos,
+            credentials,
+        );
+        if let Some(prefix) = &opts.mqtt_homeassistant_discovery {
+            mqtt = mqtt.with_discovery(prefix.clone());
+        }
+        outputs.add(mqtt, *opts.push_interval);
+    }
+    if let Some(server) = &opts.zabbix_server {
+        let zabbix = push::Zabbix::new(
+            server.clone(),
+            opts.zabbix_host.clone(),
+            opts.zabbix_key.clone(),
+            hostname(),
+        );
+        outputs.add(zabbix, *opts.push_interval);
+    }
+    if let Some(addr) = &opts.statsd_addr {
+        let statsd = push::StatsD::new(addr, opts.statsd_prefix.clone(), opts.statsd_tags)?;
+        outputs.add(statsd, *opts.push_interval);
+    }
+    if let Some(addr) = &opts.graphite_addr {
+        let graphite = push::Graphite::new(
+            addr.clone(),
+            opts.graphite_prefix.clone(),
+            opts.graphite_tags,
+        );
+        let interval = opts.graphite_interval.unwrap_or(opts.push_interval);
+        outputs.add(graphite, *interval);
+    }
+    if let Some(dir) = &opts.textfile_dir {
+        outputs.add(push::Textfile::new(dir.clone()), *opts.push_interval);
+    }
+    if let Some(interval) = opts.log_metrics_interval {
+        let file = opts.log_metrics_file.clone();
+        let log = push::MetricsLog::new(
+            opts.log_metrics_format,
+            opts.log_metric.clone(),
+            file.map(|file| (file, opts.log_metrics_max_bytes)),
+        );
+        outputs.add(log, *interval);
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(file) = &opts.history_file {
+        let history = history::History::open(file, *opts.history_retention)?;
+        outputs.add(history, *opts.push_interval);
+    }
+    if !opts.alert.is_empty() {
+        let alerts = push::Alerts::new(
+            opts.alert.clone(),
+            opts.alert_webhook.clone(),
+            opts.alert_command.clone(),
+            *opts.push_timeout,
+        );
+        outputs.add(alerts, *opts.push_interval);
+    }
+    if addr.is_none() && outputs.timeout().is_none() {
+        return Err("Nothing to do without a listener or push target".into());
+    }
+    #[cfg(target_os = "linux")]
+    if opts.sandbox {
+        // Where files are written or removed later on
+        let unix_socket = match (&opts.listen, activated, opts.no_listen) {
+            (http::Listen::Unix(path), false, false) => Some(path),
+            _ => None,
         };
-        lastdevices = devices.len();
-        let nextupdate = Instant::now() + refresh_interval;
+        #[cfg(feature = "sqlite")]
+        let history = opts.history_file.as_deref();
+        #[cfg(not(feature = "sqlite"))]
+        let history = None;
+        let files = [
+            opts.pid_file.as_deref(),
+            unix_socket.map(PathBuf::as_path),
+            opts.log_metrics_file.as_deref(),
+            history,
+        ];
+        let dirs = files
+            .into_iter()
+            .flatten()
+            .filter_map(std::path::Path::parent);
+        let writable = opts
+            .textfile_dir
+            .as_deref()
+            .into_iter()
+            .chain(dirs)
+            .map(|dir| match dir.as_os_str().is_empty() {
+                true => std::path::Path::new("."),
+                false => dir,
+            })
+            .collect::<Vec<_>>();
+        // nvidia-smi, and the shell for alert commands
+        let exec = matches!(collector.backend(), Backend::Smi) || opts.alert_command.is_some();
+        // The TLS relay's socket
+        let removable = terminator
+            .iter()
+            .map(tls::Terminator::dir)
+            .collect::<Vec<_>>();
+        sandbox::apply(&writable, &removable, exec)?;
+    }
+    // Threads from here on
+    let mut server = listener
+        .map(|listener| tiny_http::Server::from_listener(listener, ssl))
+        .transpose()?;
+    #[cfg(unix)]
+    if let (Some(terminator), Some(config)) = (&mut terminator, rustls) {
+        server = Some(terminator.start(config)?);
+    }
+    #[cfg(unix)]
+    match (opts.mdns, ip) {
+        (true, Some(addr)) => {
+            if let Err(e) = mdns::announce(&hostname(), addr) {
+                warn!("Failed to announce via mDNS: {}", e);
+            }
+        }
+        (true, None) => warn!("Not announcing via mDNS without a TCP listener"),
+        (false, _) => (),
+    }
+    let server = server.map(Arc::new);
+    let shutdown = shutdown_on_signal(server.clone())?;
+
+    let notifier = systemd::Notifier::from_env();
+    let mut lastdevices = None;
+
+    let sampling = opts.aggregates || !opts.histogram.is_empty();
+    let mut next_sample = sampling.then(Instant::now);
+    notifier.ready();
+    #[cfg(unix)]
+    daemon.ready()?;
+
+    loop {
+        let mut next_check = Instant::now();
+        while !shutdown.load(Ordering::SeqCst) {
+            notifier.watchdog();
+            // Collections notice devices coming and going too, this logs it, and notices a lost
+            // driver connection without scrapes
+            if next_check <= Instant::now() {
+                next_check = Instant::now() + DEVICE_CHECK_INTERVAL;
+                if matches!(collector.backend(), Backend::Absent) {
+                    match backend(opts)? {
+                        Backend::Absent => (),
+                        found => {
+                            info!("Found a GPU driver");
+                            drop(collector.set_backend(found));
+                        }
+                    }
+                }
+                match collector.devices().map(|devices| devices.len()) {
+                    Ok(devices) if lastdevices != Some(devices) => {
+                        info!("Found {} devices", devices);
+                        lastdevices = Some(devices);
+                    }
+                    Ok(_) => (),
+                    Err(e) => {
+                        warn!("Failed to list devices, reinitializing: {}", e);
+                        break;
+                    }
+                }
+            }
+            if outputs.due() {
+                let families = gather(opts, &collector, opts.deadline(None));
+                if let Some(families) = opts.tolerate(families)? {
+                    outputs.push(&families);
+                }
+            }
+            if next_sample.is_some_and(|at| at <= Instant::now()) {
+                if let Err(e) = collector.sample() {
+                    warn!("Sampling failed: {}", e);
+                }
+                next_sample = Some(Instant::now() + opts.sample_interval);
+            }
+            let sample_timeout = next_sample.map(|at| at.saturating_duration_since(Instant::now()));
+            let timeout = [
+                notifier.watchdog_interval(),
+                outputs.timeout(),
+                sample_timeout,
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            if limits.is_empty() {
+                let request = match (&server, timeout) {
+                    (Some(server), Some(timeout)) => server.recv_timeout(timeout),
+                    (Some(server), None) => server.recv().map(Some),
+                    (None, timeout) => {
+                        // Woken early on shutdown
+                        std::thread::park_timeout(timeout.unwrap_or(Duration::MAX));
+                        Ok(None)
+                    }
+                };
+                match request {
+                    Ok(Some(request)) => limits.admit(request),
+                    Ok(None) => continue,
+                    // The server was unblocked by a signal
+                    Err(_) if shutdown.load(Ordering::SeqCst) => break,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            if let Some(server) = &server {
+                limits.accept(server);
+            }
+            let Some(request) = limits.next() else {
+                continue;
+            };
+            debug!(method = %request.method(), url = request.url(), remote = ?request.remote_addr(), "Request");
+            if !auth.check(&request) {
+                warn!(remote = ?request.remote_addr(), "Rejected unauthenticated request");
+                auth::unauthorized(request).ok();
+                continue;
+            }
+            let collected = SystemTime::now();
+            let deadline = opts.deadline(http::scrape_timeout(&request));
+            let families = match http::route(&request) {
+                http::Route::Metrics => {
+                    // ?device=0,1 or ?device=GPU-…&device=GPU-…, for sharding across scrape jobs
+                    let devices = http::query_params(&request, "device")
+                        .iter()
+                        .flat_map(|devices| devices.split(','))
+                        .map(str::to_owned)
+                        .collect::<Vec<_>>();
+                    // ?collect[]=memory&collect[]=power, node_exporter style
+                    let collectors = http::query_params(&request, "collect[]");
+                    let known = collector.collectors().iter().map(|c| c.name());
+                    let known = known.collect::<Vec<_>>();
+                    if let Some(unknown) = collectors.iter().find(|c| !known.contains(&c.as_str()))
+                    {
+                        let message = format!("Unknown collector {unknown}");
+                        http::error(request, 400, &message).ok();
+                        continue;
+                    }
+                    let selection = Selection {
+                        devices: (!devices.is_empty()).then_some(devices),
+                        collectors: (!collectors.is_empty()).then_some(collectors),
+                    };
+                    // Only full scrapes are cached, selections are collected as asked
+                    let full = selection.devices.is_none() && selection.collectors.is_none();
+                    let fresh = http::query_param(&request, "fresh").as_deref() == Some("1");
+                    let cached = cache.as_ref().filter(|_| full);
+                    if let Some((at, families)) = cached.and_then(|c| c.get(fresh, Instant::now()))
+                    {
+                        let collected = opts.metric_timestamps.then_some(at);
+                        if let Err(e) = http::metrics(request, families, collected) {
+                            warn!("Failed to respond: {}", e);
+                        }
+                        continue;
+                    }
+                    let started = Instant::now();
+                    let families = gather_selected(opts, &collector, &selection, deadline);
+                    match opts.tolerate(families)? {
+                        Some(Some(families)) => {
+                            if let Some(cache) = cache.as_mut().filter(|_| full) {
+                                cache.store(collected, started, &families);
+                            }
+                            families
+                        }
+                        Some(None) => {
+                            http::error(request, 404, "No such gpu").ok();
+                            continue;
+                        }
+                        None => {
+                            http::error(request, 500, "Collection failed").ok();
+                            continue;
+                        }
+                    }
+                }
+                http::Route::Probe => {
+                    let Some(target) = http::query_param(&request, "gpu") else {
+                        http::error(request, 400, "Missing gpu parameter").ok();
+                        continue;
+                    };
+                    let families = collector.gather(&Selection::devices(vec![target]), deadline);
+                    let mut families = match opts.tolerate(families)? {
+                        Some(Some(families)) => families,
+                        Some(None) => {
+                            http::error(request, 404, "No such gpu").ok();
+                            continue;
+                        }
+                        None => {
+                            http::error(request, 500, "Collection failed").ok();
+                            continue;
+                        }
+                    };
+                    relabel(opts, &collector, &mut families);
+                    families
+                }
+                http::Route::ServiceDiscovery if opts.service_discovery => {
+                    let devices = match collector.devices() {
+                        Ok(devices) => devices,
+                        Err(e) => {
+                            warn!("Failed to list the devices: {}", e);
+                            http::error(request, 500, "Failed to list the devices").ok();
+                            continue;
+                        }
+                    };
+                    let address = opts.sd_address.as_deref();
+                    if let Err(e) = http::service_discovery(request, devices, address, https) {
+                        warn!("Failed to respond: {}", e);
+                    }
+                    continue;
+                }
+                http::Route::Devices => {
+                    let gathered = collector.devices().and_then(|devices| {
+                        let families = collector.gather(&Selection::default(), deadline)?;
+                        Ok((devices, families.unwrap_or_default()))
+                    });
+                    let (devices, families) = match gathered {
+                        Ok(gathered) => gathered,
+                        Err(e) => {
+                            warn!("Failed to collect metrics: {}", e);
+                            http::error(request, 500, "Collection failed").ok();
+                            continue;
+                        }
+                    };
+                    if let Err(e) = http::devices(request, devices, &families, collected) {
+                        warn!("Failed to respond: {}", e);
+                    }
+                    continue;
+                }
+                http::Route::Dashboard if opts.dashboard => {
+                    if let Err(e) = http::dashboard(request) {
+                        warn!("Failed to respond: {}", e);
+                    }
+                    continue;
+                }
+                route @ (http::Route::PowerLimit
+                | http::Route::LockedClocks
+                | http::Route::ResetCounters)
+                    if opts.enable_admin_api =>
+                {
+                    if !auth.admin(&request) {
+                        warn!(remote = ?request.remote_addr(), "Rejected request from a user who isn't an admin");
+                        http::error(request, 403, "Forbidden").ok();
+                        continue;
+                    }
+                    if let Err(e) = admin(&collector, request, route, &counter_resets) {
+                        warn!("Failed to respond: {}", e);
+                    }
+                    continue;
+                }
+                http::Route::ServiceDiscovery
+                | http::Route::Dashboard
+                | http::Route::PowerLimit
+                | http::Route::LockedClocks
+                | http::Route::ResetCounters
+                | http::Route::Other => {
+                    http::redirect(request).ok();
+                    continue;
+                }
+            };
+            let collected = opts.metric_timestamps.then_some(collected);
+            if let Err(e) = http::metrics(request, families, collected) {
+                warn!("Failed to respond: {}", e);
+            }
+        }
 
-        while Instant::now() < nextupdate {
-            let _update_guard = exporter.wait_request();
-            for dev in &devices {
-                dev.update()?;
+        if shutdown.load(Ordering::SeqCst) {
+            info!("Shutting down");
+            notifier.stopping();
+            collector.into_backend().shutdown()?;
+            break;
+        }
+        // E.g. after the driver was reloaded
+        drop(collector.set_backend(backend(opts)?));
+        reinitializations.inc();
+    }
+
+    if let Some(consul) = consul {
+        if let Err(e) = consul.deregister() {
+            warn!("Failed to deregister from Consul: {}", e);
+        }
+    }
+    #[cfg(unix)]
+    daemon.stop();
+    drop(server);
+    if let (http::Listen::Unix(path), false, false) = (&opts.listen, activated, opts.no_listen) {
+        std::fs::remove_file(path).ok();
+    }
+    Ok(())
+}
+
+/// Have SIGTERM/SIGINT stop the main loop once the request in flight is done
+#[cfg(unix)]
+fn shutdown_on_signal(server: Option<Arc<tiny_http::Server>>) -> Result<Arc<AtomicBool>> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let mut signals = signal_hook::iterator::Signals::new([SIGTERM, SIGINT])?;
+    let (flag, main) = (shutdown.clone(), std::thread::current());
+    std::thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            flag.store(true, Ordering::SeqCst);
+            if let Some(server) = server {
+                server.unblock();
             }
+            main.unpark();
         }
+    });
+    Ok(shutdown)
+}
+
+/// Have the service manager stop the main loop, when run as a Windows service
+#[cfg(not(unix))]
+fn shutdown_on_signal(server: Option<Arc<tiny_http::Server>>) -> Result<Arc<AtomicBool>> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    #[cfg(windows)]
+    {
+        let (flag, main) = (shutdown.clone(), std::thread::current());
+        service::on_stop(move || {
+            flag.store(true, Ordering::SeqCst);
+            if let Some(server) = &server {
+                server.unblock();
+            }
+            main.unpark();
+        });
     }
+    #[cfg(not(windows))]
+    drop(server);
+    Ok(shutdown)
 }
@@ -0,0 +1,247 @@
+//! Announcing the exporter via multicast DNS as a `_prometheus-http._tcp` service (DNS-SD), for
+//! discovery without static scrape configs
+//!
+//! Only the little of RFC 6762 a single service needs: answering queries for its records, and
+//! announcing them at startup.
+
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::fd::FromRawFd;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::Result;
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+const SERVICE: &str = "_prometheus-http._tcp.local";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Records only this host has, replacing what others have cached
+const CACHE_FLUSH: u16 = 0x8000;
+
+struct Service {
+    instance: String,
+    host: String,
+    addr: Ipv4Addr,
+    port: u16,
+}
+
+/// Answer queries for the service in the background, after announcing it
+pub fn announce(hostname: &str, listening: SocketAddr) -> Result<()> {
+    let addr = match listening.ip() {
+        std::net::IpAddr::V4(ip) if !ip.is_unspecified() => ip,
+        _ => outgoing_addr()?,
+    };
+    let service = Service {
+        instance: format!("nvml-exporter-{hostname}-{}.{SERVICE}", listening.port()),
+        host: format!("{hostname}.local"),
+        addr,
+        port: listening.port(),
+    };
+    let socket = bind()?;
+    socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    let response = service.response()?;
+    info!(instance = service.instance, %addr, "Announcing via mDNS");
+    std::thread::spawn(move || {
+        // Twice, a second apart, in case the first is lost
+        for _ in 0..2 {
+            socket.send_to(&response, (GROUP, PORT)).ok();
+            std::thread::sleep(Duration::from_secs(1));
+        }
+        let mut query = [0; 9000];
+        loop {
+            let len = match socket.recv_from(&mut query) {
+                Ok((len, _)) => len,
+                Err(e) => {
+                    warn!("Stopped answering mDNS queries: {}", e);
+                    return;
+                }
+            };
+            if service.is_asked_for(&query[..len]) {
+                debug!("Answering mDNS query");
+                if let Err(e) = socket.send_to(&response, (GROUP, PORT)) {
+                    warn!("Failed to send mDNS response: {}", e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The address of the interface multicast goes out of
+fn outgoing_addr() -> Result<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((GROUP, PORT))?;
+    match socket.local_addr()?.ip() {
+        std::net::IpAddr::V4(ip) => Ok(ip),
+        std::net::IpAddr::V6(_) => Err("No IPv4 address to announce".into()),
+    }
+}
+
+/// Port 5353, shared with other mDNS responders, like avahi
+fn bind() -> Result<UdpSocket> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    let one: libc::c_int = 1;
+    for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        let set = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &one as *const _ as _,
+                std::mem::size_of_val(&one) as _,
+            )
+        };
+        if set != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    let addr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as _,
+        sin_port: PORT.to_be(),
+        sin_addr: libc::in_addr { s_addr: 0 },
+        ..unsafe { std::mem::zeroed() }
+    };
+    let bound = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const _ as _,
+            std::mem::size_of_val(&addr) as _,
+        )
+    };
+    if bound != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(socket)
+}
+
+impl Service {
+    /// Whether a packet is a query for any of the service's records
+    fn is_asked_for(&self, packet: &[u8]) -> bool {
+        let Some(header) = packet.get(..12) else {
+            return false;
+        };
+        // Responses of others
+        if header[2] & 0x80 != 0 {
+            return false;
+        }
+        let questions = u16::from_be_bytes([header[4], header[5]]);
+        let mut at = 12;
+        for _ in 0..questions {
+            let Some((name, end)) = read_name(packet, at) else {
+                return false;
+            };
+            let Some(qtype) = packet.get(end..end + 2) else {
+                return false;
+            };
+            at = end + 4;
+            let qtype = u16::from_be_bytes([qtype[0], qtype[1]]);
+            let is = |own: &str, types: &[u16]| {
+                name.eq_ignore_ascii_case(own) && (qtype == TYPE_ANY || types.contains(&qtype))
+            };
+            if is(SERVICE, &[TYPE_PTR])
+                || is(&self.instance, &[TYPE_SRV, TYPE_TXT])
+                || is(&self.host, &[TYPE_A])
+            {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// All records, answering any of the queries, and also the announcement
+    fn response(&self) -> Result<Vec<u8>> {
+        // Authoritative answer, four of them
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 4, 0, 0, 0, 0];
+        let mut srv = vec![0, 0, 0, 0];
+        srv.extend(self.port.to_be_bytes());
+        write_name(&mut srv, &self.host)?;
+        let txt = b"path=/metrics";
+        let records = [
+            (SERVICE, TYPE_PTR, CLASS_IN, 4500, name(&self.instance)?),
+            (&*self.instance, TYPE_SRV, CLASS_IN | CACHE_FLUSH, 120, srv),
+            (
+                &*self.instance,
+                TYPE_TXT,
+                CLASS_IN | CACHE_FLUSH,
+                4500,
+                [&[txt.len() as u8][..], txt].concat(),
+            ),
+            (
+                &*self.host,
+                TYPE_A,
+                CLASS_IN | CACHE_FLUSH,
+                120,
+                self.addr.octets().to_vec(),
+            ),
+        ];
+        for (owner, rtype, class, ttl, data) in records {
+            write_name(&mut packet, owner)?;
+            packet.extend(rtype.to_be_bytes());
+            packet.extend(class.to_be_bytes());
+            packet.extend((ttl as u32).to_be_bytes());
+            packet.extend((data.len() as u16).to_be_bytes());
+            packet.extend(data);
+        }
+        Ok(packet)
+    }
+}
+
+fn name(name: &str) -> Result<Vec<u8>> {
+    let mut encoded = vec![];
+    write_name(&mut encoded, name)?;
+    Ok(encoded)
+}
+
+/// As length prefixed labels, the first of which may contain dots, as instance names can
+fn write_name(packet: &mut Vec<u8>, name: &str) -> Result<()> {
+    let (first, rest) = match name.strip_suffix(&format!(".{SERVICE}")) {
+        Some(instance) => (instance, SERVICE),
+        None => name.split_once('.').unwrap_or((name, "")),
+    };
+    for label in std::iter::once(first).chain(rest.split('.').filter(|l| !l.is_empty())) {
+        if label.len() > 63 {
+            return Err(format!("mDNS name too long: {label}").into());
+        }
+        packet.push(label.len() as u8);
+        packet.extend(label.as_bytes());
+    }
+    packet.push(0);
+    Ok(())
+}
+
+/// The name at an offset, with the offset after it, following compression pointers
+fn read_name(packet: &[u8], mut at: usize) -> Option<(String, usize)> {
+    let mut labels = vec![];
+    let mut end = None;
+    // Bounded, against pointer loops
+    for _ in 0..128 {
+        let len = *packet.get(at)? as usize;
+        match len {
+            0 => {
+                let name = labels.join(".");
+                return Some((name, end.unwrap_or(at + 1)));
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let pointer = (len & 0x3f) << 8 | *packet.get(at + 1)? as usize;
+                end.get_or_insert(at + 2);
+                at = pointer;
+            }
+            len => {
+                let label = packet.get(at + 1..at + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                at += 1 + len;
+            }
+        }
+    }
+    None
+}
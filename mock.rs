@@ -0,0 +1,143 @@
+//! Synthetic devices for working on dashboards, alerts and the exporter itself without
+//! NVIDIA hardware
+
+use nvml_wrapper::enum_wrappers::device::{Clock, PerformanceState, TemperatureSensor};
+use nvml_wrapper::enums::device::UsedGpuMemory;
+use nvml_wrapper::error::NvmlError;
+use nvml_wrapper::struct_wrappers::device::{
+    MemoryInfo, ProcessInfo, ProcessUtilizationSample, Utilization,
+};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use crate::gpu::{Gpu, RetiredPages};
+
+const MEMORY: u64 = 16 << 30;
+const POWER_IDLE_MW: u32 = 50_000;
+const POWER_LIMIT_MW: u32 = 250_000;
+
+/// Shared by all instances, so counters keep counting across rediscovery
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+pub struct MockGpu {
+    index: u32,
+    started: Instant,
+}
+
+impl MockGpu {
+    pub fn new(index: u32) -> MockGpu {
+        MockGpu {
+            index,
+            started: *STARTED.get_or_init(Instant::now),
+        }
+    }
+
+    /// Utilization between 0.1 and 0.9, slowly oscillating and out of phase between devices
+    fn load(&self) -> f64 {
+        let t = self.started.elapsed().as_secs_f64();
+        0.5 + 0.4 * (t / 60. + self.index as f64).sin()
+    }
+}
+
+impl Gpu for MockGpu {
+    fn uuid(&self) -> Result<String, NvmlError> {
+        Ok(format!("GPU-00000000-0000-0000-0000-{:012x}", self.index))
+    }
+    fn name(&self) -> Result<String, NvmlError> {
+        Ok("Mock GPU".into())
+    }
+    fn pci_bus_id(&self) -> Result<String, NvmlError> {
+        Ok(format!("00000000:{:02X}:00.0", self.index + 1))
+    }
+    fn index(&self) -> Result<u32, NvmlError> {
+        Ok(self.index)
+    }
+    fn mig_mode(&self) -> Result<bool, NvmlError> {
+        Ok(false)
+    }
+    fn vbios_version(&self) -> Result<String, NvmlError> {
+        Ok("00.00.00.00.00".into())
+    }
+    fn driver_version(&self) -> Result<String, NvmlError> {
+        Ok(env!("CARGO_PKG_VERSION").into())
+    }
+    fn memory_info(&self) -> Result<MemoryInfo, NvmlError> {
+        let used = (MEMORY as f64 * self.load()) as u64;
+        Ok(MemoryInfo {
+            free: MEMORY - used,
+            total: MEMORY,
+            used,
+        })
+    }
+    fn fan_speed(&self, fan: u32) -> Result<u32, NvmlError> {
+        match fan {
+            0 => Ok((30. + 60. * self.load()) as u32),
+            _ => Err(NvmlError::InvalidArg),
+        }
+    }
+    fn temperature(&self, _: TemperatureSensor) -> Result<u32, NvmlError> {
+        Ok((35. + 45. * self.load()) as u32)
+    }
+    fn performance_state(&self) -> Result<PerformanceState, NvmlError> {
+        Ok(match self.load() > 0.3 {
+            true => PerformanceState::Zero,
+            false => PerformanceState::Eight,
+        })
+    }
+    fn power_usage(&self) -> Result<u32, NvmlError> {
+        let range = (POWER_LIMIT_MW - POWER_IDLE_MW) as f64;
+        Ok(POWER_IDLE_MW + (range * self.load()) as u32)
+    }
+    fn enforced_power_limit(&self) -> Result<u32, NvmlError> {
+        Ok(POWER_LIMIT_MW)
+    }
+    fn total_energy_consumption(&self) -> Result<u64, NvmlError> {
+        // Drawing the average power since start, mW * ms / 1000 = mJ
+        let average = (POWER_IDLE_MW + POWER_LIMIT_MW) as u128 / 2;
+        Ok((average * self.started.elapsed().as_millis() / 1000) as u64)
+    }
+    fn pcie_replay_counter(&self) -> Result<u32, NvmlError> {
+        Ok(0)
+    }
+    /// The driver version is this exporter's, too old for remapped rows
+    fn retired_pages(&self) -> Result<RetiredPages, NvmlError> {
+        Ok(RetiredPages::default())
+    }
+    fn utilization_rates(&self) -> Result<Utilization, NvmlError> {
+        Ok(Utilization {
+            gpu: (100. * self.load()) as u32,
+            memory: (60. * self.load()) as u32,
+        })
+    }
+    fn clock_info(&self, clock: Clock) -> Result<u32, NvmlError> {
+        let max = match clock {
+            Clock::Graphics | Clock::SM => 1800.,
+            Clock::Memory => 7000.,
+            Clock::Video => 1500.,
+        };
+        Ok((max * (0.2 + 0.8 * self.load())) as u32)
+    }
+    fn running_compute_processes(&self) -> Result<Vec<ProcessInfo>, NvmlError> {
+        // The exporter itself, so there is something to attribute
+        Ok(vec![ProcessInfo {
+            pid: std::process::id(),
+            used_gpu_memory: UsedGpuMemory::Used(self.memory_info()?.used),
+            gpu_instance_id: None,
+            compute_instance_id: None,
+        }])
+    }
+    fn running_graphics_processes(&self) -> Result<Vec<ProcessInfo>, NvmlError> {
+        Ok(vec![])
+    }
+    fn process_utilization_stats(&self) -> Result<Vec<ProcessUtilizationSample>, NvmlError> {
+        let utilization = self.utilization_rates()?;
+        Ok(vec![ProcessUtilizationSample {
+            pid: std::process::id(),
+            timestamp: self.started.elapsed().as_micros() as u64,
+            sm_util: utilization.gpu,
+            mem_util: utilization.memory,
+            enc_util: (30. * self.load()) as u32,
+            dec_util: (20. * self.load()) as u32,
+        }])
+    }
+}
@@ -0,0 +1,212 @@
+//! OpenMetrics 1.0 text exposition, as negotiated by scrapers sending
+//! `Accept: application/openmetrics-text`
+
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::Encoder;
+use std::io::Write;
+
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Name suffixes that are reported as `# UNIT`
+static UNITS: [&str; 7] = [
+    "bytes", "seconds", "celsius", "watts", "joules", "ratio", "hertz",
+];
+
+pub struct OpenMetricsEncoder;
+
+impl Encoder for OpenMetricsEncoder {
+    fn encode<W: Write>(
+        &self,
+        metric_families: &[MetricFamily],
+        writer: &mut W,
+    ) -> prometheus::Result<()> {
+        for mf in metric_families {
+            let metric_type = mf.get_field_type();
+            // Counter samples carry the _total suffix, the family name must not
+            let name = match metric_type {
+                MetricType::COUNTER => {
+                    let name = mf.get_name();
+                    name.strip_suffix("_total").unwrap_or(name)
+                }
+                _ => mf.get_name(),
+            };
+            let type_name = match metric_type {
+                MetricType::COUNTER => "counter",
+                MetricType::GAUGE => "gauge",
+                MetricType::HISTOGRAM => "histogram",
+                MetricType::SUMMARY => "summary",
+                MetricType::UNTYPED => "unknown",
+            };
+            writeln!(writer, "# TYPE {} {}", name, type_name)?;
+            if let Some(unit) = UNITS
+                .iter()
+                .find(|unit| name.strip_suffix(*unit).is_some_and(|n| n.ends_with('_')))
+            {
+                writeln!(writer, "# UNIT {} {}", name, unit)?;
+            }
+            if !mf.get_help().is_empty() {
+                writeln!(writer, "# HELP {} {}", name, escape(mf.get_help()))?;
+            }
+
+            for m in mf.get_metric() {
+                match metric_type {
+                    MetricType::COUNTER => {
+                        let value = m.get_counter().get_value();
+                        write_sample(writer, name, "_total", m, None, value)?;
+                    }
+                    MetricType::GAUGE => {
+                        write_sample(writer, name, "", m, None, m.get_gauge().get_value())?;
+                    }
+                    MetricType::UNTYPED => {
+                        write_sample(writer, name, "", m, None, m.get_untyped().get_value())?;
+                    }
+                    MetricType::HISTOGRAM => {
+                        let h = m.get_histogram();
+                        let mut inf_seen = false;
+                        for b in h.get_bucket() {
+                            let le = format_value(b.get_upper_bound());
+                            let count = b.get_cumulative_count() as f64;
+                            write_sample(writer, name, "_bucket", m, Some(("le", &le)), count)?;
+                            inf_seen |= b.get_upper_bound() == f64::INFINITY;
+                        }
+                        if !inf_seen {
+                            let count = h.get_sample_count() as f64;
+                            write_sample(writer, name, "_bucket", m, Some(("le", "+Inf")), count)?;
+                        }
+                        write_sample(writer, name, "_sum", m, None, h.get_sample_sum())?;
+                        let count = h.get_sample_count() as f64;
+                        write_sample(writer, name, "_count", m, None, count)?;
+                    }
+                    MetricType::SUMMARY => {
+                        let s = m.get_summary();
+                        for q in s.get_quantile() {
+                            let quantile = format_value(q.get_quantile());
+                            let label = Some(("quantile", quantile.as_str()));
+                            write_sample(writer, name, "", m, label, q.get_value())?;
+                        }
+                        write_sample(writer, name, "_sum", m, None, s.get_sample_sum())?;
+                        let count = s.get_sample_count() as f64;
+                        write_sample(writer, name, "_count", m, None, count)?;
+                    }
+                }
+            }
+        }
+        writeln!(writer, "# EOF")?;
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        OPENMETRICS_FORMAT
+    }
+}
+
+fn write_sample<W: Write>(
+    writer: &mut W,
+    name: &str,
+    suffix: &str,
+    m: &Metric,
+    additional_label: Option<(&str, &str)>,
+    value: f64,
+) -> std::io::Result<()> {
+    write!(writer, "{}{}", name, suffix)?;
+    write_labels(writer, m.get_label(), additional_label)?;
+    write!(writer, " {}", format_value(value))?;
+    let timestamp = m.get_timestamp_ms();
+    if timestamp != 0 {
+        // OpenMetrics timestamps are in seconds
+        write!(writer, " {}", timestamp as f64 / 1000.)?;
+    }
+    writeln!(writer)
+}
+
+fn write_labels<W: Write>(
+    writer: &mut W,
+    labels: &[LabelPair],
+    additional_label: Option<(&str, &str)>,
+) -> std::io::Result<()> {
+    let mut labels = labels
+        .iter()
+        .map(|l| (l.get_name(), l.get_value()))
+        .chain(additional_label)
+        .peekable();
+    if labels.peek().is_none() {
+        return Ok(());
+    }
+    write!(writer, "{{")?;
+    for (i, (name, value)) in labels.enumerate() {
+        if i > 0 {
+            write!(writer, ",")?;
+        }
+        write!(writer, "{}=\"{}\"", name, escape(value))?;
+    }
+    write!(writer, "}}")
+}
+
+fn format_value(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".into()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".into()
+    } else if value.is_nan() {
+        "NaN".into()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+
+    fn samples(encoder: &impl Encoder, families: &[MetricFamily]) -> (String, Vec<String>) {
+        let mut buf = vec![];
+        encoder.encode(families, &mut buf).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        let samples = text
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(str::to_owned)
+            .collect();
+        (text, samples)
+    }
+
+    #[test]
+    fn formats_agree() {
+        let registry = Registry::new();
+        let counter = IntCounterVec::new(Opts::new("nvml_pci_replay_total", "Replays"), &["uuid"]);
+        let gauge = IntGaugeVec::new(Opts::new("nvml_temp", "Temperature"), &["uuid"]);
+        let info = IntGaugeVec::new(Opts::new("nvml_driver_info", "The driver"), &["version"]);
+        let (counter, gauge, info) = (counter.unwrap(), gauge.unwrap(), info.unwrap());
+        counter.with_label_values(&["GPU-0"]).inc_by(3);
+        gauge.with_label_values(&["GPU-0"]).set(57);
+        info.with_label_values(&["550.54"]).set(1);
+        registry.register(Box::new(counter)).unwrap();
+        registry.register(Box::new(gauge)).unwrap();
+        registry.register(Box::new(info)).unwrap();
+        let families = registry.gather();
+
+        let (text, prometheus) = samples(&TextEncoder::new(), &families);
+        let (openmetrics, samples) = samples(&OpenMetricsEncoder, &families);
+        assert_eq!(samples, prometheus, "{}\n{}", text, openmetrics);
+        assert_eq!(
+            samples,
+            [
+                "nvml_driver_info{version=\"550.54\"} 1",
+                "nvml_pci_replay_total{uuid=\"GPU-0\"} 3",
+                "nvml_temp{uuid=\"GPU-0\"} 57",
+            ]
+        );
+        assert!(text.contains("# TYPE nvml_pci_replay_total counter"));
+        assert!(openmetrics.contains("# TYPE nvml_pci_replay counter"));
+        assert!(openmetrics.contains("# TYPE nvml_driver_info gauge"));
+        assert!(openmetrics.ends_with("# EOF\n"));
+    }
+}
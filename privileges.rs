@@ -0,0 +1,110 @@
+//! Dropping root privileges once everything that needs them is set up
+
+use std::ffi::{CStr, CString};
+use std::path::Path;
+
+use crate::Result;
+
+/// Switch to the given user and/or group (names or numeric ids). Without a group, the user's
+/// primary group and supplementary groups are used.
+pub fn drop_to(user: Option<&str>, group: Option<&str>) -> Result<()> {
+    let (user, gid) = lookup(user, group)?;
+    if let Some(gid) = gid {
+        let ok = match (&user, group) {
+            (Some((name, _, _)), None) => unsafe { libc::initgroups(name.as_ptr(), gid as _) },
+            _ => unsafe { libc::setgroups(1, &gid) },
+        };
+        if ok != 0 {
+            return Err(os_error("Failed to set supplementary groups"));
+        }
+        if unsafe { libc::setgid(gid) } != 0 {
+            return Err(os_error("Failed to set group"));
+        }
+    }
+    if let Some((_, uid, _)) = user {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(os_error("Failed to set user"));
+        }
+    }
+    Ok(())
+}
+
+/// Hand what is created before privileges are dropped, and used after, to the user and/or group
+/// they're dropped to
+pub fn chown(path: &Path, user: Option<&str>, group: Option<&str>) -> Result<()> {
+    let (user, gid) = lookup(user, group)?;
+    std::os::unix::fs::chown(path, user.map(|(_, uid, _)| uid), gid)
+        .map_err(|e| format!("Failed to hand {} over: {}", path.display(), e))?;
+    Ok(())
+}
+
+/// The user, and the group being the given one or else the user's
+#[allow(clippy::type_complexity)]
+fn lookup(
+    user: Option<&str>,
+    group: Option<&str>,
+) -> Result<(
+    Option<(CString, libc::uid_t, libc::gid_t)>,
+    Option<libc::gid_t>,
+)> {
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match (group, &user) {
+        (Some(group), _) => Some(lookup_group(group)?),
+        (None, Some((_, _, gid))) => Some(*gid),
+        (None, None) => None,
+    };
+    Ok((user, gid))
+}
+
+fn os_error(what: &str) -> Box<dyn std::error::Error + Send + Sync> {
+    format!("{}: {}", what, std::io::Error::last_os_error()).into()
+}
+
+fn lookup_user(user: &str) -> Result<(CString, libc::uid_t, libc::gid_t)> {
+    let name = CString::new(user)?;
+    let mut buf = vec![0; 16384];
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let ret = match user.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe {
+            libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result)
+        },
+        Err(_) => unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        },
+    };
+    if ret != 0 || result.is_null() {
+        return Err(format!("Unknown user {}", user).into());
+    }
+    let name = unsafe { CStr::from_ptr(pwd.pw_name) }.to_owned();
+    Ok((name, pwd.pw_uid, pwd.pw_gid))
+}
+
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group)?;
+    let mut buf = vec![0; 16384];
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let ret = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 || result.is_null() {
+        return Err(format!("Unknown group {}", group).into());
+    }
+    Ok(grp.gr_gid)
+}
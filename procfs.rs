@@ -0,0 +1,98 @@
+//! What a process is, from /proc: the user running it, its command line, and when it started
+//!
+//! Like [`crate::cgroup`], this needs the host's PID namespace in a container.
+
+/// Command lines are cut to this many characters, to keep label values manageable
+pub const COMMAND_LENGTH: usize = 64;
+
+/// Empty, or None, where unknown
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Metadata {
+    pub user: String,
+    pub command: String,
+    /// Seconds since the epoch
+    pub start_time: Option<f64>,
+}
+
+impl Metadata {
+    pub fn of(pid: u32) -> Metadata {
+        let dir = format!("/proc/{pid}");
+        let command = std::fs::read(format!("{dir}/cmdline")).unwrap_or_default();
+        // Arguments are separated, and ended, by NUL
+        let command = String::from_utf8_lossy(&command).replace('\0', " ");
+        Metadata {
+            user: user(&dir).unwrap_or_default(),
+            command: crate::labels::sanitize(&command)
+                .chars()
+                .take(COMMAND_LENGTH)
+                .collect(),
+            start_time: start_time(&dir),
+        }
+    }
+}
+
+/// The owner of /proc/<pid>, by name if it has one
+#[cfg(unix)]
+fn user(dir: &str) -> Option<String> {
+    use std::collections::HashMap;
+    use std::os::unix::fs::MetadataExt;
+    use std::sync::Mutex;
+    // Looking users up may go over the network, e.g. to LDAP
+    static NAMES: Mutex<Option<HashMap<u32, String>>> = Mutex::new(None);
+    let uid = std::fs::metadata(dir).ok()?.uid();
+    let mut names = NAMES.lock().unwrap();
+    let names = names.get_or_insert_with(HashMap::new);
+    let name = names
+        .entry(uid)
+        .or_insert_with(|| user_name(uid).unwrap_or_else(|| uid.to_string()));
+    Some(name.clone())
+}
+
+#[cfg(not(unix))]
+fn user(_: &str) -> Option<String> {
+    None
+}
+
+#[cfg(unix)]
+fn user_name(uid: u32) -> Option<String> {
+    let mut buf = vec![0; 4096];
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    let ret =
+        unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if ret != 0 || found.is_null() {
+        return None;
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// From the start time in /proc/<pid>/stat, in clock ticks after boot, and the boot time in
+/// /proc/stat
+fn start_time(dir: &str) -> Option<f64> {
+    let stat = std::fs::read_to_string(format!("{dir}/stat")).ok()?;
+    // After the command in parentheses, which may contain anything, field 3 onwards
+    let fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+    let ticks: u64 = fields.into_iter().nth(22 - 3)?.parse().ok()?;
+    let boot: u64 = std::fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(boot as f64 + ticks as f64 / clock_ticks()?)
+}
+
+#[cfg(unix)]
+fn clock_ticks() -> Option<f64> {
+    match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        ticks if ticks > 0 => Some(ticks as f64),
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn clock_ticks() -> Option<f64> {
+    None
+}
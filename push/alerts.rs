@@ -0,0 +1,195 @@
+//! Basic alerting without Alertmanager: thresholds on the samples of each collection, notifying
+//! a webhook or running a command when a series crosses one, and again when it's back
+//!
+//! Any metric can be thresholded, e.g. `nvml_temp>85`.
+
+use prometheus::proto::MetricFamily;
+use serde_json::json;
+use std::collections::HashMap;
+use std::process::Child;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use super::{samples, Sink};
+use crate::Result;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Comparison {
+    Above,
+    AtLeast,
+    Below,
+    AtMost,
+}
+
+/// `<metric><comparison><value>`, with `>`, `>=`, `<`, or `<=`
+#[derive(Clone, Debug)]
+pub struct Threshold {
+    metric: String,
+    comparison: Comparison,
+    value: f64,
+    spec: String,
+}
+
+impl FromStr for Threshold {
+    type Err = String;
+    fn from_str(spec: &str) -> std::result::Result<Self, String> {
+        // Two character comparisons first
+        let comparisons = [
+            (">=", Comparison::AtLeast),
+            ("<=", Comparison::AtMost),
+            (">", Comparison::Above),
+            ("<", Comparison::Below),
+        ];
+        let (metric, comparison, value) = comparisons
+            .iter()
+            .find_map(|&(op, comparison)| {
+                let (metric, value) = spec.split_once(op)?;
+                Some((metric, comparison, value))
+            })
+            .ok_or_else(|| format!("{spec:?} is not of the form metric>value"))?;
+        Ok(Threshold {
+            metric: metric.trim().to_owned(),
+            comparison,
+            value: value
+                .trim()
+                .parse()
+                .map_err(|e| format!("Threshold of {spec:?}: {e}"))?,
+            spec: spec.to_owned(),
+        })
+    }
+}
+
+impl Threshold {
+    fn crossed(&self, value: f64) -> bool {
+        match self.comparison {
+            Comparison::Above => value > self.value,
+            Comparison::AtLeast => value >= self.value,
+            Comparison::Below => value < self.value,
+            Comparison::AtMost => value <= self.value,
+        }
+    }
+}
+
+pub struct Alerts {
+    thresholds: Vec<Threshold>,
+    webhook: Option<String>,
+    /// Run by the shell, with the alert in `NVML_ALERT_*` environment variables
+    command: Option<String>,
+    agent: ureq::Agent,
+    /// Commands still running, reaped on later pushes, not to hold up scrapes waiting for them
+    running: Vec<Child>,
+    /// The series that crossed their threshold, by threshold and label values
+    firing: HashMap<(usize, Vec<(String, String)>), f64>,
+}
+
+impl Alerts {
+    pub fn new(
+        thresholds: Vec<Threshold>,
+        webhook: Option<String>,
+        command: Option<String>,
+        timeout: Duration,
+    ) -> Alerts {
+        Alerts {
+            thresholds,
+            webhook,
+            command,
+            agent: super::agent(timeout),
+            running: vec![],
+            firing: HashMap::new(),
+        }
+    }
+
+    /// The command started, if there is one
+    fn notify(
+        &self,
+        status: &str,
+        threshold: &Threshold,
+        labels: &[(String, String)],
+        value: f64,
+    ) -> Option<Child> {
+        info!(alert = threshold.spec, status, ?labels, value, "Alert");
+        if let Some(url) = &self.webhook {
+            let body = json!({
+                "status": status,
+                "alert": threshold.spec,
+                "metric": threshold.metric,
+                "labels": labels.iter().cloned().collect::<HashMap<_, _>>(),
+                "value": value,
+            });
+            let request = self.agent.post(url).set("Content-Type", "application/json");
+            if let Err(e) = request.send_string(&body.to_string()) {
+                warn!("Failed to notify the alert webhook: {}", e);
+            }
+        }
+        if let Some(command) = &self.command {
+            let mut shell = match cfg!(windows) {
+                true => std::process::Command::new("cmd"),
+                false => std::process::Command::new("sh"),
+            };
+            shell
+                .arg(if cfg!(windows) { "/C" } else { "-c" })
+                .arg(command)
+                .env("NVML_ALERT", &threshold.spec)
+                .env("NVML_ALERT_STATUS", status)
+                .env("NVML_ALERT_VALUE", value.to_string());
+            for (name, value) in labels {
+                shell.env(format!("NVML_ALERT_LABEL_{}", name.to_uppercase()), value);
+            }
+            match shell.spawn() {
+                Ok(child) => return Some(child),
+                Err(e) => warn!("Failed to run the alert command: {}", e),
+            }
+        }
+        None
+    }
+
+    /// Forget the commands that exited, reporting those that failed
+    fn reap(&mut self) {
+        self.running.retain_mut(|child| match child.try_wait() {
+            Ok(None) => true,
+            Ok(Some(exit)) if exit.success() => false,
+            Ok(Some(exit)) => {
+                warn!("Alert command failed with {}", exit);
+                false
+            }
+            Err(e) => {
+                warn!("Failed to wait for the alert command: {}", e);
+                false
+            }
+        });
+    }
+}
+
+impl Sink for Alerts {
+    fn name(&self) -> &'static str {
+        "alerts"
+    }
+
+    fn push(&mut self, families: &[MetricFamily]) -> Result<()> {
+        self.reap();
+        let mut started = vec![];
+        let mut firing = HashMap::new();
+        for sample in samples(families) {
+            for (i, threshold) in self.thresholds.iter().enumerate() {
+                if sample.name != threshold.metric || !threshold.crossed(sample.value) {
+                    continue;
+                }
+                let key = (i, sample.labels.clone());
+                if !self.firing.contains_key(&key) {
+                    started.extend(self.notify("firing", threshold, &sample.labels, sample.value));
+                }
+                firing.insert(key, sample.value);
+            }
+        }
+        // Including those whose series is gone, e.g. a hot GPU falling off the bus
+        for ((i, labels), value) in &self.firing {
+            if !firing.contains_key(&(*i, labels.clone())) {
+                started.extend(self.notify("resolved", &self.thresholds[*i], labels, *value));
+            }
+        }
+        self.firing = firing;
+        self.running.extend(started);
+        Ok(())
+    }
+}
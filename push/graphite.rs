@@ -0,0 +1,60 @@
+use prometheus::proto::MetricFamily;
+use std::io::{BufWriter, Write};
+use std::net::TcpStream;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{samples, Sink};
+use crate::Result;
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum GraphiteTags {
+    /// Graphite 1.1 style `;label=value` tags
+    Tagged,
+    /// Label values as additional path components
+    Path,
+}
+
+/// Carbon plaintext protocol over TCP
+pub struct Graphite {
+    addr: String,
+    prefix: String,
+    tags: GraphiteTags,
+}
+
+impl Graphite {
+    pub fn new(addr: String, prefix: String, tags: GraphiteTags) -> Graphite {
+        Graphite { addr, prefix, tags }
+    }
+}
+
+impl Sink for Graphite {
+    fn name(&self) -> &'static str {
+        "graphite"
+    }
+
+    fn push(&mut self, families: &[MetricFamily]) -> Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let stream = TcpStream::connect(&self.addr)?;
+        stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+        let mut stream = BufWriter::new(stream);
+        for sample in samples(families) {
+            let mut path = format!("{}{}", self.prefix, sanitize(&sample.name));
+            for (name, value) in &sample.labels {
+                match self.tags {
+                    GraphiteTags::Tagged => {
+                        path.push_str(&format!(";{}={}", name, sanitize(value)))
+                    }
+                    GraphiteTags::Path => path.push_str(&format!(".{}", sanitize(value))),
+                }
+            }
+            writeln!(stream, "{} {} {}", path, sample.value, timestamp)?;
+        }
+        stream.flush()?;
+        Ok(())
+    }
+}
+
+/// Path components are dot-separated, and lines whitespace-separated
+fn sanitize(s: &str) -> String {
+    s.replace(['.', ' ', ';', '=', '~', '\n'], "_")
+}
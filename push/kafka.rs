@@ -0,0 +1,381 @@
+//! Producing to a Kafka topic, with the wire protocol spoken directly: a `Metadata` request for
+//! the leader of partition 0, then a `Produce` request (version 3, Kafka 0.11 and later) with one
+//! record batch
+//!
+//! There's no batching across pushes, compression, or retrying, the next push is soon enough.
+
+use prometheus::proto::MetricFamily;
+use prometheus::Encoder;
+use serde_json::json;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{samples, Sink};
+use crate::openmetrics::OpenMetricsEncoder;
+use crate::Result;
+
+const METADATA: i16 = 3;
+const PRODUCE: i16 = 0;
+const PARTITION: i32 = 0;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum KafkaFormat {
+    /// A message per sample, `{"name": …, "labels": {…}, "value": …, "timestamp": …}`, keyed by
+    /// the device's uuid
+    Json,
+    /// A message per push, in the OpenMetrics text format
+    Openmetrics,
+}
+
+pub struct Kafka {
+    /// host:port, tried in turn for the metadata
+    brokers: Vec<String>,
+    topic: String,
+    format: KafkaFormat,
+    correlation_id: i32,
+}
+
+impl Kafka {
+    pub fn new(brokers: Vec<String>, topic: String, format: KafkaFormat) -> Kafka {
+        Kafka {
+            brokers,
+            topic,
+            format,
+            correlation_id: 0,
+        }
+    }
+
+    /// Send a request, and read the response body after its correlation id
+    fn request(
+        &mut self,
+        stream: &mut TcpStream,
+        api_key: i16,
+        version: i16,
+        body: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        let mut request = vec![];
+        request.extend(api_key.to_be_bytes());
+        request.extend(version.to_be_bytes());
+        request.extend(self.correlation_id.to_be_bytes());
+        string(&mut request, env!("CARGO_PKG_NAME"));
+        request.extend(body);
+        stream.write_all(&(request.len() as i32).to_be_bytes())?;
+        stream.write_all(&request)?;
+        let mut size = [0; 4];
+        stream.read_exact(&mut size)?;
+        let mut response = vec![0; i32::from_be_bytes(size).try_into()?];
+        stream.read_exact(&mut response)?;
+        let mut response = Reader(&response);
+        if response.i32()? != self.correlation_id {
+            return Err("Kafka response out of order".into());
+        }
+        Ok(response.0.to_vec())
+    }
+
+    /// The address of the leader of the partition written to
+    fn leader(&mut self) -> Result<String> {
+        let mut last_error = None;
+        for broker in self.brokers.clone() {
+            match self.query_leader(&broker) {
+                Ok(leader) => return Ok(leader),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| "No Kafka brokers".into()))
+    }
+
+    fn query_leader(&mut self, broker: &str) -> Result<String> {
+        let mut stream = connect(broker)?;
+        let mut body = vec![];
+        body.extend(1i32.to_be_bytes());
+        string(&mut body, &self.topic);
+        let response = self.request(&mut stream, METADATA, 0, &body)?;
+        let mut response = Reader(&response);
+        let mut brokers = vec![];
+        for _ in 0..response.i32()? {
+            let id = response.i32()?;
+            let host = response.string()?;
+            let port = response.i32()?;
+            brokers.push((id, format!("{host}:{port}")));
+        }
+        let mut leader = None;
+        for _ in 0..response.i32()? {
+            error(response.i16()?)?;
+            response.string()?;
+            for _ in 0..response.i32()? {
+                let partition_error = response.i16()?;
+                let partition = response.i32()?;
+                let partition_leader = response.i32()?;
+                for _ in 0..2 {
+                    let replicas = response.i32()?;
+                    response.take(4 * usize::try_from(replicas)?)?;
+                }
+                if partition == PARTITION {
+                    error(partition_error)?;
+                    leader = Some(partition_leader);
+                }
+            }
+        }
+        let leader = leader.ok_or_else(|| format!("No partition {PARTITION} in {}", self.topic))?;
+        let (_, addr) = brokers
+            .into_iter()
+            .find(|&(id, _)| id == leader)
+            .ok_or("Kafka partition leader not among the brokers")?;
+        Ok(addr)
+    }
+
+    fn messages(
+        &self,
+        families: &[MetricFamily],
+        timestamp: i64,
+    ) -> Result<Vec<(Option<String>, Vec<u8>)>> {
+        Ok(match self.format {
+            KafkaFormat::Json => samples(families)
+                .into_iter()
+                .map(|sample| {
+                    let key = sample
+                        .labels
+                        .iter()
+                        .find(|(name, _)| name == crate::GPU_LABELS[0])
+                        .map(|(_, uuid)| uuid.clone());
+                    let labels = sample.labels.into_iter();
+                    let labels = labels
+                        .map(|(name, value)| (name, value.into()))
+                        .collect::<serde_json::Map<_, _>>();
+                    let message = json!({
+                        "name": sample.name,
+                        "labels": labels,
+                        "value": sample.value,
+                        "timestamp": timestamp,
+                    });
+                    (key, message.to_string().into_bytes())
+                })
+                .collect(),
+            KafkaFormat::Openmetrics => {
+                let mut body = vec![];
+                OpenMetricsEncoder.encode(families, &mut body)?;
+                vec![(None, body)]
+            }
+        })
+    }
+}
+
+impl Sink for Kafka {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    fn push(&mut self, families: &[MetricFamily]) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .as_millis()
+            .try_into()?;
+        let messages = self.messages(families, timestamp)?;
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let batch = record_batch(&messages, timestamp);
+        let mut body = vec![];
+        // No transactional id, acks from the leader
+        body.extend((-1i16).to_be_bytes());
+        body.extend(1i16.to_be_bytes());
+        body.extend((TIMEOUT.as_millis() as i32).to_be_bytes());
+        body.extend(1i32.to_be_bytes());
+        string(&mut body, &self.topic);
+        body.extend(1i32.to_be_bytes());
+        body.extend(PARTITION.to_be_bytes());
+        body.extend((batch.len() as i32).to_be_bytes());
+        body.extend(batch);
+        let leader = self.leader()?;
+        let mut stream = connect(&leader)?;
+        let response = self.request(&mut stream, PRODUCE, 3, &body)?;
+        let mut response = Reader(&response);
+        for _ in 0..response.i32()? {
+            response.string()?;
+            for _ in 0..response.i32()? {
+                response.i32()?;
+                error(response.i16()?)?;
+                // Base offset and log append time
+                response.take(16)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn connect(addr: &str) -> Result<TcpStream> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    Ok(stream)
+}
+
+fn error(code: i16) -> Result<()> {
+    match code {
+        0 => Ok(()),
+        code => Err(format!("Kafka error code {code}").into()),
+    }
+}
+
+fn string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend((s.len() as i16).to_be_bytes());
+    buf.extend(s.as_bytes());
+}
+
+/// Record batch version 2 of the messages, keys optional
+fn record_batch(messages: &[(Option<String>, Vec<u8>)], timestamp: i64) -> Vec<u8> {
+    let mut records = vec![];
+    for (offset, (key, value)) in messages.iter().enumerate() {
+        let mut record = vec![0];
+        // Timestamp and offset deltas
+        varint(&mut record, 0);
+        varint(&mut record, offset as i64);
+        match key {
+            Some(key) => {
+                varint(&mut record, key.len() as i64);
+                record.extend(key.as_bytes());
+            }
+            None => varint(&mut record, -1),
+        }
+        varint(&mut record, value.len() as i64);
+        record.extend(value);
+        // No headers
+        varint(&mut record, 0);
+        varint(&mut records, record.len() as i64);
+        records.extend(record);
+    }
+    // From the attributes on, which the checksum covers
+    let mut checked = vec![];
+    checked.extend(0i16.to_be_bytes());
+    checked.extend((messages.len() as i32 - 1).to_be_bytes());
+    checked.extend(timestamp.to_be_bytes());
+    checked.extend(timestamp.to_be_bytes());
+    // No idempotence: producer id, epoch, and base sequence
+    checked.extend((-1i64).to_be_bytes());
+    checked.extend((-1i16).to_be_bytes());
+    checked.extend((-1i32).to_be_bytes());
+    checked.extend((messages.len() as i32).to_be_bytes());
+    checked.extend(records);
+    let mut batch = vec![];
+    batch.extend(0i64.to_be_bytes());
+    // The length after itself: leader epoch, magic, checksum, and the rest
+    batch.extend((4 + 1 + 4 + checked.len() as i32).to_be_bytes());
+    batch.extend((-1i32).to_be_bytes());
+    batch.push(2);
+    batch.extend(crc32c(&checked).to_be_bytes());
+    batch.extend(checked);
+    batch
+}
+
+/// Zigzag encoded
+fn varint(buf: &mut Vec<u8>, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// CRC-32C (Castagnoli), bitwise, as batches are small
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82f6_3b78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Big endian fields from a response
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err("Truncated Kafka response".into());
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+    fn i16(&mut self) -> Result<i16> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into()?))
+    }
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+    fn string(&mut self) -> Result<String> {
+        let len = self.i16()?;
+        let bytes = self.take(len.max(0) as usize)?;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::core::Collector;
+
+    #[test]
+    fn openmetrics() {
+        let counter = prometheus::IntCounter::new("nvml_test_total", "A test").unwrap();
+        counter.inc();
+        let kafka = Kafka::new(vec![], "gpus".into(), KafkaFormat::Openmetrics);
+        let messages = kafka.messages(&counter.collect(), 0).unwrap();
+        let [(None, body)] = &messages[..] else {
+            panic!("Not a single unkeyed message");
+        };
+        let body = String::from_utf8(body.clone()).unwrap();
+        assert!(body.contains("# TYPE nvml_test counter\n"), "{body}");
+        assert!(body.contains("\nnvml_test_total 1\n"), "{body}");
+        assert!(body.ends_with("# EOF\n"), "{body}");
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn zigzag_varints() {
+        for (value, encoded) in [(0, &[0x00][..]), (-1, &[0x01]), (64, &[0x80, 0x01])] {
+            let mut buf = vec![];
+            varint(&mut buf, value);
+            assert_eq!(buf, encoded, "{value}");
+        }
+    }
+
+    #[test]
+    fn single_record_batch() {
+        let messages = [(Some("k".to_owned()), b"v".to_vec())];
+        let timestamp = 0x0000_018b_cfe5_6800;
+        #[rustfmt::skip]
+        let expected = [
+            // Base offset, length, leader epoch, magic, CRC-32C
+            0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 58,
+            0xff, 0xff, 0xff, 0xff,
+            2,
+            0xe9, 0x9b, 0x8d, 0xd8,
+            // Attributes, last offset delta, first and max timestamp
+            0, 0,
+            0, 0, 0, 0,
+            0x00, 0x00, 0x01, 0x8b, 0xcf, 0xe5, 0x68, 0x00,
+            0x00, 0x00, 0x01, 0x8b, 0xcf, 0xe5, 0x68, 0x00,
+            // Producer id, epoch, base sequence, record count
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0xff, 0xff,
+            0xff, 0xff, 0xff, 0xff,
+            0, 0, 0, 1,
+            // Record length, attributes, timestamp and offset deltas, key, value, headers
+            0x10, 0, 0, 0, 0x02, b'k', 0x02, b'v', 0,
+        ];
+        assert_eq!(record_batch(&messages, timestamp), expected);
+    }
+}
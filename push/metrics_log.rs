@@ -0,0 +1,147 @@
+//! Appending samples to stdout or a file, for rigs that want a flat file rather than a TSDB
+//!
+//! Files are rotated once they reach their maximum size, to `<file>.1` and so on, keeping
+//! [`ROTATED`] old ones.
+
+use prometheus::proto::MetricFamily;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use super::{samples, Sample, Sink};
+use crate::{Result, GPU_LABELS};
+
+const ROTATED: usize = 5;
+const CSV_HEADER: &str = "timestamp,metric,uuid,name,pci,labels,value\n";
+
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum MetricsLogFormat {
+    /// `<timestamp> <metric>{<labels>} <value>`
+    Text,
+    /// A row per sample, with the labels beyond the GPU's as `label=value;…`
+    Csv,
+}
+
+pub struct MetricsLog {
+    format: MetricsLogFormat,
+    /// Of the metrics to log, all if empty
+    metrics: Vec<String>,
+    /// With the maximum size in bytes, stdout if none
+    file: Option<(PathBuf, u64)>,
+    header_written: bool,
+}
+
+impl MetricsLog {
+    pub fn new(
+        format: MetricsLogFormat,
+        metrics: Vec<String>,
+        file: Option<(PathBuf, u64)>,
+    ) -> MetricsLog {
+        MetricsLog {
+            format,
+            metrics,
+            file,
+            header_written: false,
+        }
+    }
+
+    fn line(&self, timestamp: &str, sample: &Sample) -> String {
+        let label = |name| {
+            let value = sample.labels.iter().find(|(n, _)| n == name);
+            value.map_or("", |(_, value)| value.as_str())
+        };
+        let others = sample
+            .labels
+            .iter()
+            .filter(|(name, _)| !GPU_LABELS.contains(&name.as_str()));
+        match self.format {
+            MetricsLogFormat::Text => {
+                let labels = sample
+                    .labels
+                    .iter()
+                    .map(|(name, value)| format!("{name}={value:?}"))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                match labels.is_empty() {
+                    true => format!("{timestamp} {} {}\n", sample.name, sample.value),
+                    false => format!("{timestamp} {}{{{labels}}} {}\n", sample.name, sample.value),
+                }
+            }
+            MetricsLogFormat::Csv => {
+                let others = others
+                    .map(|(name, value)| format!("{name}={value}"))
+                    .collect::<Vec<_>>()
+                    .join(";");
+                let fields = [
+                    timestamp,
+                    &sample.name,
+                    label(GPU_LABELS[0]),
+                    label(GPU_LABELS[1]),
+                    label(GPU_LABELS[2]),
+                    &others,
+                    &sample.value.to_string(),
+                ];
+                let fields = fields.map(csv_field);
+                format!("{}\n", fields.join(","))
+            }
+        }
+    }
+
+    /// The file to append to, rotated first if it's full
+    fn open(&mut self, path: &PathBuf, max_size: u64) -> Result<File> {
+        let size = std::fs::metadata(path).map_or(0, |meta| meta.len());
+        if size >= max_size {
+            let rotated = |i| PathBuf::from(format!("{}.{i}", path.display()));
+            for i in (1..ROTATED).rev() {
+                std::fs::rename(rotated(i), rotated(i + 1)).ok();
+            }
+            std::fs::rename(path, rotated(1))?;
+        }
+        self.header_written = size > 0 && size < max_size;
+        Ok(OpenOptions::new().create(true).append(true).open(path)?)
+    }
+}
+
+impl Sink for MetricsLog {
+    fn name(&self) -> &'static str {
+        "metrics log"
+    }
+
+    fn push(&mut self, families: &[MetricFamily]) -> Result<()> {
+        let timestamp = humantime::format_rfc3339_millis(SystemTime::now()).to_string();
+        let mut out = String::new();
+        for sample in samples(families) {
+            if self.metrics.is_empty() || self.metrics.contains(&sample.name) {
+                out.push_str(&self.line(&timestamp, &sample));
+            }
+        }
+        match self.file.clone() {
+            Some((path, max_size)) => {
+                let mut file = self.open(&path, max_size)?;
+                if self.format == MetricsLogFormat::Csv && !self.header_written {
+                    file.write_all(CSV_HEADER.as_bytes())?;
+                }
+                file.write_all(out.as_bytes())?;
+            }
+            None => {
+                let mut stdout = std::io::stdout().lock();
+                if self.format == MetricsLogFormat::Csv && !self.header_written {
+                    stdout.write_all(CSV_HEADER.as_bytes())?;
+                }
+                stdout.write_all(out.as_bytes())?;
+                stdout.flush()?;
+            }
+        }
+        self.header_written = true;
+        Ok(())
+    }
+}
+
+/// Quoted if it has to be
+pub fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_owned(),
+    }
+}
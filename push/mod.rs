@@ -0,0 +1,199 @@
+//! Periodically sending metrics somewhere, for hosts that can't be scraped
+
+use prometheus::proto::{MetricFamily, MetricType};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+use crate::Result;
+
+mod alerts;
+mod graphite;
+mod kafka;
+mod metrics_log;
+mod mqtt;
+mod otlp;
+mod pushgateway;
+mod remote_write;
+mod statsd;
+mod textfile;
+mod victoriametrics;
+mod zabbix;
+
+pub use alerts::{Alerts, Threshold};
+pub use graphite::{Graphite, GraphiteTags};
+pub use kafka::{Kafka, KafkaFormat};
+#[cfg(feature = "sqlite")]
+pub use metrics_log::csv_field;
+pub use metrics_log::{MetricsLog, MetricsLogFormat};
+pub use mqtt::Mqtt;
+pub use otlp::Otlp;
+pub use pushgateway::Pushgateway;
+pub use remote_write::RemoteWrite;
+pub use statsd::{StatsD, StatsDTags};
+pub use textfile::Textfile;
+pub use victoriametrics::VictoriaMetrics;
+pub use zabbix::Zabbix;
+
+pub trait Sink {
+    fn name(&self) -> &'static str;
+    fn push(&mut self, families: &[MetricFamily]) -> Result<()>;
+}
+
+struct Scheduled {
+    sink: Box<dyn Sink>,
+    interval: Duration,
+    next: Instant,
+}
+
+/// All configured sinks, each pushed to on its own interval
+#[derive(Default)]
+pub struct Outputs(Vec<Scheduled>);
+
+impl Outputs {
+    pub fn add(&mut self, sink: impl Sink + 'static, interval: Duration) {
+        self.0.push(Scheduled {
+            sink: Box::new(sink),
+            interval,
+            next: Instant::now(),
+        });
+    }
+
+    /// Time until the next sink is due, if there are any
+    pub fn timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.0
+            .iter()
+            .map(|s| s.next.saturating_duration_since(now))
+            .min()
+    }
+
+    pub fn due(&self) -> bool {
+        self.timeout() == Some(Duration::ZERO)
+    }
+
+    /// Push to all sinks that are due. Failures are logged, not fatal.
+    pub fn push(&mut self, families: &[MetricFamily]) {
+        let now = Instant::now();
+        for scheduled in self.0.iter_mut().filter(|s| s.next <= now) {
+            scheduled.next = now + scheduled.interval;
+            match scheduled.sink.push(families) {
+                Ok(()) => debug!(sink = scheduled.sink.name(), "Pushed metrics"),
+                Err(e) => warn!(
+                    sink = scheduled.sink.name(),
+                    "Failed to push metrics: {}", e
+                ),
+            }
+        }
+    }
+}
+
+/// For HTTP push targets. Pushes run on the serving thread, so a target that hangs mustn't hold
+/// it up for longer than `timeout`.
+pub fn agent(timeout: Duration) -> ureq::Agent {
+    ureq::AgentBuilder::new().timeout(timeout).build()
+}
+
+/// Request headers for authenticating against a push target, plus any `name=value` extras
+pub fn auth_headers(
+    username: Option<&str>,
+    password_file: Option<&Path>,
+    bearer_token_file: Option<&Path>,
+    extra: &[String],
+) -> Result<Vec<(String, String)>> {
+    use base64::Engine;
+    let mut headers = vec![];
+    if let Some(username) = username {
+        let password = match password_file {
+            Some(path) => std::fs::read_to_string(path)?.trim_end().to_owned(),
+            None => String::new(),
+        };
+        let credentials =
+            base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, password));
+        headers.push(("Authorization".into(), format!("Basic {}", credentials)));
+    }
+    if let Some(path) = bearer_token_file {
+        let token = std::fs::read_to_string(path)?;
+        headers.push((
+            "Authorization".into(),
+            format!("Bearer {}", token.trim_end()),
+        ));
+    }
+    for header in extra {
+        match header.split_once('=') {
+            Some((name, value)) => headers.push((name.to_owned(), value.to_owned())),
+            None => return Err(format!("Header {:?} is not of the form name=value", header).into()),
+        }
+    }
+    Ok(headers)
+}
+
+/// A single flattened value, as it would appear as a line in the text exposition format
+pub struct Sample {
+    pub name: String,
+    /// Type of the family the sample came from
+    pub metric_type: MetricType,
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+}
+
+/// Flatten metric families into samples, with histograms and summaries broken down into their
+/// `_bucket`/`_sum`/`_count` series
+pub fn samples(families: &[MetricFamily]) -> Vec<Sample> {
+    let mut samples = vec![];
+    for mf in families {
+        let name = mf.get_name();
+        for m in mf.get_metric() {
+            let labels = m
+                .get_label()
+                .iter()
+                .map(|l| (l.get_name().to_owned(), l.get_value().to_owned()))
+                .collect::<Vec<_>>();
+            let mut sample = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+                let mut labels = labels.clone();
+                labels.extend(extra.map(|(k, v)| (k.to_owned(), v)));
+                samples.push(Sample {
+                    name: format!("{}{}", name, suffix),
+                    metric_type: mf.get_field_type(),
+                    labels,
+                    value,
+                });
+            };
+            match mf.get_field_type() {
+                MetricType::COUNTER => sample("", None, m.get_counter().get_value()),
+                MetricType::GAUGE => sample("", None, m.get_gauge().get_value()),
+                MetricType::UNTYPED => sample("", None, m.get_untyped().get_value()),
+                MetricType::HISTOGRAM => {
+                    let h = m.get_histogram();
+                    for b in h.get_bucket() {
+                        let le = match b.get_upper_bound() {
+                            f64::INFINITY => "+Inf".to_owned(),
+                            bound => bound.to_string(),
+                        };
+                        sample("_bucket", Some(("le", le)), b.get_cumulative_count() as f64);
+                    }
+                    if !h
+                        .get_bucket()
+                        .iter()
+                        .any(|b| b.get_upper_bound() == f64::INFINITY)
+                    {
+                        let count = h.get_sample_count() as f64;
+                        sample("_bucket", Some(("le", "+Inf".to_owned())), count);
+                    }
+                    sample("_sum", None, h.get_sample_sum());
+                    sample("_count", None, h.get_sample_count() as f64);
+                }
+                MetricType::SUMMARY => {
+                    let s = m.get_summary();
+                    for q in s.get_quantile() {
+                        let quantile = q.get_quantile().to_string();
+                        sample("", Some(("quantile", quantile)), q.get_value());
+                    }
+                    sample("_sum", None, s.get_sample_sum());
+                    sample("_count", None, s.get_sample_count() as f64);
+                }
+            }
+        }
+    }
+    samples
+}
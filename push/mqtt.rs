@@ -0,0 +1,501 @@
+//! Publishing to an MQTT broker, MQTT 3.1.1 over TCP, with a message per sample
+//!
+//! The connection is kept between pushes, without keep alive, as pushes may be far apart. One
+//! that broke shows when publishing, and the next push reconnects.
+
+use prometheus::proto::MetricFamily;
+use serde_json::json;
+use std::collections::HashSet;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use super::{samples, Sample, Sink};
+use crate::{Result, GPU_LABELS};
+use nvml_exporter::collectors::PROCESS_LABELS;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const PUBREC: u8 = 0x50;
+const PUBREL: u8 = 0x62;
+const PUBCOMP: u8 = 0x70;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const DISCONNECT: u8 = 0xe0;
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Of a label value in a topic level
+const MAX_LEVEL: usize = 64;
+
+pub struct Mqtt {
+    broker: String,
+    /// Messages go to `<prefix>/<uuid>/<metric>`, followed by the values of the labels beyond
+    /// [`GPU_LABELS`], e.g. the fan
+    prefix: String,
+    qos: u8,
+    credentials: Option<(String, String)>,
+    /// Home Assistant's discovery prefix, if sensors are to be announced to it
+    discovery: Option<String>,
+    /// Of the sensors announced so far, on this connection and since Home Assistant came online
+    announced: HashSet<String>,
+    connection: Option<Connection>,
+}
+
+/// Metrics announced to Home Assistant: the metric name, the sensor's name and device class,
+/// the unit, and how to get there from the value
+const SENSORS: [(&str, &str, &str, &str, &str); 3] = [
+    (
+        "nvml_temp",
+        "Temperature",
+        "temperature",
+        "°C",
+        "{{ value }}",
+    ),
+    (
+        "nvml_power_usage_current_mw",
+        "Power",
+        "power",
+        "W",
+        "{{ value | float / 1000 }}",
+    ),
+    (
+        "nvml_fan_speed",
+        "Fan",
+        "",
+        "%",
+        "{{ (value | float * 100) | round(0) }}",
+    ),
+];
+
+impl Mqtt {
+    pub fn new(
+        broker: String,
+        prefix: String,
+        qos: u8,
+        credentials: Option<(String, String)>,
+    ) -> Mqtt {
+        Mqtt {
+            broker,
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            qos,
+            credentials,
+            discovery: None,
+            announced: HashSet::new(),
+            connection: None,
+        }
+    }
+
+    /// Announce temperature, power, and fan sensors to Home Assistant, with discovery messages
+    /// under the prefix, usually `homeassistant`
+    pub fn with_discovery(mut self, prefix: String) -> Mqtt {
+        self.discovery = Some(prefix.trim_end_matches('/').to_owned());
+        self
+    }
+
+    /// `<prefix>/<uuid>/<metric>/<other label values…>`, of the labels telling a metric's series
+    /// apart. A process' pid does, its command line and container only describe it.
+    fn topic(&self, sample: &Sample) -> String {
+        let uuid = label(sample, GPU_LABELS[0]).unwrap_or("host");
+        let mut topic = format!("{}/{}/{}", self.prefix, level(uuid), sample.name);
+        for (_, value) in sample.labels.iter().filter(|(name, _)| {
+            !GPU_LABELS.contains(&name.as_str()) && !PROCESS_LABELS[1..].contains(&name.as_str())
+        }) {
+            topic.push('/');
+            topic.push_str(&level(value));
+        }
+        topic
+    }
+
+    /// The discovery topic and config of the sensor the sample is for, if it is for one
+    fn sensor(&self, sample: &Sample, state_topic: String) -> Option<(String, serde_json::Value)> {
+        let discovery = self.discovery.as_ref()?;
+        let &(_, name, class, unit, template) = SENSORS.iter().find(|s| s.0 == sample.name)?;
+        let uuid = label(sample, GPU_LABELS[0])?;
+        let model = label(sample, GPU_LABELS[1]).unwrap_or_default();
+        let (name, object) = match label(sample, "fan") {
+            Some(fan) => (format!("{name} {fan}"), format!("fan_{fan}")),
+            None => (name.to_owned(), class.to_owned()),
+        };
+        // Node and object ids are limited to these
+        let id = |s: &str| s.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_");
+        let topic = format!("{discovery}/sensor/{}/{}/config", id(uuid), id(&object));
+        let mut config = json!({
+            "name": name,
+            "unique_id": format!("{}_{}", id(uuid), id(&object)),
+            "state_topic": state_topic,
+            "state_class": "measurement",
+            "unit_of_measurement": unit,
+            "value_template": template,
+            "device": {
+                "identifiers": [uuid],
+                "name": model,
+                "model": model,
+                "manufacturer": "NVIDIA",
+            },
+        });
+        if !class.is_empty() {
+            config["device_class"] = class.into();
+        }
+        Some((topic, config))
+    }
+
+    /// The connection, made unless there is one already
+    fn connect(&mut self) -> Result<Connection> {
+        if let Some(connection) = self.connection.take() {
+            return Ok(connection);
+        }
+        let mut connection = Connection::open(&self.broker, self.credentials.as_ref())?;
+        if let Some(discovery) = &self.discovery {
+            // Home Assistant's birth message, when it starts, and has forgotten the sensors
+            // unless their configs were retained
+            connection.status = Some(format!("{discovery}/status"));
+            connection.subscribe(&format!("{discovery}/status"))?;
+        }
+        // Whether the broker kept retained messages is anyone's guess
+        self.announced.clear();
+        Ok(connection)
+    }
+}
+
+impl Sink for Mqtt {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn push(&mut self, families: &[MetricFamily]) -> Result<()> {
+        let mut connection = self.connect()?;
+        connection.poll()?;
+        if std::mem::take(&mut connection.online) {
+            self.announced.clear();
+        }
+        for sample in samples(families) {
+            let topic = self.topic(&sample);
+            // Retained, for Home Assistant to find when it starts
+            if let Some((config_topic, config)) = self.sensor(&sample, topic.clone()) {
+                if !self.announced.contains(&config_topic) {
+                    let config = config.to_string();
+                    connection.publish(&config_topic, config.as_bytes(), self.qos, true)?;
+                    self.announced.insert(config_topic);
+                }
+            }
+            let payload = sample.value.to_string();
+            connection.publish(&topic, payload.as_bytes(), self.qos, false)?;
+        }
+        // Only kept if it worked
+        self.connection = Some(connection);
+        Ok(())
+    }
+}
+
+fn label<'a>(sample: &'a Sample, name: &str) -> Option<&'a str> {
+    sample
+        .labels
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// A topic level, of letters, digits, `-`, `.`, and `_`, and at most [`MAX_LEVEL`] of them
+fn level(value: &str) -> String {
+    match value {
+        "" => "_".into(),
+        value => value
+            .chars()
+            .take(MAX_LEVEL)
+            .map(|c| match c.is_ascii_alphanumeric() || "-._".contains(c) {
+                true => c,
+                false => '_',
+            })
+            .collect(),
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    packet_id: u16,
+    /// Home Assistant's status topic, if subscribed to
+    status: Option<String>,
+    /// Whether Home Assistant said it's online on the status topic, since the last look
+    online: bool,
+}
+
+impl Connection {
+    fn open(broker: &str, credentials: Option<&(String, String)>) -> Result<Connection> {
+        let stream = TcpStream::connect(broker)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut connection = Connection {
+            stream,
+            packet_id: 0,
+            status: None,
+            online: false,
+        };
+        let mut body = vec![];
+        string(&mut body, b"MQTT");
+        // Protocol level 4 is 3.1.1, and a clean session
+        body.push(4);
+        let mut flags = 0x02;
+        if credentials.is_some() {
+            flags |= 0x80 | 0x40;
+        }
+        body.push(flags);
+        // No keep alive, pushes may be further apart than brokers allow
+        body.extend(0u16.to_be_bytes());
+        let client_id = format!("{}-{}", env!("CARGO_PKG_NAME"), std::process::id());
+        string(&mut body, client_id.as_bytes());
+        if let Some((username, password)) = credentials {
+            string(&mut body, username.as_bytes());
+            string(&mut body, password.as_bytes());
+        }
+        connection.send(CONNECT, &body)?;
+        let ack = connection.receive(CONNACK)?;
+        match ack.get(1) {
+            Some(0) => Ok(connection),
+            Some(code) => Err(format!("MQTT broker refused the connection with {code}").into()),
+            None => Err("Malformed CONNACK".into()),
+        }
+    }
+
+    /// Publish, waiting for the broker's acknowledgement with QoS 1 and 2
+    fn publish(&mut self, topic: &str, payload: &[u8], qos: u8, retain: bool) -> Result<()> {
+        let mut body = vec![];
+        string(&mut body, topic.as_bytes());
+        if qos > 0 {
+            self.packet_id = self.packet_id.checked_add(1).unwrap_or(1);
+            body.extend(self.packet_id.to_be_bytes());
+        }
+        body.extend(payload);
+        self.send(PUBLISH | qos << 1 | u8::from(retain), &body)?;
+        let id = self.packet_id.to_be_bytes();
+        match qos {
+            0 => (),
+            1 => self.acknowledged(PUBACK, id)?,
+            _ => {
+                self.acknowledged(PUBREC, id)?;
+                self.send(PUBREL, &id)?;
+                self.acknowledged(PUBCOMP, id)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn subscribe(&mut self, topic: &str) -> Result<()> {
+        self.packet_id = self.packet_id.checked_add(1).unwrap_or(1);
+        let id = self.packet_id.to_be_bytes();
+        let mut body = id.to_vec();
+        string(&mut body, topic.as_bytes());
+        // QoS 0
+        body.push(0);
+        self.send(SUBSCRIBE, &body)?;
+        let ack = self.receive(SUBACK)?;
+        match ack.get(..3) {
+            Some([a, b, code]) if [*a, *b] == id && *code != 0x80 => Ok(()),
+            _ => Err(format!("MQTT broker refused the subscription to {topic}").into()),
+        }
+    }
+
+    /// Handle the messages that came in since, without waiting for more
+    fn poll(&mut self) -> Result<()> {
+        loop {
+            self.stream.set_nonblocking(true)?;
+            let mut header = [0];
+            let read = self.stream.read(&mut header);
+            self.stream.set_nonblocking(false)?;
+            match read {
+                Ok(0) => return Err("MQTT broker closed the connection".into()),
+                Ok(_) => {
+                    let body = self.body()?;
+                    self.incoming(header[0], &body)?;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// A packet the broker sent on its own: messages on the status topic
+    fn incoming(&mut self, header: u8, body: &[u8]) -> Result<()> {
+        if header & 0xf0 != PUBLISH {
+            return Err(format!("Unexpected MQTT packet {:#x}", header).into());
+        }
+        let len = body
+            .get(..2)
+            .map(|len| u16::from_be_bytes([len[0], len[1]]) as usize)
+            .ok_or("Malformed PUBLISH")?;
+        let topic = body.get(2..2 + len).ok_or("Malformed PUBLISH")?;
+        // Subscribed with QoS 0, so there's no packet id
+        let payload = &body[2 + len..];
+        if self.status.as_deref().map(str::as_bytes) == Some(topic) && payload == b"online" {
+            self.online = true;
+        }
+        Ok(())
+    }
+
+    fn acknowledged(&mut self, kind: u8, id: [u8; 2]) -> Result<()> {
+        match self.receive(kind)? == id {
+            true => Ok(()),
+            false => Err("MQTT acknowledgement for another message".into()),
+        }
+    }
+
+    fn send(&mut self, kind: u8, body: &[u8]) -> Result<()> {
+        let mut packet = vec![kind];
+        // Remaining length, 7 bits at a time
+        let mut len = body.len();
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+            packet.push(if len > 0 { byte | 0x80 } else { byte });
+            if len == 0 {
+                break;
+            }
+        }
+        packet.extend(body);
+        self.stream.write_all(&packet)?;
+        Ok(())
+    }
+
+    /// The body of the next packet, which has to be of the given kind, after any messages
+    fn receive(&mut self, kind: u8) -> Result<Vec<u8>> {
+        loop {
+            let mut header = [0];
+            self.stream.read_exact(&mut header)?;
+            let body = self.body()?;
+            if header[0] & 0xf0 == kind & 0xf0 {
+                return Ok(body);
+            }
+            self.incoming(header[0], &body)?;
+        }
+    }
+
+    /// The rest of a packet after its header byte
+    fn body(&mut self) -> Result<Vec<u8>> {
+        let (mut len, mut shift) = (0, 0);
+        loop {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte)?;
+            len |= ((byte[0] & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 || shift > 21 {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        self.stream.read_exact(&mut body)?;
+        Ok(body)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.send(DISCONNECT, &[]).ok();
+    }
+}
+
+fn string(buf: &mut Vec<u8>, s: &[u8]) {
+    buf.extend((s.len() as u16).to_be_bytes());
+    buf.extend(s);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::core::Collector;
+    use prometheus::proto::MetricType;
+    use std::net::TcpListener;
+
+    /// The header and body of the next packet, read like a broker would
+    fn packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut header = [0];
+        stream.read_exact(&mut header).unwrap();
+        let mut connection = Connection {
+            stream: stream.try_clone().unwrap(),
+            packet_id: 0,
+            status: None,
+            online: false,
+        };
+        let body = connection.body().unwrap();
+        // Not to send DISCONNECT
+        std::mem::forget(connection);
+        (header[0], body)
+    }
+
+    fn topic(stream: &mut TcpStream) -> String {
+        let (header, body) = packet(stream);
+        assert_eq!(header & 0xf0, PUBLISH);
+        let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+        String::from_utf8(body[2..2 + len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn rediscovery() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broker = listener.local_addr().unwrap().to_string();
+        let mut mqtt = Mqtt::new(broker, "nvml".into(), 0, None).with_discovery("ha".into());
+        let accepting = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(packet(&mut stream).0, CONNECT);
+            stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+            let (header, body) = packet(&mut stream);
+            assert_eq!(header, SUBSCRIBE);
+            assert_eq!(&body[4..13], b"ha/status");
+            stream.write_all(&[SUBACK, 3, body[0], body[1], 0]).unwrap();
+            stream
+        });
+        let temp = prometheus::IntGaugeVec::new(
+            prometheus::Opts::new("nvml_temp", "Temperature"),
+            &GPU_LABELS,
+        )
+        .unwrap();
+        temp.with_label_values(&["GPU-1", "A100", "0"]).set(57);
+        let families = temp.collect();
+        let config = "ha/sensor/GPU-1/temperature/config";
+
+        mqtt.push(&families).unwrap();
+        let mut stream = accepting.join().unwrap();
+        assert_eq!(topic(&mut stream), config);
+        assert_eq!(topic(&mut stream), "nvml/GPU-1/nvml_temp");
+        // Announced already
+        mqtt.push(&families).unwrap();
+        assert_eq!(topic(&mut stream), "nvml/GPU-1/nvml_temp");
+        // Home Assistant restarted
+        let mut online = vec![PUBLISH, 0];
+        string(&mut online, b"ha/status");
+        online.extend(b"online");
+        online[1] = online.len() as u8 - 2;
+        stream.write_all(&online).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        mqtt.push(&families).unwrap();
+        assert_eq!(topic(&mut stream), config);
+        assert_eq!(topic(&mut stream), "nvml/GPU-1/nvml_temp");
+    }
+
+    #[test]
+    fn process_topics() {
+        let mqtt = Mqtt::new(String::new(), "nvml".into(), 0, None);
+        let labels = [
+            ("uuid", "GPU-1"),
+            ("pid", "4242"),
+            ("command", "python train.py --data /mnt/set#1"),
+            ("user", "alice"),
+            ("engine", "sm"),
+        ];
+        let sample = Sample {
+            name: "nvml_process_utilization_ratio".into(),
+            metric_type: MetricType::GAUGE,
+            labels: labels
+                .iter()
+                .map(|&(name, value)| (name.into(), value.into()))
+                .collect(),
+            value: 0.5,
+        };
+        assert_eq!(
+            mqtt.topic(&sample),
+            "nvml/GPU-1/nvml_process_utilization_ratio/4242/sm"
+        );
+        assert_eq!(level("a/b+c#d e"), "a_b_c_d_e");
+        assert_eq!(level(&"x".repeat(100)).len(), MAX_LEVEL);
+        assert_eq!(level(""), "_");
+    }
+}
@@ -0,0 +1,150 @@
+//! OpenTelemetry OTLP/HTTP with the JSON encoding
+
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::Sink;
+use crate::Result;
+
+pub struct Otlp {
+    url: String,
+    headers: Vec<(String, String)>,
+    agent: ureq::Agent,
+    start: SystemTime,
+}
+
+impl Otlp {
+    /// `endpoint` is the collector's base URL, e.g. `http://collector:4318`
+    pub fn new(endpoint: &str, headers: Vec<(String, String)>, timeout: Duration) -> Otlp {
+        Otlp {
+            url: format!("{}/v1/metrics", endpoint.trim_end_matches('/')),
+            headers,
+            agent: super::agent(timeout),
+            start: SystemTime::now(),
+        }
+    }
+}
+
+impl Sink for Otlp {
+    fn name(&self) -> &'static str {
+        "otlp"
+    }
+
+    fn push(&mut self, families: &[MetricFamily]) -> Result<()> {
+        let now = nanos(SystemTime::now())?;
+        let start = nanos(self.start)?;
+        let metrics = families
+            .iter()
+            .map(|mf| metric(mf, &start, &now))
+            .collect::<Vec<_>>();
+        let body = json!({
+            "resourceMetrics": [{
+                "resource": {
+                    "attributes": [attribute("service.name", env!("CARGO_PKG_NAME"))],
+                },
+                "scopeMetrics": [{
+                    "scope": {
+                        "name": env!("CARGO_PKG_NAME"),
+                        "version": env!("CARGO_PKG_VERSION"),
+                    },
+                    "metrics": metrics,
+                }],
+            }],
+        });
+        let mut req = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", "application/json");
+        for (name, value) in &self.headers {
+            req = req.set(name, value);
+        }
+        req.send_bytes(&serde_json::to_vec(&body)?)?;
+        Ok(())
+    }
+}
+
+/// 64 bit integers are strings in OTLP's JSON mapping
+fn nanos(time: SystemTime) -> Result<String> {
+    Ok(time.duration_since(UNIX_EPOCH)?.as_nanos().to_string())
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn attributes(labels: &[LabelPair]) -> Vec<Value> {
+    labels
+        .iter()
+        .map(|l| attribute(l.get_name(), l.get_value()))
+        .collect()
+}
+
+fn metric(mf: &MetricFamily, start: &str, now: &str) -> Value {
+    let points = mf.get_metric().iter().map(|m| {
+        let mut point = json!({
+            "attributes": attributes(m.get_label()),
+            "startTimeUnixNano": start,
+            "timeUnixNano": now,
+        });
+        let fields = match mf.get_field_type() {
+            MetricType::COUNTER => json!({ "asDouble": m.get_counter().get_value() }),
+            MetricType::GAUGE => json!({ "asDouble": m.get_gauge().get_value() }),
+            MetricType::UNTYPED => json!({ "asDouble": m.get_untyped().get_value() }),
+            MetricType::HISTOGRAM => {
+                let h = m.get_histogram();
+                let buckets = h
+                    .get_bucket()
+                    .iter()
+                    .filter(|b| b.get_upper_bound().is_finite())
+                    .collect::<Vec<_>>();
+                // OTLP wants per-bucket counts with an implicit +Inf bucket at the end
+                let mut counts = vec![];
+                let mut previous = 0;
+                for b in &buckets {
+                    counts.push((b.get_cumulative_count() - previous).to_string());
+                    previous = b.get_cumulative_count();
+                }
+                counts.push((h.get_sample_count() - previous).to_string());
+                json!({
+                    "count": h.get_sample_count().to_string(),
+                    "sum": h.get_sample_sum(),
+                    "bucketCounts": counts,
+                    "explicitBounds": buckets.iter().map(|b| b.get_upper_bound()).collect::<Vec<_>>(),
+                })
+            }
+            MetricType::SUMMARY => {
+                let s = m.get_summary();
+                json!({
+                    "count": s.get_sample_count().to_string(),
+                    "sum": s.get_sample_sum(),
+                    "quantileValues": s.get_quantile().iter().map(|q| json!({
+                        "quantile": q.get_quantile(),
+                        "value": q.get_value(),
+                    })).collect::<Vec<_>>(),
+                })
+            }
+        };
+        point
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        point
+    });
+    let points = points.collect::<Vec<_>>();
+    let data = match mf.get_field_type() {
+        MetricType::COUNTER => (
+            "sum",
+            json!({ "dataPoints": points, "aggregationTemporality": 2, "isMonotonic": true }),
+        ),
+        MetricType::GAUGE | MetricType::UNTYPED => ("gauge", json!({ "dataPoints": points })),
+        MetricType::HISTOGRAM => (
+            "histogram",
+            json!({ "dataPoints": points, "aggregationTemporality": 2 }),
+        ),
+        MetricType::SUMMARY => ("summary", json!({ "dataPoints": points })),
+    };
+    let mut metric = json!({ "name": mf.get_name(), "description": mf.get_help() });
+    metric[data.0] = data.1;
+    metric
+}
@@ -0,0 +1,39 @@
+use prometheus::proto::MetricFamily;
+use prometheus::{Encoder, TextEncoder};
+use std::time::Duration;
+
+use super::Sink;
+use crate::Result;
+
+/// Replaces a Pushgateway group, e.g. `http://pushgateway:9091/metrics/job/nvml`
+pub struct Pushgateway {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl Pushgateway {
+    pub fn new(url: String, timeout: Duration) -> Pushgateway {
+        Pushgateway {
+            url,
+            agent: super::agent(timeout),
+        }
+    }
+}
+
+impl Sink for Pushgateway {
+    fn name(&self) -> &'static str {
+        "pushgateway"
+    }
+
+    fn push(&mut self, families: &[MetricFamily]) -> Result<()> {
+        let encoder = TextEncoder::new();
+        let mut body = vec![];
+        encoder.encode(families, &mut body)?;
+        // PUT, so series that vanished here vanish from the group, too
+        self.agent
+            .put(&self.url)
+            .set("Content-Type", encoder.format_type())
+            .send_bytes(&body)?;
+        Ok(())
+    }
+}
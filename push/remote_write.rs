@@ -0,0 +1,87 @@
+//! Prometheus remote_write 1.0: snappy-compressed protobuf `WriteRequest`s
+
+use prometheus::proto::MetricFamily;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{samples, Sink};
+use crate::Result;
+
+pub struct RemoteWrite {
+    url: String,
+    headers: Vec<(String, String)>,
+    agent: ureq::Agent,
+}
+
+impl RemoteWrite {
+    /// `headers` are sent with every request, e.g. for authorization or tenant selection
+    pub fn new(url: String, headers: Vec<(String, String)>, timeout: Duration) -> RemoteWrite {
+        RemoteWrite {
+            url,
+            headers,
+            agent: super::agent(timeout),
+        }
+    }
+}
+
+impl Sink for RemoteWrite {
+    fn name(&self) -> &'static str {
+        "remote_write"
+    }
+
+    fn push(&mut self, families: &[MetricFamily]) -> Result<()> {
+        let timestamp: i64 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .as_millis()
+            .try_into()?;
+        let mut request = vec![];
+        for sample in samples(families) {
+            let mut series = vec![];
+            // Labels must be sorted by name, with __name__ being part of them
+            let mut labels = sample.labels;
+            labels.push(("__name__".to_owned(), sample.name));
+            labels.sort();
+            for (name, value) in labels {
+                let mut label = vec![];
+                bytes_field(&mut label, 1, name.as_bytes());
+                bytes_field(&mut label, 2, value.as_bytes());
+                bytes_field(&mut series, 1, &label);
+            }
+            let mut value = vec![];
+            // Sample.value, fixed64
+            varint(&mut value, 1 << 3 | 1);
+            value.extend_from_slice(&sample.value.to_le_bytes());
+            // Sample.timestamp, varint
+            varint(&mut value, 2 << 3);
+            varint(&mut value, timestamp as u64);
+            bytes_field(&mut series, 2, &value);
+            bytes_field(&mut request, 1, &series);
+        }
+        let body = snap::raw::Encoder::new().compress_vec(&request)?;
+        let mut req = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", "application/x-protobuf")
+            .set("Content-Encoding", "snappy")
+            .set("X-Prometheus-Remote-Write-Version", "0.1.0");
+        for (name, value) in &self.headers {
+            req = req.set(name, value);
+        }
+        req.send_bytes(&body)?;
+        Ok(())
+    }
+}
+
+fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// A length-delimited field: strings and embedded messages
+fn bytes_field(buf: &mut Vec<u8>, field: u64, data: &[u8]) {
+    varint(buf, field << 3 | 2);
+    varint(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
@@ -0,0 +1,106 @@
+use prometheus::proto::{MetricFamily, MetricType};
+use std::collections::HashMap;
+use std::net::UdpSocket;
+
+use super::{samples, Sink};
+use crate::Result;
+
+/// Keep datagrams below a typical MTU
+const MAX_DATAGRAM: usize = 1432;
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum StatsDTags {
+    /// DogStatsD style `|#label:value` tags
+    Dogstatsd,
+    /// Label values appended to the metric name, for plain StatsD
+    Name,
+}
+
+/// Sends gauges as `g` and counters as `c` increments over UDP
+pub struct StatsD {
+    socket: UdpSocket,
+    prefix: String,
+    tags: StatsDTags,
+    // Counters need to be sent as increments since the last push
+    counters: HashMap<String, f64>,
+}
+
+impl StatsD {
+    pub fn new(addr: &str, prefix: String, tags: StatsDTags) -> Result<StatsD> {
+        let socket = UdpSocket::bind(match addr.starts_with('[') {
+            true => "[::]:0",
+            false => "0.0.0.0:0",
+        })?;
+        socket.connect(addr)?;
+        Ok(StatsD {
+            socket,
+            prefix,
+            tags,
+            counters: HashMap::new(),
+        })
+    }
+}
+
+impl Sink for StatsD {
+    fn name(&self) -> &'static str {
+        "statsd"
+    }
+
+    fn push(&mut self, families: &[MetricFamily]) -> Result<()> {
+        let mut datagram = String::new();
+        for sample in samples(families) {
+            let mut line = format!("{}{}", self.prefix, sanitize(&sample.name));
+            let mut tags = String::new();
+            for (name, value) in &sample.labels {
+                match self.tags {
+                    StatsDTags::Name => {
+                        line.push('.');
+                        line.push_str(&sanitize(value));
+                    }
+                    StatsDTags::Dogstatsd => {
+                        tags.push(if tags.is_empty() { '#' } else { ',' });
+                        tags.push_str(&format!("{}:{}", name, sanitize(value)));
+                    }
+                }
+            }
+            let value = match sample.metric_type {
+                MetricType::COUNTER => {
+                    let previous = self.counters.insert(line.clone() + &tags, sample.value);
+                    match previous {
+                        // A reset starts counting from zero again
+                        Some(previous) if previous <= sample.value => {
+                            format!("{}|c", sample.value - previous)
+                        }
+                        Some(_) => format!("{}|c", sample.value),
+                        // No baseline yet
+                        None => continue,
+                    }
+                }
+                _ => format!("{}|g", sample.value),
+            };
+            line.push(':');
+            line.push_str(&value);
+            if !tags.is_empty() {
+                line.push('|');
+                line.push_str(&tags);
+            }
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+                self.socket.send(datagram.as_bytes())?;
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        if !datagram.is_empty() {
+            self.socket.send(datagram.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Replace characters that have meaning in the StatsD line format
+fn sanitize(s: &str) -> String {
+    s.replace([':', '|', '@', ',', '#', ' '], "_")
+}
@@ -0,0 +1,43 @@
+use prometheus::proto::MetricFamily;
+use prometheus::{Encoder, TextEncoder};
+use std::path::PathBuf;
+
+use super::Sink;
+use crate::Result;
+
+const FILE_NAME: &str = "nvml.prom";
+
+/// Writes a file for node_exporter's textfile collector
+pub struct Textfile {
+    dir: PathBuf,
+}
+
+impl Textfile {
+    pub fn new(dir: PathBuf) -> Textfile {
+        Textfile { dir }
+    }
+}
+
+impl Sink for Textfile {
+    fn name(&self) -> &'static str {
+        "textfile"
+    }
+
+    fn push(&mut self, families: &[MetricFamily]) -> Result<()> {
+        // node_exporter has its own process_* metrics and refuses duplicates
+        let families = families
+            .iter()
+            .filter(|mf| !mf.get_name().starts_with("process_"))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut body = vec![];
+        TextEncoder::new().encode(&families, &mut body)?;
+        // Write and rename, so the collector never sees a partial file
+        let tmp = self
+            .dir
+            .join(format!(".{}.{}", FILE_NAME, std::process::id()));
+        std::fs::write(&tmp, body)?;
+        std::fs::rename(&tmp, self.dir.join(FILE_NAME))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+//! VictoriaMetrics' import of the Prometheus text format, for single node instances without
+//! vmagent in front
+
+use prometheus::proto::MetricFamily;
+use prometheus::{Encoder, TextEncoder};
+use std::time::Duration;
+
+use super::Sink;
+use crate::Result;
+
+const PATH: &str = "/api/v1/import/prometheus";
+
+pub struct VictoriaMetrics {
+    url: String,
+    headers: Vec<(String, String)>,
+    agent: ureq::Agent,
+}
+
+impl VictoriaMetrics {
+    /// `url` is VictoriaMetrics' base URL, e.g. `http://victoriametrics:8428`. The tenant, as in
+    /// `account[:project]`, goes into the `AccountID` and `ProjectID` headers.
+    pub fn new(
+        url: &str,
+        tenant: Option<&str>,
+        mut headers: Vec<(String, String)>,
+        timeout: Duration,
+    ) -> VictoriaMetrics {
+        if let Some(tenant) = tenant {
+            let (account, project) = tenant.split_once(':').unwrap_or((tenant, "0"));
+            headers.push(("AccountID".into(), account.into()));
+            headers.push(("ProjectID".into(), project.into()));
+        }
+        VictoriaMetrics {
+            url: format!("{}{}", url.trim_end_matches('/'), PATH),
+            headers,
+            agent: super::agent(timeout),
+        }
+    }
+}
+
+impl Sink for VictoriaMetrics {
+    fn name(&self) -> &'static str {
+        "victoriametrics"
+    }
+
+    fn push(&mut self, families: &[MetricFamily]) -> Result<()> {
+        let encoder = TextEncoder::new();
+        let mut body = vec![];
+        encoder.encode(families, &mut body)?;
+        let mut req = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", encoder.format_type());
+        for (name, value) in &self.headers {
+            req = req.set(name, value);
+        }
+        req.send_bytes(&body)?;
+        Ok(())
+    }
+}
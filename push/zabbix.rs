@@ -0,0 +1,138 @@
+//! Zabbix' sender protocol, as zabbix_sender speaks it to the server's or proxy's trapper port
+//!
+//! Each sample becomes a value of the item with the mapped host and key, which have to be set up
+//! in Zabbix as trapper items.
+
+use prometheus::proto::MetricFamily;
+use serde_json::json;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+use super::{samples, Sample, Sink};
+use crate::{Result, GPU_LABELS};
+
+const HEADER: &[u8] = b"ZBXD\x01";
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Responses are a line of JSON, this is plenty
+const MAX_RESPONSE: u64 = 1 << 16;
+
+pub struct Zabbix {
+    /// host:port of the trapper, 10051 if not given
+    server: String,
+    /// Templates, see [`Zabbix::new`]
+    host: String,
+    key: String,
+    hostname: String,
+}
+
+impl Zabbix {
+    /// The host and key templates can contain `{metric}`, `{hostname}`, any `{<label>}` (empty
+    /// for the GPU's labels on host wide metrics), and `{params}`, the values of the labels beyond
+    /// the GPU's name and PCI address as item key parameters. An empty `[{params}]` is dropped, so
+    /// that metrics without labels have plain keys.
+    pub fn new(server: String, host: String, key: String, hostname: String) -> Zabbix {
+        let server = match server.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => server,
+            _ => format!("{server}:10051"),
+        };
+        Zabbix {
+            server,
+            host,
+            key,
+            hostname,
+        }
+    }
+
+    fn render(&self, template: &str, sample: &Sample) -> String {
+        let params = sample
+            .labels
+            .iter()
+            .filter(|(name, _)| !GPU_LABELS[1..].contains(&name.as_str()))
+            .map(|(_, value)| param(value))
+            .collect::<Vec<_>>()
+            .join(",");
+        let mut rendered = match params.is_empty() {
+            true => template.replace("[{params}]", ""),
+            false => template.to_owned(),
+        };
+        rendered = rendered
+            .replace("{params}", &params)
+            .replace("{metric}", &sample.name)
+            .replace("{hostname}", &self.hostname);
+        for (name, value) in &sample.labels {
+            rendered = rendered.replace(&format!("{{{name}}}"), value);
+        }
+        // Host wide metrics have no GPU
+        for name in GPU_LABELS {
+            rendered = rendered.replace(&format!("{{{name}}}"), "");
+        }
+        rendered
+    }
+}
+
+impl Sink for Zabbix {
+    fn name(&self) -> &'static str {
+        "zabbix"
+    }
+
+    fn push(&mut self, families: &[MetricFamily]) -> Result<()> {
+        let clock = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let data = samples(families)
+            .iter()
+            .map(|sample| {
+                json!({
+                    "host": self.render(&self.host, sample),
+                    "key": self.render(&self.key, sample),
+                    "value": sample.value.to_string(),
+                    "clock": clock,
+                })
+            })
+            .collect::<Vec<_>>();
+        if data.is_empty() {
+            return Ok(());
+        }
+        let request = json!({"request": "sender data", "data": data}).to_string();
+        let mut stream = TcpStream::connect(&self.server)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut packet = HEADER.to_vec();
+        packet.extend((request.len() as u64).to_le_bytes());
+        packet.extend(request.as_bytes());
+        stream.write_all(&packet)?;
+
+        let mut header = [0; 13];
+        stream.read_exact(&mut header)?;
+        if &header[..5] != HEADER {
+            return Err("Not a Zabbix response".into());
+        }
+        let len = u64::from_le_bytes(header[5..].try_into()?);
+        let mut response = vec![];
+        stream
+            .take(len.min(MAX_RESPONSE))
+            .read_to_end(&mut response)?;
+        let response: serde_json::Value = serde_json::from_slice(&response)?;
+        let info = response["info"].as_str().unwrap_or_default();
+        if response["response"] != "success" {
+            return Err(format!("Zabbix refused the values: {info}").into());
+        }
+        // E.g. "processed: 3; failed: 1; total: 4; seconds spent: 0.000055"
+        match info.contains("failed: 0;") {
+            true => debug!("Zabbix {}", info),
+            false => warn!(
+                "Zabbix didn't take all values, check the items exist: {}",
+                info
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// An item key parameter, quoted where it has to be
+fn param(value: &str) -> String {
+    match value.contains([',', '[', ']', '"', ' ']) {
+        true => format!("\"{}\"", value.replace('"', "\\\"")),
+        false => value.to_owned(),
+    }
+}
@@ -0,0 +1,209 @@
+//! NVML functions nvml-wrapper doesn't wrap, called through nvml-wrapper-sys
+
+use nvml_wrapper::error::{nvml_try, NvmlError};
+use nvml_wrapper::Device;
+use nvml_wrapper_sys::bindings::{
+    nvmlDeviceAttributes_t, nvmlDevice_t, nvmlGpmMetricsGet_t, nvmlGpmSample_t, nvmlGpmSupport_t,
+    nvmlReturn_t, NvmlLib, NVML_DEVICE_MIG_ENABLE, NVML_GPM_METRICS_GET_VERSION,
+    NVML_GPM_SUPPORT_VERSION,
+};
+use std::collections::HashMap;
+use std::ffi::c_uint;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+use crate::gpu::{FabricInfo, RemappedRows};
+
+/// A second handle on the library [`nvml_wrapper::Nvml`] was initialized from. NVML's state is
+/// per process, so this needs no initialization of its own.
+static LIB: OnceLock<Option<NvmlLib>> = OnceLock::new();
+
+/// Only the first call has an effect
+pub fn load(path: &Path) {
+    LIB.get_or_init(|| unsafe { NvmlLib::new(path) }.ok());
+}
+
+fn lib() -> Result<&'static NvmlLib, NvmlError> {
+    LIB.get()
+        .and_then(Option::as_ref)
+        .ok_or(NvmlError::FunctionNotFound)
+}
+
+/// Whether MIG is currently enabled
+pub fn mig_mode(device: &Device) -> Result<bool, NvmlError> {
+    let get = lib()?
+        .nvmlDeviceGetMigMode
+        .as_ref()
+        .map_err(|_| NvmlError::FunctionNotFound)?;
+    let (mut current, mut pending) = (0, 0);
+    nvml_try(unsafe { get(device.handle(), &mut current, &mut pending) })?;
+    Ok(current == NVML_DEVICE_MIG_ENABLE)
+}
+
+/// The MIG devices the GPU is partitioned into, by uuid, with their profile. The profile name is
+/// made up from the slices and memory like NVIDIA's, e.g. `1g.10gb`.
+pub fn mig_profiles(device: &Device) -> Result<Vec<(String, String)>, NvmlError> {
+    let lib = lib()?;
+    let missing = |_| NvmlError::FunctionNotFound;
+    let max_count = lib
+        .nvmlDeviceGetMaxMigDeviceCount
+        .as_ref()
+        .map_err(missing)?;
+    let by_index = lib
+        .nvmlDeviceGetMigDeviceHandleByIndex
+        .as_ref()
+        .map_err(missing)?;
+    let get_attributes = lib.nvmlDeviceGetAttributes_v2.as_ref().map_err(missing)?;
+    let mut count = 0;
+    nvml_try(unsafe { max_count(device.handle(), &mut count) })?;
+    let mut profiles = vec![];
+    for index in 0..count {
+        let mut handle = std::ptr::null_mut();
+        match nvml_try(unsafe { by_index(device.handle(), index, &mut handle) }) {
+            // Unused slot
+            Err(NvmlError::NotFound) => continue,
+            result => result?,
+        }
+        let mut attributes: nvmlDeviceAttributes_t = unsafe { std::mem::zeroed() };
+        nvml_try(unsafe { get_attributes(handle, &mut attributes) })?;
+        let mig = unsafe { Device::new(handle, device.nvml()) };
+        let memory = attributes.memorySizeMB.div_ceil(1024);
+        let profile = format!("{}g.{}gb", attributes.gpuInstanceSliceCount, memory);
+        profiles.push((mig.uuid()?, profile));
+    }
+    Ok(profiles)
+}
+
+pub fn remapped_rows(device: &Device) -> Result<RemappedRows, NvmlError> {
+    let get = lib()?
+        .nvmlDeviceGetRemappedRows
+        .as_ref()
+        .map_err(|_| NvmlError::FunctionNotFound)?;
+    let (mut correctable, mut uncorrectable, mut pending, mut failed) = (0, 0, 0, 0);
+    nvml_try(unsafe {
+        get(
+            device.handle(),
+            &mut correctable,
+            &mut uncorrectable,
+            &mut pending,
+            &mut failed,
+        )
+    })?;
+    Ok(RemappedRows {
+        correctable,
+        uncorrectable,
+        pending: pending != 0,
+        failed: failed != 0,
+    })
+}
+
+/// A GPM sample of a device, freed when dropped
+struct GpmSample(nvmlGpmSample_t);
+
+// Only ever used under the lock of GPM_SAMPLES
+unsafe impl Send for GpmSample {}
+
+impl Drop for GpmSample {
+    fn drop(&mut self) {
+        if let Ok(Ok(free)) = lib().map(|lib| lib.nvmlGpmSampleFree.as_ref()) {
+            unsafe { free(self.0) };
+        }
+    }
+}
+
+/// The previous sample of each device, by handle, for the metrics since
+static GPM_SAMPLES: OnceLock<Mutex<HashMap<usize, GpmSample>>> = OnceLock::new();
+
+pub fn gpm_metrics(device: &Device, ids: &[u32]) -> Result<Vec<Result<f64, NvmlError>>, NvmlError> {
+    let lib = lib()?;
+    let missing = |_| NvmlError::FunctionNotFound;
+    let query_support = lib.nvmlGpmQueryDeviceSupport.as_ref().map_err(missing)?;
+    let alloc = lib.nvmlGpmSampleAlloc.as_ref().map_err(missing)?;
+    let get_sample = lib.nvmlGpmSampleGet.as_ref().map_err(missing)?;
+    let get_metrics = lib.nvmlGpmMetricsGet.as_ref().map_err(missing)?;
+    let handle = unsafe { device.handle() };
+    let mut support = nvmlGpmSupport_t {
+        version: NVML_GPM_SUPPORT_VERSION,
+        isSupportedDevice: 0,
+    };
+    nvml_try(unsafe { query_support(handle, &mut support) })?;
+    if support.isSupportedDevice == 0 {
+        return Err(NvmlError::NotSupported);
+    }
+    let mut sample = std::ptr::null_mut();
+    nvml_try(unsafe { alloc(&mut sample) })?;
+    let sample = GpmSample(sample);
+    nvml_try(unsafe { get_sample(handle, sample.0) })?;
+    let current = sample.0;
+    let mut samples = GPM_SAMPLES.get_or_init(Default::default).lock().unwrap();
+    // Freed once the metrics are computed
+    let Some(previous) = samples.insert(handle as usize, sample) else {
+        return Err(NvmlError::NoData);
+    };
+    let mut get: nvmlGpmMetricsGet_t = unsafe { std::mem::zeroed() };
+    let ids = &ids[..ids.len().min(get.metrics.len())];
+    get.version = NVML_GPM_METRICS_GET_VERSION;
+    get.numMetrics = ids.len() as c_uint;
+    (get.sample1, get.sample2) = (previous.0, current);
+    for (metric, &id) in get.metrics.iter_mut().zip(ids) {
+        metric.metricId = id;
+    }
+    nvml_try(unsafe { get_metrics(&mut get) })?;
+    Ok(get.metrics[..ids.len()]
+        .iter()
+        .map(|metric| nvml_try(metric.nvmlReturn).map(|()| metric.value))
+        .collect())
+}
+
+/// `nvmlGpuFabricInfo_t`, which nvml-wrapper-sys predates, as are the functions below
+#[repr(C)]
+struct GpuFabricInfo {
+    cluster_uuid: [u8; 16],
+    status: nvmlReturn_t,
+    clique_id: c_uint,
+    state: u8,
+}
+
+pub fn fabric_info(device: &Device) -> Result<FabricInfo, NvmlError> {
+    type Get = unsafe extern "C" fn(nvmlDevice_t, *mut GpuFabricInfo) -> nvmlReturn_t;
+    let get = unsafe { lib()?.__library.get::<Get>(b"nvmlDeviceGetGpuFabricInfo\0") }
+        .map_err(|_| NvmlError::FunctionNotFound)?;
+    let mut info: GpuFabricInfo = unsafe { std::mem::zeroed() };
+    nvml_try(unsafe { get(device.handle(), &mut info) })?;
+    let state = match info.state {
+        1 => "not_started",
+        2 => "in_progress",
+        3 => "completed",
+        _ => return Err(NvmlError::NotSupported),
+    };
+    let hex = info.cluster_uuid.map(|b| format!("{:02x}", b)).concat();
+    let cluster_uuid = [
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..],
+    ]
+    .join("-");
+    Ok(FabricInfo {
+        cluster_uuid,
+        clique_id: info.clique_id,
+        state,
+        failed: state == "completed" && nvml_try(info.status).is_err(),
+    })
+}
+
+/// `nvmlC2cModeInfo_v1_t`
+#[repr(C)]
+struct C2cModeInfo {
+    is_c2c_enabled: c_uint,
+}
+
+pub fn c2c_mode(device: &Device) -> Result<bool, NvmlError> {
+    type Get = unsafe extern "C" fn(nvmlDevice_t, *mut C2cModeInfo) -> nvmlReturn_t;
+    let get = unsafe { lib()?.__library.get::<Get>(b"nvmlDeviceGetC2cModeInfoV\0") }
+        .map_err(|_| NvmlError::FunctionNotFound)?;
+    let mut info = C2cModeInfo { is_c2c_enabled: 0 };
+    nvml_try(unsafe { get(device.handle(), &mut info) })?;
+    Ok(info.is_c2c_enabled != 0)
+}
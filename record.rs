@@ -0,0 +1,263 @@
+//! Capturing raw device readings to a file, and serving them again later, e.g. to reproduce
+//! what a GPU in a bug report looked like without having the hardware
+
+use nvml_wrapper::enum_wrappers::device::{Clock, PerformanceState, TemperatureSensor};
+use nvml_wrapper::error::NvmlError;
+use nvml_wrapper::struct_wrappers::device::{MemoryInfo, ProcessInfo, Utilization};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::errors;
+use crate::gpu::{Gpu, CLOCKS};
+
+/// A reading, or the code of the error it failed with
+type Reading<T> = Result<T, String>;
+
+fn read<T>(result: Result<T, NvmlError>) -> Reading<T> {
+    result.map_err(|e| errors::code(&e).to_owned())
+}
+
+/// For readings that recordings made by older versions lack
+fn not_recorded<T>() -> Reading<T> {
+    read(Err(NvmlError::NotSupported))
+}
+
+/// For [`Gpu::memory_utilization`], which recordings made by older versions lack, as most
+/// backends have it
+fn recorded() -> bool {
+    true
+}
+
+/// Everything [`Gpu`] can tell about a device at one point in time
+#[derive(Serialize, Deserialize)]
+struct Sample {
+    uuid: Reading<String>,
+    name: Reading<String>,
+    pci_bus_id: Reading<String>,
+    index: Reading<u32>,
+    mig_mode: Reading<bool>,
+    memory_info: Reading<MemoryInfo>,
+    /// Up to and including the first failing fan
+    fan_speeds: Vec<Reading<u32>>,
+    temperature: Reading<u32>,
+    performance_state: Reading<PerformanceState>,
+    power_usage: Reading<u32>,
+    enforced_power_limit: Reading<u32>,
+    total_energy_consumption: Reading<u64>,
+    pcie_replay_counter: Reading<u32>,
+    #[serde(default = "not_recorded")]
+    utilization_rates: Reading<Utilization>,
+    #[serde(default = "recorded")]
+    memory_utilization: bool,
+    /// In the order of [`CLOCKS`]
+    #[serde(default)]
+    clocks: Vec<Reading<u32>>,
+    #[serde(default = "not_recorded")]
+    compute_processes: Reading<Vec<ProcessInfo>>,
+    #[serde(default = "not_recorded")]
+    graphics_processes: Reading<Vec<ProcessInfo>>,
+}
+
+impl Sample {
+    fn take(gpu: &dyn Gpu) -> Sample {
+        let mut fan_speeds = vec![];
+        while fan_speeds.last().is_none_or(Reading::is_ok) && fan_speeds.len() <= 10_000 {
+            fan_speeds.push(read(gpu.fan_speed(fan_speeds.len() as u32)));
+        }
+        Sample {
+            uuid: read(gpu.uuid()),
+            name: read(gpu.name()),
+            pci_bus_id: read(gpu.pci_bus_id()),
+            index: read(gpu.index()),
+            mig_mode: read(gpu.mig_mode()),
+            memory_info: read(gpu.memory_info()),
+            fan_speeds,
+            temperature: read(gpu.temperature(TemperatureSensor::Gpu)),
+            performance_state: read(gpu.performance_state()),
+            power_usage: read(gpu.power_usage()),
+            enforced_power_limit: read(gpu.enforced_power_limit()),
+            total_energy_consumption: read(gpu.total_energy_consumption()),
+            pcie_replay_counter: read(gpu.pcie_replay_counter()),
+            utilization_rates: read(gpu.utilization_rates()),
+            memory_utilization: gpu.memory_utilization(),
+            clocks: CLOCKS
+                .iter()
+                .map(|clock| read(gpu.clock_info(clock.clone())))
+                .collect(),
+            compute_processes: read(gpu.running_compute_processes()),
+            graphics_processes: read(gpu.running_graphics_processes()),
+        }
+    }
+}
+
+/// One line of a recording
+#[derive(Serialize, Deserialize)]
+struct Frame {
+    /// Since the start of the recording
+    elapsed_ms: u64,
+    devices: Vec<Sample>,
+}
+
+/// Write a frame for the given devices every `interval`, for `duration` or forever
+pub fn record(
+    devices: &[&dyn Gpu],
+    output: &Path,
+    interval: Duration,
+    duration: Option<Duration>,
+) -> crate::Result<()> {
+    let mut output = std::fs::File::create(output)?;
+    let started = Instant::now();
+    let mut next = started;
+    loop {
+        let frame = Frame {
+            elapsed_ms: started.elapsed().as_millis().try_into()?,
+            devices: devices.iter().map(|gpu| Sample::take(*gpu)).collect(),
+        };
+        let mut line = serde_json::to_vec(&frame)?;
+        line.push(b'\n');
+        output.write_all(&line)?;
+        next += interval;
+        if duration.is_some_and(|duration| next - started > duration) {
+            return Ok(());
+        }
+        std::thread::sleep(next.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// A loaded recording, played back in real time from when it was first loaded. After the last
+/// frame, its values are held.
+pub struct Recording {
+    frames: Vec<Frame>,
+    started: Instant,
+}
+
+static RECORDING: OnceLock<Arc<Recording>> = OnceLock::new();
+
+impl Recording {
+    /// Loaded only once, so playback continues across rediscovery
+    pub fn load(path: &Path) -> crate::Result<Arc<Recording>> {
+        if let Some(recording) = RECORDING.get() {
+            return Ok(recording.clone());
+        }
+        let mut frames = vec![];
+        for (i, line) in BufReader::new(std::fs::File::open(path)?)
+            .lines()
+            .enumerate()
+        {
+            let frame = serde_json::from_str(&line?)
+                .map_err(|e| format!("{}:{}: {}", path.display(), i + 1, e))?;
+            frames.push(frame);
+        }
+        if frames.is_empty() {
+            return Err(format!("{} is empty", path.display()).into());
+        }
+        let recording = Arc::new(Recording {
+            frames,
+            started: Instant::now(),
+        });
+        Ok(RECORDING.get_or_init(|| recording).clone())
+    }
+
+    pub fn device_count(&self) -> usize {
+        self.frames[0].devices.len()
+    }
+
+    fn current(&self) -> &Frame {
+        let elapsed: u64 = self
+            .started
+            .elapsed()
+            .as_millis()
+            .try_into()
+            .unwrap_or(u64::MAX);
+        let played = self.frames.partition_point(|f| f.elapsed_ms <= elapsed);
+        &self.frames[played.saturating_sub(1)]
+    }
+}
+
+/// The device at `index` in each frame of a recording
+pub struct ReplayGpu {
+    recording: Arc<Recording>,
+    index: usize,
+}
+
+impl ReplayGpu {
+    pub fn new(recording: Arc<Recording>, index: usize) -> ReplayGpu {
+        ReplayGpu { recording, index }
+    }
+
+    fn get<T: Clone>(
+        &self,
+        reading: impl Fn(&Sample) -> Option<&Reading<T>>,
+    ) -> Result<T, NvmlError> {
+        let frame = self.recording.current();
+        let sample = frame.devices.get(self.index).ok_or(NvmlError::GpuLost)?;
+        let reading = reading(sample).ok_or(NvmlError::InvalidArg)?;
+        reading.clone().map_err(|code| errors::from_code(&code))
+    }
+}
+
+impl Gpu for ReplayGpu {
+    fn uuid(&self) -> Result<String, NvmlError> {
+        self.get(|s| Some(&s.uuid))
+    }
+    fn name(&self) -> Result<String, NvmlError> {
+        self.get(|s| Some(&s.name))
+    }
+    fn pci_bus_id(&self) -> Result<String, NvmlError> {
+        self.get(|s| Some(&s.pci_bus_id))
+    }
+    fn index(&self) -> Result<u32, NvmlError> {
+        self.get(|s| Some(&s.index))
+    }
+    fn mig_mode(&self) -> Result<bool, NvmlError> {
+        self.get(|s| Some(&s.mig_mode))
+    }
+    fn memory_info(&self) -> Result<MemoryInfo, NvmlError> {
+        self.get(|s| Some(&s.memory_info))
+    }
+    fn fan_speed(&self, fan: u32) -> Result<u32, NvmlError> {
+        self.get(|s| s.fan_speeds.get(fan as usize))
+    }
+    fn temperature(&self, _: TemperatureSensor) -> Result<u32, NvmlError> {
+        self.get(|s| Some(&s.temperature))
+    }
+    fn performance_state(&self) -> Result<PerformanceState, NvmlError> {
+        self.get(|s| Some(&s.performance_state))
+    }
+    fn power_usage(&self) -> Result<u32, NvmlError> {
+        self.get(|s| Some(&s.power_usage))
+    }
+    fn enforced_power_limit(&self) -> Result<u32, NvmlError> {
+        self.get(|s| Some(&s.enforced_power_limit))
+    }
+    fn total_energy_consumption(&self) -> Result<u64, NvmlError> {
+        self.get(|s| Some(&s.total_energy_consumption))
+    }
+    fn pcie_replay_counter(&self) -> Result<u32, NvmlError> {
+        self.get(|s| Some(&s.pcie_replay_counter))
+    }
+    fn utilization_rates(&self) -> Result<Utilization, NvmlError> {
+        self.get(|s| Some(&s.utilization_rates))
+    }
+    fn memory_utilization(&self) -> bool {
+        let frame = self.recording.current();
+        let sample = frame.devices.get(self.index);
+        sample.is_none_or(|sample| sample.memory_utilization)
+    }
+    fn clock_info(&self, clock: Clock) -> Result<u32, NvmlError> {
+        let i = CLOCKS.iter().position(|c| *c == clock);
+        match self.get(|s| s.clocks.get(i?)) {
+            Err(NvmlError::InvalidArg) => Err(NvmlError::NotSupported),
+            reading => reading,
+        }
+    }
+    fn running_compute_processes(&self) -> Result<Vec<ProcessInfo>, NvmlError> {
+        self.get(|s| Some(&s.compute_processes))
+    }
+    fn running_graphics_processes(&self) -> Result<Vec<ProcessInfo>, NvmlError> {
+        self.get(|s| Some(&s.graphics_processes))
+    }
+}
@@ -0,0 +1,521 @@
+//! Just enough of RE2's syntax for relabeling: literals and escapes, `.`, classes, groups,
+//! alternation, and greedy or lazy repetition. Matches are anchored at both ends, like
+//! Prometheus' relabel regexes, and found by backtracking, which is fine for label values. Not
+//! for patterns like `(a+)+$` though, which backtrack exponentially, so a match gives up, and
+//! fails, after a budget of steps.
+
+use std::cell::Cell;
+use std::fmt;
+use tracing::warn;
+
+/// Plenty for label values, and still quick
+const STEPS: usize = 100_000;
+
+#[derive(Clone, Debug)]
+enum Node {
+    Char(char),
+    /// Anything but a newline
+    Any,
+    Class(Vec<(char, char)>, bool),
+    Start,
+    End,
+    Group(Box<Node>, Option<usize>),
+    Alternation(Vec<Node>),
+    Concatenation(Vec<Node>),
+    Repetition {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+}
+
+#[derive(Clone)]
+pub struct Regex {
+    pattern: String,
+    root: Node,
+    groups: usize,
+}
+
+/// Group 0 is the whole match, by char indices
+type Captures = Vec<Option<(usize, usize)>>;
+
+struct Input<'a> {
+    chars: &'a [char],
+    /// Left of the budget
+    steps: Cell<usize>,
+}
+
+impl Input<'_> {
+    fn get(&self, pos: usize) -> Option<char> {
+        self.chars.get(pos).copied()
+    }
+
+    fn len(&self) -> usize {
+        self.chars.len()
+    }
+
+    /// Whether there's budget left for another step
+    fn step(&self) -> bool {
+        let steps = self.steps.get();
+        self.steps.set(steps.saturating_sub(1));
+        steps > 0
+    }
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, String> {
+        let mut parser = Parser {
+            chars: pattern.chars().collect(),
+            pos: 0,
+            groups: 0,
+        };
+        let root = parser.alternation()?;
+        if parser.pos < parser.chars.len() {
+            return Err(format!("Unmatched ) in {pattern:?}"));
+        }
+        Ok(Regex {
+            pattern: pattern.to_owned(),
+            root,
+            groups: parser.groups,
+        })
+    }
+
+    pub fn is_match(&self, input: &str) -> bool {
+        self.captures(input).is_some()
+    }
+
+    /// The groups' matches, none if the whole input doesn't match
+    pub fn captures(&self, input: &str) -> Option<Vec<Option<String>>> {
+        let chars = input.chars().collect::<Vec<_>>();
+        let input = Input {
+            chars: &chars,
+            steps: Cell::new(STEPS),
+        };
+        let mut captures = vec![None; self.groups + 1];
+        let mut done = |pos: usize, _: &mut Captures| pos == chars.len();
+        if !matches(&self.root, &input, 0, &mut captures, &mut done) {
+            if input.steps.get() == 0 {
+                warn!(regex = ?self, "Gave up matching, too much backtracking");
+            }
+            return None;
+        }
+        captures[0] = Some((0, chars.len()));
+        let group =
+            |c: Option<(usize, usize)>| c.map(|(start, end)| chars[start..end].iter().collect());
+        Some(captures.into_iter().map(group).collect())
+    }
+
+    /// The replacement with `$1` or `${1}` replaced by the groups' matches, and `$$` by `$`
+    pub fn expand(replacement: &str, captures: &[Option<String>]) -> String {
+        let mut expanded = String::new();
+        let mut chars = replacement.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '$' {
+                expanded.push(c);
+                continue;
+            }
+            if chars.next_if_eq(&'$').is_some() {
+                expanded.push('$');
+                continue;
+            }
+            let braced = chars.next_if_eq(&'{').is_some();
+            let mut index = String::new();
+            while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                index.push(digit);
+            }
+            let closed = !braced || chars.next_if_eq(&'}').is_some();
+            match index.parse::<usize>() {
+                Ok(index) if closed => {
+                    let group = captures.get(index).cloned().flatten();
+                    expanded.push_str(&group.unwrap_or_default());
+                }
+                // Not a reference after all
+                _ => {
+                    expanded.push_str(if braced { "${" } else { "$" });
+                    expanded.push_str(&index);
+                }
+            }
+        }
+        expanded
+    }
+}
+
+impl fmt::Debug for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.pattern)
+    }
+}
+
+/// Whether the node matches at `pos` with the rest, `next`, matching after it
+fn matches(
+    node: &Node,
+    input: &Input,
+    pos: usize,
+    captures: &mut Captures,
+    next: &mut dyn FnMut(usize, &mut Captures) -> bool,
+) -> bool {
+    if !input.step() {
+        return false;
+    }
+    let char_if = |f: &dyn Fn(char) -> bool| input.get(pos).is_some_and(f);
+    match node {
+        Node::Char(expected) => char_if(&|c| c == *expected) && next(pos + 1, captures),
+        Node::Any => char_if(&|c| c != '\n') && next(pos + 1, captures),
+        Node::Class(ranges, negated) => {
+            let in_class = |c| ranges.iter().any(|&(from, to)| (from..=to).contains(&c));
+            char_if(&|c| in_class(c) != *negated) && next(pos + 1, captures)
+        }
+        Node::Start => pos == 0 && next(pos, captures),
+        Node::End => pos == input.len() && next(pos, captures),
+        Node::Group(node, None) => matches(node, input, pos, captures, next),
+        Node::Group(node, Some(index)) => {
+            let index = *index;
+            matches(node, input, pos, captures, &mut |end, captures| {
+                let before = captures[index].replace((pos, end));
+                next(end, captures) || {
+                    captures[index] = before;
+                    false
+                }
+            })
+        }
+        Node::Alternation(alternatives) => alternatives
+            .iter()
+            .any(|node| matches(node, input, pos, captures, next)),
+        Node::Concatenation(nodes) => concatenation(nodes, input, pos, captures, next),
+        Node::Repetition {
+            node,
+            min,
+            max,
+            greedy,
+        } => repetition(node, (*min, *max, *greedy), 0, input, pos, captures, next),
+    }
+}
+
+fn concatenation(
+    nodes: &[Node],
+    input: &Input,
+    pos: usize,
+    captures: &mut Captures,
+    next: &mut dyn FnMut(usize, &mut Captures) -> bool,
+) -> bool {
+    match nodes.split_first() {
+        None => next(pos, captures),
+        Some((first, rest)) => matches(first, input, pos, captures, &mut |pos, captures| {
+            concatenation(rest, input, pos, captures, next)
+        }),
+    }
+}
+
+fn repetition(
+    node: &Node,
+    (min, max, greedy): (usize, Option<usize>, bool),
+    count: usize,
+    input: &Input,
+    pos: usize,
+    captures: &mut Captures,
+    next: &mut dyn FnMut(usize, &mut Captures) -> bool,
+) -> bool {
+    let again = |captures: &mut Captures, next: &mut dyn FnMut(usize, &mut Captures) -> bool| {
+        max.is_none_or(|max| count < max)
+            && matches(node, input, pos, captures, &mut |end, captures| {
+                // Once the minimum is reached, only iterations that consume something, or
+                // something like (a*)* would go on forever
+                (end != pos || count < min)
+                    && repetition(
+                        node,
+                        (min, max, greedy),
+                        count + 1,
+                        input,
+                        end,
+                        captures,
+                        next,
+                    )
+            })
+    };
+    match (count < min, greedy) {
+        (true, _) => again(captures, next),
+        (false, true) => again(captures, next) || next(pos, captures),
+        // Lazy, as little as possible
+        (false, false) => next(pos, captures) || again(captures, next),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    groups: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let eaten = self.peek() == Some(c);
+        self.pos += usize::from(eaten);
+        eaten
+    }
+
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut alternatives = vec![self.concatenation()?];
+        while self.eat('|') {
+            alternatives.push(self.concatenation()?);
+        }
+        Ok(match alternatives.len() {
+            1 => alternatives.remove(0),
+            _ => Node::Alternation(alternatives),
+        })
+    }
+
+    fn concatenation(&mut self) -> Result<Node, String> {
+        let mut nodes = vec![];
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantified(atom)?);
+        }
+        Ok(Node::Concatenation(nodes))
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let c = self.peek().ok_or("Unexpected end of regex")?;
+        self.pos += 1;
+        Ok(match c {
+            '(' => {
+                let index = match self.eat('?') {
+                    true if self.eat(':') => None,
+                    true => return Err("Only (?:…) groups are supported".into()),
+                    false => {
+                        self.groups += 1;
+                        Some(self.groups)
+                    }
+                };
+                let node = self.alternation()?;
+                if !self.eat(')') {
+                    return Err("Missing )".into());
+                }
+                Node::Group(Box::new(node), index)
+            }
+            '[' => self.class()?,
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '\\' => match self.escape()? {
+                Ok(c) => Node::Char(c),
+                Err(class) => class,
+            },
+            '*' | '+' | '?' => return Err(format!("Nothing to repeat with {c}")),
+            c => Node::Char(c),
+        })
+    }
+
+    /// The escaped char, or the class it stands for
+    fn escape(&mut self) -> Result<Result<char, Node>, String> {
+        let c = self.peek().ok_or("Trailing \\")?;
+        self.pos += 1;
+        let digits = vec![('0', '9')];
+        let word = vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+        let space = vec![('\t', '\n'), ('\x0c', '\r'), (' ', ' ')];
+        Ok(match c {
+            'd' => Err(Node::Class(digits, false)),
+            'D' => Err(Node::Class(digits, true)),
+            'w' => Err(Node::Class(word, false)),
+            'W' => Err(Node::Class(word, true)),
+            's' => Err(Node::Class(space, false)),
+            'S' => Err(Node::Class(space, true)),
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            c if c.is_ascii_alphanumeric() => return Err(format!("Unsupported escape \\{c}")),
+            c => Ok(c),
+        })
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.eat('^');
+        let mut ranges = vec![];
+        let mut first = true;
+        loop {
+            let c = self.peek().ok_or("Missing ]")?;
+            self.pos += 1;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let from = match c {
+                '\\' => match self.escape()? {
+                    Ok(c) => c,
+                    Err(Node::Class(class, false)) => {
+                        ranges.extend(class);
+                        continue;
+                    }
+                    Err(_) => return Err("Negated classes within classes are unsupported".into()),
+                },
+                c => c,
+            };
+            match (self.peek(), self.chars.get(self.pos + 1)) {
+                (Some('-'), Some(&to)) if to != ']' => {
+                    self.pos += 2;
+                    let to = match to {
+                        '\\' => self.escape()?.map_err(|_| "Class as end of a range")?,
+                        to => to,
+                    };
+                    if to < from {
+                        return Err(format!("Invalid range {from}-{to}"));
+                    }
+                    ranges.push((from, to));
+                }
+                _ => ranges.push((from, from)),
+            }
+        }
+        Ok(Node::Class(ranges, negated))
+    }
+
+    fn quantified(&mut self, mut node: Node) -> Result<Node, String> {
+        loop {
+            let (min, max) = match self.peek() {
+                Some('{') => match self.counts() {
+                    Some(counts) => counts,
+                    // A literal {, like RE2
+                    None => return Ok(node),
+                },
+                Some(c @ ('*' | '+' | '?')) => {
+                    self.pos += 1;
+                    match c {
+                        '*' => (0, None),
+                        '+' => (1, None),
+                        _ => (0, Some(1)),
+                    }
+                }
+                _ => return Ok(node),
+            };
+            if max.is_some_and(|max| max < min) {
+                return Err(format!("Invalid repetition {{{min},{max:?}}}"));
+            }
+            if matches!(node, Node::Start | Node::End | Node::Repetition { .. }) {
+                return Err("Nothing to repeat".into());
+            }
+            let greedy = !self.eat('?');
+            node = Node::Repetition {
+                node: Box::new(node),
+                min,
+                max,
+                greedy,
+            };
+        }
+    }
+
+    /// `{n}`, `{n,}`, or `{n,m}`, consumed if it is one
+    fn counts(&mut self) -> Option<(usize, Option<usize>)> {
+        let rest = self.chars[self.pos + 1..].iter().collect::<String>();
+        let (counts, _) = rest.split_once('}')?;
+        let parsed = match counts.split_once(',') {
+            None => counts.parse().ok().map(|n| (n, Some(n))),
+            Some((min, "")) => min.parse().ok().map(|min| (min, None)),
+            Some((min, max)) => min.parse().ok().zip(max.parse().ok().map(Some)),
+        };
+        if parsed.is_some() {
+            self.pos += counts.chars().count() + 2;
+        }
+        parsed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captures(pattern: &str, input: &str) -> Option<Vec<Option<String>>> {
+        Regex::new(pattern).unwrap().captures(input)
+    }
+
+    #[test]
+    fn anchored() {
+        let regex = Regex::new("gpu").unwrap();
+        assert!(regex.is_match("gpu"));
+        assert!(!regex.is_match("gpu0"));
+        assert!(!regex.is_match("a gpu"));
+        assert!(Regex::new("gpu.*").unwrap().is_match("gpu0"));
+        assert!(Regex::new("^gpu$").unwrap().is_match("gpu"));
+    }
+
+    #[test]
+    fn alternation() {
+        let regex = Regex::new("nvml_(temp|power)_.*|up").unwrap();
+        assert!(regex.is_match("nvml_temp_max"));
+        assert!(regex.is_match("nvml_power_limit"));
+        assert!(regex.is_match("up"));
+        assert!(!regex.is_match("nvml_fan_speed"));
+        assert!(!regex.is_match("upper"));
+    }
+
+    #[test]
+    fn classes() {
+        let regex = Regex::new("[a-c0-9_]+").unwrap();
+        assert!(regex.is_match("ab_09"));
+        assert!(!regex.is_match("abd"));
+        assert!(Regex::new("[^-]+").unwrap().is_match("GPU"));
+        assert!(!Regex::new("[^-]+").unwrap().is_match("GPU-1"));
+        assert!(Regex::new(r"\d{2,3}\s\w+").unwrap().is_match("100 MHz"));
+        assert!(!Regex::new(r"\d{2,3}").unwrap().is_match("1000"));
+        assert!(Regex::new(r"[\d.]+").unwrap().is_match("535.104"));
+    }
+
+    #[test]
+    fn groups() {
+        assert_eq!(
+            captures("(GPU)-(\\w+)-(?:.*)", "GPU-abc-def"),
+            Some(vec![
+                Some("GPU-abc-def".into()),
+                Some("GPU".into()),
+                Some("abc".into())
+            ])
+        );
+        // Unmatched groups are none
+        assert_eq!(
+            captures("(a)|(b)", "b"),
+            Some(vec![Some("b".into()), None, Some("b".into())])
+        );
+        // Greedy and lazy
+        assert_eq!(
+            captures("(.*)-(.*)", "a-b-c").unwrap()[1].as_deref(),
+            Some("a-b")
+        );
+        assert_eq!(
+            captures("(.*?)-(.*)", "a-b-c").unwrap()[1].as_deref(),
+            Some("a")
+        );
+        assert_eq!(captures("(a)", "b"), None);
+    }
+
+    #[test]
+    fn expand() {
+        let captures = captures("(\\w+)-(\\w+)", "GPU-abc").unwrap();
+        assert_eq!(Regex::expand("$2_$1", &captures), "abc_GPU");
+        assert_eq!(Regex::expand("${1}x", &captures), "GPUx");
+        assert_eq!(Regex::expand("$0 costs $$5", &captures), "GPU-abc costs $5");
+        // Groups that don't exist are empty, and what isn't a reference stays
+        assert_eq!(Regex::expand("$3|$x|${1", &captures), "|$x|${1");
+    }
+
+    #[test]
+    fn invalid() {
+        for pattern in ["(", "a)", "[a", "*", "a{2,1}", "(?P<n>a)", r"\p", "[z-a]"] {
+            assert!(Regex::new(pattern).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn backtracking() {
+        // Exponential without the budget
+        let regex = Regex::new("(a+)+$").unwrap();
+        let input = format!("{}b", "a".repeat(40));
+        let start = std::time::Instant::now();
+        assert!(!regex.is_match(&input));
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+        assert!(regex.is_match(&"a".repeat(40)));
+    }
+}
@@ -0,0 +1,338 @@
+//! Rewriting metrics before they are exposed or pushed, configured like Prometheus'
+//! `metric_relabel_configs`, for naming schemes that can't wait for the scraper, which pushes
+//! don't have
+//!
+//! The metric name is the `__name__` label. Series a rule renames move to a family of the new
+//! name, with the old one's help and type.
+
+use prometheus::proto::{LabelPair, MetricFamily};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::regex::Regex;
+use crate::Result;
+
+const NAME: &str = "__name__";
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    /// Set the target label to the replacement, if the regex matches
+    #[default]
+    Replace,
+    /// Only series where the regex matches
+    Keep,
+    /// No series where the regex matches
+    Drop,
+    /// Remove the labels whose names match
+    LabelDrop,
+    /// Remove the labels whose names don't match
+    LabelKeep,
+    /// Copy the labels whose names match to the replacement's name
+    LabelMap,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    #[serde(default)]
+    source_labels: Vec<String>,
+    #[serde(default)]
+    separator: Option<String>,
+    #[serde(default)]
+    regex: Option<String>,
+    #[serde(default)]
+    target_label: Option<String>,
+    #[serde(default)]
+    replacement: Option<String>,
+    #[serde(default)]
+    action: Action,
+}
+
+#[derive(Clone)]
+struct Rule {
+    source_labels: Vec<String>,
+    separator: String,
+    regex: Regex,
+    target_label: String,
+    replacement: String,
+    action: Action,
+}
+
+#[derive(Clone)]
+pub struct Rules(Vec<Rule>);
+
+impl Rules {
+    /// A YAML list of rules, as under `metric_relabel_configs`
+    pub fn load(path: &str) -> Result<Rules> {
+        let path = Path::new(path);
+        Rules::parse(&std::fs::read_to_string(path)?, path)
+    }
+
+    fn parse(yaml: &str, path: &Path) -> Result<Rules> {
+        let configs: Vec<RuleConfig> = serde_yaml::from_str(yaml)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+        let mut rules = vec![];
+        for (i, config) in configs.into_iter().enumerate() {
+            let regex = config.regex.as_deref().unwrap_or("(.*)");
+            let regex = Regex::new(regex)
+                .map_err(|e| format!("Rule {} of {}: {}", i + 1, path.display(), e))?;
+            if config.action == Action::Replace && config.target_label.is_none() {
+                return Err(format!(
+                    "Rule {} of {} replaces without a target_label",
+                    i + 1,
+                    path.display()
+                )
+                .into());
+            }
+            rules.push(Rule {
+                source_labels: config.source_labels,
+                separator: config.separator.unwrap_or_else(|| ";".into()),
+                regex,
+                target_label: config.target_label.unwrap_or_default(),
+                replacement: config.replacement.unwrap_or_else(|| "$1".into()),
+                action: config.action,
+            });
+        }
+        Ok(Rules(rules))
+    }
+
+    /// The series' labels after the rules, none if one drops it
+    fn apply(&self, mut labels: BTreeMap<String, String>) -> Option<BTreeMap<String, String>> {
+        for rule in &self.0 {
+            let value = rule
+                .source_labels
+                .iter()
+                .map(|name| labels.get(name).map_or("", String::as_str))
+                .collect::<Vec<_>>()
+                .join(&rule.separator);
+            match rule.action {
+                Action::Replace => {
+                    let Some(captures) = rule.regex.captures(&value) else {
+                        continue;
+                    };
+                    let target = Regex::expand(&rule.target_label, &captures);
+                    match Regex::expand(&rule.replacement, &captures) {
+                        replacement if replacement.is_empty() => labels.remove(&target),
+                        replacement => labels.insert(target, replacement),
+                    };
+                }
+                Action::Keep if !rule.regex.is_match(&value) => return None,
+                Action::Drop if rule.regex.is_match(&value) => return None,
+                Action::Keep | Action::Drop => (),
+                Action::LabelDrop => {
+                    labels.retain(|name, _| name == NAME || !rule.regex.is_match(name))
+                }
+                Action::LabelKeep => {
+                    labels.retain(|name, _| name == NAME || rule.regex.is_match(name))
+                }
+                Action::LabelMap => {
+                    let mapped = labels
+                        .iter()
+                        .filter_map(|(name, value)| {
+                            let captures = rule.regex.captures(name)?;
+                            Some((Regex::expand(&rule.replacement, &captures), value.clone()))
+                        })
+                        .collect::<Vec<_>>();
+                    labels.extend(mapped);
+                }
+            }
+        }
+        // Without a name, it's nothing
+        labels
+            .get(NAME)
+            .is_some_and(|name| !name.is_empty())
+            .then_some(labels)
+    }
+
+    /// Apply the rules to every series, regrouping them into families by their new names
+    pub fn relabel(&self, families: &mut Vec<MetricFamily>) {
+        let mut relabeled: BTreeMap<String, MetricFamily> = BTreeMap::new();
+        for mut mf in families.drain(..) {
+            for mut metric in mf.take_metric().into_iter() {
+                let mut labels = metric
+                    .take_label()
+                    .into_iter()
+                    .map(|mut l| (l.take_name(), l.take_value()))
+                    .collect::<BTreeMap<_, _>>();
+                labels.insert(NAME.into(), mf.get_name().into());
+                let Some(mut labels) = self.apply(labels) else {
+                    continue;
+                };
+                let name = labels.remove(NAME).unwrap_or_default();
+                let pairs = labels.into_iter().map(|(name, value)| {
+                    let mut pair = LabelPair::default();
+                    pair.set_name(name);
+                    pair.set_value(value);
+                    pair
+                });
+                metric.set_label(pairs.collect());
+                let family = relabeled.entry(name.clone()).or_insert_with(|| {
+                    let mut family = mf.clone();
+                    family.set_name(name);
+                    family
+                });
+                family.mut_metric().push(metric);
+            }
+        }
+        families.extend(relabeled.into_values());
+    }
+
+    /// The name and label names the rules give a series of the metric with the labels, none if
+    /// they drop it. Each label's value is its name, so only rules that rename regardless of the
+    /// values, as is usual for naming schemes, are followed.
+    pub fn schema(&self, name: &str, labels: &[&str]) -> Option<(String, Vec<Option<String>>)> {
+        let mut series = labels
+            .iter()
+            .map(|&label| (label.to_owned(), label.to_owned()))
+            .collect::<BTreeMap<_, _>>();
+        series.insert(NAME.into(), name.into());
+        let mut series = self.apply(series)?;
+        let name = series.remove(NAME).unwrap_or_default();
+        let labels = labels.iter().map(|&label| match series.get(label) {
+            Some(value) if value == label => Some(label.to_owned()),
+            _ => series
+                .iter()
+                .find(|(_, value)| *value == label)
+                .map(|(name, _)| name.clone()),
+        });
+        Some((name, labels.collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(yaml: &str) -> Rules {
+        Rules::parse(yaml, Path::new("test.yml")).unwrap()
+    }
+
+    fn series(labels: &[(&str, &str)]) -> BTreeMap<String, String> {
+        labels
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    const TEMP: &[(&str, &str)] = &[
+        ("__name__", "nvml_temp"),
+        ("uuid", "GPU-1"),
+        ("name", "A100"),
+    ];
+
+    #[test]
+    fn replace() {
+        let rules = rules(
+            "- {source_labels: [__name__], regex: 'nvml_(.*)', target_label: __name__, replacement: 'gpu_$1'}
+- {source_labels: [name, uuid], target_label: id}
+- {source_labels: [uuid], regex: 'nothing', target_label: uuid, replacement: ''}",
+        );
+        assert_eq!(
+            rules.apply(series(TEMP)),
+            Some(series(&[
+                ("__name__", "gpu_temp"),
+                ("id", "A100;GPU-1"),
+                ("name", "A100"),
+                ("uuid", "GPU-1"),
+            ]))
+        );
+        // An empty replacement removes the label
+        let rules = self::rules("- {source_labels: [uuid], target_label: uuid, replacement: ''}");
+        assert_eq!(
+            rules.apply(series(TEMP)),
+            Some(series(&[("__name__", "nvml_temp"), ("name", "A100")]))
+        );
+    }
+
+    #[test]
+    fn keep() {
+        let rules = rules("- {source_labels: [name], regex: 'A100|H100', action: keep}");
+        assert!(rules.apply(series(TEMP)).is_some());
+        assert!(rules
+            .apply(series(&[("__name__", "nvml_temp"), ("name", "T4")]))
+            .is_none());
+        // Missing labels are empty
+        assert!(rules.apply(series(&[("__name__", "nvml_temp")])).is_none());
+    }
+
+    #[test]
+    fn drop() {
+        let rules = rules("- {source_labels: [__name__], regex: 'nvml_temp', action: drop}");
+        assert!(rules.apply(series(TEMP)).is_none());
+        assert!(rules
+            .apply(series(&[("__name__", "nvml_temp_max")]))
+            .is_some());
+    }
+
+    #[test]
+    fn labeldrop() {
+        let rules = rules("- {regex: 'u.*|__name__', action: labeldrop}");
+        assert_eq!(
+            rules.apply(series(TEMP)),
+            Some(series(&[("__name__", "nvml_temp"), ("name", "A100")]))
+        );
+    }
+
+    #[test]
+    fn labelkeep() {
+        let rules = rules("- {regex: 'uuid', action: labelkeep}");
+        assert_eq!(
+            rules.apply(series(TEMP)),
+            Some(series(&[("__name__", "nvml_temp"), ("uuid", "GPU-1")]))
+        );
+    }
+
+    #[test]
+    fn labelmap() {
+        let rules = rules("- {regex: '(uuid|name)', replacement: 'gpu_$1', action: labelmap}");
+        assert_eq!(
+            rules.apply(series(TEMP)),
+            Some(series(&[
+                ("__name__", "nvml_temp"),
+                ("gpu_name", "A100"),
+                ("gpu_uuid", "GPU-1"),
+                ("name", "A100"),
+                ("uuid", "GPU-1"),
+            ]))
+        );
+    }
+
+    #[test]
+    fn nameless() {
+        let rules = rules(
+            "- {source_labels: [uuid], regex: 'GPU-1', target_label: __name__, replacement: ''}",
+        );
+        assert!(rules.apply(series(TEMP)).is_none());
+    }
+
+    #[test]
+    fn invalid() {
+        let e = Rules::parse("- {action: replace}", Path::new("test.yml"))
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.to_string(),
+            "Rule 1 of test.yml replaces without a target_label"
+        );
+        assert!(Rules::parse("- {regex: '(', action: drop}", Path::new("test.yml")).is_err());
+    }
+
+    #[test]
+    fn relabel() {
+        let rules = rules(
+            "- {source_labels: [__name__], regex: 'nvml_(.*)', target_label: __name__, replacement: 'gpu_$1'}",
+        );
+        let mut family = MetricFamily::default();
+        family.set_name("nvml_temp".into());
+        family.set_help("Temperature".into());
+        family.mut_metric().push(Default::default());
+        let mut families = vec![family];
+        rules.relabel(&mut families);
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].get_name(), "gpu_temp");
+        assert_eq!(families[0].get_help(), "Temperature");
+        assert_eq!(families[0].get_metric().len(), 1);
+    }
+}
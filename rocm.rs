@@ -0,0 +1,214 @@
+//! AMD GPUs, through ROCm SMI (librocm_smi64), loaded at runtime like NVML
+
+use libloading::{Library, Symbol};
+use nvml_wrapper::enum_wrappers::device::{Clock, PerformanceState, TemperatureSensor};
+use nvml_wrapper::error::NvmlError;
+use nvml_wrapper::struct_wrappers::device::{MemoryInfo, ProcessInfo, Utilization};
+use std::ffi::{c_char, CStr};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::gpu::Gpu;
+
+/// rsmi_status_t
+type Status = u32;
+
+const RSMI_MEM_TYPE_VRAM: u32 = 0;
+const RSMI_TEMP_TYPE_EDGE: u32 = 0;
+const RSMI_TEMP_CURRENT: u32 = 0;
+
+fn check(status: Status) -> Result<(), NvmlError> {
+    Err(match status {
+        0 => return Ok(()),
+        1 | 7 => NvmlError::InvalidArg,
+        2 | 9 => NvmlError::NotSupported,
+        4 => NvmlError::NoPermission,
+        8 => NvmlError::Uninitialized,
+        10 => NvmlError::NotFound,
+        11 => NvmlError::InsufficientSize(None),
+        14 => NvmlError::NoData,
+        _ => NvmlError::Unknown,
+    })
+}
+
+/// The initialized library, shut down on drop
+pub struct Rocm {
+    lib: Library,
+}
+
+impl Rocm {
+    pub fn init(path: &Path) -> crate::Result<Rocm> {
+        let rocm = Rocm {
+            lib: unsafe { Library::new(path)? },
+        };
+        let init: Symbol<unsafe extern "C" fn(u64) -> Status> = rocm.sym(b"rsmi_init\0")?;
+        check(unsafe { init(0) })?;
+        Ok(rocm)
+    }
+
+    fn sym<F>(&self, name: &[u8]) -> Result<Symbol<'_, F>, NvmlError> {
+        unsafe { self.lib.get(name) }.map_err(|_| NvmlError::FunctionNotFound)
+    }
+
+    pub fn device_count(&self) -> Result<u32, NvmlError> {
+        let get: Symbol<unsafe extern "C" fn(*mut u32) -> Status> =
+            self.sym(b"rsmi_num_monitor_devices\0")?;
+        let mut count = 0;
+        check(unsafe { get(&mut count) })?;
+        Ok(count)
+    }
+
+    /// For the many `rsmi_dev_*_get(index, *value)` functions
+    fn get<T: Default>(&self, name: &[u8], index: u32) -> Result<T, NvmlError> {
+        let get: Symbol<unsafe extern "C" fn(u32, *mut T) -> Status> = self.sym(name)?;
+        let mut value = T::default();
+        check(unsafe { get(index, &mut value) })?;
+        Ok(value)
+    }
+
+    /// For those that take a sensor or type argument
+    fn get_of<T: Default>(&self, name: &[u8], index: u32, of: u32) -> Result<T, NvmlError> {
+        let get: Symbol<unsafe extern "C" fn(u32, u32, *mut T) -> Status> = self.sym(name)?;
+        let mut value = T::default();
+        check(unsafe { get(index, of, &mut value) })?;
+        Ok(value)
+    }
+}
+
+impl Drop for Rocm {
+    fn drop(&mut self) {
+        if let Ok(shut_down) = self.sym::<unsafe extern "C" fn() -> Status>(b"rsmi_shut_down\0") {
+            unsafe { shut_down() };
+        }
+    }
+}
+
+pub struct RocmGpu {
+    rocm: Arc<Rocm>,
+    index: u32,
+}
+
+impl RocmGpu {
+    pub fn new(rocm: Arc<Rocm>, index: u32) -> RocmGpu {
+        RocmGpu { rocm, index }
+    }
+}
+
+impl Gpu for RocmGpu {
+    fn uuid(&self) -> Result<String, NvmlError> {
+        let id: u64 = self.rocm.get(b"rsmi_dev_unique_id_get\0", self.index)?;
+        Ok(format!("GPU-{id:016x}"))
+    }
+    fn name(&self) -> Result<String, NvmlError> {
+        let get: Symbol<unsafe extern "C" fn(u32, *mut c_char, usize) -> Status> =
+            self.rocm.sym(b"rsmi_dev_name_get\0")?;
+        let mut name = [0 as c_char; 256];
+        check(unsafe { get(self.index, name.as_mut_ptr(), name.len()) })?;
+        let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+        Ok(name.to_string_lossy().into_owned())
+    }
+    fn pci_bus_id(&self) -> Result<String, NvmlError> {
+        // BDFID: domain << 32 | bus << 8 | device << 3 | function
+        let bdf: u64 = self.rocm.get(b"rsmi_dev_pci_id_get\0", self.index)?;
+        Ok(format!(
+            "{:08X}:{:02X}:{:02X}.{:X}",
+            bdf >> 32,
+            (bdf >> 8) & 0xff,
+            (bdf >> 3) & 0x1f,
+            bdf & 0x7
+        ))
+    }
+    fn index(&self) -> Result<u32, NvmlError> {
+        Ok(self.index)
+    }
+    fn mig_mode(&self) -> Result<bool, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn memory_info(&self) -> Result<MemoryInfo, NvmlError> {
+        let total: u64 = self.rocm.get_of(
+            b"rsmi_dev_memory_total_get\0",
+            self.index,
+            RSMI_MEM_TYPE_VRAM,
+        )?;
+        let used: u64 = self.rocm.get_of(
+            b"rsmi_dev_memory_usage_get\0",
+            self.index,
+            RSMI_MEM_TYPE_VRAM,
+        )?;
+        Ok(MemoryInfo {
+            free: total.saturating_sub(used),
+            total,
+            used,
+        })
+    }
+    fn fan_speed(&self, fan: u32) -> Result<u32, NvmlError> {
+        let speed: i64 = self
+            .rocm
+            .get_of(b"rsmi_dev_fan_speed_get\0", self.index, fan)?;
+        let max: u64 = self
+            .rocm
+            .get_of(b"rsmi_dev_fan_speed_max_get\0", self.index, fan)?;
+        Ok((speed.max(0) as u64 * 100 / max.max(1)) as u32)
+    }
+    fn temperature(&self, _: TemperatureSensor) -> Result<u32, NvmlError> {
+        let get: Symbol<unsafe extern "C" fn(u32, u32, u32, *mut i64) -> Status> =
+            self.rocm.sym(b"rsmi_dev_temp_metric_get\0")?;
+        let mut millidegrees = 0;
+        check(unsafe {
+            get(
+                self.index,
+                RSMI_TEMP_TYPE_EDGE,
+                RSMI_TEMP_CURRENT,
+                &mut millidegrees,
+            )
+        })?;
+        Ok((millidegrees.max(0) / 1000) as u32)
+    }
+    fn performance_state(&self) -> Result<PerformanceState, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn power_usage(&self) -> Result<u32, NvmlError> {
+        let microwatts: u64 = self
+            .rocm
+            .get_of(b"rsmi_dev_power_ave_get\0", self.index, 0)?;
+        Ok((microwatts / 1000) as u32)
+    }
+    fn enforced_power_limit(&self) -> Result<u32, NvmlError> {
+        let microwatts: u64 = self
+            .rocm
+            .get_of(b"rsmi_dev_power_cap_get\0", self.index, 0)?;
+        Ok((microwatts / 1000) as u32)
+    }
+    fn total_energy_consumption(&self) -> Result<u64, NvmlError> {
+        let get: Symbol<unsafe extern "C" fn(u32, *mut u64, *mut f32, *mut u64) -> Status> =
+            self.rocm.sym(b"rsmi_dev_energy_count_get\0")?;
+        let (mut count, mut resolution, mut timestamp) = (0, 0., 0);
+        check(unsafe { get(self.index, &mut count, &mut resolution, &mut timestamp) })?;
+        // In units of `resolution` µJ, NVML counts mJ
+        Ok((count as f64 * resolution as f64 / 1000.) as u64)
+    }
+    fn pcie_replay_counter(&self) -> Result<u32, NvmlError> {
+        let count: u64 = self
+            .rocm
+            .get(b"rsmi_dev_pci_replay_counter_get\0", self.index)?;
+        Ok(count as u32)
+    }
+    fn utilization_rates(&self) -> Result<Utilization, NvmlError> {
+        Ok(Utilization {
+            gpu: self.rocm.get(b"rsmi_dev_busy_percent_get\0", self.index)?,
+            memory: self
+                .rocm
+                .get(b"rsmi_dev_memory_busy_percent_get\0", self.index)?,
+        })
+    }
+    fn clock_info(&self, _: Clock) -> Result<u32, NvmlError> {
+        // rsmi_frequencies_t changed layout between ROCm versions
+        Err(NvmlError::NotSupported)
+    }
+    fn running_compute_processes(&self) -> Result<Vec<ProcessInfo>, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn running_graphics_processes(&self) -> Result<Vec<ProcessInfo>, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+}
@@ -0,0 +1,161 @@
+//! Prometheus alerting and recording rules for this exporter's metrics
+
+use prometheus::core::Collector;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use nvml_exporter::collectors::{Memory, Pcie, Power, Thermal};
+use nvml_exporter::{Backend, NvmlCollector};
+
+/// Thresholds for `gen-rules`
+#[derive(clap::Args)]
+pub struct Thresholds {
+    /// Alert on GPU temperatures above this (°C)
+    #[arg(long, default_value = "85")]
+    temperature: f64,
+    /// Alert on stopped fans while the GPU is warmer than this (°C)
+    #[arg(long, default_value = "50")]
+    fan_temperature: f64,
+    /// Alert on memory usage above this fraction of the total
+    #[arg(long, default_value = "0.95")]
+    memory: f64,
+    /// Alert when power draw is above this fraction of the enforced limit
+    #[arg(long, default_value = "0.98")]
+    power: f64,
+    /// How long a condition has to hold before alerting
+    #[arg(long = "for", default_value = "5m")]
+    for_: humantime::Duration,
+}
+
+#[derive(Serialize)]
+struct RuleFile {
+    groups: Vec<Group>,
+}
+
+#[derive(Serialize)]
+struct Group {
+    name: &'static str,
+    rules: Vec<Rule>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Rule {
+    Record {
+        record: String,
+        expr: String,
+    },
+    Alert {
+        alert: &'static str,
+        expr: String,
+        #[serde(rename = "for")]
+        for_: String,
+        labels: BTreeMap<&'static str, &'static str>,
+        annotations: BTreeMap<&'static str, String>,
+    },
+}
+
+/// The name used in expressions, taken from the metric itself so the rules can't drift
+fn name(metric: &dyn Collector) -> String {
+    metric.desc()[0].fq_name.clone()
+}
+
+fn alert(
+    alert: &'static str,
+    expr: String,
+    for_: &humantime::Duration,
+    severity: &'static str,
+    summary: &str,
+) -> Rule {
+    Rule::Alert {
+        alert,
+        expr,
+        for_: for_.to_string(),
+        labels: [("severity", severity)].into(),
+        annotations: [("summary", summary.to_owned())].into(),
+    }
+}
+
+/// The rule file as YAML. ECC and XID rules are not included, as there are no metrics for
+/// them (yet).
+pub fn generate(t: &Thresholds) -> crate::Result<String> {
+    let (memory, thermal, power, pcie) = (
+        Memory::default(),
+        Thermal::default(),
+        Power::default(),
+        Pcie::default(),
+    );
+    let collector = NvmlCollector::new(Backend::Mock(0));
+    let memory_ratio = "nvml:memory_used_bytes:ratio".to_owned();
+    let recording = vec![Rule::Record {
+        record: memory_ratio.clone(),
+        expr: format!("{} / {}", name(&memory.used), name(&memory.total)),
+    }];
+    let alerting = vec![
+        alert(
+            "NvmlGpuTemperatureHigh",
+            format!("{} > {}", name(&thermal.temperature), t.temperature),
+            &t.for_,
+            "warning",
+            "GPU {{ $labels.uuid }} on {{ $labels.instance }} is at {{ $value }}°C",
+        ),
+        alert(
+            "NvmlGpuFanStopped",
+            format!(
+                "{} == 0 and on (uuid) ({} > {})",
+                name(&thermal.fan_speed),
+                name(&thermal.temperature),
+                t.fan_temperature
+            ),
+            &t.for_,
+            "critical",
+            "Fan {{ $labels.fan }} of GPU {{ $labels.uuid }} on {{ $labels.instance }} stopped",
+        ),
+        alert(
+            "NvmlGpuMemoryPressure",
+            format!("{} > {}", memory_ratio, t.memory),
+            &t.for_,
+            "warning",
+            "GPU {{ $labels.uuid }} on {{ $labels.instance }} has little free memory",
+        ),
+        alert(
+            "NvmlGpuPowerCapped",
+            format!(
+                "{} >= {} * {}",
+                name(&power.usage),
+                t.power,
+                name(&power.max)
+            ),
+            &t.for_,
+            "info",
+            "GPU {{ $labels.uuid }} on {{ $labels.instance }} is running at its power limit",
+        ),
+        alert(
+            "NvmlPcieReplays",
+            format!("rate({}[5m]) > 0", name(&pcie.replay)),
+            &t.for_,
+            "warning",
+            "GPU {{ $labels.uuid }} on {{ $labels.instance }} is retrying PCIe transfers",
+        ),
+        alert(
+            "NvmlQueriesFailing",
+            format!("rate({}[5m]) > 0", name(collector.errors())),
+            &t.for_,
+            "warning",
+            "NVML {{ $labels.function }} fails with {{ $labels.code }} on {{ $labels.instance }}",
+        ),
+    ];
+    let file = RuleFile {
+        groups: vec![
+            Group {
+                name: "nvml.rules",
+                rules: recording,
+            },
+            Group {
+                name: "nvml.alerts",
+                rules: alerting,
+            },
+        ],
+    };
+    Ok(serde_yaml::to_string(&file)?)
+}
@@ -0,0 +1,42 @@
+//! Which GPUs the kernel suspended for runtime power management, from sysfs, as on laptops that
+//! only power up the discrete GPU when it's used
+//!
+//! Any NVML query of a suspended GPU wakes it up, reading sysfs doesn't.
+
+const DEVICES: &str = "/sys/bus/pci/devices";
+const NVIDIA: &str = "0x10de";
+
+/// An NVIDIA display controller on the PCI bus
+pub struct PciGpu {
+    /// As NVML has it, e.g. `00000000:01:00.0`
+    pub bus_id: String,
+    pub suspended: bool,
+}
+
+/// By bus id, the order of NVML's indices. Empty without sysfs.
+pub fn nvidia_gpus() -> Vec<PciGpu> {
+    let Ok(entries) = std::fs::read_dir(DEVICES) else {
+        return vec![];
+    };
+    let mut gpus = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let read = |name: &str| {
+                let value = std::fs::read_to_string(path.join(name)).ok()?;
+                Some(value.trim().to_owned())
+            };
+            // Class 0x03 are display controllers, VGA or 3D
+            if read("vendor")? != NVIDIA || !read("class")?.starts_with("0x03") {
+                return None;
+            }
+            // Padded to 8 digits by NVML, 4 by the kernel
+            let (domain, address) = path.file_name()?.to_str()?.split_once(':')?;
+            Some(PciGpu {
+                bus_id: format!("{domain:0>8}:{address}").to_uppercase(),
+                suspended: read("power/runtime_status").as_deref() == Some("suspended"),
+            })
+        })
+        .collect::<Vec<_>>();
+    gpus.sort_by(|a, b| a.bus_id.cmp(&b.bus_id));
+    gpus
+}
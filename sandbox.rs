@@ -0,0 +1,343 @@
+//! Denying the exporter what it doesn't need, once it is set up, as it listens on the network
+//!
+//! A seccomp filter allows only the system calls the exporter needs, failing any other, like
+//! loading kernel modules or tracing other processes, and Landlock limits file system access to
+//! reading, and writing to devices and the given directories.
+//!
+//! Both are applied before the exporter starts its threads, as Landlock only restricts the thread
+//! applying it and those it starts later on. The seccomp filter is synchronized to all threads,
+//! for any a library may have started.
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use tracing::{info, warn};
+
+use crate::Result;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// What the exporter, NVML, and the libraries they use need. Anything else fails with EPERM.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const ALLOWED: &[libc::c_long] = &[
+    // Files
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_openat,
+    libc::SYS_close,
+    libc::SYS_close_range,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_lseek,
+    libc::SYS_getdents64,
+    libc::SYS_readlinkat,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_flock,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_ftruncate,
+    libc::SYS_fchmod,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat2,
+    libc::SYS_mkdirat,
+    libc::SYS_getcwd,
+    libc::SYS_chdir,
+    libc::SYS_umask,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_eventfd2,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    // Memory
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mprotect,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    libc::SYS_membarrier,
+    libc::SYS_get_mempolicy,
+    // Threads and the process
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_wait4,
+    libc::SYS_waitid,
+    libc::SYS_set_robust_list,
+    libc::SYS_set_tid_address,
+    libc::SYS_rseq,
+    libc::SYS_futex,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_getpid,
+    libc::SYS_getppid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getgroups,
+    libc::SYS_getresuid,
+    libc::SYS_getresgid,
+    libc::SYS_prlimit64,
+    libc::SYS_getrusage,
+    libc::SYS_sysinfo,
+    libc::SYS_uname,
+    libc::SYS_prctl,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigtimedwait,
+    libc::SYS_sigaltstack,
+    libc::SYS_tgkill,
+    // Time
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettimeofday,
+    libc::SYS_getrandom,
+    // Networking
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_accept4,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    libc::SYS_shutdown,
+];
+
+/// The older variants, for what doesn't use the *at ones, like NVML built against old glibc
+#[cfg(target_arch = "x86_64")]
+const LEGACY: &[libc::c_long] = &[
+    libc::SYS_open,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_access,
+    libc::SYS_readlink,
+    libc::SYS_getdents,
+    libc::SYS_unlink,
+    libc::SYS_rename,
+    libc::SYS_renameat,
+    libc::SYS_mkdir,
+    libc::SYS_rmdir,
+    libc::SYS_dup2,
+    libc::SYS_pipe,
+    libc::SYS_poll,
+    libc::SYS_select,
+    libc::SYS_epoll_wait,
+    libc::SYS_accept,
+    libc::SYS_getrlimit,
+    libc::SYS_arch_prctl,
+    libc::SYS_time,
+];
+#[cfg(target_arch = "aarch64")]
+const LEGACY: &[libc::c_long] = &[];
+
+/// Only for the nvidia-smi fallback and alert commands
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const EXEC: &[libc::c_long] = &[libc::SYS_execve, libc::SYS_execveat, libc::SYS_kill];
+
+// Landlock ABI 1
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+/// All of ABI 1
+const ACCESS_FS_ALL: u64 = (1 << 13) - 1;
+const RULE_PATH_BENEATH: libc::c_int = 1;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: libc::c_int,
+}
+
+/// Apply to the calling thread, and, for seccomp, all others. To be called before starting
+/// threads, which are then restricted too. `removable` are directories that are removed, but not
+/// written to otherwise.
+pub fn apply(writable: &[&Path], removable: &[&Path], allow_exec: bool) -> Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    match landlock(writable, removable) {
+        Ok(()) => info!("Restricted file system access with Landlock"),
+        Err(e) => warn!("Not restricting file system access: {}", e),
+    }
+    seccomp(allow_exec)?;
+    info!("Restricted system calls with seccomp");
+    Ok(())
+}
+
+fn os_error() -> Box<dyn std::error::Error + Send + Sync> {
+    std::io::Error::last_os_error().into()
+}
+
+fn landlock(writable: &[&Path], removable: &[&Path]) -> Result<()> {
+    let attr = RulesetAttr {
+        handled_access_fs: ACCESS_FS_ALL,
+    };
+    let ruleset = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if ruleset < 0 {
+        return Err(os_error());
+    }
+    let ruleset = ruleset as libc::c_int;
+    let read = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR | ACCESS_FS_EXECUTE;
+    let write = ACCESS_FS_WRITE_FILE | ACCESS_FS_MAKE_REG | ACCESS_FS_REMOVE_FILE;
+    let rules = [
+        (Path::new("/"), read),
+        (Path::new("/dev"), ACCESS_FS_WRITE_FILE),
+    ];
+    let rules = rules
+        .into_iter()
+        .chain(writable.iter().map(|&dir| (dir, write)))
+        .chain(removable.iter().map(|&dir| (dir, ACCESS_FS_REMOVE_FILE)))
+        .chain(
+            // Removing a directory is up to its parent
+            removable
+                .iter()
+                .filter_map(|dir| dir.parent())
+                .map(|parent| (parent, ACCESS_FS_REMOVE_DIR)),
+        );
+    let result = (|| {
+        for (path, access) in rules {
+            let path = CString::new(path.as_os_str().as_bytes())?;
+            let fd = unsafe { libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+            if fd < 0 {
+                return Err(os_error());
+            }
+            let rule = PathBeneathAttr {
+                allowed_access: access,
+                parent_fd: fd,
+            };
+            let added = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset,
+                    RULE_PATH_BENEATH,
+                    &rule as *const PathBeneathAttr,
+                    0,
+                )
+            };
+            unsafe { libc::close(fd) };
+            if added != 0 {
+                return Err(os_error());
+            }
+        }
+        match unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0) } {
+            0 => Ok(()),
+            _ => Err(os_error()),
+        }
+    })();
+    unsafe { libc::close(ruleset) };
+    result
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn seccomp(allow_exec: bool) -> Result<()> {
+    use libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+    let stmt = |code: u32, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let allowed = match allow_exec {
+        true => [ALLOWED, LEGACY, EXEC].concat(),
+        false => [ALLOWED, LEGACY].concat(),
+    };
+    let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    // Offsets into struct seccomp_data
+    let (nr, arch) = (0, 4);
+    let mut filter = vec![
+        stmt(BPF_LD | BPF_W | BPF_ABS, arch),
+        // Skip the deny if it is the native architecture, rather than e.g. i386 on x86_64
+        libc::sock_filter {
+            jt: 1,
+            ..stmt(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH)
+        },
+        stmt(BPF_RET | BPF_K, deny),
+        stmt(BPF_LD | BPF_W | BPF_ABS, nr),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    filter.extend([
+        // x32 system calls, numbered from 0x40000000
+        libc::sock_filter {
+            jf: 1,
+            ..stmt(BPF_JMP | libc::BPF_JGE | BPF_K, 0x4000_0000)
+        },
+        stmt(BPF_RET | BPF_K, deny),
+    ]);
+    for &syscall in &allowed {
+        filter.push(libc::sock_filter {
+            jf: 1,
+            ..stmt(BPF_JMP | BPF_JEQ | BPF_K, syscall as u32)
+        });
+        filter.push(stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+    }
+    filter.push(stmt(BPF_RET | BPF_K, deny));
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    let set = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &program as *const libc::sock_fprog,
+        )
+    };
+    match set {
+        0 => Ok(()),
+        _ => Err(os_error()),
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn seccomp(_: bool) -> Result<()> {
+    Err("No seccomp filter for this architecture".into())
+}
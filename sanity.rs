@@ -0,0 +1,184 @@
+//! Dropping readings no GPU can produce, which some drivers return anyway
+//!
+//! Fan speeds over 100%, temperatures of 0 or 999, counters jumping by 2^32 and the like are
+//! counted in `nvml_invalid_readings_total` and logged with the raw value. The metric keeps its
+//! previous value, if it had one. A counter that jumped is believed once the next reading
+//! confirms it, as energy can grow a lot between rare collections.
+
+use prometheus::IntCounterVec;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::ops::RangeInclusive;
+use std::sync::Mutex;
+use tracing::warn;
+
+use crate::int_counter_vec;
+
+/// More than counters grow by between collections, short of wrapping around 32 bits
+const COUNTER_JUMP: u64 = 1 << 31;
+
+/// What drivers return for values they don't have, rather than an error
+const SENTINELS: [u64; 2] = [u32::MAX as u64, u64::MAX];
+
+/// The readings of a counter so far
+struct Counter {
+    /// The last one that was passed on
+    accepted: u64,
+    /// The last one that was dropped as a jump, to be confirmed by the next
+    jumped: Option<u64>,
+}
+
+pub struct Sanity {
+    pub invalid: IntCounterVec,
+    /// By uuid and metric
+    counters: Mutex<HashMap<(String, &'static str), Counter>>,
+}
+
+impl Default for Sanity {
+    fn default() -> Self {
+        Sanity {
+            invalid: int_counter_vec(
+                "nvml_invalid_readings_total",
+                "Implausible readings that were dropped, by metric",
+                &["uuid", "metric"],
+            ),
+            counters: Default::default(),
+        }
+    }
+}
+
+impl Sanity {
+    /// The reading if it is within `valid`
+    pub fn gauge(
+        &self,
+        uuid: &str,
+        metric: &'static str,
+        value: f64,
+        valid: RangeInclusive<f64>,
+    ) -> Option<f64> {
+        if valid.contains(&value) {
+            return Some(value);
+        }
+        self.invalid(uuid, metric, value);
+        None
+    }
+
+    /// The reading unless it jumped ahead of the previous one, and the next one didn't follow
+    /// ahead of it. Going back is left to Prometheus, as a counter reset. The first reading has
+    /// nothing to compare to, it is only checked for being a sentinel value.
+    pub fn counter(&self, uuid: &str, metric: &'static str, value: u64) -> Option<u64> {
+        let mut counters = self.counters.lock().unwrap();
+        let key = (uuid.to_owned(), metric);
+        let plausible = match counters.get_mut(&key) {
+            None => {
+                let plausible = !SENTINELS.contains(&value);
+                if plausible {
+                    let counter = Counter {
+                        accepted: value,
+                        jumped: None,
+                    };
+                    counters.insert(key, counter);
+                }
+                plausible
+            }
+            Some(counter) => {
+                let near = |base: u64| value.saturating_sub(base) < COUNTER_JUMP;
+                let confirmed = counter
+                    .jumped
+                    .is_some_and(|jumped| value >= jumped && near(jumped));
+                if near(counter.accepted) || confirmed {
+                    counter.accepted = value;
+                    counter.jumped = None;
+                    true
+                } else {
+                    counter.jumped = Some(value);
+                    false
+                }
+            }
+        };
+        drop(counters);
+        if !plausible {
+            self.invalid(uuid, metric, value);
+            return None;
+        }
+        Some(value)
+    }
+
+    /// The previous readings of a device that is gone
+    pub fn forget(&self, uuid: &str) {
+        self.counters.lock().unwrap().retain(|(u, _), _| u != uuid);
+    }
+
+    fn invalid(&self, uuid: &str, metric: &str, raw: impl Display) {
+        warn!(uuid, metric, "Dropping implausible reading {}", raw);
+        self.invalid.with_label_values(&[uuid, metric]).inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: &str = "GPU-0";
+    const METRIC: &str = "nvml_power_used_mj_total";
+
+    fn readings(values: &[u64]) -> Vec<Option<u64>> {
+        let sanity = Sanity::default();
+        values
+            .iter()
+            .map(|&value| sanity.counter(UUID, METRIC, value))
+            .collect()
+    }
+
+    #[test]
+    fn spike() {
+        let spike = 100 + (1 << 40);
+        assert_eq!(
+            readings(&[100, spike, 110, 120]),
+            [Some(100), None, Some(110), Some(120)]
+        );
+    }
+
+    #[test]
+    fn jump() {
+        let jump = 100 + (1 << 33);
+        assert_eq!(
+            readings(&[100, jump, jump + 5, jump + 10]),
+            [Some(100), None, Some(jump + 5), Some(jump + 10)]
+        );
+    }
+
+    #[test]
+    fn spikes_dont_confirm_each_other() {
+        let (a, b) = (1 << 40, 1 << 50);
+        assert_eq!(
+            readings(&[100, a, b, 110]),
+            [Some(100), None, None, Some(110)]
+        );
+    }
+
+    #[test]
+    fn reset() {
+        assert_eq!(
+            readings(&[100, 200, 5, 10]),
+            [Some(100), Some(200), Some(5), Some(10)]
+        );
+    }
+
+    #[test]
+    fn sentinel_first() {
+        assert_eq!(
+            readings(&[u64::MAX, u32::MAX.into(), 100, 110]),
+            [None, None, Some(100), Some(110)]
+        );
+    }
+
+    #[test]
+    fn invalid_readings_are_counted() {
+        let sanity = Sanity::default();
+        sanity.counter(UUID, METRIC, 100);
+        sanity.counter(UUID, METRIC, 1 << 40);
+        let invalid = sanity.invalid.with_label_values(&[UUID, METRIC]);
+        assert_eq!(invalid.get(), 1);
+    }
+}
@@ -0,0 +1,207 @@
+//! Running as a native Windows service
+
+use std::ffi::OsStr;
+use std::os::windows::ffi::OsStrExt;
+use std::sync::{Mutex, OnceLock};
+use tracing::{error, info};
+use windows_sys::Win32::Foundation::{
+    ERROR_CALL_NOT_IMPLEMENTED, ERROR_SERVICE_SPECIFIC_ERROR, NO_ERROR,
+};
+use windows_sys::Win32::Security::SC_HANDLE;
+use windows_sys::Win32::System::Services::*;
+
+use crate::Result;
+
+const NAME: &str = "prometheus-nvml-exporter";
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Action {
+    /// Register with the service manager, to start on boot with the other options given
+    Install,
+    Uninstall,
+    /// What the service manager starts
+    Run,
+}
+
+fn wide(s: impl AsRef<OsStr>) -> Vec<u16> {
+    s.as_ref().encode_wide().chain([0]).collect()
+}
+
+/// A service control manager handle, closed on drop
+struct Handle(SC_HANDLE);
+
+impl Handle {
+    fn new(handle: SC_HANDLE) -> Result<Handle> {
+        match handle {
+            0 => Err(std::io::Error::last_os_error().into()),
+            handle => Ok(Handle(handle)),
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe { CloseServiceHandle(self.0) };
+    }
+}
+
+fn manager() -> Result<Handle> {
+    Handle::new(unsafe {
+        OpenSCManagerW(std::ptr::null(), std::ptr::null(), SC_MANAGER_ALL_ACCESS)
+    })
+}
+
+/// Register the service, with the current executable and arguments, minus `--service install`
+pub fn install() -> Result<()> {
+    let mut args = std::env::args().collect::<Vec<_>>();
+    let at = args
+        .iter()
+        .position(|arg| arg == "--service" || arg.starts_with("--service="))
+        .ok_or("--service not found in the arguments")?;
+    let take = if args[at] == "--service" { 2 } else { 1 };
+    args.splice(at..at + take, ["--service".into(), "run".into()]);
+    args[0] = std::env::current_exe()?.display().to_string();
+    let command = args
+        .iter()
+        .map(|arg| match arg.contains(' ') {
+            true => format!("\"{arg}\""),
+            false => arg.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    let manager = manager()?;
+    let (name, command) = (wide(NAME), wide(&command));
+    let service = Handle::new(unsafe {
+        CreateServiceW(
+            manager.0,
+            name.as_ptr(),
+            name.as_ptr(),
+            SERVICE_ALL_ACCESS,
+            SERVICE_WIN32_OWN_PROCESS,
+            SERVICE_AUTO_START,
+            SERVICE_ERROR_NORMAL,
+            command.as_ptr(),
+            std::ptr::null(),
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    })?;
+    let mut description = wide("Exports NVIDIA GPU metrics for Prometheus");
+    let description = SERVICE_DESCRIPTIONW {
+        lpDescription: description.as_mut_ptr(),
+    };
+    unsafe {
+        ChangeServiceConfig2W(
+            service.0,
+            SERVICE_CONFIG_DESCRIPTION,
+            &description as *const _ as _,
+        )
+    };
+    info!(service = NAME, "Installed");
+    Ok(())
+}
+
+pub fn uninstall() -> Result<()> {
+    let manager = manager()?;
+    let service =
+        Handle::new(unsafe { OpenServiceW(manager.0, wide(NAME).as_ptr(), SERVICE_ALL_ACCESS) })?;
+    if unsafe { DeleteService(service.0) } == 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    info!(service = NAME, "Uninstalled");
+    Ok(())
+}
+
+type Main = Box<dyn FnOnce() -> Result<()> + Send>;
+type Stop = Box<dyn Fn() + Send>;
+
+static MAIN: Mutex<Option<Main>> = Mutex::new(None);
+static STOP: Mutex<Option<Stop>> = Mutex::new(None);
+static STATUS: OnceLock<SERVICE_STATUS_HANDLE> = OnceLock::new();
+
+/// Hand control to the service manager, which runs `main` until it is stopped
+pub fn run(main: impl FnOnce() -> Result<()> + Send + 'static) -> Result<()> {
+    *MAIN.lock().unwrap() = Some(Box::new(main));
+    let mut name = wide(NAME);
+    let table = [
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: name.as_mut_ptr(),
+            lpServiceProc: Some(service_main),
+        },
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: std::ptr::null_mut(),
+            lpServiceProc: None,
+        },
+    ];
+    // Returns once the service has stopped
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Called when the service manager asks to stop, at most once. Does nothing when not run as a
+/// service.
+pub fn on_stop(stop: impl Fn() + Send + 'static) {
+    *STOP.lock().unwrap() = Some(Box::new(stop));
+}
+
+fn set_status(state: SERVICE_STATUS_CURRENT_STATE, exit_code: u32) {
+    let Some(&handle) = STATUS.get() else {
+        return;
+    };
+    let status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: match state {
+            SERVICE_RUNNING => SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN,
+            _ => 0,
+        },
+        dwWin32ExitCode: match exit_code {
+            0 => NO_ERROR,
+            _ => ERROR_SERVICE_SPECIFIC_ERROR,
+        },
+        dwServiceSpecificExitCode: exit_code,
+        dwCheckPoint: 0,
+        dwWaitHint: 10_000,
+    };
+    unsafe { SetServiceStatus(handle, &status) };
+}
+
+unsafe extern "system" fn handler(
+    control: u32,
+    _: u32,
+    _: *mut std::ffi::c_void,
+    _: *mut std::ffi::c_void,
+) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            set_status(SERVICE_STOP_PENDING, 0);
+            if let Some(stop) = STOP.lock().unwrap().take() {
+                stop();
+            }
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+unsafe extern "system" fn service_main(_: u32, _: *mut windows_sys::core::PWSTR) {
+    let handle =
+        RegisterServiceCtrlHandlerExW(wide(NAME).as_ptr(), Some(handler), std::ptr::null());
+    if handle == 0 {
+        error!("Failed to register the service control handler");
+        return;
+    }
+    STATUS.set(handle).ok();
+    set_status(SERVICE_RUNNING, 0);
+    let main = MAIN.lock().unwrap().take();
+    let result = main.map_or(Ok(()), |main| main());
+    if let Err(e) = &result {
+        error!("Service failed: {}", e);
+    }
+    set_status(SERVICE_STOPPED, result.is_err().into());
+}
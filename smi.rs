@@ -0,0 +1,174 @@
+//! Devices read from `nvidia-smi`, for when NVML can't be loaded directly but the tool works,
+//! as in some locked-down container images. Only the core metrics are available.
+
+use nvml_wrapper::enum_wrappers::device::{Clock, PerformanceState, TemperatureSensor};
+use nvml_wrapper::error::NvmlError;
+use nvml_wrapper::struct_wrappers::device::{MemoryInfo, ProcessInfo, Utilization};
+use std::process::Command;
+
+use crate::gpu::{Gpu, CLOCKS};
+
+/// The fields queried, in the order [`SmiGpu::parse`] expects them
+const FIELDS: &[&str] = &[
+    "index",
+    "uuid",
+    "name",
+    "pci.bus_id",
+    "mig.mode.current",
+    "memory.total",
+    "memory.used",
+    "memory.free",
+    "fan.speed",
+    "temperature.gpu",
+    "pstate",
+    "power.draw",
+    "enforced.power.limit",
+    "utilization.gpu",
+    "utilization.memory",
+    "clocks.gr",
+    "clocks.sm",
+    "clocks.mem",
+    "clocks.video",
+];
+
+/// One line of `nvidia-smi --query-gpu` output
+pub struct SmiGpu {
+    index: u32,
+    uuid: String,
+    name: String,
+    pci_bus_id: String,
+    mig_mode: Option<bool>,
+    memory: Option<MemoryInfo>,
+    fan_speed: Option<u32>,
+    temperature: Option<u32>,
+    performance_state: Option<u32>,
+    power_usage: Option<u32>,
+    enforced_power_limit: Option<u32>,
+    utilization: Option<Utilization>,
+    /// In the order of [`CLOCKS`]
+    clocks: [Option<u32>; 4],
+}
+
+/// Run `nvidia-smi` and parse its output, one entry per device
+pub fn query() -> crate::Result<Vec<SmiGpu>> {
+    let output = Command::new("nvidia-smi")
+        .arg(format!("--query-gpu={}", FIELDS.join(",")))
+        .arg("--format=csv,noheader,nounits")
+        .output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "nvidia-smi failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    String::from_utf8(output.stdout)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(SmiGpu::parse)
+        .collect()
+}
+
+impl SmiGpu {
+    fn parse(line: &str) -> crate::Result<SmiGpu> {
+        let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+        if fields.len() != FIELDS.len() {
+            return Err(format!("Unexpected nvidia-smi output: {line:?}").into());
+        }
+        // Unsupported values show as "[N/A]", "[Not Supported]", …
+        let value = |i: usize| Some(fields[i]).filter(|v| !v.starts_with('['));
+        let number = |i: usize| value(i).and_then(|v| v.parse::<f64>().ok());
+        let mib = |i: usize| number(i).map(|v| v as u64 * (1 << 20));
+        let utilization = match (number(13), number(14)) {
+            (Some(gpu), Some(memory)) => Some(Utilization {
+                gpu: gpu as u32,
+                memory: memory as u32,
+            }),
+            _ => None,
+        };
+        let memory = match (mib(5), mib(6), mib(7)) {
+            (Some(total), Some(used), Some(free)) => Some(MemoryInfo { total, used, free }),
+            _ => None,
+        };
+        Ok(SmiGpu {
+            index: fields[0].parse()?,
+            uuid: fields[1].into(),
+            name: fields[2].into(),
+            pci_bus_id: fields[3].into(),
+            mig_mode: value(4).map(|v| v == "Enabled"),
+            memory,
+            fan_speed: number(8).map(|v| v as u32),
+            temperature: number(9).map(|v| v as u32),
+            performance_state: value(10).and_then(|v| v.strip_prefix('P')?.parse().ok()),
+            power_usage: number(11).map(|w| (w * 1000.) as u32),
+            enforced_power_limit: number(12).map(|w| (w * 1000.) as u32),
+            utilization,
+            clocks: [15, 16, 17, 18].map(|i| number(i).map(|mhz| mhz as u32)),
+        })
+    }
+}
+
+fn supported<T>(value: Option<T>) -> Result<T, NvmlError> {
+    value.ok_or(NvmlError::NotSupported)
+}
+
+impl Gpu for SmiGpu {
+    fn uuid(&self) -> Result<String, NvmlError> {
+        Ok(self.uuid.clone())
+    }
+    fn name(&self) -> Result<String, NvmlError> {
+        Ok(self.name.clone())
+    }
+    fn pci_bus_id(&self) -> Result<String, NvmlError> {
+        Ok(self.pci_bus_id.clone())
+    }
+    fn index(&self) -> Result<u32, NvmlError> {
+        Ok(self.index)
+    }
+    fn mig_mode(&self) -> Result<bool, NvmlError> {
+        supported(self.mig_mode)
+    }
+    fn memory_info(&self) -> Result<MemoryInfo, NvmlError> {
+        supported(self.memory.clone())
+    }
+    fn fan_speed(&self, fan: u32) -> Result<u32, NvmlError> {
+        match fan {
+            0 => supported(self.fan_speed),
+            _ => Err(NvmlError::InvalidArg),
+        }
+    }
+    fn temperature(&self, sensor: TemperatureSensor) -> Result<u32, NvmlError> {
+        match sensor {
+            TemperatureSensor::Gpu => supported(self.temperature),
+        }
+    }
+    fn performance_state(&self) -> Result<PerformanceState, NvmlError> {
+        PerformanceState::try_from(supported(self.performance_state)?)
+    }
+    fn power_usage(&self) -> Result<u32, NvmlError> {
+        supported(self.power_usage)
+    }
+    fn enforced_power_limit(&self) -> Result<u32, NvmlError> {
+        supported(self.enforced_power_limit)
+    }
+    fn total_energy_consumption(&self) -> Result<u64, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn pcie_replay_counter(&self) -> Result<u32, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn utilization_rates(&self) -> Result<Utilization, NvmlError> {
+        supported(self.utilization.clone())
+    }
+    fn clock_info(&self, clock: Clock) -> Result<u32, NvmlError> {
+        let i = CLOCKS.iter().position(|c| *c == clock);
+        supported(i.and_then(|i| self.clocks[i]))
+    }
+    fn running_compute_processes(&self) -> Result<Vec<ProcessInfo>, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn running_graphics_processes(&self) -> Result<Vec<ProcessInfo>, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+}
@@ -0,0 +1,61 @@
+//! Structured JSON view of the collected metrics, per device and metric
+
+use prometheus::proto::MetricFamily;
+use serde_json::{json, Map, Value};
+
+use crate::{push, GPU_LABELS};
+
+/// Metrics grouped by device. Metrics without further labels map to their value, others to a
+/// list of `{"labels": {...}, "value": ...}`.
+pub fn devices(families: &[MetricFamily]) -> Value {
+    let mut devices: Vec<(Vec<String>, Map<String, Value>)> = vec![];
+    for sample in push::samples(families) {
+        let (gpu, other): (Vec<_>, Vec<_>) = sample
+            .labels
+            .into_iter()
+            .partition(|(name, _)| GPU_LABELS.contains(&name.as_str()));
+        // Not a per-device metric
+        if gpu.len() != GPU_LABELS.len() {
+            continue;
+        }
+        let gpu = GPU_LABELS
+            .iter()
+            .map(|label| {
+                gpu.iter()
+                    .find(|(name, _)| name == label)
+                    .unwrap()
+                    .1
+                    .clone()
+            })
+            .collect::<Vec<_>>();
+        let metrics = match devices.iter_mut().find(|(labels, _)| *labels == gpu) {
+            Some((_, metrics)) => metrics,
+            None => {
+                devices.push((gpu, Map::new()));
+                &mut devices.last_mut().unwrap().1
+            }
+        };
+        if other.is_empty() {
+            metrics.insert(sample.name, json!(sample.value));
+        } else {
+            let series = json!({
+                "labels": other.into_iter().map(|(k, v)| (k, json!(v))).collect::<Map<_, _>>(),
+                "value": sample.value,
+            });
+            match metrics.entry(sample.name).or_insert_with(|| json!([])) {
+                Value::Array(list) => list.push(series),
+                _ => unreachable!("metric with and without extra labels"),
+            }
+        }
+    }
+    let devices = devices.into_iter().map(|(labels, metrics)| {
+        let mut device = GPU_LABELS
+            .iter()
+            .zip(labels)
+            .map(|(name, value)| (name.to_string(), json!(value)))
+            .collect::<Map<_, _>>();
+        device.insert("metrics".into(), Value::Object(metrics));
+        device
+    });
+    json!({ "devices": devices.collect::<Vec<_>>() })
+}
@@ -0,0 +1,113 @@
+//! Integration with systemd's service manager protocols
+
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+#[cfg(unix)]
+use crate::Result;
+
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Take the first socket passed via `LISTEN_FDS`, if we were socket activated
+#[cfg(unix)]
+pub fn listener() -> Result<Option<tiny_http::Listener>> {
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if pid.and_then(|pid| pid.parse().ok()) != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let fds: i32 = fds.ok_or("LISTEN_PID set without LISTEN_FDS")?.parse()?;
+    if fds < 1 {
+        return Ok(None);
+    }
+    if fds > 1 {
+        return Err("Socket activation with more than one socket is not supported".into());
+    }
+    let fd = SD_LISTEN_FDS_START;
+    // Don't leak the socket into child processes
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let tcp = unsafe { TcpListener::from_raw_fd(fd) };
+    Ok(Some(match tcp.local_addr() {
+        Ok(_) => tcp.into(),
+        Err(_) => unsafe { UnixListener::from_raw_fd(tcp.into_raw_fd()) }.into(),
+    }))
+}
+
+/// sd_notify(3) client. Does nothing when not run by systemd.
+pub struct Notifier {
+    #[cfg(unix)]
+    socket: Option<UnixDatagram>,
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    pub fn from_env() -> Notifier {
+        #[cfg(unix)]
+        let socket = std::env::var_os("NOTIFY_SOCKET").and_then(|path| {
+            let socket = UnixDatagram::unbound().ok()?;
+            #[cfg(target_os = "linux")]
+            if let Some(name) = path.to_str().and_then(|p| p.strip_prefix('@')) {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name).ok()?;
+                socket.connect_addr(&addr).ok()?;
+                return Some(socket);
+            }
+            socket.connect(path).ok()?;
+            Some(socket)
+        });
+        let watchdog = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .filter(|_| {
+                std::env::var("WATCHDOG_PID")
+                    .map_or(true, |pid| pid.parse() == Ok(std::process::id()))
+            })
+            .and_then(|usec| usec.parse().ok())
+            .map(Duration::from_micros);
+        Notifier {
+            #[cfg(unix)]
+            socket,
+            watchdog,
+        }
+    }
+
+    fn notify(&self, state: &str) {
+        #[cfg(unix)]
+        if let Some(socket) = &self.socket {
+            socket.send(state.as_bytes()).ok();
+        }
+        #[cfg(not(unix))]
+        let _ = state;
+    }
+
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    pub fn watchdog(&self) {
+        if self.watchdog.is_some() {
+            self.notify("WATCHDOG=1");
+        }
+    }
+
+    /// How often the watchdog needs to be fed, with some margin
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog.map(|timeout| timeout / 2)
+    }
+}
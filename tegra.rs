@@ -0,0 +1,203 @@
+//! The integrated GPU of Jetson (Tegra) boards, which NVML doesn't know about, read from sysfs
+//!
+//! The GPU shares system memory, so there are no memory metrics, and the memory controller's load
+//! isn't available the same way, so there's no memory utilization either. Of the board's power
+//! rails, only the GPU's is reported.
+
+use nvml_wrapper::enum_wrappers::device::{Clock, PerformanceState, TemperatureSensor};
+use nvml_wrapper::error::NvmlError;
+use nvml_wrapper::struct_wrappers::device::{MemoryInfo, ProcessInfo, Utilization};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::gpu::Gpu;
+
+/// devfreq device names of the integrated GPUs, from TX1 to Orin
+const GPU_DEVICES: &[&str] = &["gm20b", "gp10b", "gv11b", "ga10b", "gpu"];
+
+/// The sysfs files of the GPU, found once
+#[derive(Clone)]
+pub struct TegraGpu {
+    /// The platform device, e.g. 17000000.ga10b
+    device: String,
+    model: String,
+    serial: Option<String>,
+    /// Load in per mille
+    load: Option<PathBuf>,
+    /// Current frequency in Hz
+    frequency: PathBuf,
+    /// Temperature in millidegrees Celsius
+    temperature: Option<PathBuf>,
+    power: Option<Rail>,
+}
+
+/// A power monitor channel
+#[derive(Clone)]
+enum Rail {
+    /// hwmon ina3221 (since L4T 34), in mV and mA
+    Hwmon { voltage: PathBuf, current: PathBuf },
+    /// iio ina3221x (until L4T 32), in mW
+    Iio(PathBuf),
+}
+
+fn read(path: &Path) -> Option<String> {
+    Some(
+        fs::read_to_string(path)
+            .ok()?
+            .trim_end_matches(['\n', '\0'])
+            .to_owned(),
+    )
+}
+
+fn number(path: &Path) -> Result<u64, NvmlError> {
+    read(path)
+        .and_then(|v| v.parse().ok())
+        .ok_or(NvmlError::Unknown)
+}
+
+fn entries(dir: impl AsRef<Path>) -> impl Iterator<Item = PathBuf> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+}
+
+impl TegraGpu {
+    /// The GPU, if this is a Jetson board
+    pub fn find() -> Option<TegraGpu> {
+        let devfreq = entries("/sys/class/devfreq").find(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str()?.rsplit('.').next())
+                .is_some_and(|name| GPU_DEVICES.contains(&name))
+        })?;
+        let device = devfreq.file_name()?.to_str()?.to_owned();
+        let load = [
+            devfreq.join("device/load"),
+            PathBuf::from("/sys/devices/gpu.0/load"),
+            PathBuf::from("/sys/devices/platform/gpu.0/load"),
+        ]
+        .into_iter()
+        .find(|path| path.exists());
+        let temperature = entries("/sys/class/thermal")
+            .find(|zone| {
+                read(&zone.join("type")).is_some_and(|kind| kind.to_lowercase().starts_with("gpu"))
+            })
+            .map(|zone| zone.join("temp"));
+        Some(TegraGpu {
+            model: read(Path::new("/proc/device-tree/model"))
+                .unwrap_or_else(|| "NVIDIA Tegra".into()),
+            serial: read(Path::new("/proc/device-tree/serial-number")),
+            frequency: devfreq.join("cur_freq"),
+            device,
+            load,
+            temperature,
+            power: Rail::find(),
+        })
+    }
+}
+
+impl Rail {
+    fn find() -> Option<Rail> {
+        let is_gpu = |label: &Path| read(label).is_some_and(|label| label.contains("GPU"));
+        for hwmon in
+            entries("/sys/bus/i2c/drivers/ina3221").flat_map(|dev| entries(dev.join("hwmon")))
+        {
+            for channel in 1..=3 {
+                if is_gpu(&hwmon.join(format!("in{channel}_label"))) {
+                    return Some(Rail::Hwmon {
+                        voltage: hwmon.join(format!("in{channel}_input")),
+                        current: hwmon.join(format!("curr{channel}_input")),
+                    });
+                }
+            }
+        }
+        for iio in entries("/sys/bus/i2c/drivers/ina3221x").flat_map(entries) {
+            for channel in 0..3 {
+                if is_gpu(&iio.join(format!("rail_name_{channel}"))) {
+                    return Some(Rail::Iio(iio.join(format!("in_power{channel}_input"))));
+                }
+            }
+        }
+        None
+    }
+
+    /// In mW
+    fn power(&self) -> Result<u32, NvmlError> {
+        let mw = match self {
+            Rail::Hwmon { voltage, current } => number(voltage)? * number(current)? / 1000,
+            Rail::Iio(power) => number(power)?,
+        };
+        Ok(mw as u32)
+    }
+}
+
+impl Gpu for TegraGpu {
+    fn uuid(&self) -> Result<String, NvmlError> {
+        // There is none, but the board's serial number comes close
+        Ok(match &self.serial {
+            Some(serial) => format!("tegra-{serial}"),
+            None => format!("tegra-{}", self.device),
+        })
+    }
+    fn name(&self) -> Result<String, NvmlError> {
+        Ok(self.model.clone())
+    }
+    fn pci_bus_id(&self) -> Result<String, NvmlError> {
+        // Not on PCI, the platform device identifies it just as well
+        Ok(self.device.clone())
+    }
+    fn index(&self) -> Result<u32, NvmlError> {
+        Ok(0)
+    }
+    fn mig_mode(&self) -> Result<bool, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn memory_info(&self) -> Result<MemoryInfo, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn fan_speed(&self, _: u32) -> Result<u32, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn temperature(&self, _: TemperatureSensor) -> Result<u32, NvmlError> {
+        let path = self.temperature.as_ref().ok_or(NvmlError::NotSupported)?;
+        Ok((number(path)? / 1000) as u32)
+    }
+    fn performance_state(&self) -> Result<PerformanceState, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn power_usage(&self) -> Result<u32, NvmlError> {
+        self.power.as_ref().ok_or(NvmlError::NotSupported)?.power()
+    }
+    fn enforced_power_limit(&self) -> Result<u32, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn total_energy_consumption(&self) -> Result<u64, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn pcie_replay_counter(&self) -> Result<u32, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn utilization_rates(&self) -> Result<Utilization, NvmlError> {
+        let path = self.load.as_ref().ok_or(NvmlError::NotSupported)?;
+        Ok(Utilization {
+            gpu: (number(path)? / 10) as u32,
+            memory: 0,
+        })
+    }
+    fn memory_utilization(&self) -> bool {
+        false
+    }
+    fn clock_info(&self, clock: Clock) -> Result<u32, NvmlError> {
+        match clock {
+            Clock::Graphics | Clock::SM => Ok((number(&self.frequency)? / 1_000_000) as u32),
+            _ => Err(NvmlError::NotSupported),
+        }
+    }
+    fn running_compute_processes(&self) -> Result<Vec<ProcessInfo>, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+    fn running_graphics_processes(&self) -> Result<Vec<ProcessInfo>, NvmlError> {
+        Err(NvmlError::NotSupported)
+    }
+}
@@ -0,0 +1,168 @@
+//! Terminating TLS in the exporter, for what tiny_http's TLS can't do, like verifying client
+//! certificates
+//!
+//! Connections are accepted here, and once their handshake succeeded, the plaintext is relayed
+//! to tiny_http, listening on a unix socket in a directory only the exporter can enter. Requests
+//! reach it from that socket, so they have no remote address, and per client rate limits don't
+//! apply to them.
+
+use rustls::{ServerConfig, ServerConnection};
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tiny_http::Listener;
+use tracing::debug;
+
+use crate::Result;
+
+/// For clients to finish their handshake, before they're dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Removes the socket's directory when dropped
+pub struct Terminator {
+    dir: PathBuf,
+    /// Until started
+    sockets: Option<(Listener, UnixListener)>,
+}
+
+impl Terminator {
+    /// Set up to relay connections from `listener`, which only starts threads once started
+    pub fn new(listener: Listener) -> Result<Terminator> {
+        let mut id = [0; 8];
+        std::fs::File::open("/dev/urandom")?.read_exact(&mut id)?;
+        let id: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+        let dir = std::env::temp_dir().join(format!("prometheus-nvml-exporter-{}", id));
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        let mut terminator = Terminator { dir, sockets: None };
+        let socket = UnixListener::bind(terminator.socket())?;
+        terminator.sockets = Some((listener, socket));
+        Ok(terminator)
+    }
+
+    /// The socket's directory, to hand to the user privileges are dropped to
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn socket(&self) -> PathBuf {
+        self.dir.join("http.sock")
+    }
+
+    /// Accept connections in the background, for the returned server
+    pub fn start(&mut self, config: Arc<ServerConfig>) -> Result<tiny_http::Server> {
+        let (listener, socket) = self.sockets.take().ok_or("Already started")?;
+        let server = tiny_http::Server::from_listener(socket, None)?;
+        let socket = self.socket();
+        match listener {
+            Listener::Tcp(listener) => std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).ok();
+                    let peer = stream.peer_addr().ok();
+                    let (config, socket) = (config.clone(), socket.clone());
+                    std::thread::spawn(move || {
+                        if let Err(e) = relay(stream, config, &socket) {
+                            debug!(?peer, "TLS connection failed: {}", e);
+                        }
+                    });
+                }
+            }),
+            Listener::Unix(listener) => std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).ok();
+                    let (config, socket) = (config.clone(), socket.clone());
+                    std::thread::spawn(move || {
+                        if let Err(e) = relay(stream, config, &socket) {
+                            debug!("TLS connection failed: {}", e);
+                        }
+                    });
+                }
+            }),
+        };
+        Ok(server)
+    }
+}
+
+impl Drop for Terminator {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+trait Stream: Read + Write + AsRawFd {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+}
+
+impl Stream for std::net::TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.set_read_timeout(timeout)
+    }
+}
+
+impl Stream for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.set_read_timeout(timeout)
+    }
+}
+
+fn relay(mut client: impl Stream, config: Arc<ServerConfig>, socket: &Path) -> Result<()> {
+    let mut tls = ServerConnection::new(config)?;
+    // Clients that fail verification never get to the HTTP server
+    while tls.is_handshaking() {
+        tls.complete_io(&mut client)?;
+    }
+    Stream::set_read_timeout(&client, None)?;
+    let mut server = UnixStream::connect(socket)?;
+    let mut buf = vec![0; 16384];
+    loop {
+        while tls.wants_write() {
+            tls.write_tls(&mut client)?;
+        }
+        let mut fds = [client.as_raw_fd(), server.as_raw_fd()].map(|fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e.into());
+        }
+        if fds[0].revents != 0 {
+            if tls.read_tls(&mut client)? == 0 {
+                return Ok(());
+            }
+            if let Err(e) = tls.process_new_packets() {
+                // For the alert
+                tls.write_tls(&mut client).ok();
+                return Err(e.into());
+            }
+            loop {
+                match tls.reader().read(&mut buf) {
+                    // After the client's close_notify
+                    Ok(0) => return Ok(()),
+                    Ok(n) => server.write_all(&buf[..n])?,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        if fds[1].revents != 0 {
+            match server.read(&mut buf)? {
+                0 => {
+                    tls.send_close_notify();
+                    while tls.wants_write() {
+                        tls.write_tls(&mut client)?;
+                    }
+                    return Ok(());
+                }
+                n => tls.writer().write_all(&buf[..n])?,
+            }
+        }
+    }
+}
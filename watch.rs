@@ -0,0 +1,63 @@
+//! A live table of the main readings of each GPU, like `nvidia-smi dmon`
+
+use prometheus::proto::MetricFamily;
+
+use crate::push;
+
+pub const HEADER: [&str; 8] = [
+    "UUID", "NAME", "UTIL%", "MEM MiB", "POWER W", "TEMP C", "SM MHz", "MEM MHz",
+];
+
+/// Clears the terminal and moves the cursor to the top left
+pub const CLEAR: &str = "\x1b[H\x1b[2J";
+
+/// One row per device, from the collected metrics, with - for what it doesn't report
+pub fn rows(families: &[MetricFamily]) -> Vec<[String; HEADER.len()]> {
+    let samples = push::samples(families);
+    let label = |sample: &push::Sample, name: &str| {
+        let label = sample.labels.iter().find(|(label, _)| label == name);
+        label.map(|(_, value)| value.clone())
+    };
+    let mut uuids = samples
+        .iter()
+        .filter_map(|sample| Some((label(sample, "uuid")?, label(sample, "name")?)))
+        .collect::<Vec<_>>();
+    uuids.sort();
+    uuids.dedup();
+    uuids
+        .into_iter()
+        .map(|(uuid, name)| {
+            let value = |metric: &str, clock: Option<&str>| {
+                samples.iter().find(|sample| {
+                    sample.name == metric
+                        && label(sample, "uuid").as_ref() == Some(&uuid)
+                        && (clock.is_none() || label(sample, "clock").as_deref() == clock)
+                })
+            };
+            let cell = |metric: &str, clock: Option<&str>, scale: f64| {
+                value(metric, clock)
+                    .map_or("-".into(), |sample| format!("{:.0}", sample.value * scale))
+            };
+            let memory = match (
+                value("nvml_memory_used_bytes", None),
+                value("nvml_memory_total_bytes", None),
+            ) {
+                (Some(used), Some(total)) => {
+                    let mib = |bytes: f64| bytes / (1024. * 1024.);
+                    format!("{:.0}/{:.0}", mib(used.value), mib(total.value))
+                }
+                _ => "-".into(),
+            };
+            [
+                uuid.clone(),
+                name,
+                cell("nvml_utilization_gpu", None, 100.),
+                memory,
+                cell("nvml_power_usage_current_mw", None, 1e-3),
+                cell("nvml_temp", None, 1.),
+                cell("nvml_clock_mhz", Some("sm"), 1.),
+                cell("nvml_clock_mhz", Some("memory"), 1.),
+            ]
+        })
+        .collect()
+}
@@ -0,0 +1,416 @@
+//! The web configuration file format of prometheus/exporter-toolkit, as used by node_exporter
+//! and friends via `--web.config.file`. Settings the underlying HTTP server cannot honor are
+//! rejected rather than silently ignored.
+//!
+//! tiny_http's own TLS takes a certificate and key, nothing more. For the rest, client
+//! certificates, protocol versions, cipher suites and curves, a rustls configuration is built here,
+//! for the exporter to terminate TLS itself.
+
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{DigitallySignedStruct, DistinguishedName, RootCertStore, SignatureScheme};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tiny_http::Header;
+
+use crate::Result;
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct WebConfig {
+    #[serde(default)]
+    pub tls_server_config: Option<TlsServerConfig>,
+    #[serde(default)]
+    http_server_config: HttpServerConfig,
+    #[serde(default)]
+    pub basic_auth_users: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsServerConfig {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    #[serde(default)]
+    client_auth_type: Option<String>,
+    #[serde(default)]
+    client_ca_file: Option<PathBuf>,
+    #[serde(default)]
+    min_version: Option<String>,
+    #[serde(default)]
+    max_version: Option<String>,
+    #[serde(default)]
+    cipher_suites: Vec<String>,
+    // Only meaningful together with cipher_suites
+    #[serde(default, rename = "prefer_server_cipher_suites")]
+    _prefer_server_cipher_suites: Option<bool>,
+    #[serde(default)]
+    curve_preferences: Vec<String>,
+    /// Set when tiny_http's TLS isn't enough for the above
+    #[serde(skip)]
+    pub rustls: Option<Arc<rustls::ServerConfig>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct HttpServerConfig {
+    // We only speak HTTP/1.1, which is what http2: false would ask for anyway
+    #[serde(default, rename = "http2")]
+    _http2: Option<bool>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+}
+
+/// Set by tiny_http, or by the exporter for each response
+const MANAGED_HEADERS: &[&str] = &[
+    "Connection",
+    "Content-Encoding",
+    "Content-Length",
+    "Content-Type",
+    "Date",
+    "Server",
+    "Transfer-Encoding",
+];
+
+impl WebConfig {
+    pub fn load(path: &Path) -> Result<WebConfig> {
+        let yaml = std::fs::read_to_string(path)?;
+        // Like exporter-toolkit, resolve files relative to the config file
+        WebConfig::parse(&yaml, path.parent().unwrap_or(Path::new("")))
+            .map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    fn parse(yaml: &str, base: &Path) -> Result<WebConfig> {
+        let mut config: WebConfig = serde_yaml::from_str(yaml)?;
+        if let Some(tls) = &mut config.tls_server_config {
+            tls.cert_file = base.join(&tls.cert_file);
+            tls.key_file = base.join(&tls.key_file);
+            tls.client_ca_file = tls.client_ca_file.as_ref().map(|ca| base.join(ca));
+            tls.rustls = tls.rustls()?;
+        }
+        config.headers()?;
+        Ok(config)
+    }
+
+    /// http_server_config.headers, to add to every response
+    pub fn headers(&self) -> Result<Vec<Header>> {
+        let mut headers = vec![];
+        for (name, value) in &self.http_server_config.headers {
+            if MANAGED_HEADERS.iter().any(|m| m.eq_ignore_ascii_case(name)) {
+                return Err(format!(
+                    "Header {} is set by the server and can't be configured",
+                    name
+                )
+                .into());
+            }
+            headers.push(
+                Header::from_bytes(name.as_bytes(), value.as_bytes())
+                    .map_err(|()| format!("Invalid header {}: {}", name, value))?,
+            );
+        }
+        Ok(headers)
+    }
+}
+
+/// How client certificates are asked for, and checked, by exporter-toolkit's names
+enum ClientAuth {
+    NoClientCert,
+    RequestClientCert,
+    RequireAnyClientCert,
+    VerifyClientCertIfGiven,
+    RequireAndVerifyClientCert,
+}
+
+impl TlsServerConfig {
+    /// A configuration for terminating TLS with rustls, if tiny_http's TLS can't do what is asked
+    fn rustls(&self) -> Result<Option<Arc<rustls::ServerConfig>>> {
+        let client_auth = match self.client_auth_type.as_deref() {
+            None | Some("NoClientCert") => ClientAuth::NoClientCert,
+            Some("RequestClientCert") => ClientAuth::RequestClientCert,
+            Some("RequireAnyClientCert") => ClientAuth::RequireAnyClientCert,
+            Some("VerifyClientCertIfGiven") => ClientAuth::VerifyClientCertIfGiven,
+            Some("RequireAndVerifyClientCert") => ClientAuth::RequireAndVerifyClientCert,
+            Some(other) => return Err(format!("Unknown client_auth_type {}", other).into()),
+        };
+        let min = version("min_version", self.min_version.as_deref(), 12)?;
+        let max = version("max_version", self.max_version.as_deref(), 13)?;
+        let versions: Vec<_> = [(12, &rustls::version::TLS12), (13, &rustls::version::TLS13)]
+            .into_iter()
+            .filter(|(minor, _)| (min..=max).contains(minor))
+            .map(|(_, version)| version)
+            .collect();
+        if versions.is_empty() {
+            return Err("min_version is above max_version".into());
+        }
+        if matches!(client_auth, ClientAuth::NoClientCert)
+            && versions.len() == 2
+            && self.cipher_suites.is_empty()
+            && self.curve_preferences.is_empty()
+        {
+            if self.client_ca_file.is_some() {
+                // As exporter-toolkit does, rather than accept anyone
+                return Err("client_ca_file is set, but client_auth_type is NoClientCert".into());
+            }
+            return Ok(None);
+        }
+
+        let mut provider = rustls::crypto::ring::default_provider();
+        if !self.cipher_suites.is_empty() {
+            // Like Go, the TLS 1.3 suites aren't configurable, and cipher_suites are for TLS 1.2
+            let mut suites: Vec<_> = provider
+                .cipher_suites
+                .iter()
+                .filter(|s| s.tls13().is_some())
+                .copied()
+                .collect();
+            for name in &self.cipher_suites {
+                let suite = provider
+                    .cipher_suites
+                    .iter()
+                    .find(|s| {
+                        let rustls = format!("{:?}", s.suite());
+                        // Go also has the ChaCha20 ones without the hash
+                        s.tls13().is_none()
+                            && (&rustls == name || rustls == format!("{}_SHA256", name))
+                    })
+                    .ok_or_else(|| format!("Cipher suite {} is not supported", name))?;
+                suites.push(*suite);
+            }
+            provider.cipher_suites = suites;
+        }
+        if !self.curve_preferences.is_empty() {
+            let mut groups = vec![];
+            for name in &self.curve_preferences {
+                let group = match name.as_str() {
+                    "X25519" => rustls::NamedGroup::X25519,
+                    "CurveP256" => rustls::NamedGroup::secp256r1,
+                    "CurveP384" => rustls::NamedGroup::secp384r1,
+                    _ => return Err(format!("Curve {} is not supported", name).into()),
+                };
+                groups.extend(provider.kx_groups.iter().find(|g| g.name() == group));
+            }
+            provider.kx_groups = groups;
+        }
+        let provider = Arc::new(provider);
+
+        let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&versions)?;
+        let verifier: Arc<dyn ClientCertVerifier> = match client_auth {
+            ClientAuth::NoClientCert => Arc::new(rustls::server::NoClientAuth),
+            ClientAuth::RequestClientCert | ClientAuth::RequireAnyClientCert => {
+                Arc::new(Unverified {
+                    required: matches!(client_auth, ClientAuth::RequireAnyClientCert),
+                    algorithms: provider.signature_verification_algorithms,
+                })
+            }
+            ClientAuth::VerifyClientCertIfGiven | ClientAuth::RequireAndVerifyClientCert => {
+                let path = self.client_ca_file.as_ref().ok_or_else(|| {
+                    format!(
+                        "client_auth_type {} needs a client_ca_file",
+                        self.client_auth_type.as_deref().unwrap_or_default()
+                    )
+                })?;
+                let mut roots = RootCertStore::empty();
+                for cert in certificates(path)? {
+                    roots.add(cert)?;
+                }
+                let builder =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                match client_auth {
+                    ClientAuth::VerifyClientCertIfGiven => {
+                        builder.allow_unauthenticated().build()?
+                    }
+                    _ => builder.build()?,
+                }
+            }
+        };
+        let key = PrivateKeyDer::from_pem_file(&self.key_file)
+            .map_err(|e| format!("Failed to read {}: {}", self.key_file.display(), e))?;
+        let mut config = builder
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certificates(&self.cert_file)?, key)?;
+        config.ignore_client_order = self._prefer_server_cipher_suites.unwrap_or(true);
+        Ok(Some(Arc::new(config)))
+    }
+}
+
+/// The minor version, from 1.x
+fn version(option: &str, name: Option<&str>, default: u8) -> Result<u8> {
+    match name {
+        None => Ok(default),
+        Some("TLS12") => Ok(12),
+        Some("TLS13") => Ok(13),
+        Some(other @ ("TLS10" | "TLS11")) => Err(format!(
+            "{} {} is not supported, only TLS 1.2 and 1.3 are",
+            option, other
+        )
+        .into()),
+        Some(other) => Err(format!("Unknown {} {}", option, other).into()),
+    }
+}
+
+fn certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates in {}", path.display()).into());
+    }
+    Ok(certs)
+}
+
+/// Asks for a certificate and checks the client has its key, but not who issued it, for
+/// RequestClientCert and RequireAnyClientCert
+#[derive(Debug)]
+struct Unverified {
+    required: bool,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ClientCertVerifier for Unverified {
+    fn client_auth_mandatory(&self) -> bool {
+        self.required
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> std::result::Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(yaml: &str) -> String {
+        match WebConfig::parse(yaml, Path::new("/nonexistent")) {
+            Ok(_) => panic!("{} was accepted", yaml),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    const TLS: &str = "tls_server_config: {cert_file: c.pem, key_file: k.pem";
+
+    #[test]
+    fn tiny_http() {
+        let config = WebConfig::parse(&format!("{}}}", TLS), Path::new("/etc")).unwrap();
+        let tls = config.tls_server_config.unwrap();
+        assert_eq!(tls.cert_file, Path::new("/etc/c.pem"));
+        assert!(tls.rustls.is_none());
+        let config =
+            WebConfig::parse(&format!("{}, min_version: TLS12}}", TLS), Path::new("")).unwrap();
+        assert!(config.tls_server_config.unwrap().rustls.is_none());
+    }
+
+    #[test]
+    fn client_auth() {
+        assert_eq!(
+            error(&format!(
+                "{}, client_auth_type: VerifyClientCertIfGiven}}",
+                TLS
+            )),
+            "client_auth_type VerifyClientCertIfGiven needs a client_ca_file"
+        );
+        assert_eq!(
+            error(&format!("{}, client_ca_file: ca.pem}}", TLS)),
+            "client_ca_file is set, but client_auth_type is NoClientCert"
+        );
+        assert_eq!(
+            error(&format!("{}, client_auth_type: Sometimes}}", TLS)),
+            "Unknown client_auth_type Sometimes"
+        );
+        // Configured for rustls, which fails at reading the files
+        let e = error(&format!(
+            "{}, client_auth_type: RequireAnyClientCert}}",
+            TLS
+        ));
+        assert!(e.starts_with("Failed to read /nonexistent/k.pem"), "{}", e);
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(
+            error(&format!("{}, min_version: TLS10}}", TLS)),
+            "min_version TLS10 is not supported, only TLS 1.2 and 1.3 are"
+        );
+        assert_eq!(
+            error(&format!(
+                "{}, min_version: TLS13, max_version: TLS12}}",
+                TLS
+            )),
+            "min_version is above max_version"
+        );
+    }
+
+    #[test]
+    fn suites_and_curves() {
+        assert_eq!(
+            error(&format!(
+                "{}, cipher_suites: [TLS_RSA_WITH_RC4_128_SHA]}}",
+                TLS
+            )),
+            "Cipher suite TLS_RSA_WITH_RC4_128_SHA is not supported"
+        );
+        assert_eq!(
+            error(&format!("{}, curve_preferences: [CurveP521]}}", TLS)),
+            "Curve CurveP521 is not supported"
+        );
+        let e = error(&format!(
+            "{}, cipher_suites: [TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305], curve_preferences: [X25519]}}",
+            TLS
+        ));
+        assert!(e.starts_with("Failed to read"), "{}", e);
+    }
+
+    #[test]
+    fn headers() {
+        let config = WebConfig::parse(
+            "http_server_config: {headers: {X-Frame-Options: deny}}",
+            Path::new(""),
+        )
+        .unwrap();
+        let headers = config.headers().unwrap();
+        assert_eq!(headers.len(), 1);
+        assert!(headers[0].field.equiv("x-frame-options"));
+        assert_eq!(
+            error("http_server_config: {headers: {content-length: '1'}}"),
+            "Header content-length is set by the server and can't be configured"
+        );
+    }
+}